@@ -12,6 +12,15 @@ pub enum GprIndex {}
 /// Hypercall message.
 pub enum HyperCallMsg {}
 
+/// Hypercall handler define.
+pub type HyperCallHandler = ();
+
+/// Hypercraft SBI extension ID.
+pub const EID_HYPERCRAFT: usize = 0;
+
+/// SBI machine identity define.
+pub struct SbiIdentity {}
+
 /// Nested page table define.
 pub struct NestedPageTable<I: PagingIf> {
     _marker: core::marker::PhantomData<I>,
@@ -34,10 +43,77 @@ pub struct VM<H: HyperCraftHal> {
     _marker: core::marker::PhantomData<H>,
 }
 
+/// Emulated MMIO device define.
+pub trait MmioDevice {}
+
+/// Passthrough device define.
+pub struct PassthroughDevice {}
+
+/// Guest memory accessor define.
+pub struct VmPages;
+
+/// Virtio devices define.
+pub mod virtio {}
+
 /// PerCpu define.
 pub struct PerCpu<H: HyperCraftHal> {
     _marker: core::marker::PhantomData<H>,
 }
 
+/// Guest crash report define.
+pub struct CrashReport;
+
+/// VM exit statistics define.
+pub struct ExitStats;
+
+/// VM exit record define.
+pub struct ExitRecord;
+
+/// VM exit trace define.
+pub struct ExitTrace;
+
+/// Page fault region define.
+pub enum FaultRegion {}
+
+/// Length of VM exit trace.
+pub const EXIT_TRACE_LEN: usize = 0;
+
+/// GDB stub action define.
+pub enum GdbAction {}
+
+/// GDB connection define.
+pub trait GdbConnection {}
+
+/// GDB stub define.
+pub struct GdbStub<C: GdbConnection> {
+    _marker: core::marker::PhantomData<C>,
+}
+
+/// Migration transport define.
+pub trait MigrationTransport {}
+
+/// In-memory migration transport define.
+pub struct MemoryTransport;
+
+/// Migration source define.
+pub struct MigrationSource;
+
+/// Migration destination define.
+pub struct MigrationDestination;
+
+/// Snapshot writer define.
+pub struct SnapshotWriter;
+
+/// Snapshot reader define.
+pub struct SnapshotReader<'a> {
+    _marker: core::marker::PhantomData<&'a [u8]>,
+}
+
+/// Debug trigger kind define.
+pub enum TriggerKind {}
+
 /// VM exit information.
 pub struct VmExitInfo {}
+
+/// VM exit reason reported to the VMM.
+pub enum VmExitReason {}
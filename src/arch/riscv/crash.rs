@@ -0,0 +1,193 @@
+//! Crash reports of guests, describing the state of a vCPU that hit a fault the hypervisor cannot
+//! handle.
+//!
+//! The report is taken on the hart the vCPU ran on, right after its exit: the VS-level CSRs and
+//! the VS-stage page table used to read guest memory are those live on the hart. The guest stack
+//! is walked with the frame pointer `s0`, assuming the frame record of the RISC-V psABI: the
+//! return address at `fp - 8` and the caller's frame pointer at `fp - 16`. Guests built without
+//! frame pointers give a partial or empty backtrace.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::debug;
+use super::regs::GprIndex;
+use super::vcpu::VCpu;
+use super::vm_pages::VmPages;
+use super::{RiscvCsrTrait, CSR};
+use crate::{GuestVirtAddr, HyperCraftHal, HyperResult};
+
+/// The most return addresses walked on the guest stack.
+const MAX_FRAMES: usize = 32;
+
+/// The ABI names of the GPRs.
+const GPR_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// The state of a vCPU whose fault stopped its VM, see `VM::crash_report`.
+#[derive(Clone, Debug)]
+pub struct CrashReport {
+    /// The vCPU that faulted.
+    pub vcpu_id: usize,
+    /// What the hypervisor failed to handle.
+    pub reason: String,
+    /// The general purpose registers, indexed by number.
+    pub gprs: [usize; 32],
+    /// The address of the faulting instruction.
+    pub pc: GuestVirtAddr,
+    /// The guest `sstatus`.
+    pub sstatus: usize,
+    /// The guest `hstatus`.
+    pub hstatus: usize,
+    /// The guest's `vsstatus`.
+    pub vsstatus: usize,
+    /// The guest's `vsie`.
+    pub vsie: usize,
+    /// The guest's `vstvec`.
+    pub vstvec: usize,
+    /// The guest's `vsscratch`.
+    pub vsscratch: usize,
+    /// The guest's `vsepc`.
+    pub vsepc: usize,
+    /// The guest's `vscause`.
+    pub vscause: usize,
+    /// The guest's `vstval`.
+    pub vstval: usize,
+    /// The guest's `vsatp`.
+    pub vsatp: usize,
+    /// The trap cause.
+    pub scause: usize,
+    /// The trap value.
+    pub stval: usize,
+    /// The guest physical address of a guest page fault, shifted right by 2.
+    pub htval: usize,
+    /// The transformed trapping instruction, if the hardware reports it.
+    pub htinst: usize,
+    /// The faulting instruction, if it could be read from guest memory.
+    pub inst: Option<u32>,
+    /// The faulting instruction decoded, if it is valid.
+    pub disassembly: Option<String>,
+    /// The return addresses found on the guest stack, from the innermost frame.
+    pub backtrace: Vec<GuestVirtAddr>,
+}
+
+impl CrashReport {
+    /// Takes the report of `vcpu_id`, which just exited to the hypervisor on this hart.
+    pub(crate) fn new<H: HyperCraftHal>(
+        vcpu_id: usize,
+        vcpu: &mut VCpu<H>,
+        mem: &VmPages,
+        reason: String,
+    ) -> Self {
+        let mut gprs = [0; 32];
+        for (index, gpr) in gprs.iter_mut().enumerate() {
+            *gpr = vcpu.get_gpr(GprIndex::from_raw(index as u32).unwrap());
+        }
+        let regs = vcpu.regs();
+        let pc = regs.guest_regs.sepc;
+        let inst =
+            mem.fetch_guest_instruction(pc).ok().map(
+                |inst| match riscv_decode::instruction_length(inst as u16) {
+                    2 => inst & 0xffff,
+                    _ => inst,
+                },
+            );
+        let disassembly = inst
+            .and_then(|inst| riscv_decode::decode(inst).ok())
+            .map(|decoded| format!("{:?}", decoded));
+        Self {
+            vcpu_id,
+            reason,
+            gprs,
+            pc,
+            sstatus: regs.guest_regs.sstatus,
+            hstatus: regs.guest_regs.hstatus,
+            vsstatus: CSR.vsstatus.get_value(),
+            vsie: CSR.vsie.get_value(),
+            vstvec: CSR.vstvec.get_value(),
+            vsscratch: CSR.vsscratch.get_value(),
+            vsepc: CSR.vsepc.get_value(),
+            vscause: CSR.vscause.get_value(),
+            vstval: CSR.vstval.get_value(),
+            vsatp: CSR.vsatp.get_value(),
+            scause: regs.trap_csrs.scause,
+            stval: regs.trap_csrs.stval,
+            htval: regs.trap_csrs.htval,
+            htinst: regs.trap_csrs.htinst,
+            inst,
+            disassembly,
+            backtrace: walk_stack(mem, gprs[GprIndex::S0 as usize]),
+        }
+    }
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "vCPU {} crashed: {}", self.vcpu_id, self.reason)?;
+        write!(f, "pc: {:#018x}", self.pc)?;
+        match (self.inst, self.disassembly.as_ref()) {
+            (Some(inst), Some(disassembly)) => writeln!(f, " inst: {:#x} {}", inst, disassembly)?,
+            (Some(inst), None) => writeln!(f, " inst: {:#x} (invalid)", inst)?,
+            (None, _) => writeln!(f, " inst: (unreadable)")?,
+        }
+        for (names, values) in GPR_NAMES.chunks(4).zip(self.gprs.chunks(4)) {
+            for (name, value) in names.iter().zip(values) {
+                write!(f, "{:>4}: {:#018x} ", name, value)?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(
+            f,
+            "sstatus: {:#x} hstatus: {:#x} scause: {:#x} stval: {:#x} htval: {:#x} htinst: {:#x}",
+            self.sstatus, self.hstatus, self.scause, self.stval, self.htval, self.htinst
+        )?;
+        writeln!(
+            f,
+            "vsstatus: {:#x} vsie: {:#x} vstvec: {:#x} vsscratch: {:#x}",
+            self.vsstatus, self.vsie, self.vstvec, self.vsscratch
+        )?;
+        writeln!(
+            f,
+            "vsepc: {:#x} vscause: {:#x} vstval: {:#x} vsatp: {:#x}",
+            self.vsepc, self.vscause, self.vstval, self.vsatp
+        )?;
+        writeln!(f, "backtrace:")?;
+        writeln!(f, "  #0 {:#018x}", self.pc)?;
+        for (depth, ra) in self.backtrace.iter().enumerate() {
+            writeln!(f, "  #{} {:#018x}", depth + 1, ra)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the return addresses of the frames on the guest stack from the frame pointer `fp`.
+fn walk_stack(mem: &VmPages, mut fp: GuestVirtAddr) -> Vec<GuestVirtAddr> {
+    let mut backtrace = Vec::new();
+    while backtrace.len() < MAX_FRAMES && fp >= 16 && fp % 8 == 0 {
+        let (ra, caller_fp) = match (read_guest(mem, fp - 8), read_guest(mem, fp - 16)) {
+            (Ok(ra), Ok(caller_fp)) => (ra, caller_fp),
+            _ => break,
+        };
+        if ra == 0 {
+            break;
+        }
+        backtrace.push(ra);
+        // Callers' frames are higher up the stack, anything else is not a frame record.
+        if caller_fp <= fp {
+            break;
+        }
+        fp = caller_fp;
+    }
+    backtrace
+}
+
+/// Reads the doubleword at the guest virtual address `gva`, of the vCPU live on this hart.
+fn read_guest(mem: &VmPages, gva: GuestVirtAddr) -> HyperResult<usize> {
+    let gpa = debug::translate(mem, CSR.vsatp.get_value(), gva)?;
+    mem.read_u64(gpa).map(|val| val as usize)
+}
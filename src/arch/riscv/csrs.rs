@@ -11,6 +11,21 @@ pub struct CSR {
     pub hideleg: ReadWriteCsr<hideleg::Register, CSR_HIDELEG>,
     pub hcounteren: ReadWriteCsr<hcounteren::Register, CSR_HCOUNTEREN>,
     pub hvip: ReadWriteCsr<hvip::Register, CSR_HVIP>,
+    pub hgeie: ReadWriteCsr<hgeie::Register, CSR_HGEIE>,
+    pub hgeip: ReadWriteCsr<hgeie::Register, CSR_HGEIP>,
+    pub hip: ReadWriteCsr<(), CSR_HIP>,
+    pub hgatp: ReadWriteCsr<(), CSR_HGATP>,
+    pub htimedelta: ReadWriteCsr<(), CSR_HTIMEDELTA>,
+    pub vsstatus: ReadWriteCsr<(), CSR_VSSTATUS>,
+    pub vsie: ReadWriteCsr<(), CSR_VSIE>,
+    pub vstvec: ReadWriteCsr<(), CSR_VSTVEC>,
+    pub vsscratch: ReadWriteCsr<(), CSR_VSSCRATCH>,
+    pub vsepc: ReadWriteCsr<(), CSR_VSEPC>,
+    pub vscause: ReadWriteCsr<(), CSR_VSCAUSE>,
+    pub vstval: ReadWriteCsr<(), CSR_VSTVAL>,
+    pub vsiselect: ReadWriteCsr<(), CSR_VSISELECT>,
+    pub vsireg: ReadWriteCsr<(), CSR_VSIREG>,
+    pub vsatp: ReadWriteCsr<(), CSR_VSATP>,
 }
 
 #[allow(clippy::identity_op, clippy::erasing_op)]
@@ -21,6 +36,21 @@ pub const CSR: &CSR = &CSR {
     hideleg: ReadWriteCsr::new(),
     hcounteren: ReadWriteCsr::new(),
     hvip: ReadWriteCsr::new(),
+    hgeie: ReadWriteCsr::new(),
+    hgeip: ReadWriteCsr::new(),
+    hip: ReadWriteCsr::new(),
+    hgatp: ReadWriteCsr::new(),
+    htimedelta: ReadWriteCsr::new(),
+    vsstatus: ReadWriteCsr::new(),
+    vsie: ReadWriteCsr::new(),
+    vstvec: ReadWriteCsr::new(),
+    vsscratch: ReadWriteCsr::new(),
+    vsepc: ReadWriteCsr::new(),
+    vscause: ReadWriteCsr::new(),
+    vstval: ReadWriteCsr::new(),
+    vsiselect: ReadWriteCsr::new(),
+    vsireg: ReadWriteCsr::new(),
+    vsatp: ReadWriteCsr::new(),
 };
 
 /// Trait defining the possible operations on a RISC-V CSR.
@@ -260,6 +290,13 @@ pub mod defs {
     ]
     ];
 
+    // Hypervisor guest external interrupt enable/pending. Bit N is guest interrupt file N.
+    register_bitfields![usize,
+    pub hgeie [
+        files OFFSET(1) NUMBITS(63) [],
+    ]
+    ];
+
     // Hypervisor virtual interrupt pending.
     register_bitfields![usize,
     pub hvip [
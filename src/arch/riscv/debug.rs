@@ -0,0 +1,467 @@
+//! Debugging of guests: software breakpoints patched into guest code, hardware breakpoints and
+//! watchpoints set with debug triggers, and single-stepping.
+//!
+//! Guest virtual addresses are translated through the VS-stage page table of the vCPU given, so
+//! that code can be patched whatever the permissions the guest gave its pages. Single-stepping
+//! uses an instruction count trigger if the hart has one. Otherwise it places temporary
+//! breakpoints at the possible successors of the instruction at the vCPU's pc, and a trap or
+//! interrupt taken by the guest in between is not stepped into: the vCPU stops once the handler
+//! returns to one of the successors. Another vCPU reaching one of them runs the original
+//! instruction, the breakpoint being lifted until the stepping vCPU runs again.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use super::regs::{GeneralPurposeRegisters, GprIndex};
+use super::triggers::{TriggerKind, Triggers};
+use super::vcpu::GuestVsCsrs;
+use super::vm_pages::VmPages;
+use crate::memory::PAGE_SIZE_4K;
+use crate::vcpus::VM_CPUS_MAX;
+use crate::{GuestPhysAddr, GuestVirtAddr, HyperError, HyperResult};
+
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u16 = 0x9002;
+const SRET: u32 = 0x1020_0073;
+
+// VS-stage page table entry bits.
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_X: u64 = 1 << 3;
+const PTE_PPN_MASK: u64 = (1 << 44) - 1;
+
+/// A breakpoint instruction patched into guest code.
+struct Patch {
+    // Where the instruction is in guest physical memory.
+    gpa: GuestPhysAddr,
+    // The instruction replaced, whose length is that of the breakpoint.
+    orig: [u8; 4],
+    len: usize,
+    // Whether the debugger set a breakpoint here.
+    user: bool,
+    // Bit `i` is set if vCPU `i` single-steps to here.
+    steppers: usize,
+    // Whether the breakpoint is in guest memory, rather than lifted for a vCPU other than the
+    // steppers to run the instruction.
+    armed: bool,
+}
+
+/// How a vCPU goes on after taking a breakpoint exception.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakpointHit {
+    /// The breakpoint or trigger is the debugger's, and the vCPU stops.
+    Stop,
+    /// The breakpoint is the temporary one of another vCPU, lifted for this one to run the
+    /// instruction.
+    Retry,
+    /// The breakpoint is the guest's own, and the exception is delivered to it.
+    Guest,
+}
+
+/// The debugging state of a VM.
+#[derive(Default)]
+pub struct GuestDebug {
+    patches: BTreeMap<GuestVirtAddr, Patch>,
+    // The temporary breakpoints of each vCPU being single-stepped.
+    steps: [Vec<GuestVirtAddr>; VM_CPUS_MAX],
+    triggers: Triggers,
+}
+
+impl GuestDebug {
+    /// Reads `buf.len()` bytes of guest memory at `gva`, translated with `vsatp`, as they were
+    /// before breakpoints were patched in.
+    pub fn read_memory(
+        &self,
+        mem: &VmPages,
+        vsatp: usize,
+        gva: GuestVirtAddr,
+        buf: &mut [u8],
+    ) -> HyperResult<()> {
+        for_each_page(gva, buf.len(), |addr, range| {
+            mem.copy_from_guest(&mut buf[range], translate(mem, vsatp, addr)?)
+        })?;
+        let end = gva + buf.len();
+        for (&addr, patch) in self.patches.range(gva.saturating_sub(3)..end) {
+            for i in 0..patch.len {
+                if (gva..end).contains(&(addr + i)) {
+                    buf[addr + i - gva] = patch.orig[i];
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `data` to the guest memory at `gva`, translated with `vsatp`, keeping the
+    /// breakpoints in it.
+    pub fn write_memory(
+        &mut self,
+        mem: &VmPages,
+        vsatp: usize,
+        gva: GuestVirtAddr,
+        data: &[u8],
+    ) -> HyperResult<()> {
+        for_each_page(gva, data.len(), |addr, range| {
+            mem.copy_to_guest(translate(mem, vsatp, addr)?, &data[range])
+        })?;
+        let end = gva + data.len();
+        for (_, patch) in self.patches.range_mut(gva.saturating_sub(3)..end) {
+            mem.copy_from_guest(&mut patch.orig[..patch.len], patch.gpa)?;
+            if patch.armed {
+                write_breakpoint(mem, patch)?;
+            }
+        }
+        sync_icache();
+        Ok(())
+    }
+
+    /// Sets a breakpoint at `gva`, translated with `vsatp`.
+    pub fn set_breakpoint(
+        &mut self,
+        mem: &VmPages,
+        vsatp: usize,
+        gva: GuestVirtAddr,
+    ) -> HyperResult<()> {
+        let patch = self.patch(mem, vsatp, gva)?;
+        patch.user = true;
+        if !patch.armed {
+            write_breakpoint(mem, patch)?;
+            patch.armed = true;
+        }
+        sync_icache();
+        Ok(())
+    }
+
+    /// Clears the breakpoint at `gva`.
+    pub fn clear_breakpoint(&mut self, mem: &VmPages, gva: GuestVirtAddr) -> HyperResult<()> {
+        let patch = self
+            .patches
+            .get_mut(&gva)
+            .filter(|patch| patch.user)
+            .ok_or(HyperError::NotFound)?;
+        patch.user = false;
+        self.unpatch_unused(mem, gva)?;
+        sync_icache();
+        Ok(())
+    }
+
+    /// Makes `vcpu_id`, which is about to run the instruction at `pc` with `gprs` and the VS-level
+    /// CSRs `vs`, stop after it.
+    pub fn step(
+        &mut self,
+        mem: &VmPages,
+        vcpu_id: usize,
+        pc: GuestVirtAddr,
+        gprs: &GeneralPurposeRegisters,
+        vs: &GuestVsCsrs,
+    ) -> HyperResult<()> {
+        self.cancel_step(mem, vcpu_id)?;
+        match self.triggers.step(vcpu_id) {
+            Err(HyperError::NotSupported) => {}
+            result => return result,
+        }
+        let mut raw = [0u8; 4];
+        self.read_memory(mem, vs.vsatp, pc, &mut raw[..2])?;
+        if raw[0] & 0b11 == 0b11 {
+            self.read_memory(mem, vs.vsatp, pc + 2, &mut raw[2..])?;
+        }
+        for next in successors(u32::from_le_bytes(raw), pc, gprs, vs.vsepc) {
+            if self.steps[vcpu_id].contains(&next) {
+                continue;
+            }
+            self.patch(mem, vs.vsatp, next)?.steppers |= 1 << vcpu_id;
+            self.steps[vcpu_id].push(next);
+        }
+        sync_icache();
+        Ok(())
+    }
+
+    /// Handles `vcpu_id` taking a breakpoint exception at `pc` with trap value `tval`. The vCPU
+    /// stops, ending its single-step if any, at the debugger's breakpoints and its own temporary
+    /// ones. Those of the other vCPUs are lifted for it to run the instruction again.
+    pub fn hit(
+        &mut self,
+        mem: &VmPages,
+        vcpu_id: usize,
+        pc: GuestVirtAddr,
+        tval: usize,
+    ) -> HyperResult<BreakpointHit> {
+        let (patched, lifted) = match self.patches.get_mut(&pc) {
+            Some(patch) if patch.user || patch.steppers & (1 << vcpu_id) != 0 => (true, false),
+            Some(patch) => {
+                mem.copy_to_guest(patch.gpa, &patch.orig[..patch.len])?;
+                patch.armed = false;
+                sync_icache();
+                (false, true)
+            }
+            None => (false, false),
+        };
+        if patched {
+            self.cancel_step(mem, vcpu_id)?;
+        }
+        let triggered = self.triggers.hit(vcpu_id, pc, tval)?;
+        Ok(if patched || triggered {
+            BreakpointHit::Stop
+        } else if lifted {
+            BreakpointHit::Retry
+        } else {
+            BreakpointHit::Guest
+        })
+    }
+
+    /// Puts back the temporary breakpoints of `vcpu_id` lifted for other vCPUs, as it is about to
+    /// run.
+    pub fn arm_steps(&mut self, mem: &VmPages, vcpu_id: usize) -> HyperResult<()> {
+        let mut armed = false;
+        for gva in self.steps[vcpu_id].iter() {
+            if let Some(patch) = self.patches.get_mut(gva).filter(|patch| !patch.armed) {
+                write_breakpoint(mem, patch)?;
+                patch.armed = true;
+                armed = true;
+            }
+        }
+        if armed {
+            sync_icache();
+        }
+        Ok(())
+    }
+
+    /// Sets a trigger of `kind` on the `len` bytes from `gva`.
+    pub fn set_trigger(
+        &mut self,
+        kind: TriggerKind,
+        gva: GuestVirtAddr,
+        len: usize,
+    ) -> HyperResult<()> {
+        self.triggers.insert(kind, gva, len)
+    }
+
+    /// Clears the trigger of `kind` on the `len` bytes from `gva`.
+    pub fn clear_trigger(
+        &mut self,
+        kind: TriggerKind,
+        gva: GuestVirtAddr,
+        len: usize,
+    ) -> HyperResult<()> {
+        self.triggers.remove(kind, gva, len)
+    }
+
+    /// Returns the watchpoint `vcpu_id` last stopped at and the address it accessed, if any.
+    pub fn watch_hit(&self, vcpu_id: usize) -> Option<(TriggerKind, GuestVirtAddr)> {
+        self.triggers.watch_hit(vcpu_id)
+    }
+
+    /// Enables the triggers for `vcpu_id`, which is about to run on this hart.
+    pub fn enable_triggers(&self, vcpu_id: usize) {
+        if !self.triggers.is_empty() {
+            self.triggers.enable(vcpu_id);
+        }
+    }
+
+    /// Disables the triggers once `vcpu_id` exits.
+    pub fn disable_triggers(&self, vcpu_id: usize) {
+        if !self.triggers.is_empty() {
+            self.triggers.disable(vcpu_id);
+        }
+    }
+
+    /// Removes all the breakpoints from guest code and the triggers.
+    pub fn clear_all(&mut self, mem: &VmPages) -> HyperResult<()> {
+        self.triggers.clear()?;
+        for patch in self.patches.values() {
+            mem.copy_to_guest(patch.gpa, &patch.orig[..patch.len])?;
+        }
+        self.patches.clear();
+        self.steps = Default::default();
+        sync_icache();
+        Ok(())
+    }
+
+    /// Removes the temporary breakpoints of `vcpu_id`.
+    fn cancel_step(&mut self, mem: &VmPages, vcpu_id: usize) -> HyperResult<()> {
+        for gva in core::mem::take(&mut self.steps[vcpu_id]) {
+            if let Some(patch) = self.patches.get_mut(&gva) {
+                patch.steppers &= !(1 << vcpu_id);
+                self.unpatch_unused(mem, gva)?;
+            }
+        }
+        sync_icache();
+        Ok(())
+    }
+
+    /// Returns the breakpoint at `gva`, translated with `vsatp`, patching it in if there is none
+    /// yet.
+    fn patch(
+        &mut self,
+        mem: &VmPages,
+        vsatp: usize,
+        gva: GuestVirtAddr,
+    ) -> HyperResult<&mut Patch> {
+        if !self.patches.contains_key(&gva) {
+            let gpa = translate(mem, vsatp, gva)?;
+            let mut orig = [0u8; 4];
+            mem.copy_from_guest(&mut orig[..2], gpa)?;
+            let len = if orig[0] & 0b11 == 0b11 { 4 } else { 2 };
+            if len == 4 {
+                // A patch must not straddle a page, whose next one may be mapped elsewhere.
+                if gva % PAGE_SIZE_4K > PAGE_SIZE_4K - 4 {
+                    return Err(HyperError::NotSupported);
+                }
+                mem.copy_from_guest(&mut orig[2..], gpa + 2)?;
+            }
+            let patch = Patch {
+                gpa,
+                orig,
+                len,
+                user: false,
+                steppers: 0,
+                armed: true,
+            };
+            write_breakpoint(mem, &patch)?;
+            self.patches.insert(gva, patch);
+        }
+        Ok(self.patches.get_mut(&gva).unwrap())
+    }
+
+    /// Restores the instruction at `gva` if no breakpoint is left there.
+    fn unpatch_unused(&mut self, mem: &VmPages, gva: GuestVirtAddr) -> HyperResult<()> {
+        if let Some(patch) = self
+            .patches
+            .get(&gva)
+            .filter(|patch| !patch.user && patch.steppers == 0)
+        {
+            mem.copy_to_guest(patch.gpa, &patch.orig[..patch.len])?;
+            self.patches.remove(&gva);
+        }
+        Ok(())
+    }
+}
+
+/// Translates `gva` through the VS-stage page table of `vsatp`.
+pub fn translate(mem: &VmPages, vsatp: usize, gva: GuestVirtAddr) -> HyperResult<GuestPhysAddr> {
+    let levels = match vsatp >> 60 {
+        0 => return Ok(gva),
+        8 => 3,
+        9 => 4,
+        10 => 5,
+        _ => return Err(HyperError::NotSupported),
+    };
+    let mut table = (vsatp & PTE_PPN_MASK as usize) << 12;
+    for level in (0..levels).rev() {
+        let shift = 12 + 9 * level;
+        let pte = mem.read_u64(table + ((gva >> shift) & 0x1ff) * 8)?;
+        if pte & PTE_V == 0 {
+            return Err(HyperError::PageFault);
+        }
+        let addr = (((pte >> 10) & PTE_PPN_MASK) << 12) as usize;
+        if pte & (PTE_R | PTE_X) != 0 {
+            let offset_mask = (1 << shift) - 1;
+            return Ok(addr & !offset_mask | gva & offset_mask);
+        }
+        table = addr;
+    }
+    Err(HyperError::PageFault)
+}
+
+/// Calls `f` with the guest virtual address and the range of offsets of each page-sized chunk of
+/// `gva..gva + len`.
+fn for_each_page(
+    gva: GuestVirtAddr,
+    len: usize,
+    mut f: impl FnMut(GuestVirtAddr, core::ops::Range<usize>) -> HyperResult<()>,
+) -> HyperResult<()> {
+    let mut offset = 0;
+    while offset < len {
+        let addr = gva + offset;
+        let chunk = (PAGE_SIZE_4K - addr % PAGE_SIZE_4K).min(len - offset);
+        f(addr, offset..offset + chunk)?;
+        offset += chunk;
+    }
+    Ok(())
+}
+
+fn write_breakpoint(mem: &VmPages, patch: &Patch) -> HyperResult<()> {
+    if patch.len == 4 {
+        mem.write_u32(patch.gpa, EBREAK)
+    } else {
+        mem.write_u16(patch.gpa, C_EBREAK)
+    }
+}
+
+/// Makes the instructions patched visible to the guest when it next runs on this hart.
+fn sync_icache() {
+    unsafe { core::arch::asm!("fence.i") };
+}
+
+/// Returns the addresses the instruction `inst` at `pc` may continue at, with `gprs` and `vsepc`
+/// as the registers it runs with.
+fn successors(
+    inst: u32,
+    pc: GuestVirtAddr,
+    gprs: &GeneralPurposeRegisters,
+    vsepc: usize,
+) -> Vec<GuestVirtAddr> {
+    let reg = |index: u32| gprs.reg(GprIndex::from_raw(index & 0x1f).unwrap());
+    if inst & 0b11 != 0b11 {
+        let inst = inst & 0xffff;
+        let next = pc + 2;
+        return match (inst & 0b11, (inst >> 13) & 0b111) {
+            // c.j
+            (0b01, 0b101) => {
+                let imm = ((inst >> 12) & 1) << 11
+                    | ((inst >> 11) & 1) << 4
+                    | ((inst >> 9) & 0b11) << 8
+                    | ((inst >> 8) & 1) << 10
+                    | ((inst >> 7) & 1) << 6
+                    | ((inst >> 6) & 1) << 7
+                    | ((inst >> 3) & 0b111) << 1
+                    | ((inst >> 2) & 1) << 5;
+                alloc::vec![pc.wrapping_add(sign_extend(imm, 12))]
+            }
+            // c.beqz, c.bnez
+            (0b01, 0b110 | 0b111) => {
+                let imm = ((inst >> 12) & 1) << 8
+                    | ((inst >> 10) & 0b11) << 3
+                    | ((inst >> 5) & 0b11) << 6
+                    | ((inst >> 3) & 0b11) << 1
+                    | ((inst >> 2) & 1) << 5;
+                alloc::vec![pc.wrapping_add(sign_extend(imm, 9)), next]
+            }
+            // c.jr, c.jalr
+            (0b10, 0b100) if (inst >> 2) & 0x1f == 0 && (inst >> 7) & 0x1f != 0 => {
+                alloc::vec![reg(inst >> 7) & !1]
+            }
+            _ => alloc::vec![next],
+        };
+    }
+    let next = pc + 4;
+    match inst & 0x7f {
+        // jal
+        0x6f => {
+            let imm = ((inst >> 31) & 1) << 20
+                | ((inst >> 21) & 0x3ff) << 1
+                | ((inst >> 20) & 1) << 11
+                | ((inst >> 12) & 0xff) << 12;
+            alloc::vec![pc.wrapping_add(sign_extend(imm, 21))]
+        }
+        // jalr
+        0x67 => {
+            let imm = sign_extend(inst >> 20, 12);
+            alloc::vec![reg(inst >> 15).wrapping_add(imm) & !1]
+        }
+        // Conditional branches.
+        0x63 => {
+            let imm = ((inst >> 31) & 1) << 12
+                | ((inst >> 7) & 1) << 11
+                | ((inst >> 25) & 0x3f) << 5
+                | ((inst >> 8) & 0xf) << 1;
+            alloc::vec![pc.wrapping_add(sign_extend(imm, 13)), next]
+        }
+        _ if inst == SRET => alloc::vec![vsepc],
+        _ => alloc::vec![next],
+    }
+}
+
+/// Sign-extends the `bits`-bit value `val`.
+fn sign_extend(val: u32, bits: u32) -> usize {
+    (((val as u64) << (64 - bits)) as i64 >> (64 - bits)) as usize
+}
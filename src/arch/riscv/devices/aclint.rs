@@ -0,0 +1,164 @@
+//! Emulated ACLINT MTIMER and SSWI devices.
+//!
+//! The MTIMER's `mtimecmp` registers drive the guest's supervisor timer interrupt and the SSWI's
+//! `setssip` registers raise supervisor software interrupts, for guests that program these
+//! devices directly instead of going through the SBI. Hart N of each device is vCPU N of the VM.
+
+use riscv::register::time;
+
+use super::MmioDevice;
+use crate::arch::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::{vcpus::VM_CPUS_MAX, HyperError, HyperResult};
+
+/// Size of the MTIMER register window.
+pub const MTIMER_SIZE: usize = 0x8000;
+/// Size of the SSWI register window.
+pub const SSWI_SIZE: usize = 0x4000;
+
+/// Offset of the `mtime` register in the MTIMER.
+const MTIME: usize = 0x7ff8;
+
+/// An ACLINT MTIMER device, reporting the platform time and holding one `mtimecmp` per vCPU.
+pub struct AclintMtimer {
+    mtimecmp: [u64; VM_CPUS_MAX],
+}
+
+impl Default for AclintMtimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AclintMtimer {
+    /// Creates an MTIMER with no timer armed.
+    pub fn new() -> Self {
+        Self {
+            mtimecmp: [u64::MAX; VM_CPUS_MAX],
+        }
+    }
+
+    /// Returns the 64-bit register containing `offset`, and the shift of `offset` within it.
+    fn reg(&mut self, offset: usize, width: usize) -> HyperResult<(Option<&mut u64>, usize)> {
+        if !matches!(width, 4 | 8) || offset % width != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        let shift = (offset % 8) * 8;
+        let reg = match offset & !7 {
+            MTIME => None,
+            cmp => Some(
+                self.mtimecmp
+                    .get_mut(cmp / 8)
+                    .ok_or(HyperError::InvalidParam)?,
+            ),
+        };
+        Ok((reg, shift))
+    }
+}
+
+impl MmioDevice for AclintMtimer {
+    fn size(&self) -> usize {
+        MTIMER_SIZE
+    }
+
+    fn read(&mut self, offset: usize, width: usize) -> HyperResult<usize> {
+        let (reg, shift) = self.reg(offset, width)?;
+        let val = match reg {
+            Some(mtimecmp) => *mtimecmp,
+            None => time::read() as u64,
+        };
+        Ok((val >> shift) as usize)
+    }
+
+    fn write(&mut self, offset: usize, width: usize, val: usize) -> HyperResult<()> {
+        let (reg, shift) = self.reg(offset, width)?;
+        // The platform time is shared with the host, so writes to mtime are ignored.
+        if let Some(mtimecmp) = reg {
+            let mask = if width == 8 { u64::MAX } else { 0xffff_ffff };
+            *mtimecmp = *mtimecmp & !(mask << shift) | (val as u64 & mask) << shift;
+        }
+        Ok(())
+    }
+
+    fn vcpu_timer(&self, vcpu_id: usize) -> Option<u64> {
+        // An all-ones mtimecmp never fires, which guests use to disarm the timer.
+        self.mtimecmp
+            .get(vcpu_id)
+            .copied()
+            .filter(|&cmp| cmp != u64::MAX)
+    }
+
+    fn reset(&mut self) {
+        self.mtimecmp = [u64::MAX; VM_CPUS_MAX];
+    }
+
+    fn save(&self, out: &mut SnapshotWriter) -> HyperResult<()> {
+        self.mtimecmp.iter().for_each(|&cmp| out.write_u64(cmp));
+        Ok(())
+    }
+
+    fn restore(&mut self, input: &mut SnapshotReader) -> HyperResult<()> {
+        for cmp in self.mtimecmp.iter_mut() {
+            *cmp = input.read_u64()?;
+        }
+        Ok(())
+    }
+}
+
+/// An ACLINT SSWI device, with one `setssip` register per vCPU.
+#[derive(Default)]
+pub struct AclintSswi {
+    // Bitmap of the vCPUs with a software interrupt sent but not yet delivered.
+    pending: usize,
+}
+
+impl AclintSswi {
+    /// Creates an SSWI with no interrupts pending.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl MmioDevice for AclintSswi {
+    fn size(&self) -> usize {
+        SSWI_SIZE
+    }
+
+    fn read(&mut self, offset: usize, width: usize) -> HyperResult<usize> {
+        if width != 4 || offset % 4 != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        // setssip always reads as zero.
+        Ok(0)
+    }
+
+    fn write(&mut self, offset: usize, width: usize, val: usize) -> HyperResult<()> {
+        if width != 4 || offset % 4 != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        let hart = offset / 4;
+        if hart < VM_CPUS_MAX && val & 1 != 0 {
+            self.pending |= 1 << hart;
+        }
+        Ok(())
+    }
+
+    fn take_vcpu_soft_irq(&mut self, vcpu_id: usize) -> bool {
+        let pending = self.pending & (1 << vcpu_id) != 0;
+        self.pending &= !(1 << vcpu_id);
+        pending
+    }
+
+    fn reset(&mut self) {
+        self.pending = 0;
+    }
+
+    fn save(&self, out: &mut SnapshotWriter) -> HyperResult<()> {
+        out.write_usize(self.pending);
+        Ok(())
+    }
+
+    fn restore(&mut self, input: &mut SnapshotReader) -> HyperResult<()> {
+        self.pending = input.read_usize()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,566 @@
+//! Emulated AIA APLIC interrupt domain.
+//!
+//! A single supervisor-level domain without child domains. In direct delivery mode interrupts are
+//! signalled through the per-hart interrupt delivery controls (IDC) as the guest's VS-level
+//! external interrupt; in MSI delivery mode they are forwarded as MSIs to the IMSIC interrupt
+//! files of the target vCPUs.
+//!
+//! Level-triggered host interrupts stay claimed at the host PLIC until the guest is done with
+//! them: it claims them through `claimi`, clears them, or checks their input through `in_clrip`
+//! as MSI-mode drivers do before retriggering. Completing them any earlier would raise them again
+//! at the host while the device still asserts them.
+
+use arrayvec::ArrayVec;
+
+use super::MAX_SOURCES;
+use crate::{
+    arch::csrs::{traps, RiscvCsrTrait, CSR},
+    arch::snapshot::{SnapshotReader, SnapshotWriter},
+    vcpus::VM_CPUS_MAX,
+    HyperError, HyperResult,
+};
+
+/// Size of the APLIC domain's register space.
+pub const APLIC_SIZE: usize = 0x8000;
+/// The maximum number of MSIs waiting to be delivered by the VM.
+const MAX_QUEUED_MSIS: usize = 32;
+
+const DOMAINCFG: usize = 0x0000;
+const SOURCECFG_BASE: usize = 0x0004;
+const SOURCECFG_LAST: usize = 0x0ffc;
+const SETIP_BASE: usize = 0x1c00;
+const SETIP_LAST: usize = 0x1c7c;
+const SETIPNUM: usize = 0x1cdc;
+const IN_CLRIP_BASE: usize = 0x1d00;
+const IN_CLRIP_LAST: usize = 0x1d7c;
+const CLRIPNUM: usize = 0x1ddc;
+const SETIE_BASE: usize = 0x1e00;
+const SETIE_LAST: usize = 0x1e7c;
+const SETIENUM: usize = 0x1edc;
+const CLRIE_BASE: usize = 0x1f00;
+const CLRIE_LAST: usize = 0x1f7c;
+const CLRIENUM: usize = 0x1fdc;
+const SETIPNUM_LE: usize = 0x2000;
+const SETIPNUM_BE: usize = 0x2004;
+const GENMSI: usize = 0x3000;
+const TARGET_BASE: usize = 0x3004;
+const TARGET_LAST: usize = 0x3ffc;
+const IDC_BASE: usize = 0x4000;
+const IDC_LAST: usize = IDC_BASE + IDC_SIZE * VM_CPUS_MAX - 1;
+const IDC_SIZE: usize = 0x20;
+
+const IDC_IDELIVERY: usize = 0x00;
+const IDC_IFORCE: usize = 0x04;
+const IDC_ITHRESHOLD: usize = 0x08;
+const IDC_TOPI: usize = 0x18;
+const IDC_CLAIMI: usize = 0x1c;
+
+// domaincfg fields. The top byte reads as 0x80 to distinguish it from the PLIC.
+const DOMAINCFG_FIXED: u32 = 0x8000_0000;
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM: u32 = 1 << 2;
+
+// sourcecfg fields.
+const SOURCECFG_D: u32 = 1 << 10;
+const SOURCECFG_SM_MASK: u32 = 0x7;
+const SM_INACTIVE: u32 = 0;
+const SM_DETACHED: u32 = 1;
+const SM_EDGE1: u32 = 4;
+const SM_EDGE0: u32 = 5;
+const SM_LEVEL1: u32 = 6;
+const SM_LEVEL0: u32 = 7;
+
+// target fields.
+const TARGET_HART_SHIFT: u32 = 18;
+const TARGET_GUEST_SHIFT: u32 = 12;
+const TARGET_GUEST_MASK: u32 = 0x3f;
+const TARGET_EIID_MASK: u32 = 0x7ff;
+const TARGET_IPRIO_MASK: u32 = 0xff;
+
+/// An MSI generated by the APLIC in MSI delivery mode.
+#[derive(Clone, Copy, Debug)]
+pub struct AplicMsi {
+    /// The target hart, i.e. vCPU id.
+    pub hart: usize,
+    /// The target guest interrupt file of the hart.
+    pub guest: usize,
+    /// The external interrupt identity.
+    pub eiid: u32,
+}
+
+/// Interrupt delivery control of one hart, used in direct delivery mode.
+#[derive(Clone, Copy, Default)]
+struct Idc {
+    idelivery: u32,
+    iforce: u32,
+    ithreshold: u32,
+}
+
+pub struct AplicState {
+    base: usize,
+    domaincfg: u32,
+    sourcecfg: [u32; MAX_SOURCES],
+    target: [u32; MAX_SOURCES],
+    pending: [u32; MAX_SOURCES / 32],
+    enable: [u32; MAX_SOURCES / 32],
+    idcs: [Idc; VM_CPUS_MAX],
+    // The hart (vCPU) currently running, whose VS-level external interrupt mirrors its IDC.
+    active_hart: usize,
+    msis: ArrayVec<AplicMsi, MAX_QUEUED_MSIS>,
+    // Line levels of the emulated sources.
+    levels: [u32; MAX_SOURCES / 32],
+    // Level-triggered host interrupts injected and not yet handled by the guest.
+    host_irqs: [u32; MAX_SOURCES / 32],
+    // Host interrupts the guest is done with, to be completed at the host PLIC.
+    host_completions: [u32; MAX_SOURCES / 32],
+}
+
+impl AplicState {
+    pub fn new(base: usize) -> Self {
+        Self {
+            base,
+            domaincfg: 0,
+            sourcecfg: [0; MAX_SOURCES],
+            target: [0; MAX_SOURCES],
+            pending: [0; MAX_SOURCES / 32],
+            enable: [0; MAX_SOURCES / 32],
+            idcs: [Idc::default(); VM_CPUS_MAX],
+            active_hart: 0,
+            msis: ArrayVec::new(),
+            levels: [0; MAX_SOURCES / 32],
+            host_irqs: [0; MAX_SOURCES / 32],
+            host_completions: [0; MAX_SOURCES / 32],
+        }
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Sets the hart whose interrupt delivery control drives the guest's external interrupt.
+    pub fn set_active_hart(&mut self, hart: usize) {
+        self.active_hart = hart;
+        self.update();
+    }
+
+    /// Injects the interrupt `irq`, claimed from the host PLIC. Its completion at the host is
+    /// returned by `take_host_completions`, once the guest has handled it if its source is
+    /// level-triggered.
+    pub fn inject_irq(&mut self, irq: u32) {
+        let source = irq as usize;
+        if source >= MAX_SOURCES {
+            return;
+        }
+        let sm = self.sourcecfg_ref(source) & SOURCECFG_SM_MASK;
+        if sm == SM_LEVEL1 || sm == SM_LEVEL0 {
+            Self::set_bit(&mut self.host_irqs, source, true);
+        } else {
+            Self::set_bit(&mut self.host_completions, source, true);
+        }
+        self.set_pending(source, true);
+        self.update();
+    }
+
+    /// Sets the level of the interrupt line `irq` of an emulated device.
+    pub fn set_irq_level(&mut self, irq: u32, level: bool) {
+        let source = irq as usize;
+        if source >= MAX_SOURCES {
+            return;
+        }
+        let was_high = self.rectified_input(source);
+        Self::set_bit(&mut self.levels, source, level);
+        let high = self.rectified_input(source);
+        match self.sourcecfg_ref(source) & SOURCECFG_SM_MASK {
+            SM_EDGE1 | SM_EDGE0 if high && !was_high => self.set_pending(source, true),
+            SM_LEVEL1 | SM_LEVEL0 if !high => self.set_pending(source, false),
+            // In MSI mode, a level-triggered interrupt is forwarded once per assertion.
+            SM_LEVEL1 | SM_LEVEL0 if !was_high || self.domaincfg & DOMAINCFG_DM == 0 => {
+                self.set_pending(source, true)
+            }
+            _ => return,
+        }
+        self.update();
+    }
+
+    /// Returns the domain to its state at power-on. The host interrupts the guest had not handled
+    /// yet are returned by `take_host_completions`.
+    pub fn reset(&mut self) {
+        let mut host_completions = self.host_completions;
+        for (completions, irqs) in host_completions.iter_mut().zip(self.host_irqs.iter()) {
+            *completions |= irqs;
+        }
+        *self = Self {
+            host_completions,
+            ..Self::new(self.base)
+        };
+    }
+
+    /// Removes and returns the MSIs generated since the last call.
+    pub fn take_msis(&mut self) -> impl Iterator<Item = AplicMsi> + '_ {
+        self.msis.drain(..)
+    }
+
+    /// Calls `complete` with each host interrupt to complete at the host PLIC, which the guest
+    /// has handled since the last call.
+    pub fn take_host_completions(&mut self, mut complete: impl FnMut(u32)) {
+        for (index, bits) in self.host_completions.iter_mut().enumerate() {
+            while *bits != 0 {
+                let bit = bits.trailing_zeros();
+                *bits &= !(1 << bit);
+                complete(index as u32 * 32 + bit);
+            }
+        }
+    }
+
+    pub fn read_u32(&mut self, addr: usize) -> u32 {
+        let offset = addr.wrapping_sub(self.base);
+        match offset {
+            DOMAINCFG => DOMAINCFG_FIXED | self.domaincfg,
+            SOURCECFG_BASE..=SOURCECFG_LAST => {
+                self.sourcecfg_ref(Self::reg_source(offset, SOURCECFG_BASE))
+            }
+            SETIP_BASE..=SETIP_LAST => Self::bits_ref(&self.pending, offset - SETIP_BASE),
+            IN_CLRIP_BASE..=IN_CLRIP_LAST => {
+                // Drivers check the input of level-triggered interrupts here once they handled
+                // them. That of host interrupts is not known until they are completed, and they
+                // are injected again if still asserted.
+                let first = (offset - IN_CLRIP_BASE) / 4 * 32;
+                self.release_host_irqs(first, u32::MAX);
+                (0..32)
+                    .filter(|bit| self.rectified_input(first + bit))
+                    .fold(0, |val, bit| val | 1 << bit)
+            }
+            SETIE_BASE..=SETIE_LAST => Self::bits_ref(&self.enable, offset - SETIE_BASE),
+            // Busy bit is never set as MSIs are sent synchronously.
+            GENMSI => 0,
+            TARGET_BASE..=TARGET_LAST => {
+                let source = Self::reg_source(offset, TARGET_BASE);
+                if self.is_active(source) {
+                    self.target[source]
+                } else {
+                    0
+                }
+            }
+            IDC_BASE..=IDC_LAST => {
+                let hart = (offset - IDC_BASE) / IDC_SIZE;
+                match (offset - IDC_BASE) % IDC_SIZE {
+                    IDC_IDELIVERY => self.idcs[hart].idelivery,
+                    IDC_IFORCE => self.idcs[hart].iforce,
+                    IDC_ITHRESHOLD => self.idcs[hart].ithreshold,
+                    IDC_TOPI => self.topi(hart),
+                    IDC_CLAIMI => self.claimi(hart),
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn write_u32(&mut self, addr: usize, val: u32) {
+        let offset = addr.wrapping_sub(self.base);
+        match offset {
+            DOMAINCFG => self.domaincfg = val & (DOMAINCFG_IE | DOMAINCFG_DM),
+            SOURCECFG_BASE..=SOURCECFG_LAST => {
+                self.write_sourcecfg(Self::reg_source(offset, SOURCECFG_BASE), val)
+            }
+            SETIP_BASE..=SETIP_LAST => self.write_bits(offset - SETIP_BASE, val, true, true),
+            SETIPNUM | SETIPNUM_LE => self.set_pending(val as usize, true),
+            SETIPNUM_BE => self.set_pending(val.swap_bytes() as usize, true),
+            IN_CLRIP_BASE..=IN_CLRIP_LAST => {
+                self.write_bits(offset - IN_CLRIP_BASE, val, true, false);
+                self.release_host_irqs((offset - IN_CLRIP_BASE) / 4 * 32, val);
+            }
+            CLRIPNUM => {
+                self.set_pending(val as usize, false);
+                self.release_host_irqs(val as usize & !31, 1 << (val % 32));
+            }
+            SETIE_BASE..=SETIE_LAST => self.write_bits(offset - SETIE_BASE, val, false, true),
+            SETIENUM => self.set_enable(val as usize, true),
+            CLRIE_BASE..=CLRIE_LAST => self.write_bits(offset - CLRIE_BASE, val, false, false),
+            CLRIENUM => self.set_enable(val as usize, false),
+            GENMSI => {
+                if self.domaincfg & DOMAINCFG_DM != 0 {
+                    self.queue_msi(val & !(TARGET_GUEST_MASK << TARGET_GUEST_SHIFT));
+                }
+            }
+            TARGET_BASE..=TARGET_LAST => {
+                let source = Self::reg_source(offset, TARGET_BASE);
+                if self.is_active(source) {
+                    self.target[source] = self.legalize_target(val);
+                }
+            }
+            IDC_BASE..=IDC_LAST => {
+                let hart = (offset - IDC_BASE) / IDC_SIZE;
+                match (offset - IDC_BASE) % IDC_SIZE {
+                    IDC_IDELIVERY => self.idcs[hart].idelivery = val & 1,
+                    IDC_IFORCE => self.idcs[hart].iforce = val & 1,
+                    IDC_ITHRESHOLD => self.idcs[hart].ithreshold = val & TARGET_IPRIO_MASK,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        self.update();
+    }
+
+    /// Saves the state of the domain into a VM snapshot.
+    pub fn save(&self, out: &mut SnapshotWriter) {
+        out.write_u32(self.domaincfg);
+        out.write_u32s(&self.sourcecfg);
+        out.write_u32s(&self.target);
+        out.write_u32s(&self.pending);
+        out.write_u32s(&self.enable);
+        for idc in self.idcs.iter() {
+            out.write_u32s(&[idc.idelivery, idc.iforce, idc.ithreshold]);
+        }
+        out.write_usize(self.active_hart);
+        out.write_usize(self.msis.len());
+        for msi in self.msis.iter() {
+            out.write_usize(msi.hart);
+            out.write_usize(msi.guest);
+            out.write_u32(msi.eiid);
+        }
+        out.write_u32s(&self.levels);
+    }
+
+    /// Restores the state saved by `save`.
+    pub fn restore(&mut self, input: &mut SnapshotReader) -> HyperResult<()> {
+        self.domaincfg = input.read_u32()?;
+        input.read_u32s(&mut self.sourcecfg)?;
+        input.read_u32s(&mut self.target)?;
+        input.read_u32s(&mut self.pending)?;
+        input.read_u32s(&mut self.enable)?;
+        for idc in self.idcs.iter_mut() {
+            idc.idelivery = input.read_u32()?;
+            idc.iforce = input.read_u32()?;
+            idc.ithreshold = input.read_u32()?;
+        }
+        self.active_hart = input.read_usize()?;
+        self.msis.clear();
+        for _ in 0..input.read_usize()? {
+            let msi = AplicMsi {
+                hart: input.read_usize()?,
+                guest: input.read_usize()?,
+                eiid: input.read_u32()?,
+            };
+            self.msis
+                .try_push(msi)
+                .map_err(|_| HyperError::InvalidParam)?;
+        }
+        input.read_u32s(&mut self.levels)?;
+        // Host interrupts are claimed by the host of the saved VM, not by ours.
+        self.host_irqs = [0; MAX_SOURCES / 32];
+        self.host_completions = [0; MAX_SOURCES / 32];
+        Ok(())
+    }
+
+    fn reg_source(offset: usize, base: usize) -> usize {
+        (offset - base) / 4 + 1
+    }
+
+    fn bits_ref(bits: &[u32], offset: usize) -> u32 {
+        bits.get(offset / 4).copied().unwrap_or(0)
+    }
+
+    fn sourcecfg_ref(&self, source: usize) -> u32 {
+        self.sourcecfg.get(source).copied().unwrap_or(0)
+    }
+
+    fn set_bit(bits: &mut [u32], source: usize, set: bool) {
+        if set {
+            bits[source / 32] |= 1 << (source % 32);
+        } else {
+            bits[source / 32] &= !(1 << (source % 32));
+        }
+    }
+
+    /// Returns the input of the emulated line `source` after inversion for active-low modes.
+    fn rectified_input(&self, source: usize) -> bool {
+        let level = source < MAX_SOURCES && self.levels[source / 32] & (1 << (source % 32)) != 0;
+        match self.sourcecfg_ref(source) & SOURCECFG_SM_MASK {
+            SM_EDGE1 | SM_LEVEL1 => level,
+            SM_EDGE0 | SM_LEVEL0 => !level,
+            _ => false,
+        }
+    }
+
+    /// Completes at the host the host interrupts among the sources `first + bit` for the bits
+    /// set in `mask`, as the guest is done with them.
+    fn release_host_irqs(&mut self, first: usize, mask: u32) {
+        if first >= MAX_SOURCES {
+            return;
+        }
+        let index = first / 32;
+        let released = self.host_irqs[index] & mask;
+        self.host_irqs[index] &= !released;
+        self.host_completions[index] |= released;
+    }
+
+    fn is_active(&self, source: usize) -> bool {
+        let sm = self.sourcecfg_ref(source) & SOURCECFG_SM_MASK;
+        source != 0 && sm != SM_INACTIVE
+    }
+
+    fn write_sourcecfg(&mut self, source: usize, val: u32) {
+        if source >= MAX_SOURCES {
+            return;
+        }
+        // There are no child domains to delegate to, so D is read-only zero and the reserved
+        // source modes make the source inactive.
+        let sm = if val & SOURCECFG_D != 0 {
+            SM_INACTIVE
+        } else {
+            match val & SOURCECFG_SM_MASK {
+                sm @ (SM_DETACHED | SM_EDGE1 | SM_EDGE0 | SM_LEVEL1 | SM_LEVEL0) => sm,
+                _ => SM_INACTIVE,
+            }
+        };
+        self.sourcecfg[source] = sm;
+        if sm == SM_INACTIVE {
+            self.release_host_irqs(source & !31, 1 << (source % 32));
+            self.set_pending(source, false);
+            self.set_enable(source, false);
+            self.target[source] = 0;
+        } else if self.target[source] == 0 {
+            self.target[source] = self.legalize_target(0);
+        }
+    }
+
+    fn legalize_target(&self, val: u32) -> u32 {
+        let hart = (val >> TARGET_HART_SHIFT) as usize;
+        let hart = if hart < VM_CPUS_MAX { hart as u32 } else { 0 };
+        if self.domaincfg & DOMAINCFG_DM != 0 {
+            hart << TARGET_HART_SHIFT
+                | val & (TARGET_GUEST_MASK << TARGET_GUEST_SHIFT)
+                | val & TARGET_EIID_MASK
+        } else {
+            // Priority 0 is reserved and reads as 1.
+            let iprio = match val & TARGET_IPRIO_MASK {
+                0 => 1,
+                iprio => iprio,
+            };
+            hart << TARGET_HART_SHIFT | iprio
+        }
+    }
+
+    fn set_pending(&mut self, source: usize, pending: bool) {
+        if source >= MAX_SOURCES || (pending && !self.is_active(source)) {
+            return;
+        }
+        if pending {
+            self.pending[source / 32] |= 1 << (source % 32);
+        } else {
+            self.pending[source / 32] &= !(1 << (source % 32));
+        }
+    }
+
+    fn set_enable(&mut self, source: usize, enable: bool) {
+        if source >= MAX_SOURCES || (enable && !self.is_active(source)) {
+            return;
+        }
+        if enable {
+            self.enable[source / 32] |= 1 << (source % 32);
+        } else {
+            self.enable[source / 32] &= !(1 << (source % 32));
+        }
+    }
+
+    /// Sets or clears the pending (`pending` is true) or enable bits of the 32 sources covered by
+    /// the register at `offset` from the start of its register array.
+    fn write_bits(&mut self, offset: usize, val: u32, pending: bool, set: bool) {
+        let first = offset / 4 * 32;
+        for bit in 0..32 {
+            if val & (1 << bit) == 0 {
+                continue;
+            }
+            match pending {
+                true => self.set_pending(first + bit, set),
+                false => self.set_enable(first + bit, set),
+            }
+        }
+    }
+
+    fn is_pending_and_enabled(&self, source: usize) -> bool {
+        (self.pending[source / 32] & self.enable[source / 32]) & (1 << (source % 32)) != 0
+    }
+
+    /// Returns the highest-priority interrupt targeting `hart` in direct mode, encoded as in
+    /// `topi`: the source number in bits 25:16 and its priority in bits 7:0.
+    fn topi(&self, hart: usize) -> u32 {
+        let threshold = self.idcs[hart].ithreshold;
+        let mut top: Option<(u32, usize)> = None;
+        for source in 1..MAX_SOURCES {
+            if !self.is_pending_and_enabled(source) {
+                continue;
+            }
+            let target = self.target[source];
+            if (target >> TARGET_HART_SHIFT) as usize != hart {
+                continue;
+            }
+            let iprio = target & TARGET_IPRIO_MASK;
+            if threshold != 0 && iprio >= threshold {
+                continue;
+            }
+            // Lower priority numbers take precedence, ties go to the lower source number.
+            if top.map_or(true, |(top_prio, _)| iprio < top_prio) {
+                top = Some((iprio, source));
+            }
+        }
+        top.map_or(0, |(iprio, source)| (source as u32) << 16 | iprio)
+    }
+
+    fn claimi(&mut self, hart: usize) -> u32 {
+        let topi = self.topi(hart);
+        if topi == 0 {
+            // A forced interrupt is claimed as interrupt 0.
+            self.idcs[hart].iforce = 0;
+        } else {
+            let source = (topi >> 16) as usize;
+            self.set_pending(source, false);
+            self.release_host_irqs(source & !31, 1 << (source % 32));
+        }
+        self.update();
+        topi
+    }
+
+    fn queue_msi(&mut self, target: u32) {
+        let msi = AplicMsi {
+            hart: (target >> TARGET_HART_SHIFT) as usize,
+            guest: ((target >> TARGET_GUEST_SHIFT) & TARGET_GUEST_MASK) as usize,
+            eiid: target & TARGET_EIID_MASK,
+        };
+        if self.msis.try_push(msi).is_err() {
+            warn!("APLIC: MSI queue full, dropping {:?}", msi);
+        }
+    }
+
+    /// Delivers the pending and enabled interrupts according to the delivery mode.
+    fn update(&mut self) {
+        if self.domaincfg & DOMAINCFG_IE == 0 {
+            // In MSI mode the external interrupt belongs to the IMSIC.
+            if self.domaincfg & DOMAINCFG_DM == 0 {
+                CSR.hvip
+                    .read_and_clear_bits(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
+            }
+            return;
+        }
+        if self.domaincfg & DOMAINCFG_DM != 0 {
+            // In MSI mode, an interrupt is forwarded and stops being pending at the APLIC.
+            for source in 1..MAX_SOURCES {
+                if self.is_pending_and_enabled(source) {
+                    self.set_pending(source, false);
+                    self.queue_msi(self.target[source]);
+                }
+            }
+            return;
+        }
+        let idc = self.idcs[self.active_hart];
+        if idc.idelivery != 0 && (idc.iforce != 0 || self.topi(self.active_hart) != 0) {
+            CSR.hvip
+                .read_and_set_bits(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
+        } else {
+            CSR.hvip
+                .read_and_clear_bits(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
+        }
+    }
+}
@@ -0,0 +1,346 @@
+//! AIA IMSIC support for guests.
+//!
+//! Each hart's IMSIC provides a number of guest interrupt files (GEILEN, discovered through
+//! `hgeie`). A vCPU that owns a guest file has it selected in `hstatus.VGEIN` and mapped at its
+//! guest IMSIC address, so the guest receives and claims MSIs without trapping. While the vCPU is
+//! not resident, interrupts to its file are signalled through `hgeip` as supervisor guest
+//! external interrupts. When a hart runs out of guest files, the interrupt file is emulated in
+//! software instead, and the VM raises the external interrupt of the owning vCPU from its state.
+
+use spin::{Mutex, Once};
+use tock_registers::LocalRegisterCopy;
+
+use crate::{
+    arch::csrs::{defs::hstatus, RiscvCsrTrait, CSR},
+    arch::snapshot::{SnapshotReader, SnapshotWriter},
+    vcpus::MAX_CPUS,
+    HostPhysAddr, HyperError, HyperResult,
+};
+
+/// Size of one interrupt file in the IMSIC's address space.
+pub const IMSIC_FILE_SIZE: usize = 0x1000;
+/// Number of interrupt identities implemented by the software IMSIC.
+pub const SOFT_IMSIC_NR_IDS: usize = 256;
+
+/// Offset of the little-endian `seteipnum` register in an interrupt file.
+const SETEIPNUM_LE: usize = 0x0;
+/// Offset of the big-endian `seteipnum` register in an interrupt file.
+const SETEIPNUM_BE: usize = 0x4;
+
+// Indirectly accessed interrupt file registers, selected through `siselect`.
+const ISELECT_EIDELIVERY: usize = 0x70;
+const ISELECT_EITHRESHOLD: usize = 0x72;
+const ISELECT_EIP0: usize = 0x80;
+const ISELECT_EIP63: usize = 0xbf;
+const ISELECT_EIE0: usize = 0xc0;
+const ISELECT_EIE63: usize = 0xff;
+/// Number of indirectly accessed registers holding the state of an interrupt file on RV64.
+const NR_STATE_IREGS: usize = 2 + (ISELECT_EIE63 - ISELECT_EIP0 + 1) / 2;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNPROBED: Once<usize> = Once::new();
+/// Bitmap of the guest interrupt files implemented by each hart, bit N being file N. Harts may
+/// implement different numbers of files, so each is probed the first time it allocates one.
+static GUEST_FILES: [Once<usize>; MAX_CPUS] = [UNPROBED; MAX_CPUS];
+/// Bitmap of the guest interrupt files in use on each hart.
+static USED_GUEST_FILES: Mutex<[usize; MAX_CPUS]> = Mutex::new([0; MAX_CPUS]);
+
+/// Returns the guest interrupt files of `hart_id`, which must be the current hart.
+fn guest_files(hart_id: usize) -> usize {
+    *GUEST_FILES[hart_id].call_once(|| {
+        // Bits of unimplemented guest interrupt files are read-only zero.
+        let enabled = CSR.hgeie.get_value();
+        CSR.hgeie.write_value(usize::MAX);
+        let files = CSR.hgeie.get_value();
+        CSR.hgeie.write_value(enabled);
+        debug!("Hart {} IMSIC guest interrupt files: {:#x}", hart_id, files);
+        files
+    })
+}
+
+/// Returns the indirectly accessed registers holding the state of an interrupt file. On RV64,
+/// only the even-numbered `eipN`/`eieN` registers exist.
+fn state_iregs() -> impl Iterator<Item = usize> {
+    [ISELECT_EIDELIVERY, ISELECT_EITHRESHOLD]
+        .into_iter()
+        .chain((ISELECT_EIP0..=ISELECT_EIP63).step_by(2))
+        .chain((ISELECT_EIE0..=ISELECT_EIE63).step_by(2))
+}
+
+/// Runs `f` with the guest interrupt file `file` of this hart selected in `hstatus.VGEIN`, so that
+/// `vsiselect` and `vsireg` access its registers.
+fn with_guest_file<T>(file: usize, f: impl FnOnce() -> T) -> T {
+    let hstatus = CSR.hstatus.get_value();
+    let vsiselect = CSR.vsiselect.get_value();
+    let mut selected = LocalRegisterCopy::<usize, hstatus::Register>::new(hstatus);
+    selected.modify(hstatus::vgein.val(file));
+    CSR.hstatus.write_value(selected.get());
+    let ret = f();
+    CSR.vsiselect.write_value(vsiselect);
+    CSR.hstatus.write_value(hstatus);
+    ret
+}
+
+/// Allocates a guest interrupt file on `hart_id`, the current hart, returning its number (the
+/// `VGEIN` value).
+pub fn alloc_guest_file(hart_id: usize) -> Option<usize> {
+    let mut used_files = USED_GUEST_FILES.lock();
+    let used = used_files.get_mut(hart_id)?;
+    let free = guest_files(hart_id) & !*used;
+    if free == 0 {
+        return None;
+    }
+    let file = free.trailing_zeros() as usize;
+    *used |= 1 << file;
+    Some(file)
+}
+
+/// Returns the guest interrupt file `file` of `hart_id` to the allocator.
+pub fn free_guest_file(hart_id: usize, file: usize) {
+    disable_notification(file);
+    if let Some(used) = USED_GUEST_FILES.lock().get_mut(hart_id) {
+        *used &= !(1 << file);
+    }
+}
+
+/// Enables supervisor guest external interrupts for `file`, used while the vCPU owning it is not
+/// resident on this hart.
+pub fn enable_notification(file: usize) {
+    CSR.hgeie.read_and_set_bits(1 << file);
+}
+
+/// Disables supervisor guest external interrupts for `file`.
+pub fn disable_notification(file: usize) {
+    CSR.hgeie.read_and_clear_bits(1 << file);
+}
+
+/// Returns the bitmap of guest interrupt files with a pending and enabled interrupt.
+pub fn pending_files() -> usize {
+    CSR.hgeip.get_value() & CSR.hgeie.get_value()
+}
+
+/// The IMSIC interrupt file backing a vCPU.
+pub enum VcpuImsic {
+    /// A hardware guest interrupt file of the hart the vCPU runs on.
+    Hardware {
+        /// The hart owning the interrupt file.
+        hart_id: usize,
+        /// The guest interrupt file number.
+        file: usize,
+        /// The host physical address of the interrupt file.
+        addr: HostPhysAddr,
+    },
+    /// An interrupt file emulated in software.
+    Software(SoftImsic),
+}
+
+impl VcpuImsic {
+    /// Returns the host physical address of the guest interrupt file `file` in the IMSIC whose
+    /// supervisor-level interrupt file is at `s_file_base`.
+    pub fn guest_file_addr(s_file_base: HostPhysAddr, file: usize) -> HostPhysAddr {
+        s_file_base + file * IMSIC_FILE_SIZE
+    }
+
+    /// Signals the interrupt identity `id` to this interrupt file, as an MSI would.
+    pub fn send_msi(&mut self, id: u32) {
+        match self {
+            Self::Hardware { addr, .. } => unsafe {
+                core::ptr::write_volatile((*addr + SETEIPNUM_LE) as *mut u32, id);
+            },
+            Self::Software(imsic) => imsic.set_pending(id as usize),
+        }
+    }
+
+    /// Returns whether the interrupt file has an interrupt to deliver to the vCPU.
+    pub fn pending(&self) -> bool {
+        match self {
+            Self::Hardware { file, .. } => CSR.hgeip.get_value() & (1 << file) != 0,
+            Self::Software(imsic) => imsic.pending(),
+        }
+    }
+
+    /// Saves the state of the interrupt file into a VM snapshot, in the same format for hardware
+    /// and software files. A hardware file is read through `vsireg`, so this must run on the hart
+    /// owning it.
+    pub fn save(&self, out: &mut SnapshotWriter) {
+        match self {
+            Self::Hardware { file, .. } => with_guest_file(*file, || {
+                for iselect in state_iregs() {
+                    CSR.vsiselect.write_value(iselect);
+                    out.write_usize(CSR.vsireg.get_value());
+                }
+            }),
+            Self::Software(imsic) => {
+                for iselect in state_iregs() {
+                    out.write_usize(imsic.read_ireg(iselect).unwrap_or(0));
+                }
+            }
+        }
+    }
+
+    /// Restores the state saved by `save`, which must run on the hart owning a hardware file.
+    /// Identities beyond those the file implements are dropped.
+    pub fn restore(&mut self, input: &mut SnapshotReader) -> HyperResult<()> {
+        let mut vals = [0; NR_STATE_IREGS];
+        for val in vals.iter_mut() {
+            *val = input.read_usize()?;
+        }
+        match self {
+            Self::Hardware { file, .. } => with_guest_file(*file, || {
+                for (iselect, val) in state_iregs().zip(vals) {
+                    CSR.vsiselect.write_value(iselect);
+                    CSR.vsireg.write_value(val);
+                }
+            }),
+            Self::Software(imsic) => {
+                *imsic = SoftImsic::new();
+                for (iselect, val) in state_iregs().zip(vals) {
+                    imsic.write_ireg(iselect, val)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A software-emulated IMSIC interrupt file.
+///
+/// The memory-mapped `seteipnum` registers are handled as emulated MMIO and the indirectly
+/// accessed registers through the virtual-instruction traps raised when the guest accesses
+/// `sireg`/`stopei` with `hstatus.VGEIN` set to 0.
+pub struct SoftImsic {
+    eidelivery: usize,
+    eithreshold: usize,
+    eip: [u64; SOFT_IMSIC_NR_IDS / 64],
+    eie: [u64; SOFT_IMSIC_NR_IDS / 64],
+}
+
+impl Default for SoftImsic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SoftImsic {
+    /// Creates an interrupt file with delivery disabled and no pending interrupts.
+    pub fn new() -> Self {
+        Self {
+            eidelivery: 0,
+            eithreshold: 0,
+            eip: [0; SOFT_IMSIC_NR_IDS / 64],
+            eie: [0; SOFT_IMSIC_NR_IDS / 64],
+        }
+    }
+
+    /// Handles a guest load from the interrupt file page at `offset`.
+    pub fn read_u32(&self, _offset: usize) -> u32 {
+        // The seteipnum registers read as zero.
+        0
+    }
+
+    /// Handles a guest store of `val` to the interrupt file page at `offset`.
+    pub fn write_u32(&mut self, offset: usize, val: u32) {
+        let id = match offset {
+            SETEIPNUM_LE => val,
+            SETEIPNUM_BE => val.swap_bytes(),
+            _ => return,
+        };
+        self.set_pending(id as usize);
+    }
+
+    /// Marks interrupt identity `id` pending, as an MSI write to this file would.
+    pub fn set_pending(&mut self, id: usize) {
+        if id != 0 && id < SOFT_IMSIC_NR_IDS {
+            self.eip[id / 64] |= 1 << (id % 64);
+        }
+    }
+
+    /// Reads the indirectly accessed register selected by `iselect`. Fails with `InvalidParam`
+    /// for registers that do not exist, whose accesses raise an illegal instruction exception.
+    pub fn read_ireg(&self, iselect: usize) -> HyperResult<usize> {
+        match iselect {
+            ISELECT_EIDELIVERY => Ok(self.eidelivery),
+            ISELECT_EITHRESHOLD => Ok(self.eithreshold),
+            ISELECT_EIP0..=ISELECT_EIP63 => Self::array_reg(&self.eip, iselect - ISELECT_EIP0),
+            ISELECT_EIE0..=ISELECT_EIE63 => Self::array_reg(&self.eie, iselect - ISELECT_EIE0),
+            _ => Err(HyperError::InvalidParam),
+        }
+    }
+
+    /// Writes `val` to the indirectly accessed register selected by `iselect`. Fails with
+    /// `InvalidParam` for registers that do not exist, like `read_ireg`.
+    pub fn write_ireg(&mut self, iselect: usize, val: usize) -> HyperResult<()> {
+        match iselect {
+            ISELECT_EIDELIVERY => self.eidelivery = val & 1,
+            ISELECT_EITHRESHOLD => self.eithreshold = val & (SOFT_IMSIC_NR_IDS - 1),
+            ISELECT_EIP0..=ISELECT_EIP63 => {
+                Self::set_array_reg(&mut self.eip, iselect - ISELECT_EIP0, val)?
+            }
+            ISELECT_EIE0..=ISELECT_EIE63 => {
+                Self::set_array_reg(&mut self.eie, iselect - ISELECT_EIE0, val)?
+            }
+            _ => return Err(HyperError::InvalidParam),
+        }
+        // Identity 0 is never implemented.
+        self.eip[0] &= !1;
+        self.eie[0] &= !1;
+        Ok(())
+    }
+
+    /// Returns the value of `stopei`: the highest-priority pending and enabled identity.
+    pub fn topei(&self) -> usize {
+        match self.top_id() {
+            // Both the identity and the priority fields hold the identity number.
+            Some(id) => id << 16 | id,
+            None => 0,
+        }
+    }
+
+    /// Handles a write to `stopei`, which claims the highest-priority interrupt.
+    pub fn claim_top(&mut self) {
+        if let Some(id) = self.top_id() {
+            self.eip[id / 64] &= !(1 << (id % 64));
+        }
+    }
+
+    /// Returns whether the file asserts the guest's external interrupt.
+    pub fn pending(&self) -> bool {
+        self.eidelivery != 0 && self.top_id().is_some()
+    }
+
+    fn top_id(&self) -> Option<usize> {
+        let (index, bits) = self
+            .eip
+            .iter()
+            .zip(self.eie.iter())
+            .map(|(eip, eie)| eip & eie)
+            .enumerate()
+            .find(|(_, bits)| *bits != 0)?;
+        let id = index * 64 + bits.trailing_zeros() as usize;
+        // A threshold of 0 means no interrupt is masked.
+        if self.eithreshold == 0 || id < self.eithreshold {
+            Some(id)
+        } else {
+            None
+        }
+    }
+
+    // On RV64, only the even-numbered eipN/eieN registers exist and each holds 64 identities.
+    fn array_reg(array: &[u64], index: usize) -> HyperResult<usize> {
+        if index % 2 != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(array.get(index / 2).copied().unwrap_or(0) as usize)
+    }
+
+    fn set_array_reg(array: &mut [u64], index: usize, val: usize) -> HyperResult<()> {
+        if index % 2 != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        // Registers of unimplemented identities are read-only zero.
+        if let Some(reg) = array.get_mut(index / 2) {
+            *reg = val as u64;
+        }
+        Ok(())
+    }
+}
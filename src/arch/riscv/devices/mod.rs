@@ -1 +1,173 @@
+pub mod aclint;
+pub mod aplic;
+pub mod imsic;
+pub mod passthrough;
 pub mod plic;
+pub mod rtc;
+pub mod test;
+pub mod uart;
+pub mod virtio;
+
+use alloc::boxed::Box;
+
+use super::snapshot::{SnapshotReader, SnapshotWriter};
+use super::vm_pages::VmPages;
+use super::VmExitReason;
+use crate::{GuestPhysAddr, HyperError, HyperResult};
+use aplic::AplicState;
+use plic::PlicState;
+
+/// Number of interrupt sources tracked by the virtual interrupt controllers.
+pub const MAX_SOURCES: usize = 512;
+
+/// Size of the PLIC's register space.
+pub const PLIC_SIZE: usize = 0x0400_0000;
+
+/// The virtual interrupt controller of a VM, through which device interrupts are injected.
+pub enum IrqChip {
+    /// A PLIC whose context registers are emulated.
+    Plic(PlicState),
+    /// An emulated AIA APLIC domain.
+    Aplic(AplicState),
+}
+
+impl IrqChip {
+    /// Returns whether `addr` is within the controller's registers.
+    pub fn contains(&self, addr: usize) -> bool {
+        let (base, size) = match self {
+            Self::Plic(plic) => (plic.base(), PLIC_SIZE),
+            Self::Aplic(aplic) => (aplic.base(), aplic::APLIC_SIZE),
+        };
+        (base..base + size).contains(&addr)
+    }
+
+    /// Injects the interrupt `irq` into the guest's S-mode context.
+    pub fn inject_irq(&mut self, irq: u32) {
+        match self {
+            // Context 1 is hart 0's S-mode context.
+            Self::Plic(plic) => plic.inject_irq(1, irq),
+            Self::Aplic(aplic) => aplic.inject_irq(irq),
+        }
+    }
+
+    /// Sets the level of the interrupt line `irq` of an emulated device.
+    pub fn set_irq_level(&mut self, irq: u32, level: bool) {
+        match self {
+            Self::Plic(plic) => plic.set_irq_level(1, irq, level),
+            Self::Aplic(aplic) => aplic.set_irq_level(irq, level),
+        }
+    }
+
+    /// Handles a guest load from the controller's register at `addr`.
+    pub fn read_u32(&mut self, addr: usize) -> u32 {
+        match self {
+            Self::Plic(plic) => plic.read_u32(addr),
+            Self::Aplic(aplic) => aplic.read_u32(addr),
+        }
+    }
+
+    /// Handles a guest store of `val` to the controller's register at `addr`.
+    pub fn write_u32(&mut self, addr: usize, val: u32) {
+        match self {
+            Self::Plic(plic) => plic.write_u32(addr, val),
+            Self::Aplic(aplic) => aplic.write_u32(addr, val),
+        }
+    }
+
+    /// Returns the controller to its state at power-on, as the VM reboots.
+    pub fn reset(&mut self) {
+        match self {
+            Self::Plic(plic) => plic.reset(),
+            Self::Aplic(aplic) => aplic.reset(),
+        }
+    }
+
+    /// Saves the state of the controller into a VM snapshot.
+    pub fn save(&self, out: &mut SnapshotWriter) {
+        match self {
+            Self::Plic(plic) => plic.save(out),
+            Self::Aplic(aplic) => aplic.save(out),
+        }
+    }
+
+    /// Restores the state saved by `save` into a controller of the same kind.
+    pub fn restore(&mut self, input: &mut SnapshotReader) -> HyperResult<()> {
+        match self {
+            Self::Plic(plic) => plic.restore(input),
+            Self::Aplic(aplic) => aplic.restore(input),
+        }
+    }
+}
+
+/// A device emulated by the hypervisor, which the guest accesses through trapped MMIO.
+pub trait MmioDevice {
+    /// Returns the size of the device's register window.
+    fn size(&self) -> usize;
+
+    /// Handles a guest load of `width` bytes at `offset` in the register window.
+    fn read(&mut self, offset: usize, width: usize) -> HyperResult<usize>;
+
+    /// Handles a guest store of the low `width` bytes of `val` at `offset` in the register window.
+    fn write(&mut self, offset: usize, width: usize, val: usize) -> HyperResult<()>;
+
+    /// Returns the level of the device's interrupt line.
+    fn irq_level(&self) -> bool {
+        false
+    }
+
+    /// Gives the device the handle through which it accesses guest memory, as it is attached to
+    /// a VM. Devices must write guest memory through it for their writes to be dirty-logged.
+    fn attach(&mut self, _mem: &VmPages) {}
+
+    /// Lets the device make progress outside of guest accesses, e.g. to pick up host input.
+    /// Called on every VM exit.
+    fn poll(&mut self) {}
+
+    /// Returns the time at which the device raises the supervisor timer interrupt of vCPU
+    /// `vcpu_id`, for devices implementing per-vCPU timers, or `None` if the timer is disarmed.
+    fn vcpu_timer(&self, _vcpu_id: usize) -> Option<u64> {
+        None
+    }
+
+    /// Returns and clears whether the device sent a supervisor software interrupt to vCPU
+    /// `vcpu_id`.
+    fn take_vcpu_soft_irq(&mut self, _vcpu_id: usize) -> bool {
+        false
+    }
+
+    /// Returns and clears a request from the guest, through this device, to stop the VM.
+    fn take_exit_request(&mut self) -> Option<VmExitReason> {
+        None
+    }
+
+    /// Returns the device to its state at power-on, as the VM reboots.
+    fn reset(&mut self) {}
+
+    /// Saves the guest-visible state of the device into a VM snapshot. Devices that do not
+    /// implement snapshots fail with `NotSupported`.
+    fn save(&self, _out: &mut SnapshotWriter) -> HyperResult<()> {
+        Err(HyperError::NotSupported)
+    }
+
+    /// Restores the state saved by `save` into a device created with the same configuration.
+    fn restore(&mut self, _input: &mut SnapshotReader) -> HyperResult<()> {
+        Err(HyperError::NotSupported)
+    }
+}
+
+/// An emulated device attached to a VM.
+pub struct EmulatedDevice {
+    /// The guest physical address of the device's register window.
+    pub base: GuestPhysAddr,
+    /// The interrupt source the device's interrupt line is wired to.
+    pub irq: Option<u32>,
+    /// The device model.
+    pub device: Box<dyn MmioDevice>,
+}
+
+impl EmulatedDevice {
+    /// Returns whether `addr` is within the device's register window.
+    pub fn contains(&self, addr: GuestPhysAddr) -> bool {
+        (self.base..self.base + self.device.size()).contains(&addr)
+    }
+}
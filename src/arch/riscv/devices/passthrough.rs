@@ -0,0 +1,218 @@
+//! Assignment of host devices to VMs.
+//!
+//! A passthrough device is described by its MMIO register window and the PLIC sources it raises,
+//! usually taken from the host FDT. Assigning it to a VM maps the window into the VM's G-stage
+//! page table (either at the host address or relocated) and routes its interrupts to the owning
+//! VM's virtual interrupt controller. A device can only be owned by one VM at a time.
+
+use arrayvec::ArrayVec;
+use fdt::Fdt;
+use spin::Mutex;
+
+use super::MAX_SOURCES;
+use crate::{GuestPhysAddr, HostPhysAddr, HyperError, HyperResult};
+
+/// The maximum number of interrupts a passthrough device can raise.
+pub const MAX_DEVICE_IRQS: usize = 8;
+/// The maximum number of host devices that can be assigned at the same time.
+pub const MAX_PASSTHROUGH_DEVICES: usize = 32;
+/// The maximum number of interrupts waiting to be delivered to VMs that are not running.
+const MAX_DEFERRED_IRQS: usize = 64;
+
+/// A host device which can be assigned to a VM.
+#[derive(Clone, Debug)]
+pub struct PassthroughDevice {
+    host_base: HostPhysAddr,
+    guest_base: GuestPhysAddr,
+    size: usize,
+    irqs: ArrayVec<u32, MAX_DEVICE_IRQS>,
+}
+
+impl PassthroughDevice {
+    /// Creates a device whose registers are at `host_base..host_base + size` and which raises the
+    /// PLIC sources in `irqs`. The device is identity mapped into the guest unless relocated.
+    pub fn new(host_base: HostPhysAddr, size: usize, irqs: &[u32]) -> HyperResult<Self> {
+        if size == 0 || irqs.len() > MAX_DEVICE_IRQS {
+            return Err(HyperError::InvalidParam);
+        }
+        let irqs = irqs
+            .iter()
+            .map(|&irq| Self::check_irq(irq))
+            .collect::<HyperResult<ArrayVec<u32, MAX_DEVICE_IRQS>>>()?;
+        Ok(Self {
+            host_base,
+            guest_base: host_base,
+            size,
+            irqs,
+        })
+    }
+
+    /// Creates a device from the node at `path` in the flattened device tree `fdt`, using the
+    /// first `reg` entry as the MMIO window and the `interrupts` property as PLIC sources.
+    pub fn from_fdt(fdt: &[u8], path: &str) -> HyperResult<Self> {
+        let fdt = Fdt::new(fdt).map_err(|_| HyperError::InvalidParam)?;
+        let node = fdt.find_node(path).ok_or(HyperError::NotFound)?;
+        let region = node
+            .reg()
+            .and_then(|mut reg| reg.next())
+            .ok_or(HyperError::InvalidParam)?;
+        let size = region.size.ok_or(HyperError::InvalidParam)?;
+        let mut irqs = ArrayVec::<u32, MAX_DEVICE_IRQS>::new();
+        if let Some(interrupts) = node.interrupts() {
+            for irq in interrupts {
+                irqs.try_push(irq as u32)
+                    .map_err(|_| HyperError::OutOfRange)?;
+            }
+        }
+        Self::new(region.starting_address as HostPhysAddr, size, &irqs)
+    }
+
+    /// Maps the device at `guest_base` in the guest physical address space instead of at its
+    /// host physical address.
+    pub fn relocate(mut self, guest_base: GuestPhysAddr) -> Self {
+        self.guest_base = guest_base;
+        self
+    }
+
+    /// The host physical address of the device's registers.
+    pub fn host_base(&self) -> HostPhysAddr {
+        self.host_base
+    }
+
+    /// The guest physical address the device's registers are mapped at.
+    pub fn guest_base(&self) -> GuestPhysAddr {
+        self.guest_base
+    }
+
+    /// The size of the device's register window.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The PLIC sources raised by the device.
+    pub fn irqs(&self) -> &[u32] {
+        &self.irqs
+    }
+
+    fn check_irq(irq: u32) -> HyperResult<u32> {
+        // Source 0 is reserved by the PLIC to mean "no interrupt".
+        if irq == 0 || irq as usize >= MAX_SOURCES {
+            Err(HyperError::InvalidParam)
+        } else {
+            Ok(irq)
+        }
+    }
+
+    fn overlaps(&self, other: &PassthroughDevice) -> bool {
+        self.host_base < other.host_base + other.size
+            && other.host_base < self.host_base + self.size
+    }
+}
+
+/// A device that has been claimed by a VM.
+struct Assignment {
+    device: PassthroughDevice,
+    vm_id: usize,
+}
+
+/// Tracks which VM owns which host device and interrupt, shared by all VMs.
+struct PassthroughRegistry {
+    assignments: ArrayVec<Assignment, MAX_PASSTHROUGH_DEVICES>,
+    irq_owners: [Option<usize>; MAX_SOURCES],
+    // Interrupts claimed on behalf of a VM other than the one running when they arrived.
+    deferred: ArrayVec<(usize, u32), MAX_DEFERRED_IRQS>,
+}
+
+static REGISTRY: Mutex<PassthroughRegistry> = Mutex::new(PassthroughRegistry {
+    assignments: ArrayVec::new_const(),
+    irq_owners: [None; MAX_SOURCES],
+    deferred: ArrayVec::new_const(),
+});
+
+/// Claims `device` for the VM `vm_id`. Fails if the device's registers or any of its interrupts
+/// are already owned by a VM.
+pub fn claim(device: &PassthroughDevice, vm_id: usize) -> HyperResult<()> {
+    let mut registry = REGISTRY.lock();
+    if registry
+        .assignments
+        .iter()
+        .any(|assigned| assigned.device.overlaps(device))
+    {
+        return Err(HyperError::BadState);
+    }
+    if device
+        .irqs()
+        .iter()
+        .any(|&irq| registry.irq_owners[irq as usize].is_some())
+    {
+        return Err(HyperError::BadState);
+    }
+    registry
+        .assignments
+        .try_push(Assignment {
+            device: device.clone(),
+            vm_id,
+        })
+        .map_err(|_| HyperError::NoMemory)?;
+    for &irq in device.irqs() {
+        registry.irq_owners[irq as usize] = Some(vm_id);
+    }
+    Ok(())
+}
+
+/// Releases `device`, which must have been claimed before.
+pub fn release(device: &PassthroughDevice) {
+    let mut registry = REGISTRY.lock();
+    registry
+        .assignments
+        .retain(|assigned| assigned.device.host_base != device.host_base);
+    for &irq in device.irqs() {
+        registry.irq_owners[irq as usize] = None;
+    }
+}
+
+/// Releases every device owned by `vm_id`.
+pub fn release_all(vm_id: usize) {
+    let mut registry = REGISTRY.lock();
+    registry
+        .assignments
+        .retain(|assigned| assigned.vm_id != vm_id);
+    for owner in registry.irq_owners.iter_mut() {
+        if *owner == Some(vm_id) {
+            *owner = None;
+        }
+    }
+    registry.deferred.retain(|(owner, _)| *owner != vm_id);
+}
+
+/// Returns the VM that owns the physical interrupt `irq`, if it has been assigned.
+pub fn irq_owner(irq: u32) -> Option<usize> {
+    REGISTRY
+        .lock()
+        .irq_owners
+        .get(irq as usize)
+        .copied()
+        .flatten()
+}
+
+/// Queues `irq` for delivery the next time the VM `vm_id` is run.
+pub fn defer_irq(vm_id: usize, irq: u32) -> HyperResult<()> {
+    REGISTRY
+        .lock()
+        .deferred
+        .try_push((vm_id, irq))
+        .map_err(|_| HyperError::NoMemory)
+}
+
+/// Removes the interrupts queued for `vm_id` and passes them to `deliver`.
+pub fn take_deferred_irqs(vm_id: usize, mut deliver: impl FnMut(u32)) {
+    let mut registry = REGISTRY.lock();
+    registry.deferred.retain(|(owner, irq)| {
+        if *owner == vm_id {
+            deliver(*irq);
+            false
+        } else {
+            true
+        }
+    });
+}
@@ -1,16 +1,38 @@
+use super::MAX_SOURCES;
 use crate::{
     arch::csrs::{traps, RiscvCsrTrait, CSR},
+    arch::snapshot::{SnapshotReader, SnapshotWriter},
     vcpus::MAX_CPUS,
+    HyperResult,
 };
 
 /// Number of contexts for the PLIC. Value is twice the max number of harts because each hart will
 /// have one M-mode context and one S-mode context.
 pub const MAX_CONTEXTS: usize = 2 * MAX_CPUS;
 
+// Offsets of the register blocks of a PLIC.
+const PRIORITY_BASE: usize = 0;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const PENDING_BASE: usize = 0x1000;
+const CONTEXT_BASE: usize = 0x20_0000;
+
+/// The context interrupts are injected into, hart 0's S-mode context, whose external interrupt
+/// is the guest's VSEIP.
+const GUEST_CONTEXT: usize = 1;
+
+/// The PLIC of a VM, which shares the host's device layout. Source priorities and enables are the
+/// host PLIC's, set by the guest through its mapped pages or forwarded when trapped, and apply to
+/// the sources of emulated devices too; their interrupts are delivered by priority, above the
+/// threshold of the emulated context.
 pub struct PlicState {
     base: usize,
-    source_priority: [u32; 512],
-    pending: [u32; 16],
+    source_priority: [u32; MAX_SOURCES],
+    pending: [u32; MAX_SOURCES / 32],
+    // Sources raised by emulated devices, whose completion is not forwarded to the host PLIC.
+    emulated: [u32; MAX_SOURCES / 32],
+    // Line levels of the emulated sources.
+    levels: [u32; MAX_SOURCES / 32],
     enable: [[u32; 32]; MAX_CONTEXTS],
     thresholds: [u32; MAX_CONTEXTS],
     pub claim_complete: [u32; MAX_CONTEXTS],
@@ -20,8 +42,10 @@ impl PlicState {
     pub fn new(base: usize) -> Self {
         Self {
             base,
-            source_priority: [0; 512],
-            pending: [0; 16],
+            source_priority: [0; MAX_SOURCES],
+            pending: [0; MAX_SOURCES / 32],
+            emulated: [0; MAX_SOURCES / 32],
+            levels: [0; MAX_SOURCES / 32],
             enable: [[0; 32]; MAX_CONTEXTS],
             thresholds: [0; MAX_CONTEXTS],
             claim_complete: [0; MAX_CONTEXTS],
@@ -34,22 +58,44 @@ impl PlicState {
 
     pub fn read_u32(&mut self, addr: usize) -> u32 {
         let offset = addr.wrapping_sub(self.base);
+        if (PENDING_BASE..PENDING_BASE + MAX_SOURCES / 8).contains(&offset) {
+            // Emulated sources are pending here rather than in the host PLIC.
+            let index = (offset - PENDING_BASE) / 4;
+            return self.host_reg(offset) | self.pending[index] & self.emulated[index];
+        }
+        if offset < CONTEXT_BASE && offset % 4 == 0 {
+            // Priorities and enables trapped rather than mapped are the host PLIC's.
+            return self.host_reg(offset);
+        }
         if (0x20_0000..0x20_0000 + 0x1000 * MAX_CONTEXTS).contains(&offset) {
             // threshold/claim/complete
             let hart = (offset - 0x200000) / 0x1000;
             let index = ((offset - 0x200000) & 0xfff) >> 2;
-            if index == 1 {
+            match index {
+                0 => return self.thresholds[hart],
                 // debug!("PLIC read@{:#x} -> {:#x}", addr, self.claim_complete[hart]);
-                return self.claim_complete[hart];
+                1 => return self.claim_complete[hart],
+                _ => {}
             }
-            todo!()
         }
-        todo!()
+        warn!("PLIC: read at {:#x} ignored", offset);
+        0
     }
 
     pub fn write_u32(&mut self, addr: usize, val: u32) {
         // debug!("PLIC write@{:#x} -> {:#x}", addr, val);
         let offset = addr.wrapping_sub(self.base);
+        if offset < CONTEXT_BASE {
+            // Priorities and enables are forwarded to the host PLIC, pending bits are read-only.
+            if !(PENDING_BASE..ENABLE_BASE).contains(&offset) && offset % 4 == 0 {
+                unsafe {
+                    core::ptr::write_volatile(addr as *mut u32, val);
+                }
+                // They may unmask pending emulated sources.
+                self.update(GUEST_CONTEXT);
+            }
+            return;
+        }
         // threshold/claim/complete
         if (0x200000..0x200000 + 0x1000 * MAX_CONTEXTS).contains(&offset) {
             let hart = (offset - 0x200000) / 0x1000;
@@ -60,18 +106,171 @@ impl PlicState {
                 unsafe {
                     core::ptr::write_volatile(addr as *mut u32, val);
                 }
+                // A lower threshold may unmask pending emulated sources.
+                self.update(hart);
             } else if index == 1 {
                 // claim
-                unsafe {
-                    core::ptr::write_volatile(addr as *mut u32, val);
+                if Self::test_bit(&self.emulated, val) {
+                    // A level-triggered source still asserted is raised again.
+                    if Self::test_bit(&self.levels, val) {
+                        Self::set_bit(&mut self.pending, val, true);
+                    }
+                } else {
+                    unsafe {
+                        core::ptr::write_volatile(addr as *mut u32, val);
+                    }
                 }
                 self.claim_complete[hart] = 0;
-                // Send Interrupt to the hart
-                CSR.hvip
-                    .read_and_clear_bits(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
+                self.update(hart);
+            } else {
+                warn!("PLIC: write at {:#x} ignored", offset);
             }
         } else {
-            todo!()
+            warn!("PLIC: write at {:#x} ignored", offset);
+        }
+    }
+
+    /// Injects the physical interrupt `irq`, which has been claimed from the host PLIC, into
+    /// `context`. If the guest has not completed the previous interrupt yet, `irq` is queued
+    /// until it does.
+    pub fn inject_irq(&mut self, context: usize, irq: u32) {
+        Self::set_bit(&mut self.pending, irq, true);
+        self.update(context);
+    }
+
+    /// Sets the level of the interrupt line `irq` of an emulated device. A raised line becomes
+    /// pending unless it is in service, and a lowered one is no longer pending.
+    pub fn set_irq_level(&mut self, context: usize, irq: u32, level: bool) {
+        if irq == 0 || irq as usize >= MAX_SOURCES {
+            return;
+        }
+        Self::set_bit(&mut self.emulated, irq, true);
+        Self::set_bit(&mut self.levels, irq, level);
+        let in_service = self.claim_complete[context] == irq;
+        Self::set_bit(&mut self.pending, irq, level && !in_service);
+        self.update(context);
+    }
+
+    /// Delivers to `context`, if it has no interrupt in service, the pending interrupt of highest
+    /// priority it may take, and asserts or deasserts the guest's external interrupt accordingly.
+    /// Emulated sources whose priority or enable changed in the host PLIC are picked up here. Only
+    /// the guest context is injected into.
+    pub fn update(&mut self, context: usize) {
+        if context != GUEST_CONTEXT {
+            return;
+        }
+        if self.claim_complete[context] == 0 {
+            self.claim_complete[context] = self.take_pending(context).unwrap_or(0);
+        }
+        if self.claim_complete[context] != 0 {
+            CSR.hvip
+                .read_and_set_bits(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
+        } else {
+            CSR.hvip
+                .read_and_clear_bits(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
+        }
+    }
+
+    /// Returns the emulated contexts to their state at power-on, completing at the host PLIC the
+    /// host interrupts the guest had not completed yet.
+    pub fn reset(&mut self) {
+        for context in 0..MAX_CONTEXTS {
+            let irq = self.claim_complete[context];
+            if irq != 0 && !Self::test_bit(&self.emulated, irq) {
+                self.complete_host_irq(context, irq);
+            }
+        }
+        // Queued interrupts were all injected into the guest context.
+        for irq in 1..MAX_SOURCES as u32 {
+            if Self::test_bit(&self.pending, irq) && !Self::test_bit(&self.emulated, irq) {
+                self.complete_host_irq(GUEST_CONTEXT, irq);
+            }
+        }
+        *self = Self::new(self.base);
+    }
+
+    /// Saves the state of the emulated contexts into a VM snapshot.
+    pub fn save(&self, out: &mut SnapshotWriter) {
+        out.write_u32s(&self.source_priority);
+        out.write_u32s(&self.pending);
+        out.write_u32s(&self.emulated);
+        out.write_u32s(&self.levels);
+        self.enable.iter().for_each(|enable| out.write_u32s(enable));
+        out.write_u32s(&self.thresholds);
+        out.write_u32s(&self.claim_complete);
+    }
+
+    /// Restores the state saved by `save`. The host PLIC is left as it is.
+    pub fn restore(&mut self, input: &mut SnapshotReader) -> HyperResult<()> {
+        input.read_u32s(&mut self.source_priority)?;
+        input.read_u32s(&mut self.pending)?;
+        input.read_u32s(&mut self.emulated)?;
+        input.read_u32s(&mut self.levels)?;
+        for enable in self.enable.iter_mut() {
+            input.read_u32s(enable)?;
+        }
+        input.read_u32s(&mut self.thresholds)?;
+        input.read_u32s(&mut self.claim_complete)
+    }
+
+    fn complete_host_irq(&self, context: usize, irq: u32) {
+        let addr = self.base + 0x20_0004 + 0x1000 * context;
+        unsafe {
+            core::ptr::write_volatile(addr as *mut u32, irq);
+        }
+    }
+
+    fn test_bit(bits: &[u32], irq: u32) -> bool {
+        bits.get(irq as usize / 32)
+            .map_or(false, |word| word & (1 << (irq % 32)) != 0)
+    }
+
+    fn set_bit(bits: &mut [u32], irq: u32, set: bool) {
+        if let Some(word) = bits.get_mut(irq as usize / 32) {
+            if set {
+                *word |= 1 << (irq % 32);
+            } else {
+                *word &= !(1 << (irq % 32));
+            }
+        }
+    }
+
+    /// Reads the register at `offset` of the host PLIC.
+    fn host_reg(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.base + offset) as *const u32) }
+    }
+
+    /// Returns the priority of `irq` if `context` may take it. Host interrupts were claimed from
+    /// the host PLIC and so already passed its checks, while emulated ones must be enabled for
+    /// `context` and above its threshold.
+    fn deliverable_priority(&self, context: usize, irq: u32) -> Option<u32> {
+        let priority = self.host_reg(PRIORITY_BASE + 4 * irq as usize);
+        if !Self::test_bit(&self.emulated, irq) {
+            return Some(priority);
+        }
+        let enable_reg = ENABLE_BASE + ENABLE_STRIDE * context + 4 * (irq as usize / 32);
+        let enabled = self.host_reg(enable_reg) & (1 << (irq % 32)) != 0;
+        (enabled && priority > self.thresholds[context]).then_some(priority)
+    }
+
+    /// Takes the pending interrupt of highest priority `context` may take, the lowest-numbered
+    /// one among equals.
+    fn take_pending(&mut self, context: usize) -> Option<u32> {
+        let mut best: Option<(u32, u32)> = None;
+        let pending = self.pending.iter().enumerate().flat_map(|(index, &word)| {
+            (0..32)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| index as u32 * 32 + bit)
+        });
+        for irq in pending {
+            if let Some(priority) = self.deliverable_priority(context, irq) {
+                if best.map_or(true, |(_, best)| priority > best) {
+                    best = Some((irq, priority));
+                }
+            }
         }
+        let (irq, _) = best?;
+        Self::set_bit(&mut self.pending, irq, false);
+        Some(irq)
     }
 }
@@ -0,0 +1,160 @@
+//! Emulated Goldfish RTC.
+//!
+//! The time is read from the host through `HyperCraftHal::wall_time_nanos`; a time set by the
+//! guest is kept as an offset from it, so each VM has its own clock.
+
+use core::marker::PhantomData;
+
+use super::MmioDevice;
+use crate::arch::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::{HyperCraftHal, HyperError, HyperResult};
+
+/// Size of the RTC's register window.
+pub const RTC_SIZE: usize = 0x1000;
+
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+const ALARM_LOW: usize = 0x08;
+const ALARM_HIGH: usize = 0x0c;
+const IRQ_ENABLED: usize = 0x10;
+const CLEAR_ALARM: usize = 0x14;
+const ALARM_STATUS: usize = 0x18;
+const CLEAR_INTERRUPT: usize = 0x1c;
+
+/// A Goldfish RTC keeping nanoseconds since the Unix epoch, with a single alarm.
+pub struct GoldfishRtc<H: HyperCraftHal> {
+    // Difference between the guest's time and the host's.
+    offset: u64,
+    // High half of the time, latched by reading TIME_LOW.
+    time_high: u32,
+    // High half of the time or alarm, taking effect when the low half is written.
+    pending_high: u32,
+    alarm: Option<u64>,
+    irq_enabled: bool,
+    irq_pending: bool,
+    _marker: PhantomData<H>,
+}
+
+impl<H: HyperCraftHal> Default for GoldfishRtc<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: HyperCraftHal> GoldfishRtc<H> {
+    /// Creates an RTC following the host's time.
+    pub fn new() -> Self {
+        Self {
+            offset: 0,
+            time_high: 0,
+            pending_high: 0,
+            alarm: None,
+            irq_enabled: false,
+            irq_pending: false,
+            _marker: PhantomData,
+        }
+    }
+
+    fn now(&self) -> u64 {
+        H::wall_time_nanos().wrapping_add(self.offset)
+    }
+}
+
+impl<H: HyperCraftHal> MmioDevice for GoldfishRtc<H> {
+    fn size(&self) -> usize {
+        RTC_SIZE
+    }
+
+    fn read(&mut self, offset: usize, width: usize) -> HyperResult<usize> {
+        if width != 4 || offset % 4 != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        let val = match offset {
+            TIME_LOW => {
+                let now = self.now();
+                self.time_high = (now >> 32) as u32;
+                now as u32
+            }
+            TIME_HIGH => self.time_high,
+            ALARM_LOW => self.alarm.map_or(0, |t| t as u32),
+            ALARM_HIGH => self.alarm.map_or(0, |t| (t >> 32) as u32),
+            IRQ_ENABLED => self.irq_enabled as u32,
+            ALARM_STATUS => self.alarm.is_some() as u32,
+            CLEAR_ALARM | CLEAR_INTERRUPT => 0,
+            _ => return Err(HyperError::InvalidParam),
+        };
+        Ok(val as usize)
+    }
+
+    fn write(&mut self, offset: usize, width: usize, val: usize) -> HyperResult<()> {
+        if width != 4 || offset % 4 != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        let val = val as u32;
+        match offset {
+            TIME_HIGH | ALARM_HIGH => self.pending_high = val,
+            TIME_LOW => {
+                let time = (self.pending_high as u64) << 32 | val as u64;
+                self.offset = time.wrapping_sub(H::wall_time_nanos());
+            }
+            ALARM_LOW => {
+                let alarm = (self.pending_high as u64) << 32 | val as u64;
+                // An alarm in the past fires right away.
+                if alarm <= self.now() {
+                    self.alarm = None;
+                    self.irq_pending = true;
+                } else {
+                    self.alarm = Some(alarm);
+                }
+            }
+            IRQ_ENABLED => self.irq_enabled = val & 1 != 0,
+            CLEAR_ALARM => self.alarm = None,
+            CLEAR_INTERRUPT => self.irq_pending = false,
+            ALARM_STATUS => {}
+            _ => return Err(HyperError::InvalidParam),
+        }
+        Ok(())
+    }
+
+    fn irq_level(&self) -> bool {
+        self.irq_enabled && self.irq_pending
+    }
+
+    fn poll(&mut self) {
+        if let Some(alarm) = self.alarm {
+            if self.now() >= alarm {
+                self.alarm = None;
+                self.irq_pending = true;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        // The time set by the guest survives reboots, as a battery-backed clock's would.
+        *self = Self {
+            offset: self.offset,
+            ..Self::new()
+        };
+    }
+
+    fn save(&self, out: &mut SnapshotWriter) -> HyperResult<()> {
+        out.write_u64(self.offset);
+        out.write_u32(self.time_high);
+        out.write_u32(self.pending_high);
+        // An alarm of 0 would have fired when set, so it stands for none.
+        out.write_u64(self.alarm.unwrap_or(0));
+        out.write_bool(self.irq_enabled);
+        out.write_bool(self.irq_pending);
+        Ok(())
+    }
+
+    fn restore(&mut self, input: &mut SnapshotReader) -> HyperResult<()> {
+        self.offset = input.read_u64()?;
+        self.time_high = input.read_u32()?;
+        self.pending_high = input.read_u32()?;
+        self.alarm = Some(input.read_u64()?).filter(|&alarm| alarm != 0);
+        self.irq_enabled = input.read_bool()?;
+        self.irq_pending = input.read_bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+//! Emulated SiFive test finisher (`sifive,test`), also used as the syscon behind
+//! `syscon-poweroff` and `syscon-reboot`.
+
+use super::MmioDevice;
+use crate::arch::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::arch::VmExitReason;
+use crate::HyperResult;
+
+/// Size of the test device's register window.
+pub const TEST_SIZE: usize = 0x1000;
+
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+/// A SiFive test device, turning the guest's power-off and reset requests into VM exits.
+#[derive(Default)]
+pub struct SifiveTest {
+    request: Option<VmExitReason>,
+}
+
+impl SifiveTest {
+    /// Creates a test device with no request pending.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl MmioDevice for SifiveTest {
+    fn size(&self) -> usize {
+        TEST_SIZE
+    }
+
+    fn read(&mut self, offset: usize, width: usize) -> HyperResult<usize> {
+        // Only the finisher register exists; everything else reads as zero.
+        if offset != 0 || width != 4 {
+            warn!("SiFive test: {}-byte read at {:#x} ignored", width, offset);
+        }
+        Ok(0)
+    }
+
+    fn write(&mut self, offset: usize, width: usize, val: usize) -> HyperResult<()> {
+        if offset != 0 || width != 4 {
+            warn!(
+                "SiFive test: {}-byte write of {:#x} at {:#x} ignored",
+                width, val, offset
+            );
+            return Ok(());
+        }
+        let val = val as u32;
+        // The failure code goes in the upper 16 bits.
+        self.request = match val & 0xffff {
+            FINISHER_PASS => Some(VmExitReason::Shutdown),
+            FINISHER_FAIL => Some(VmExitReason::Failure(val >> 16)),
+            FINISHER_RESET => Some(VmExitReason::Reboot),
+            _ => None,
+        };
+        Ok(())
+    }
+
+    fn take_exit_request(&mut self) -> Option<VmExitReason> {
+        self.request.take()
+    }
+
+    fn reset(&mut self) {
+        self.request = None;
+    }
+
+    // A request is taken as soon as the write completes, so there is no state to save.
+    fn save(&self, _out: &mut SnapshotWriter) -> HyperResult<()> {
+        Ok(())
+    }
+
+    fn restore(&mut self, _input: &mut SnapshotReader) -> HyperResult<()> {
+        self.request = None;
+        Ok(())
+    }
+}
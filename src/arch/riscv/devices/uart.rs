@@ -0,0 +1,240 @@
+//! Emulated NS16550A UART.
+//!
+//! Output is written to the host console and input read from it through the `HyperCraftHal`
+//! console hooks, tagged with the VM's id so each VM gets its own serial console. Registers are
+//! byte-wide with a register shift of 0, as on the QEMU virt machine.
+
+use alloc::collections::VecDeque;
+use core::marker::PhantomData;
+
+use super::MmioDevice;
+use crate::arch::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::{HyperCraftHal, HyperError, HyperResult};
+
+/// Size of the UART's register window.
+pub const UART_SIZE: usize = 0x100;
+/// Depth of the receive FIFO.
+const FIFO_DEPTH: usize = 16;
+
+const RBR_THR_DLL: usize = 0;
+const IER_DLM: usize = 1;
+const IIR_FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+const MSR: usize = 6;
+const SCR: usize = 7;
+
+// Interrupt enable register.
+const IER_ERBFI: u8 = 1 << 0;
+const IER_ETBEI: u8 = 1 << 1;
+const IER_MASK: u8 = 0x0f;
+
+// Interrupt identification register.
+const IIR_NO_INT: u8 = 0x01;
+const IIR_THRE: u8 = 0x02;
+const IIR_RDA: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+// FIFO control register.
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+
+// Line control register.
+const LCR_DLAB: u8 = 1 << 7;
+
+// Modem control register.
+const MCR_LOOP: u8 = 1 << 4;
+
+// Line status register.
+const LSR_DR: u8 = 1 << 0;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+// Modem status register: carrier detect, data set ready and clear to send asserted.
+const MSR_DEFAULT: u8 = 0xb0;
+
+/// An emulated 16550 UART backed by the host console.
+pub struct Uart16550<H: HyperCraftHal> {
+    vm_id: usize,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    fifo_enabled: bool,
+    // Set while the "transmitter holding register empty" interrupt is pending.
+    thre_pending: bool,
+    rx_fifo: VecDeque<u8>,
+    marker: PhantomData<H>,
+}
+
+impl<H: HyperCraftHal> Uart16550<H> {
+    /// Creates a UART for the VM `vm_id`.
+    pub fn new(vm_id: usize) -> Self {
+        Self {
+            vm_id,
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            dll: 0,
+            dlm: 0,
+            fifo_enabled: false,
+            thre_pending: false,
+            rx_fifo: VecDeque::with_capacity(FIFO_DEPTH),
+            marker: PhantomData,
+        }
+    }
+
+    fn dlab(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+
+    fn transmit(&mut self, c: u8) {
+        if self.mcr & MCR_LOOP != 0 {
+            if self.rx_fifo.len() < FIFO_DEPTH {
+                self.rx_fifo.push_back(c);
+            }
+        } else {
+            H::console_putchar(self.vm_id, c);
+        }
+        // Transmission is immediate, so the holding register is empty again.
+        self.thre_pending = true;
+    }
+
+    fn iir(&mut self) -> u8 {
+        let fifo = if self.fifo_enabled {
+            IIR_FIFO_ENABLED
+        } else {
+            0
+        };
+        if self.ier & IER_ERBFI != 0 && !self.rx_fifo.is_empty() {
+            fifo | IIR_RDA
+        } else if self.ier & IER_ETBEI != 0 && self.thre_pending {
+            // Reading IIR acknowledges the THRE interrupt.
+            self.thre_pending = false;
+            fifo | IIR_THRE
+        } else {
+            fifo | IIR_NO_INT
+        }
+    }
+
+    fn lsr(&self) -> u8 {
+        let dr = if self.rx_fifo.is_empty() { 0 } else { LSR_DR };
+        dr | LSR_THRE | LSR_TEMT
+    }
+}
+
+impl<H: HyperCraftHal> MmioDevice for Uart16550<H> {
+    fn size(&self) -> usize {
+        UART_SIZE
+    }
+
+    fn read(&mut self, offset: usize, width: usize) -> HyperResult<usize> {
+        // The registers are byte-wide; other accesses read as zero rather than fault the guest.
+        if width != 1 {
+            warn!("UART: {}-byte read at {:#x} ignored", width, offset);
+            return Ok(0);
+        }
+        let val = match offset {
+            RBR_THR_DLL if self.dlab() => self.dll,
+            RBR_THR_DLL => self.rx_fifo.pop_front().unwrap_or(0),
+            IER_DLM if self.dlab() => self.dlm,
+            IER_DLM => self.ier,
+            IIR_FCR => self.iir(),
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => self.lsr(),
+            MSR => MSR_DEFAULT,
+            SCR => self.scr,
+            _ => 0,
+        };
+        Ok(val as usize)
+    }
+
+    fn write(&mut self, offset: usize, width: usize, val: usize) -> HyperResult<()> {
+        if width != 1 {
+            warn!(
+                "UART: {}-byte write of {:#x} at {:#x} ignored",
+                width, val, offset
+            );
+            return Ok(());
+        }
+        let val = val as u8;
+        match offset {
+            RBR_THR_DLL if self.dlab() => self.dll = val,
+            RBR_THR_DLL => self.transmit(val),
+            IER_DLM if self.dlab() => self.dlm = val,
+            IER_DLM => {
+                // Enabling the THRE interrupt raises it right away as the register is empty.
+                if val & IER_ETBEI != 0 && self.ier & IER_ETBEI == 0 {
+                    self.thre_pending = true;
+                }
+                self.ier = val & IER_MASK;
+            }
+            IIR_FCR => {
+                self.fifo_enabled = val & FCR_ENABLE != 0;
+                if val & FCR_CLEAR_RX != 0 {
+                    self.rx_fifo.clear();
+                }
+            }
+            LCR => self.lcr = val,
+            MCR => self.mcr = val,
+            SCR => self.scr = val,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn irq_level(&self) -> bool {
+        (self.ier & IER_ERBFI != 0 && !self.rx_fifo.is_empty())
+            || (self.ier & IER_ETBEI != 0 && self.thre_pending)
+    }
+
+    fn poll(&mut self) {
+        while self.rx_fifo.len() < FIFO_DEPTH {
+            match H::console_getchar(self.vm_id) {
+                Some(c) => self.rx_fifo.push_back(c),
+                None => break,
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.vm_id);
+    }
+
+    fn save(&self, out: &mut SnapshotWriter) -> HyperResult<()> {
+        for reg in [self.ier, self.lcr, self.mcr, self.scr, self.dll, self.dlm] {
+            out.write_u8(reg);
+        }
+        out.write_bool(self.fifo_enabled);
+        out.write_bool(self.thre_pending);
+        let (front, back) = self.rx_fifo.as_slices();
+        out.write_bytes(&[front, back].concat());
+        Ok(())
+    }
+
+    fn restore(&mut self, input: &mut SnapshotReader) -> HyperResult<()> {
+        for reg in [
+            &mut self.ier,
+            &mut self.lcr,
+            &mut self.mcr,
+            &mut self.scr,
+            &mut self.dll,
+            &mut self.dlm,
+        ] {
+            *reg = input.read_u8()?;
+        }
+        self.fifo_enabled = input.read_bool()?;
+        self.thre_pending = input.read_bool()?;
+        let rx = input.read_bytes()?;
+        if rx.len() > FIFO_DEPTH {
+            return Err(HyperError::InvalidParam);
+        }
+        self.rx_fifo = rx.iter().copied().collect();
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+//! Virtio block device.
+
+use alloc::boxed::Box;
+use alloc::vec;
+
+use super::{device_id, DescChain, VirtQueue, VirtioDevice};
+use crate::arch::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::arch::vm_pages::VmPages;
+use crate::{HyperError, HyperResult};
+
+/// Size of a block device sector, the unit of all request offsets.
+pub const SECTOR_SIZE: usize = 512;
+
+// Feature bits.
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+
+// Request types.
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+
+// Request status.
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// Size of the request header: type, reserved and sector.
+const REQ_HEADER_SIZE: usize = 16;
+/// Size of a discard segment: sector, number of sectors and flags.
+const DISCARD_SEGMENT_SIZE: usize = 16;
+/// Length of the device ID string returned by GET_ID.
+const VIRTIO_BLK_ID_BYTES: usize = 20;
+
+/// Maximum number of data segments in a request.
+const SEG_MAX: u32 = 126;
+/// Maximum number of segments in a discard request.
+const MAX_DISCARD_SEG: u32 = 32;
+/// Size of the configuration space used by this device.
+const CONFIG_SIZE: usize = 60;
+
+/// The storage behind a virtio block device, supplied by the embedding kernel.
+pub trait BlockBackend {
+    /// Returns the size of the device in sectors.
+    fn capacity(&self) -> u64;
+
+    /// Reads `buf.len()` bytes, a multiple of the sector size, starting at `sector`.
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> HyperResult<()>;
+
+    /// Writes `buf`, a multiple of the sector size long, starting at `sector`.
+    fn write(&mut self, sector: u64, buf: &[u8]) -> HyperResult<()>;
+
+    /// Makes previous writes durable.
+    fn flush(&mut self) -> HyperResult<()> {
+        Ok(())
+    }
+
+    /// Tells the backend that `count` sectors starting at `sector` are no longer used.
+    fn discard(&mut self, _sector: u64, _count: u32) -> HyperResult<()> {
+        Ok(())
+    }
+
+    /// Returns the device's identifying string, truncated to 20 bytes.
+    fn device_id(&self) -> &[u8] {
+        b""
+    }
+
+    /// Returns whether the device rejects writes.
+    fn read_only(&self) -> bool {
+        false
+    }
+}
+
+/// A virtio-blk device with a single request queue.
+pub struct VirtioBlk {
+    backend: Box<dyn BlockBackend>,
+}
+
+impl VirtioBlk {
+    /// Creates a block device whose storage is `backend`.
+    pub fn new(backend: Box<dyn BlockBackend>) -> Self {
+        Self { backend }
+    }
+
+    /// Executes the request in `chain`, returning its status and the number of bytes written to
+    /// the data buffers.
+    fn execute(&mut self, chain: &DescChain, mem: &VmPages) -> HyperResult<(u8, usize)> {
+        let mut header = [0u8; REQ_HEADER_SIZE];
+        if chain.read_at(mem, 0, &mut header)? != REQ_HEADER_SIZE {
+            return Err(HyperError::InvalidParam);
+        }
+        let req_type = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        // The status byte is the last writable byte; data buffers precede it.
+        let data_in_len = chain.writable_len() - 1;
+        let data_out_len = chain.readable_len() - REQ_HEADER_SIZE;
+
+        let result = match req_type {
+            VIRTIO_BLK_T_IN => {
+                self.check_range(sector, data_in_len)?;
+                let mut buf = vec![0; data_in_len];
+                self.backend
+                    .read(sector, &mut buf)
+                    .and_then(|_| chain.write_at(mem, 0, &buf))
+            }
+            VIRTIO_BLK_T_OUT => {
+                if self.backend.read_only() {
+                    return Ok((VIRTIO_BLK_S_IOERR, 0));
+                }
+                self.check_range(sector, data_out_len)?;
+                let mut buf = vec![0; data_out_len];
+                chain.read_at(mem, REQ_HEADER_SIZE, &mut buf)?;
+                self.backend.write(sector, &buf).map(|_| 0)
+            }
+            VIRTIO_BLK_T_FLUSH => self.backend.flush().map(|_| 0),
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = [0u8; VIRTIO_BLK_ID_BYTES];
+                let dev_id = self.backend.device_id();
+                let len = dev_id.len().min(VIRTIO_BLK_ID_BYTES);
+                id[..len].copy_from_slice(&dev_id[..len]);
+                let len = data_in_len.min(VIRTIO_BLK_ID_BYTES);
+                chain.write_at(mem, 0, &id[..len])
+            }
+            VIRTIO_BLK_T_DISCARD => {
+                if self.backend.read_only() {
+                    return Ok((VIRTIO_BLK_S_IOERR, 0));
+                }
+                self.discard(chain, mem, data_out_len).map(|_| 0)
+            }
+            _ => return Ok((VIRTIO_BLK_S_UNSUPP, 0)),
+        };
+        Ok(match result {
+            Ok(written) => (VIRTIO_BLK_S_OK, written),
+            Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+        })
+    }
+
+    fn discard(&mut self, chain: &DescChain, mem: &VmPages, len: usize) -> HyperResult<()> {
+        let nr_segs = len / DISCARD_SEGMENT_SIZE;
+        if len % DISCARD_SEGMENT_SIZE != 0 || nr_segs > MAX_DISCARD_SEG as usize {
+            return Err(HyperError::InvalidParam);
+        }
+        for i in 0..nr_segs {
+            let mut seg = [0u8; DISCARD_SEGMENT_SIZE];
+            chain.read_at(mem, REQ_HEADER_SIZE + i * DISCARD_SEGMENT_SIZE, &mut seg)?;
+            let sector = u64::from_le_bytes(seg[0..8].try_into().unwrap());
+            let count = u32::from_le_bytes(seg[8..12].try_into().unwrap());
+            self.check_range(sector, count as usize * SECTOR_SIZE)?;
+            self.backend.discard(sector, count)?;
+        }
+        Ok(())
+    }
+
+    fn check_range(&self, sector: u64, len: usize) -> HyperResult<()> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        let end = sector
+            .checked_add((len / SECTOR_SIZE) as u64)
+            .ok_or(HyperError::OutOfRange)?;
+        if end > self.backend.capacity() {
+            return Err(HyperError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    fn config(&self) -> [u8; CONFIG_SIZE] {
+        let mut config = [0u8; CONFIG_SIZE];
+        config[0..8].copy_from_slice(&self.backend.capacity().to_le_bytes());
+        config[12..16].copy_from_slice(&SEG_MAX.to_le_bytes());
+        config[20..24].copy_from_slice(&(SECTOR_SIZE as u32).to_le_bytes());
+        config[34..36].copy_from_slice(&1u16.to_le_bytes());
+        config[36..40].copy_from_slice(&u32::MAX.to_le_bytes());
+        config[40..44].copy_from_slice(&MAX_DISCARD_SEG.to_le_bytes());
+        config[44..48].copy_from_slice(&1u32.to_le_bytes());
+        config
+    }
+}
+
+impl VirtioDevice for VirtioBlk {
+    fn device_id(&self) -> u32 {
+        device_id::BLOCK
+    }
+
+    fn device_features(&self) -> u64 {
+        let mut features = VIRTIO_BLK_F_SEG_MAX
+            | VIRTIO_BLK_F_BLK_SIZE
+            | VIRTIO_BLK_F_FLUSH
+            | VIRTIO_BLK_F_DISCARD;
+        if self.backend.read_only() {
+            features |= VIRTIO_BLK_F_RO;
+        }
+        features
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn config_size(&self) -> usize {
+        CONFIG_SIZE
+    }
+
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        let config = self.config();
+        data.copy_from_slice(&config[offset..offset + data.len()]);
+    }
+
+    fn queue_notify(
+        &mut self,
+        index: usize,
+        queues: &mut [VirtQueue],
+        mem: &VmPages,
+    ) -> HyperResult<bool> {
+        let queue = &mut queues[index];
+        let mut used = false;
+        while let Some(chain) = queue.pop_avail(mem)? {
+            // A request needs at least the header and the status byte.
+            if chain.readable_len() < REQ_HEADER_SIZE || chain.writable_len() == 0 {
+                return Err(HyperError::InvalidParam);
+            }
+            let (status, written) = match self.execute(&chain, mem) {
+                Ok(result) => result,
+                Err(HyperError::PageFault) => return Err(HyperError::PageFault),
+                Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+            };
+            chain.write_at(mem, chain.writable_len() - 1, &[status])?;
+            queue.push_used(mem, chain.head(), (written + 1) as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    // Requests complete within `queue_notify` and the data lives in the backend, so the device
+    // has no state of its own.
+    fn save(&self, _out: &mut SnapshotWriter) -> HyperResult<()> {
+        Ok(())
+    }
+
+    fn restore(&mut self, _input: &mut SnapshotReader) -> HyperResult<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,369 @@
+//! Virtio console device with multiport support.
+//!
+//! Each port is a byte stream between the guest and the host. Port 0 is announced to the guest as
+//! a console; the others appear as named serial ports (`/dev/vportNpM`, named through sysfs), as
+//! used by guest agents. The host side of the ports is accessed through a `ConsoleHandle`.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::{device_id, VirtQueue, VirtioDevice};
+use crate::arch::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::arch::vm_pages::VmPages;
+use crate::{HyperError, HyperResult};
+
+// Feature bits.
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+
+// Control message events.
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// Size of a control message: port id, event and value.
+const CONTROL_MSG_SIZE: usize = 8;
+const CONFIG_SIZE: usize = 12;
+
+const CONTROL_RX_QUEUE: usize = 2;
+const CONTROL_TX_QUEUE: usize = 3;
+
+/// Maximum number of bytes buffered in each direction of a port.
+const PORT_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Default)]
+struct Port {
+    name: Option<String>,
+    // Whether the guest has the port open.
+    open: bool,
+    // Bytes from the host waiting to be received by the guest.
+    to_guest: VecDeque<u8>,
+    // Bytes sent by the guest waiting to be read by the host.
+    to_host: VecDeque<u8>,
+}
+
+/// The host side of the ports of a virtio console.
+#[derive(Clone)]
+pub struct ConsoleHandle {
+    ports: Arc<Mutex<Vec<Port>>>,
+}
+
+impl ConsoleHandle {
+    /// Returns the number of ports of the console.
+    pub fn num_ports(&self) -> usize {
+        self.ports.lock().len()
+    }
+
+    /// Returns whether the guest has opened `port`.
+    pub fn is_open(&self, port: usize) -> bool {
+        self.ports.lock().get(port).map_or(false, |p| p.open)
+    }
+
+    /// Queues `data` for the guest on `port`, returning how many bytes fit in the port's buffer.
+    pub fn write(&self, port: usize, data: &[u8]) -> HyperResult<usize> {
+        let mut ports = self.ports.lock();
+        let port = ports.get_mut(port).ok_or(HyperError::NotFound)?;
+        let len = data.len().min(PORT_BUFFER_SIZE - port.to_guest.len());
+        port.to_guest.extend(&data[..len]);
+        Ok(len)
+    }
+
+    /// Reads the bytes the guest sent on `port` into `buf`, returning how many were read.
+    pub fn read(&self, port: usize, buf: &mut [u8]) -> HyperResult<usize> {
+        let mut ports = self.ports.lock();
+        let port = ports.get_mut(port).ok_or(HyperError::NotFound)?;
+        let len = buf.len().min(port.to_host.len());
+        for (dst, src) in buf.iter_mut().zip(port.to_host.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+/// A virtio console with a fixed set of ports.
+pub struct VirtioConsole {
+    ports: Arc<Mutex<Vec<Port>>>,
+    multiport: bool,
+    // Control messages waiting to be received by the guest.
+    control_out: VecDeque<Vec<u8>>,
+}
+
+impl VirtioConsole {
+    /// Creates a console with one port per entry of `port_names`. Port 0 is the console port;
+    /// the other ports are given the corresponding names.
+    pub fn new(port_names: &[&str]) -> HyperResult<(Self, ConsoleHandle)> {
+        if port_names.is_empty() {
+            return Err(HyperError::InvalidParam);
+        }
+        let ports = port_names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| Port {
+                name: (i != 0 && !name.is_empty()).then(|| String::from(*name)),
+                ..Default::default()
+            })
+            .collect();
+        let ports = Arc::new(Mutex::new(ports));
+        let console = Self {
+            ports: ports.clone(),
+            multiport: false,
+            control_out: VecDeque::new(),
+        };
+        Ok((console, ConsoleHandle { ports }))
+    }
+
+    /// Returns the receive and transmit queue indices of `port`.
+    fn port_queues(port: usize) -> (usize, usize) {
+        match port {
+            0 => (0, 1),
+            _ => (2 + port * 2, 3 + port * 2),
+        }
+    }
+
+    /// Returns the port using queue `index` for data.
+    fn queue_port(index: usize) -> Option<usize> {
+        match index {
+            0 | 1 => Some(0),
+            CONTROL_RX_QUEUE | CONTROL_TX_QUEUE => None,
+            _ => Some(index / 2 - 1),
+        }
+    }
+
+    fn send_control(&mut self, id: u32, event: u16, value: u16, extra: &[u8]) {
+        let mut msg = Vec::with_capacity(CONTROL_MSG_SIZE + extra.len());
+        msg.extend_from_slice(&id.to_le_bytes());
+        msg.extend_from_slice(&event.to_le_bytes());
+        msg.extend_from_slice(&value.to_le_bytes());
+        msg.extend_from_slice(extra);
+        self.control_out.push_back(msg);
+    }
+
+    fn handle_control(&mut self, queue: &mut VirtQueue, mem: &VmPages) -> HyperResult<bool> {
+        let mut used = false;
+        while let Some(chain) = queue.pop_avail(mem)? {
+            let mut msg = [0u8; CONTROL_MSG_SIZE];
+            if chain.read_at(mem, 0, &mut msg)? == CONTROL_MSG_SIZE {
+                let id = u32::from_le_bytes(msg[0..4].try_into().unwrap());
+                let event = u16::from_le_bytes([msg[4], msg[5]]);
+                let value = u16::from_le_bytes([msg[6], msg[7]]);
+                self.control_event(id, event, value);
+            }
+            queue.push_used(mem, chain.head(), 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn control_event(&mut self, id: u32, event: u16, value: u16) {
+        let num_ports = self.ports.lock().len();
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY if value == 1 => {
+                for port in 0..num_ports {
+                    self.send_control(port as u32, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY if value == 1 && (id as usize) < num_ports => {
+                if id == 0 {
+                    self.send_control(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]);
+                }
+                let name = self.ports.lock()[id as usize].name.clone();
+                if let Some(name) = name {
+                    self.send_control(id, VIRTIO_CONSOLE_PORT_NAME, 1, name.as_bytes());
+                }
+                self.send_control(id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                if let Some(port) = self.ports.lock().get_mut(id as usize) {
+                    port.open = value == 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn deliver_control(&mut self, queue: &mut VirtQueue, mem: &VmPages) -> HyperResult<bool> {
+        let mut used = false;
+        while !self.control_out.is_empty() {
+            let chain = match queue.pop_avail(mem)? {
+                Some(chain) => chain,
+                None => break,
+            };
+            let msg = self.control_out.pop_front().unwrap();
+            let written = chain.write_at(mem, 0, &msg)?;
+            queue.push_used(mem, chain.head(), written as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn transmit(&mut self, port: usize, queue: &mut VirtQueue, mem: &VmPages) -> HyperResult<bool> {
+        let mut used = false;
+        while let Some(chain) = queue.pop_avail(mem)? {
+            let data = chain.read_all(mem)?;
+            if let Some(port) = self.ports.lock().get_mut(port) {
+                // Output nobody reads is dropped once the buffer is full, like a closed terminal.
+                let len = data.len().min(PORT_BUFFER_SIZE - port.to_host.len());
+                port.to_host.extend(&data[..len]);
+            }
+            queue.push_used(mem, chain.head(), 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn receive(&mut self, port: usize, queue: &mut VirtQueue, mem: &VmPages) -> HyperResult<bool> {
+        let mut ports = self.ports.lock();
+        let port = match ports.get_mut(port) {
+            Some(port) => port,
+            None => return Ok(false),
+        };
+        let mut used = false;
+        while !port.to_guest.is_empty() {
+            let chain = match queue.pop_avail(mem)? {
+                Some(chain) => chain,
+                None => break,
+            };
+            let len = chain.writable_len().min(port.to_guest.len());
+            let data: Vec<u8> = port.to_guest.drain(..len).collect();
+            let written = chain.write_at(mem, 0, &data)?;
+            queue.push_used(mem, chain.head(), written as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        device_id::CONSOLE
+    }
+
+    fn device_features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_MULTIPORT
+    }
+
+    fn num_queues(&self) -> usize {
+        // The control queues sit between port 0's and port 1's queues.
+        2 * (self.ports.lock().len() + 1)
+    }
+
+    fn config_size(&self) -> usize {
+        CONFIG_SIZE
+    }
+
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        let mut config = [0u8; CONFIG_SIZE];
+        let max_nr_ports = self.ports.lock().len() as u32;
+        config[4..8].copy_from_slice(&max_nr_ports.to_le_bytes());
+        data.copy_from_slice(&config[offset..offset + data.len()]);
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        // Emergency writes go to port 0.
+        if offset == 8 {
+            if let Some(port) = self.ports.lock().first_mut() {
+                port.to_host.push_back(data[0]);
+            }
+        }
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        self.multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+    }
+
+    fn activate(&mut self, _queues: &mut [VirtQueue], _mem: &VmPages) -> HyperResult<()> {
+        // Without multiport there's no control queue to open the port through.
+        if !self.multiport {
+            if let Some(port) = self.ports.lock().first_mut() {
+                port.open = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn queue_notify(
+        &mut self,
+        index: usize,
+        queues: &mut [VirtQueue],
+        mem: &VmPages,
+    ) -> HyperResult<bool> {
+        match (index, Self::queue_port(index)) {
+            (CONTROL_TX_QUEUE, None) => {
+                let handled = self.handle_control(&mut queues[CONTROL_TX_QUEUE], mem)?;
+                let delivered = self.deliver_control(&mut queues[CONTROL_RX_QUEUE], mem)?;
+                Ok(handled || delivered)
+            }
+            (_, None) => self.deliver_control(&mut queues[CONTROL_RX_QUEUE], mem),
+            (_, Some(port)) => {
+                let (rx, tx) = Self::port_queues(port);
+                if index == tx {
+                    self.transmit(port, &mut queues[tx], mem)
+                } else {
+                    self.receive(port, &mut queues[rx], mem)
+                }
+            }
+        }
+    }
+
+    fn poll(&mut self, queues: &mut [VirtQueue], mem: &VmPages) -> HyperResult<bool> {
+        let mut used = false;
+        if self.multiport {
+            used |= self.deliver_control(&mut queues[CONTROL_RX_QUEUE], mem)?;
+        }
+        let num_ports = if self.multiport {
+            self.ports.lock().len()
+        } else {
+            1
+        };
+        for port in 0..num_ports {
+            let (rx, _) = Self::port_queues(port);
+            used |= self.receive(port, &mut queues[rx], mem)?;
+        }
+        Ok(used)
+    }
+
+    fn reset(&mut self) {
+        self.multiport = false;
+        self.control_out.clear();
+        for port in self.ports.lock().iter_mut() {
+            port.open = false;
+        }
+    }
+
+    fn save(&self, out: &mut SnapshotWriter) -> HyperResult<()> {
+        out.write_usize(self.control_out.len());
+        self.control_out.iter().for_each(|msg| out.write_bytes(msg));
+        let ports = self.ports.lock();
+        out.write_usize(ports.len());
+        for port in ports.iter() {
+            out.write_bool(port.open);
+            for buf in [&port.to_guest, &port.to_host] {
+                let (front, back) = buf.as_slices();
+                out.write_bytes(&[front, back].concat());
+            }
+        }
+        Ok(())
+    }
+
+    fn restore(&mut self, input: &mut SnapshotReader) -> HyperResult<()> {
+        self.control_out.clear();
+        for _ in 0..input.read_usize()? {
+            self.control_out.push_back(input.read_bytes()?.to_vec());
+        }
+        let mut ports = self.ports.lock();
+        if input.read_usize()? != ports.len() {
+            return Err(HyperError::InvalidParam);
+        }
+        for port in ports.iter_mut() {
+            port.open = input.read_bool()?;
+            port.to_guest = input.read_bytes()?.iter().copied().collect();
+            port.to_host = input.read_bytes()?.iter().copied().collect();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,390 @@
+//! The virtio-mmio transport, version 2.
+
+use alloc::vec::Vec;
+
+use super::{VirtQueue, VirtioDevice, VIRTIO_F_INDIRECT_DESC, VIRTIO_F_VERSION_1};
+use crate::arch::devices::MmioDevice;
+use crate::arch::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::arch::vm_pages::VmPages;
+use crate::HyperResult;
+
+/// Size of a virtio-mmio device's register window.
+pub const VIRTIO_MMIO_SIZE: usize = 0x200;
+
+/// "virt" in little-endian.
+const MAGIC_VALUE: u32 = 0x7472_6976;
+/// The non-legacy register layout.
+const VERSION: u32 = 2;
+/// "HCRF" in little-endian.
+const VENDOR_ID: u32 = 0x4652_4348;
+
+const REG_MAGIC_VALUE: usize = 0x000;
+const REG_VERSION: usize = 0x004;
+const REG_DEVICE_ID: usize = 0x008;
+const REG_VENDOR_ID: usize = 0x00c;
+const REG_DEVICE_FEATURES: usize = 0x010;
+const REG_DEVICE_FEATURES_SEL: usize = 0x014;
+const REG_DRIVER_FEATURES: usize = 0x020;
+const REG_DRIVER_FEATURES_SEL: usize = 0x024;
+const REG_QUEUE_SEL: usize = 0x030;
+const REG_QUEUE_NUM_MAX: usize = 0x034;
+const REG_QUEUE_NUM: usize = 0x038;
+const REG_QUEUE_READY: usize = 0x044;
+const REG_QUEUE_NOTIFY: usize = 0x050;
+const REG_INTERRUPT_STATUS: usize = 0x060;
+const REG_INTERRUPT_ACK: usize = 0x064;
+const REG_STATUS: usize = 0x070;
+const REG_QUEUE_DESC_LOW: usize = 0x080;
+const REG_QUEUE_DESC_HIGH: usize = 0x084;
+const REG_QUEUE_DRIVER_LOW: usize = 0x090;
+const REG_QUEUE_DRIVER_HIGH: usize = 0x094;
+const REG_QUEUE_DEVICE_LOW: usize = 0x0a0;
+const REG_QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const REG_SHM_LEN_LOW: usize = 0x0b0;
+const REG_SHM_BASE_HIGH: usize = 0x0bc;
+const REG_CONFIG_GENERATION: usize = 0x0fc;
+const REG_CONFIG: usize = 0x100;
+
+// Device status bits.
+const STATUS_DRIVER_OK: u32 = 1 << 2;
+const STATUS_FEATURES_OK: u32 = 1 << 3;
+const STATUS_DEVICE_NEEDS_RESET: u32 = 1 << 6;
+
+// Interrupt status bits.
+const INT_USED_BUFFER: u32 = 1 << 0;
+const INT_CONFIG_CHANGE: u32 = 1 << 1;
+
+/// Feature bits implemented by the transport and virtqueues themselves.
+const TRANSPORT_FEATURES: u64 = VIRTIO_F_VERSION_1 | VIRTIO_F_INDIRECT_DESC;
+
+/// A virtio device behind the virtio-mmio register interface.
+///
+/// The interrupt line is asserted while any bit of the interrupt status is set; it is routed to
+/// the VM's virtual interrupt controller by the MMIO device dispatch.
+pub struct VirtioMmio<D: VirtioDevice> {
+    device: D,
+    queues: Vec<VirtQueue>,
+    mem: VmPages,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    status: u32,
+    interrupt_status: u32,
+    config_generation: u32,
+}
+
+impl<D: VirtioDevice> VirtioMmio<D> {
+    /// Creates a transport for `device`.
+    pub fn new(device: D) -> Self {
+        let queues = (0..device.num_queues())
+            .map(|_| VirtQueue::default())
+            .collect();
+        Self {
+            device,
+            queues,
+            mem: VmPages::default(),
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            status: 0,
+            interrupt_status: 0,
+            config_generation: 0,
+        }
+    }
+
+    /// Returns the device backend.
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Returns the device backend mutably.
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    fn offered_features(&self) -> u64 {
+        self.device.device_features() | TRANSPORT_FEATURES
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut VirtQueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn reset_transport(&mut self) {
+        self.queues.iter_mut().for_each(VirtQueue::reset);
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.queue_sel = 0;
+        self.status = 0;
+        self.interrupt_status = 0;
+        self.device.reset();
+    }
+
+    fn set_status(&mut self, status: u32) {
+        if status == 0 {
+            self.reset_transport();
+            return;
+        }
+        let added = status & !self.status;
+        let mut status = status;
+        if added & STATUS_FEATURES_OK != 0 {
+            // Refuse feature sets that weren't offered or lack VERSION_1, which the non-legacy
+            // interface requires.
+            let offered = self.offered_features();
+            if self.driver_features & !offered != 0
+                || self.driver_features & VIRTIO_F_VERSION_1 == 0
+            {
+                status &= !STATUS_FEATURES_OK;
+            } else {
+                self.device.set_driver_features(self.driver_features);
+            }
+        }
+        self.status = status;
+        if added & STATUS_DRIVER_OK != 0 {
+            if let Err(e) = self.device.activate(&mut self.queues, &self.mem) {
+                warn!("virtio: failed to activate device: {:?}", e);
+                self.fail();
+            }
+        }
+    }
+
+    /// Signals the driver that the device hit an unrecoverable error.
+    fn fail(&mut self) {
+        self.status |= STATUS_DEVICE_NEEDS_RESET;
+        self.config_changed();
+    }
+
+    fn config_changed(&mut self) {
+        self.config_generation = self.config_generation.wrapping_add(1);
+        self.interrupt_status |= INT_CONFIG_CHANGE;
+    }
+
+    fn notify(&mut self, index: usize) {
+        if self.status & STATUS_DRIVER_OK == 0 || index >= self.queues.len() {
+            return;
+        }
+        match self.device.queue_notify(index, &mut self.queues, &self.mem) {
+            Ok(true) => self.interrupt_status |= INT_USED_BUFFER,
+            Ok(false) => {}
+            Err(e) => {
+                warn!("virtio: failed to process queue {}: {:?}", index, e);
+                self.fail();
+            }
+        }
+    }
+
+    fn read_reg(&mut self, offset: usize) -> u32 {
+        match offset {
+            REG_MAGIC_VALUE => MAGIC_VALUE,
+            REG_VERSION => VERSION,
+            REG_DEVICE_ID => self.device.device_id(),
+            REG_VENDOR_ID => VENDOR_ID,
+            REG_DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.offered_features() as u32,
+                1 => (self.offered_features() >> 32) as u32,
+                _ => 0,
+            },
+            REG_QUEUE_NUM_MAX => match self.queues.get(self.queue_sel as usize) {
+                Some(_) => self.device.queue_max_size() as u32,
+                None => 0,
+            },
+            REG_QUEUE_READY => self.selected_queue().map_or(0, |q| q.ready as u32),
+            REG_INTERRUPT_STATUS => self.interrupt_status,
+            REG_STATUS => self.status,
+            REG_CONFIG_GENERATION => self.config_generation,
+            REG_DEVICE_FEATURES_SEL
+            | REG_DRIVER_FEATURES
+            | REG_DRIVER_FEATURES_SEL
+            | REG_QUEUE_SEL
+            | REG_QUEUE_NUM
+            | REG_QUEUE_NOTIFY
+            | REG_INTERRUPT_ACK
+            | REG_QUEUE_DESC_LOW..=REG_QUEUE_DEVICE_HIGH => 0,
+            // There are no shared memory regions, whose length and base read as -1.
+            REG_SHM_LEN_LOW..=REG_SHM_BASE_HIGH => u32::MAX,
+            _ => {
+                warn!("virtio: read of unknown register {:#x} ignored", offset);
+                0
+            }
+        }
+    }
+
+    fn write_reg(&mut self, offset: usize, val: u32) {
+        let max_size = self.device.queue_max_size();
+        // Virtqueue configuration may only change while the queue is disabled.
+        let set_queue = |this: &mut Self, f: &dyn Fn(&mut VirtQueue)| {
+            if let Some(queue) = this.selected_queue().filter(|q| !q.ready) {
+                f(queue);
+            }
+        };
+        match offset {
+            REG_DEVICE_FEATURES_SEL => self.device_features_sel = val,
+            REG_DRIVER_FEATURES_SEL => self.driver_features_sel = val,
+            REG_DRIVER_FEATURES => {
+                if self.status & STATUS_FEATURES_OK == 0 {
+                    match self.driver_features_sel {
+                        0 => {
+                            self.driver_features = self.driver_features & !0xffff_ffff | val as u64
+                        }
+                        1 => {
+                            self.driver_features =
+                                self.driver_features & 0xffff_ffff | (val as u64) << 32
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            REG_QUEUE_SEL => self.queue_sel = val,
+            REG_QUEUE_NUM => {
+                // Split queue sizes must be powers of 2 no larger than QueueNumMax.
+                if val.is_power_of_two() && val <= max_size as u32 {
+                    set_queue(self, &|q| q.size = val as u16);
+                }
+            }
+            REG_QUEUE_READY => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.ready = val & 1 != 0;
+                }
+            }
+            REG_QUEUE_NOTIFY => self.notify(val as usize & 0xffff),
+            REG_INTERRUPT_ACK => self.interrupt_status &= !val,
+            REG_STATUS => self.set_status(val),
+            REG_QUEUE_DESC_LOW => set_queue(self, &|q| set_low(&mut q.desc_addr, val)),
+            REG_QUEUE_DESC_HIGH => set_queue(self, &|q| set_high(&mut q.desc_addr, val)),
+            REG_QUEUE_DRIVER_LOW => set_queue(self, &|q| set_low(&mut q.avail_addr, val)),
+            REG_QUEUE_DRIVER_HIGH => set_queue(self, &|q| set_high(&mut q.avail_addr, val)),
+            REG_QUEUE_DEVICE_LOW => set_queue(self, &|q| set_low(&mut q.used_addr, val)),
+            REG_QUEUE_DEVICE_HIGH => set_queue(self, &|q| set_high(&mut q.used_addr, val)),
+            // Read-only registers.
+            REG_MAGIC_VALUE
+            | REG_VERSION
+            | REG_DEVICE_ID
+            | REG_VENDOR_ID
+            | REG_DEVICE_FEATURES
+            | REG_QUEUE_NUM_MAX
+            | REG_INTERRUPT_STATUS
+            | REG_CONFIG_GENERATION => {}
+            _ => warn!("virtio: write of unknown register {:#x} ignored", offset),
+        }
+    }
+}
+
+fn set_low(addr: &mut usize, val: u32) {
+    *addr = *addr & !0xffff_ffff | val as usize;
+}
+
+fn set_high(addr: &mut usize, val: u32) {
+    *addr = *addr & 0xffff_ffff | (val as usize) << 32;
+}
+
+impl<D: VirtioDevice> MmioDevice for VirtioMmio<D> {
+    fn size(&self) -> usize {
+        VIRTIO_MMIO_SIZE
+    }
+
+    fn read(&mut self, offset: usize, width: usize) -> HyperResult<usize> {
+        if offset >= REG_CONFIG {
+            let offset = offset - REG_CONFIG;
+            // Accesses outside the configuration space read as zero rather than fault the guest.
+            if !matches!(width, 1 | 2 | 4 | 8) || offset + width > self.device.config_size() {
+                warn!(
+                    "virtio: {}-byte config read at {:#x} ignored",
+                    width, offset
+                );
+                return Ok(0);
+            }
+            let mut buf = [0u8; 8];
+            self.device.read_config(offset, &mut buf[..width]);
+            return Ok(u64::from_le_bytes(buf) as usize);
+        }
+        if width != 4 || offset % 4 != 0 {
+            warn!("virtio: {}-byte read at {:#x} ignored", width, offset);
+            return Ok(0);
+        }
+        Ok(self.read_reg(offset) as usize)
+    }
+
+    fn write(&mut self, offset: usize, width: usize, val: usize) -> HyperResult<()> {
+        if offset >= REG_CONFIG {
+            let offset = offset - REG_CONFIG;
+            if !matches!(width, 1 | 2 | 4 | 8) || offset + width > self.device.config_size() {
+                warn!(
+                    "virtio: {}-byte config write at {:#x} ignored",
+                    width, offset
+                );
+                return Ok(());
+            }
+            let buf = (val as u64).to_le_bytes();
+            self.device.write_config(offset, &buf[..width]);
+            return Ok(());
+        }
+        if width != 4 || offset % 4 != 0 {
+            warn!("virtio: {}-byte write at {:#x} ignored", width, offset);
+            return Ok(());
+        }
+        self.write_reg(offset, val as u32);
+        Ok(())
+    }
+
+    fn irq_level(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn attach(&mut self, mem: &VmPages) {
+        self.mem = mem.clone();
+    }
+
+    fn poll(&mut self) {
+        if self.status & STATUS_DRIVER_OK == 0 || self.status & STATUS_DEVICE_NEEDS_RESET != 0 {
+            return;
+        }
+        match self.device.poll(&mut self.queues, &self.mem) {
+            Ok(true) => self.interrupt_status |= INT_USED_BUFFER,
+            Ok(false) => {}
+            Err(e) => {
+                warn!("virtio: device poll failed: {:?}", e);
+                self.fail();
+            }
+        }
+        if self.device.take_config_changed() {
+            self.config_changed();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.reset_transport();
+    }
+
+    fn save(&self, out: &mut SnapshotWriter) -> HyperResult<()> {
+        out.write_u32(self.device_features_sel);
+        out.write_u32(self.driver_features_sel);
+        out.write_u64(self.driver_features);
+        out.write_u32(self.queue_sel);
+        out.write_u32(self.status);
+        out.write_u32(self.interrupt_status);
+        out.write_u32(self.config_generation);
+        self.queues.iter().for_each(|queue| queue.save(out));
+        self.device.save(out)
+    }
+
+    fn restore(&mut self, input: &mut SnapshotReader) -> HyperResult<()> {
+        self.device_features_sel = input.read_u32()?;
+        self.driver_features_sel = input.read_u32()?;
+        self.driver_features = input.read_u64()?;
+        self.queue_sel = input.read_u32()?;
+        self.status = input.read_u32()?;
+        self.interrupt_status = input.read_u32()?;
+        self.config_generation = input.read_u32()?;
+        for queue in self.queues.iter_mut() {
+            queue.restore(input)?;
+        }
+        // The negotiated features are not part of the device's own state.
+        if self.status & STATUS_FEATURES_OK != 0 {
+            self.device.set_driver_features(self.driver_features);
+        }
+        self.device.restore(input)
+    }
+}
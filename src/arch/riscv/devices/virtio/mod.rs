@@ -0,0 +1,113 @@
+//! Virtio devices exposed to guests over the virtio-mmio transport.
+//!
+//! `VirtioMmio` implements the version 2 (non-legacy) register interface and the generic parts of
+//! the device lifecycle: status, feature negotiation and virtqueue setup. Device types plug in as
+//! `VirtioDevice` backends, which process the split virtqueues directly in guest memory.
+
+mod blk;
+mod console;
+mod mmio;
+mod net;
+mod queue;
+mod vsock;
+
+pub use blk::{BlockBackend, VirtioBlk, SECTOR_SIZE};
+pub use console::{ConsoleHandle, VirtioConsole};
+pub use mmio::{VirtioMmio, VIRTIO_MMIO_SIZE};
+pub use net::{LoopbackBackend, NetBackend, VirtioNet};
+pub use queue::{DescChain, Descriptor, VirtQueue};
+pub use vsock::{VirtioVsock, VsockConn, VsockHandle, VSOCK_HOST_CID};
+
+use crate::arch::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::arch::vm_pages::VmPages;
+use crate::{HyperError, HyperResult};
+
+/// The device complies with the virtio 1.x specification; required by the non-legacy transport.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+/// The driver can use descriptors with the INDIRECT flag.
+pub const VIRTIO_F_INDIRECT_DESC: u64 = 1 << 28;
+
+/// Virtio device IDs.
+pub mod device_id {
+    /// Network card.
+    pub const NET: u32 = 1;
+    /// Block device.
+    pub const BLOCK: u32 = 2;
+    /// Console.
+    pub const CONSOLE: u32 = 3;
+    /// Socket device.
+    pub const VSOCK: u32 = 19;
+}
+
+/// A virtio device type, driven by the transport it is attached to.
+pub trait VirtioDevice {
+    /// Returns the virtio device ID.
+    fn device_id(&self) -> u32;
+
+    /// Returns the device-specific feature bits offered to the driver. The transport adds the
+    /// feature bits it implements itself.
+    fn device_features(&self) -> u64;
+
+    /// Returns the number of virtqueues of the device.
+    fn num_queues(&self) -> usize;
+
+    /// Returns the maximum size of each virtqueue.
+    fn queue_max_size(&self) -> u16 {
+        256
+    }
+
+    /// Returns the size of the device configuration space.
+    fn config_size(&self) -> usize {
+        0
+    }
+
+    /// Reads `data.len()` bytes of the device configuration space at `offset`.
+    fn read_config(&self, _offset: usize, data: &mut [u8]) {
+        data.fill(0);
+    }
+
+    /// Writes `data` to the device configuration space at `offset`.
+    fn write_config(&mut self, _offset: usize, _data: &[u8]) {}
+
+    /// Notifies the device of the features accepted by the driver.
+    fn set_driver_features(&mut self, _features: u64) {}
+
+    /// Called once the driver has completed initialization (DRIVER_OK).
+    fn activate(&mut self, _queues: &mut [VirtQueue], _mem: &VmPages) -> HyperResult<()> {
+        Ok(())
+    }
+
+    /// Handles a driver notification for virtqueue `index`. Returns whether buffers were added to
+    /// a used ring.
+    fn queue_notify(
+        &mut self,
+        index: usize,
+        queues: &mut [VirtQueue],
+        mem: &VmPages,
+    ) -> HyperResult<bool>;
+
+    /// Makes progress on work not driven by the guest, such as received packets. Returns whether
+    /// buffers were added to a used ring.
+    fn poll(&mut self, _queues: &mut [VirtQueue], _mem: &VmPages) -> HyperResult<bool> {
+        Ok(false)
+    }
+
+    /// Returns and clears whether the configuration space changed since the last call.
+    fn take_config_changed(&mut self) -> bool {
+        false
+    }
+
+    /// Resets the device to its initial state, as requested by the driver.
+    fn reset(&mut self) {}
+
+    /// Saves the device-specific state into a VM snapshot. The transport saves its own state and
+    /// that of the virtqueues. Devices that do not implement snapshots fail with `NotSupported`.
+    fn save(&self, _out: &mut SnapshotWriter) -> HyperResult<()> {
+        Err(HyperError::NotSupported)
+    }
+
+    /// Restores the state saved by `save` into a device created with the same configuration.
+    fn restore(&mut self, _input: &mut SnapshotReader) -> HyperResult<()> {
+        Err(HyperError::NotSupported)
+    }
+}
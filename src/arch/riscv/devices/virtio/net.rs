@@ -0,0 +1,272 @@
+//! Virtio network device.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use super::{device_id, DescChain, VirtQueue, VirtioDevice};
+use crate::arch::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::arch::vm_pages::VmPages;
+use crate::{HyperError, HyperResult};
+
+// Feature bits.
+const VIRTIO_NET_F_CSUM: u64 = 1 << 0;
+const VIRTIO_NET_F_GUEST_CSUM: u64 = 1 << 1;
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_MRG_RXBUF: u64 = 1 << 15;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+/// The packet needs a checksum computed from `csum_start` and stored at `csum_offset`.
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+/// Size of the virtio-net header preceding each packet; `num_buffers` is always present with
+/// VIRTIO_F_VERSION_1.
+const NET_HDR_SIZE: usize = 12;
+const CONFIG_SIZE: usize = 10;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+/// The host side of a virtio network device, supplied by the embedding kernel.
+pub trait NetBackend {
+    /// Sends the Ethernet frame `packet` from the guest.
+    fn send(&mut self, packet: &[u8]) -> HyperResult<()>;
+
+    /// Returns the next Ethernet frame to deliver to the guest, if any.
+    fn recv(&mut self) -> Option<Vec<u8>>;
+}
+
+/// A backend returning every frame sent by the guest back to it.
+#[derive(Default)]
+pub struct LoopbackBackend {
+    packets: VecDeque<Vec<u8>>,
+}
+
+impl LoopbackBackend {
+    /// Creates a loopback backend with no frames queued.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl NetBackend for LoopbackBackend {
+    fn send(&mut self, packet: &[u8]) -> HyperResult<()> {
+        self.packets.push_back(packet.to_vec());
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.packets.pop_front()
+    }
+}
+
+/// A virtio-net device with one receive and one transmit queue.
+pub struct VirtioNet {
+    mac: [u8; 6],
+    backend: Box<dyn NetBackend>,
+    mergeable_rx: bool,
+    // A received frame waiting for the guest to provide buffers.
+    pending_rx: Option<Vec<u8>>,
+}
+
+impl VirtioNet {
+    /// Creates a network device with the MAC address `mac` whose frames go to `backend`.
+    pub fn new(mac: [u8; 6], backend: Box<dyn NetBackend>) -> Self {
+        Self {
+            mac,
+            backend,
+            mergeable_rx: false,
+            pending_rx: None,
+        }
+    }
+
+    fn transmit(&mut self, queue: &mut VirtQueue, mem: &VmPages) -> HyperResult<bool> {
+        let mut used = false;
+        while let Some(chain) = queue.pop_avail(mem)? {
+            let mut packet = chain.read_all(mem)?;
+            if packet.len() < NET_HDR_SIZE {
+                return Err(HyperError::InvalidParam);
+            }
+            if packet[0] & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+                let csum_start = u16::from_le_bytes([packet[6], packet[7]]) as usize;
+                let csum_offset = u16::from_le_bytes([packet[8], packet[9]]) as usize;
+                fill_checksum(&mut packet[NET_HDR_SIZE..], csum_start, csum_offset);
+            }
+            if let Err(e) = self.backend.send(&packet[NET_HDR_SIZE..]) {
+                warn!("virtio-net: dropping frame: {:?}", e);
+            }
+            queue.push_used(mem, chain.head(), 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn receive(&mut self, queue: &mut VirtQueue, mem: &VmPages) -> HyperResult<bool> {
+        let mut used = false;
+        loop {
+            let packet = match self.pending_rx.take().or_else(|| self.backend.recv()) {
+                Some(packet) => packet,
+                None => break,
+            };
+            match self.deliver(&packet, queue, mem)? {
+                Delivery::Delivered => used = true,
+                Delivery::Dropped => {}
+                Delivery::NoBuffers => {
+                    self.pending_rx = Some(packet);
+                    break;
+                }
+            }
+        }
+        Ok(used)
+    }
+
+    /// Copies `packet` to the guest's receive buffers.
+    fn deliver(
+        &self,
+        packet: &[u8],
+        queue: &mut VirtQueue,
+        mem: &VmPages,
+    ) -> HyperResult<Delivery> {
+        let total = NET_HDR_SIZE + packet.len();
+        let mut chains: Vec<DescChain> = Vec::new();
+        let mut space = 0;
+        while space < total {
+            let chain = match queue.pop_avail(mem)? {
+                Some(chain) => chain,
+                None => break,
+            };
+            space += chain.writable_len();
+            chains.push(chain);
+            // Without mergeable buffers the whole frame must fit in one chain.
+            if !self.mergeable_rx {
+                break;
+            }
+        }
+        if space < total {
+            chains.iter().for_each(|_| queue.undo_pop());
+            if !self.mergeable_rx && !chains.is_empty() {
+                // The buffer can never hold this frame; drop the frame rather than stall.
+                warn!(
+                    "virtio-net: dropping {} byte frame, rx buffer too small",
+                    total
+                );
+                return Ok(Delivery::Dropped);
+            }
+            return Ok(Delivery::NoBuffers);
+        }
+
+        let mut data = Vec::with_capacity(total);
+        data.extend_from_slice(&[0u8; NET_HDR_SIZE]);
+        data[10..12].copy_from_slice(&(chains.len() as u16).to_le_bytes());
+        data.extend_from_slice(packet);
+        let mut offset = 0;
+        for chain in chains.iter() {
+            let written = chain.write_at(mem, 0, &data[offset..])?;
+            queue.push_used(mem, chain.head(), written as u32)?;
+            offset += written;
+        }
+        Ok(Delivery::Delivered)
+    }
+}
+
+/// The outcome of delivering a received frame to the guest.
+enum Delivery {
+    Delivered,
+    Dropped,
+    NoBuffers,
+}
+
+/// Computes the Internet checksum of `packet[start..]` and stores it at `start + offset`.
+fn fill_checksum(packet: &mut [u8], start: usize, offset: usize) {
+    let field = start + offset;
+    if start > packet.len() || field + 2 > packet.len() {
+        return;
+    }
+    // The checksum field holds the pseudo-header sum, which is included in the sum.
+    let mut sum: u32 = packet[start..]
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    packet[field..field + 2].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_id(&self) -> u32 {
+        device_id::NET
+    }
+
+    fn device_features(&self) -> u64 {
+        VIRTIO_NET_F_CSUM
+            | VIRTIO_NET_F_GUEST_CSUM
+            | VIRTIO_NET_F_MAC
+            | VIRTIO_NET_F_MRG_RXBUF
+            | VIRTIO_NET_F_STATUS
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn config_size(&self) -> usize {
+        CONFIG_SIZE
+    }
+
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        let mut config = [0u8; CONFIG_SIZE];
+        config[0..6].copy_from_slice(&self.mac);
+        config[6..8].copy_from_slice(&VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        config[8..10].copy_from_slice(&1u16.to_le_bytes());
+        data.copy_from_slice(&config[offset..offset + data.len()]);
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        self.mergeable_rx = features & VIRTIO_NET_F_MRG_RXBUF != 0;
+    }
+
+    fn queue_notify(
+        &mut self,
+        index: usize,
+        queues: &mut [VirtQueue],
+        mem: &VmPages,
+    ) -> HyperResult<bool> {
+        match index {
+            RX_QUEUE => self.receive(&mut queues[RX_QUEUE], mem),
+            TX_QUEUE => {
+                let sent = self.transmit(&mut queues[TX_QUEUE], mem)?;
+                // Frames looped back by the backend can be delivered right away.
+                let received = self.receive(&mut queues[RX_QUEUE], mem)?;
+                Ok(sent || received)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn poll(&mut self, queues: &mut [VirtQueue], mem: &VmPages) -> HyperResult<bool> {
+        self.receive(&mut queues[RX_QUEUE], mem)
+    }
+
+    fn reset(&mut self) {
+        self.mergeable_rx = false;
+        self.pending_rx = None;
+    }
+
+    fn save(&self, out: &mut SnapshotWriter) -> HyperResult<()> {
+        out.write_bool(self.pending_rx.is_some());
+        if let Some(packet) = &self.pending_rx {
+            out.write_bytes(packet);
+        }
+        Ok(())
+    }
+
+    fn restore(&mut self, input: &mut SnapshotReader) -> HyperResult<()> {
+        self.pending_rx = match input.read_bool()? {
+            true => Some(input.read_bytes()?.to_vec()),
+            false => None,
+        };
+        Ok(())
+    }
+}
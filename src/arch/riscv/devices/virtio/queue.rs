@@ -0,0 +1,245 @@
+//! Split virtqueues in guest memory.
+
+use alloc::{vec, vec::Vec};
+use core::sync::atomic::{fence, Ordering};
+
+use crate::arch::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::arch::vm_pages::VmPages;
+use crate::{GuestPhysAddr, HyperError, HyperResult};
+
+/// The descriptor continues through the `next` field.
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+/// The buffer is device write-only.
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+/// The buffer holds a table of indirect descriptors.
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+/// Size of a descriptor in the descriptor table.
+const DESC_SIZE: usize = 16;
+/// Size of an element of the used ring.
+const USED_ELEM_SIZE: usize = 8;
+
+/// A buffer described by a virtqueue descriptor.
+#[derive(Clone, Copy, Debug)]
+pub struct Descriptor {
+    /// Guest physical address of the buffer.
+    pub addr: GuestPhysAddr,
+    /// Length of the buffer in bytes.
+    pub len: u32,
+    /// Whether the buffer is written by the device rather than read.
+    pub write: bool,
+}
+
+/// A chain of descriptors taken from the available ring.
+#[derive(Debug)]
+pub struct DescChain {
+    head: u16,
+    descs: Vec<Descriptor>,
+}
+
+impl DescChain {
+    /// Returns the index of the chain's head descriptor, used to return it to the driver.
+    pub fn head(&self) -> u16 {
+        self.head
+    }
+
+    /// Returns the descriptors of the chain in order.
+    pub fn descs(&self) -> &[Descriptor] {
+        &self.descs
+    }
+
+    /// Returns the device-readable buffers of the chain.
+    pub fn readable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descs.iter().filter(|d| !d.write)
+    }
+
+    /// Returns the device-writable buffers of the chain.
+    pub fn writable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descs.iter().filter(|d| d.write)
+    }
+
+    /// Returns the total length of the device-readable buffers.
+    pub fn readable_len(&self) -> usize {
+        self.readable().map(|d| d.len as usize).sum()
+    }
+
+    /// Returns the total length of the device-writable buffers.
+    pub fn writable_len(&self) -> usize {
+        self.writable().map(|d| d.len as usize).sum()
+    }
+
+    /// Reads up to `buf.len()` bytes starting `offset` bytes into the readable buffers, returning
+    /// the number of bytes read.
+    pub fn read_at(&self, mem: &VmPages, offset: usize, buf: &mut [u8]) -> HyperResult<usize> {
+        Self::copy_at(self.readable(), offset, buf.len(), |gpa, start, len| {
+            mem.copy_from_guest(&mut buf[start..start + len], gpa)
+        })
+    }
+
+    /// Writes up to `buf.len()` bytes starting `offset` bytes into the writable buffers, returning
+    /// the number of bytes written.
+    pub fn write_at(&self, mem: &VmPages, offset: usize, buf: &[u8]) -> HyperResult<usize> {
+        Self::copy_at(self.writable(), offset, buf.len(), |gpa, start, len| {
+            mem.copy_to_guest(gpa, &buf[start..start + len])
+        })
+    }
+
+    /// Reads all the readable buffers of the chain.
+    pub fn read_all(&self, mem: &VmPages) -> HyperResult<Vec<u8>> {
+        let mut buf = vec![0; self.readable_len()];
+        self.read_at(mem, 0, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn copy_at<'a>(
+        descs: impl Iterator<Item = &'a Descriptor>,
+        mut offset: usize,
+        total: usize,
+        mut copy: impl FnMut(GuestPhysAddr, usize, usize) -> HyperResult<()>,
+    ) -> HyperResult<usize> {
+        let mut done = 0;
+        for desc in descs {
+            if done == total {
+                break;
+            }
+            let len = desc.len as usize;
+            if offset >= len {
+                offset -= len;
+                continue;
+            }
+            let chunk = (len - offset).min(total - done);
+            copy(desc.addr + offset, done, chunk)?;
+            done += chunk;
+            offset = 0;
+        }
+        Ok(done)
+    }
+}
+
+/// The driver-visible state of a split virtqueue, as programmed through the transport.
+#[derive(Debug, Default)]
+pub struct VirtQueue {
+    /// Number of descriptors in the queue.
+    pub size: u16,
+    /// Whether the driver has enabled the queue.
+    pub ready: bool,
+    /// Guest physical address of the descriptor table.
+    pub desc_addr: GuestPhysAddr,
+    /// Guest physical address of the available (driver) ring.
+    pub avail_addr: GuestPhysAddr,
+    /// Guest physical address of the used (device) ring.
+    pub used_addr: GuestPhysAddr,
+    last_avail_idx: u16,
+    used_idx: u16,
+}
+
+impl VirtQueue {
+    /// Returns the queue to its initial state.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Takes the next descriptor chain from the available ring, if any.
+    pub fn pop_avail(&mut self, mem: &VmPages) -> HyperResult<Option<DescChain>> {
+        if !self.ready || self.size == 0 {
+            return Ok(None);
+        }
+        let avail_idx = mem.read_u16(self.avail_addr + 2)?;
+        if avail_idx == self.last_avail_idx {
+            return Ok(None);
+        }
+        // Read the ring entry only after observing the index that covers it.
+        fence(Ordering::Acquire);
+        let slot = (self.last_avail_idx % self.size) as usize;
+        let head = mem.read_u16(self.avail_addr + 4 + slot * 2)?;
+        self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
+
+        let mut descs = Vec::new();
+        let (mut table, mut table_size, mut index) = (self.desc_addr, self.size as usize, head);
+        let mut indirect = false;
+        loop {
+            // A well-formed chain never visits more descriptors than there are in its table.
+            if index as usize >= table_size || descs.len() >= table_size {
+                return Err(HyperError::InvalidParam);
+            }
+            let desc_addr = table + index as usize * DESC_SIZE;
+            let addr = mem.read_u64(desc_addr)? as usize;
+            let len = mem.read_u32(desc_addr + 8)?;
+            let flags = mem.read_u16(desc_addr + 12)?;
+            let next = mem.read_u16(desc_addr + 14)?;
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                if indirect || len as usize % DESC_SIZE != 0 || len == 0 {
+                    return Err(HyperError::InvalidParam);
+                }
+                indirect = true;
+                table = addr;
+                table_size = len as usize / DESC_SIZE;
+                index = 0;
+                continue;
+            }
+            descs.push(Descriptor {
+                addr,
+                len,
+                write: flags & VIRTQ_DESC_F_WRITE != 0,
+            });
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = next;
+        }
+        Ok(Some(DescChain { head, descs }))
+    }
+
+    /// Returns the most recently taken chain to the available ring, for a device that found it
+    /// can't use it yet.
+    pub fn undo_pop(&mut self) {
+        self.last_avail_idx = self.last_avail_idx.wrapping_sub(1);
+    }
+
+    /// Returns the chain headed by `head` to the driver, `len` bytes having been written to it.
+    pub fn push_used(&mut self, mem: &VmPages, head: u16, len: u32) -> HyperResult<()> {
+        if self.size == 0 {
+            return Err(HyperError::BadState);
+        }
+        let slot = (self.used_idx % self.size) as usize;
+        let elem = self.used_addr + 4 + slot * USED_ELEM_SIZE;
+        mem.write_u32(elem, head as u32)?;
+        mem.write_u32(elem + 4, len)?;
+        // The element must be visible before the index that publishes it.
+        fence(Ordering::Release);
+        self.used_idx = self.used_idx.wrapping_add(1);
+        mem.write_u16(self.used_addr + 2, self.used_idx)
+    }
+
+    /// Saves the queue's configuration and ring positions into a VM snapshot.
+    pub fn save(&self, out: &mut SnapshotWriter) {
+        out.write_u16(self.size);
+        out.write_bool(self.ready);
+        out.write_usize(self.desc_addr);
+        out.write_usize(self.avail_addr);
+        out.write_usize(self.used_addr);
+        out.write_u16(self.last_avail_idx);
+        out.write_u16(self.used_idx);
+    }
+
+    /// Restores the state saved by `save`.
+    pub fn restore(&mut self, input: &mut SnapshotReader) -> HyperResult<()> {
+        self.size = input.read_u16()?;
+        self.ready = input.read_bool()?;
+        self.desc_addr = input.read_usize()?;
+        self.avail_addr = input.read_usize()?;
+        self.used_addr = input.read_usize()?;
+        self.last_avail_idx = input.read_u16()?;
+        self.used_idx = input.read_u16()?;
+        Ok(())
+    }
+
+    /// Returns whether the driver has made buffers available that haven't been taken yet.
+    pub fn has_avail(&self, mem: &VmPages) -> bool {
+        self.ready
+            && self.size != 0
+            && mem
+                .read_u16(self.avail_addr + 2)
+                .map_or(false, |idx| idx != self.last_avail_idx)
+    }
+}
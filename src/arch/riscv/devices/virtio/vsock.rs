@@ -0,0 +1,561 @@
+//! Virtio socket device.
+//!
+//! Connects stream sockets in the guest with the host, which is addressed as CID 2. The host end
+//! of the connections is driven through a `VsockHandle`, which offers a small socket-like API:
+//! listening on and accepting from host ports, connecting to guest ports, and sending and
+//! receiving data with the credit-based flow control of the vsock protocol.
+
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::{device_id, VirtQueue, VirtioDevice};
+use crate::arch::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::arch::vm_pages::VmPages;
+use crate::{HyperError, HyperResult};
+
+/// The CID of the host.
+pub const VSOCK_HOST_CID: u64 = 2;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+// Operations.
+const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+const VIRTIO_VSOCK_OP_RST: u16 = 3;
+const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+const VIRTIO_VSOCK_OP_RW: u16 = 5;
+const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+// Shutdown flags.
+const VIRTIO_VSOCK_SHUTDOWN_SEND: u32 = 1 << 1;
+const VIRTIO_VSOCK_SHUTDOWN_ALL: u32 = 3;
+
+/// Size of the packet header.
+const HDR_SIZE: usize = 44;
+/// Receive buffer space the host advertises for each connection.
+const HOST_BUF_ALLOC: u32 = 64 * 1024;
+/// First port used for connections initiated by the host.
+const FIRST_EPHEMERAL_PORT: u32 = 49152;
+
+/// The host end of a vsock connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VsockConn {
+    /// The port on the host side.
+    pub host_port: u32,
+    /// The port on the guest side.
+    pub guest_port: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ConnState {
+    Connecting,
+    Connected,
+    // The guest won't send any more data.
+    PeerClosed,
+}
+
+impl ConnState {
+    fn from_raw(raw: u8) -> HyperResult<Self> {
+        match raw {
+            0 => Ok(Self::Connecting),
+            1 => Ok(Self::Connected),
+            2 => Ok(Self::PeerClosed),
+            _ => Err(HyperError::InvalidParam),
+        }
+    }
+}
+
+struct Conn {
+    state: ConnState,
+    // Data from the guest not yet read by the host.
+    rx: VecDeque<u8>,
+    // Bytes of guest data consumed by the host.
+    fwd_cnt: u32,
+    // Bytes sent to the guest.
+    tx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+}
+
+impl Conn {
+    fn new(state: ConnState) -> Self {
+        Self {
+            state,
+            rx: VecDeque::new(),
+            fwd_cnt: 0,
+            tx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+        }
+    }
+
+    /// Returns how many bytes may be sent before overrunning the guest's receive buffer.
+    fn peer_credit(&self) -> u32 {
+        self.peer_buf_alloc
+            .saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt))
+    }
+}
+
+struct Packet {
+    conn: VsockConn,
+    op: u16,
+    flags: u32,
+    payload: Vec<u8>,
+}
+
+struct VsockState {
+    guest_cid: u64,
+    listeners: BTreeMap<u32, VecDeque<VsockConn>>,
+    conns: BTreeMap<VsockConn, Conn>,
+    // Packets waiting to be received by the guest.
+    to_guest: VecDeque<Packet>,
+    used_ports: BTreeSet<u32>,
+    next_port: u32,
+}
+
+impl VsockState {
+    fn queue(&mut self, conn: VsockConn, op: u16, flags: u32, payload: Vec<u8>) {
+        self.to_guest.push_back(Packet {
+            conn,
+            op,
+            flags,
+            payload,
+        });
+    }
+
+    /// Removes `conn`, releasing its host port if the host initiated it.
+    fn remove_conn(&mut self, conn: VsockConn) -> Option<Conn> {
+        let c = self.conns.remove(&conn)?;
+        self.used_ports.remove(&conn.host_port);
+        Some(c)
+    }
+
+    fn reset_conns(&mut self) {
+        self.conns.clear();
+        self.used_ports.clear();
+        self.to_guest.clear();
+        self.listeners.values_mut().for_each(VecDeque::clear);
+    }
+
+    fn save(&self, out: &mut SnapshotWriter) {
+        let write_conn = |out: &mut SnapshotWriter, conn: &VsockConn| {
+            out.write_u32(conn.host_port);
+            out.write_u32(conn.guest_port);
+        };
+        out.write_usize(self.listeners.len());
+        for (&port, backlog) in self.listeners.iter() {
+            out.write_u32(port);
+            out.write_usize(backlog.len());
+            backlog.iter().for_each(|conn| write_conn(out, conn));
+        }
+        out.write_usize(self.conns.len());
+        for (conn, c) in self.conns.iter() {
+            write_conn(out, conn);
+            out.write_u8(c.state as u8);
+            let (front, back) = c.rx.as_slices();
+            out.write_bytes(&[front, back].concat());
+            out.write_u32(c.fwd_cnt);
+            out.write_u32(c.tx_cnt);
+            out.write_u32(c.peer_buf_alloc);
+            out.write_u32(c.peer_fwd_cnt);
+        }
+        out.write_usize(self.to_guest.len());
+        for packet in self.to_guest.iter() {
+            write_conn(out, &packet.conn);
+            out.write_u16(packet.op);
+            out.write_u32(packet.flags);
+            out.write_bytes(&packet.payload);
+        }
+        out.write_usize(self.used_ports.len());
+        self.used_ports.iter().for_each(|&port| out.write_u32(port));
+        out.write_u32(self.next_port);
+    }
+
+    fn restore(&mut self, input: &mut SnapshotReader) -> HyperResult<()> {
+        let read_conn = |input: &mut SnapshotReader| -> HyperResult<VsockConn> {
+            Ok(VsockConn {
+                host_port: input.read_u32()?,
+                guest_port: input.read_u32()?,
+            })
+        };
+        self.listeners.clear();
+        for _ in 0..input.read_usize()? {
+            let port = input.read_u32()?;
+            let mut backlog = VecDeque::new();
+            for _ in 0..input.read_usize()? {
+                backlog.push_back(read_conn(input)?);
+            }
+            self.listeners.insert(port, backlog);
+        }
+        self.conns.clear();
+        for _ in 0..input.read_usize()? {
+            let conn = read_conn(input)?;
+            let mut c = Conn::new(ConnState::from_raw(input.read_u8()?)?);
+            c.rx = input.read_bytes()?.iter().copied().collect();
+            c.fwd_cnt = input.read_u32()?;
+            c.tx_cnt = input.read_u32()?;
+            c.peer_buf_alloc = input.read_u32()?;
+            c.peer_fwd_cnt = input.read_u32()?;
+            self.conns.insert(conn, c);
+        }
+        self.to_guest.clear();
+        for _ in 0..input.read_usize()? {
+            let conn = read_conn(input)?;
+            let op = input.read_u16()?;
+            let flags = input.read_u32()?;
+            let payload = input.read_bytes()?.to_vec();
+            self.queue(conn, op, flags, payload);
+        }
+        self.used_ports.clear();
+        for _ in 0..input.read_usize()? {
+            self.used_ports.insert(input.read_u32()?);
+        }
+        self.next_port = input.read_u32()?;
+        Ok(())
+    }
+
+    fn header(&self, packet: &Packet, len: usize) -> [u8; HDR_SIZE] {
+        let (fwd_cnt, buf_alloc) = match self.conns.get(&packet.conn) {
+            Some(conn) => (conn.fwd_cnt, HOST_BUF_ALLOC),
+            None => (0, 0),
+        };
+        let mut hdr = [0u8; HDR_SIZE];
+        hdr[0..8].copy_from_slice(&VSOCK_HOST_CID.to_le_bytes());
+        hdr[8..16].copy_from_slice(&self.guest_cid.to_le_bytes());
+        hdr[16..20].copy_from_slice(&packet.conn.host_port.to_le_bytes());
+        hdr[20..24].copy_from_slice(&packet.conn.guest_port.to_le_bytes());
+        hdr[24..28].copy_from_slice(&(len as u32).to_le_bytes());
+        hdr[28..30].copy_from_slice(&VIRTIO_VSOCK_TYPE_STREAM.to_le_bytes());
+        hdr[30..32].copy_from_slice(&packet.op.to_le_bytes());
+        hdr[32..36].copy_from_slice(&packet.flags.to_le_bytes());
+        hdr[36..40].copy_from_slice(&buf_alloc.to_le_bytes());
+        hdr[40..44].copy_from_slice(&fwd_cnt.to_le_bytes());
+        hdr
+    }
+
+    /// Handles a packet sent by the guest.
+    fn handle_packet(&mut self, hdr: &[u8; HDR_SIZE], payload: Vec<u8>) {
+        let src_cid = u64::from_le_bytes(hdr[0..8].try_into().unwrap());
+        let dst_cid = u64::from_le_bytes(hdr[8..16].try_into().unwrap());
+        let conn = VsockConn {
+            guest_port: u32::from_le_bytes(hdr[16..20].try_into().unwrap()),
+            host_port: u32::from_le_bytes(hdr[20..24].try_into().unwrap()),
+        };
+        let socket_type = u16::from_le_bytes([hdr[28], hdr[29]]);
+        let op = u16::from_le_bytes([hdr[30], hdr[31]]);
+        let flags = u32::from_le_bytes(hdr[32..36].try_into().unwrap());
+        let buf_alloc = u32::from_le_bytes(hdr[36..40].try_into().unwrap());
+        let fwd_cnt = u32::from_le_bytes(hdr[40..44].try_into().unwrap());
+
+        if src_cid != self.guest_cid || dst_cid != VSOCK_HOST_CID {
+            return;
+        }
+        if socket_type != VIRTIO_VSOCK_TYPE_STREAM {
+            if op != VIRTIO_VSOCK_OP_RST {
+                self.queue(conn, VIRTIO_VSOCK_OP_RST, 0, Vec::new());
+            }
+            return;
+        }
+        if op == VIRTIO_VSOCK_OP_REQUEST {
+            if self.conns.contains_key(&conn) || !self.listeners.contains_key(&conn.host_port) {
+                self.queue(conn, VIRTIO_VSOCK_OP_RST, 0, Vec::new());
+                return;
+            }
+            let mut new_conn = Conn::new(ConnState::Connected);
+            new_conn.peer_buf_alloc = buf_alloc;
+            new_conn.peer_fwd_cnt = fwd_cnt;
+            self.conns.insert(conn, new_conn);
+            if let Some(backlog) = self.listeners.get_mut(&conn.host_port) {
+                backlog.push_back(conn);
+            }
+            self.queue(conn, VIRTIO_VSOCK_OP_RESPONSE, 0, Vec::new());
+            return;
+        }
+
+        let c = match self.conns.get_mut(&conn) {
+            Some(c) => c,
+            None => {
+                if op != VIRTIO_VSOCK_OP_RST {
+                    self.queue(conn, VIRTIO_VSOCK_OP_RST, 0, Vec::new());
+                }
+                return;
+            }
+        };
+        c.peer_buf_alloc = buf_alloc;
+        c.peer_fwd_cnt = fwd_cnt;
+        match op {
+            VIRTIO_VSOCK_OP_RESPONSE if c.state == ConnState::Connecting => {
+                c.state = ConnState::Connected;
+            }
+            VIRTIO_VSOCK_OP_RW => {
+                // The guest must respect the advertised buffer space; drop data that overruns it.
+                let space = (HOST_BUF_ALLOC as usize).saturating_sub(c.rx.len());
+                c.rx.extend(payload.iter().take(space));
+            }
+            VIRTIO_VSOCK_OP_CREDIT_REQUEST => {
+                self.queue(conn, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, Vec::new());
+            }
+            VIRTIO_VSOCK_OP_SHUTDOWN => {
+                if flags & VIRTIO_VSOCK_SHUTDOWN_SEND != 0 {
+                    c.state = ConnState::PeerClosed;
+                }
+                // Acknowledge a full shutdown. The received data stays readable until the host
+                // closes the connection.
+                if flags & VIRTIO_VSOCK_SHUTDOWN_ALL == VIRTIO_VSOCK_SHUTDOWN_ALL {
+                    self.queue(conn, VIRTIO_VSOCK_OP_RST, 0, Vec::new());
+                }
+            }
+            VIRTIO_VSOCK_OP_RST => {
+                if c.state == ConnState::Connecting {
+                    self.remove_conn(conn);
+                } else {
+                    c.state = ConnState::PeerClosed;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The host side of a virtio socket device.
+#[derive(Clone)]
+pub struct VsockHandle {
+    state: Arc<Mutex<VsockState>>,
+}
+
+impl VsockHandle {
+    /// Returns the CID of the guest.
+    pub fn guest_cid(&self) -> u64 {
+        self.state.lock().guest_cid
+    }
+
+    /// Accepts connections from the guest to the host port `port`.
+    pub fn listen(&self, port: u32) -> HyperResult<()> {
+        let mut state = self.state.lock();
+        if state.listeners.contains_key(&port) {
+            return Err(HyperError::BadState);
+        }
+        state.listeners.insert(port, VecDeque::new());
+        Ok(())
+    }
+
+    /// Returns the next connection made by the guest to the listening port `port`, if any.
+    pub fn accept(&self, port: u32) -> HyperResult<Option<VsockConn>> {
+        let mut state = self.state.lock();
+        let backlog = state.listeners.get_mut(&port).ok_or(HyperError::NotFound)?;
+        Ok(backlog.pop_front())
+    }
+
+    /// Initiates a connection to `guest_port` in the guest. The connection can carry data once
+    /// `is_connected` returns true.
+    pub fn connect(&self, guest_port: u32) -> HyperResult<VsockConn> {
+        let mut state = self.state.lock();
+        let mut host_port = state.next_port;
+        while state.listeners.contains_key(&host_port) || state.used_ports.contains(&host_port) {
+            host_port = host_port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+            if host_port == state.next_port {
+                return Err(HyperError::NoMemory);
+            }
+        }
+        state.next_port = host_port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+        state.used_ports.insert(host_port);
+        let conn = VsockConn {
+            host_port,
+            guest_port,
+        };
+        state.conns.insert(conn, Conn::new(ConnState::Connecting));
+        state.queue(conn, VIRTIO_VSOCK_OP_REQUEST, 0, Vec::new());
+        Ok(conn)
+    }
+
+    /// Returns whether `conn` is established and the guest hasn't closed it.
+    pub fn is_connected(&self, conn: VsockConn) -> bool {
+        self.state
+            .lock()
+            .conns
+            .get(&conn)
+            .map_or(false, |c| c.state == ConnState::Connected)
+    }
+
+    /// Sends `data` on `conn`, returning how many bytes the guest has room for.
+    pub fn send(&self, conn: VsockConn, data: &[u8]) -> HyperResult<usize> {
+        let mut state = self.state.lock();
+        let c = state.conns.get_mut(&conn).ok_or(HyperError::NotFound)?;
+        if c.state != ConnState::Connected {
+            return Err(HyperError::BadState);
+        }
+        let len = data.len().min(c.peer_credit() as usize);
+        if len == 0 {
+            return Ok(0);
+        }
+        c.tx_cnt = c.tx_cnt.wrapping_add(len as u32);
+        state.queue(conn, VIRTIO_VSOCK_OP_RW, 0, data[..len].to_vec());
+        Ok(len)
+    }
+
+    /// Reads data received on `conn` into `buf`, returning how many bytes were read. Returns 0
+    /// at the end of the stream.
+    pub fn recv(&self, conn: VsockConn, buf: &mut [u8]) -> HyperResult<usize> {
+        let mut state = self.state.lock();
+        let c = state.conns.get_mut(&conn).ok_or(HyperError::NotFound)?;
+        let len = buf.len().min(c.rx.len());
+        for (dst, src) in buf.iter_mut().zip(c.rx.drain(..len)) {
+            *dst = src;
+        }
+        c.fwd_cnt = c.fwd_cnt.wrapping_add(len as u32);
+        if len > 0 {
+            // Let the guest know about the freed buffer space.
+            state.queue(conn, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, Vec::new());
+        }
+        Ok(len)
+    }
+
+    /// Closes `conn`, discarding any data not yet read.
+    pub fn close(&self, conn: VsockConn) -> HyperResult<()> {
+        let mut state = self.state.lock();
+        state.remove_conn(conn).ok_or(HyperError::NotFound)?;
+        state.queue(conn, VIRTIO_VSOCK_OP_RST, 0, Vec::new());
+        Ok(())
+    }
+}
+
+/// A virtio-vsock device.
+pub struct VirtioVsock {
+    state: Arc<Mutex<VsockState>>,
+}
+
+impl VirtioVsock {
+    /// Creates a socket device giving the guest the CID `guest_cid`.
+    pub fn new(guest_cid: u64) -> HyperResult<(Self, VsockHandle)> {
+        // CIDs 0-2 are reserved and the upper 32 bits must be zero.
+        if guest_cid <= VSOCK_HOST_CID || guest_cid > u32::MAX as u64 {
+            return Err(HyperError::InvalidParam);
+        }
+        let state = Arc::new(Mutex::new(VsockState {
+            guest_cid,
+            listeners: BTreeMap::new(),
+            conns: BTreeMap::new(),
+            to_guest: VecDeque::new(),
+            used_ports: BTreeSet::new(),
+            next_port: FIRST_EPHEMERAL_PORT,
+        }));
+        Ok((
+            Self {
+                state: state.clone(),
+            },
+            VsockHandle { state },
+        ))
+    }
+
+    fn transmit(&mut self, queue: &mut VirtQueue, mem: &VmPages) -> HyperResult<bool> {
+        let mut used = false;
+        while let Some(chain) = queue.pop_avail(mem)? {
+            let mut data = chain.read_all(mem)?;
+            if data.len() >= HDR_SIZE {
+                let payload = data.split_off(HDR_SIZE);
+                let hdr: [u8; HDR_SIZE] = data[..].try_into().unwrap();
+                let len = u32::from_le_bytes(hdr[24..28].try_into().unwrap()) as usize;
+                let payload = payload.into_iter().take(len).collect();
+                self.state.lock().handle_packet(&hdr, payload);
+            }
+            queue.push_used(mem, chain.head(), 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn receive(&mut self, queue: &mut VirtQueue, mem: &VmPages) -> HyperResult<bool> {
+        let mut state = self.state.lock();
+        let mut used = false;
+        while !state.to_guest.is_empty() {
+            let chain = match queue.pop_avail(mem)? {
+                Some(chain) => chain,
+                None => break,
+            };
+            let space = chain.writable_len();
+            if space < HDR_SIZE {
+                return Err(HyperError::InvalidParam);
+            }
+            let mut packet = state.to_guest.pop_front().unwrap();
+            // Data that doesn't fit in this buffer goes out in the next packet.
+            let room = space - HDR_SIZE;
+            if packet.payload.len() > room {
+                let rest = packet.payload.split_off(room);
+                state.to_guest.push_front(Packet {
+                    conn: packet.conn,
+                    op: packet.op,
+                    flags: packet.flags,
+                    payload: rest,
+                });
+            }
+            let hdr = state.header(&packet, packet.payload.len());
+            chain.write_at(mem, 0, &hdr)?;
+            chain.write_at(mem, HDR_SIZE, &packet.payload)?;
+            queue.push_used(mem, chain.head(), (HDR_SIZE + packet.payload.len()) as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+impl VirtioDevice for VirtioVsock {
+    fn device_id(&self) -> u32 {
+        device_id::VSOCK
+    }
+
+    fn device_features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize {
+        // rx, tx and event queues; no events are ever sent.
+        3
+    }
+
+    fn config_size(&self) -> usize {
+        8
+    }
+
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        let config = self.state.lock().guest_cid.to_le_bytes();
+        data.copy_from_slice(&config[offset..offset + data.len()]);
+    }
+
+    fn queue_notify(
+        &mut self,
+        index: usize,
+        queues: &mut [VirtQueue],
+        mem: &VmPages,
+    ) -> HyperResult<bool> {
+        let sent = match index {
+            TX_QUEUE => self.transmit(&mut queues[TX_QUEUE], mem)?,
+            _ => false,
+        };
+        // Replies to the guest's packets are delivered right away.
+        let received = self.receive(&mut queues[RX_QUEUE], mem)?;
+        Ok(sent || received)
+    }
+
+    fn poll(&mut self, queues: &mut [VirtQueue], mem: &VmPages) -> HyperResult<bool> {
+        self.receive(&mut queues[RX_QUEUE], mem)
+    }
+
+    fn reset(&mut self) {
+        self.state.lock().reset_conns();
+    }
+
+    fn save(&self, out: &mut SnapshotWriter) -> HyperResult<()> {
+        self.state.lock().save(out);
+        Ok(())
+    }
+
+    fn restore(&mut self, input: &mut SnapshotReader) -> HyperResult<()> {
+        self.state.lock().restore(input)
+    }
+}
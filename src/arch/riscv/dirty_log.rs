@@ -0,0 +1,170 @@
+//! Logging of the guest pages written to, for live migration and incremental snapshots.
+//!
+//! Writes are detected either by write-protecting the logged pages in the G-stage table and
+//! logging the store faults they take, or, if the firmware enabled Svadu for the G-stage, by
+//! collecting the dirty bits the hardware sets. The hypervisor's own writes to write-protected
+//! pages go through `VmPages`, which logs them.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use page_table_entry::MappingFlags;
+
+use super::vm_pages::VmPages;
+use crate::memory::PAGE_SIZE_4K;
+use crate::{GuestPageTableTrait, GuestPhysAddr, HyperError, HyperResult};
+
+/// The dirty bitmap of a range of guest physical memory.
+pub struct DirtyLog {
+    base: GuestPhysAddr,
+    pages: usize,
+    // Bit `i % 64` of word `i / 64` is set once page `i` of the range is written to.
+    bitmap: Vec<u64>,
+    // Whether the hardware sets the dirty bits rather than the pages being write-protected.
+    hardware: bool,
+}
+
+impl DirtyLog {
+    /// Starts logging the writes to the `pages` pages from `base`, which must be writable and
+    /// mapped with 4K pages in `gpt` for as long as they are logged. The hypervisor's writes are
+    /// logged if made through `mem` or its clones.
+    pub fn start<G: GuestPageTableTrait>(
+        gpt: &mut G,
+        mem: &VmPages,
+        base: GuestPhysAddr,
+        pages: usize,
+        hardware: bool,
+    ) -> HyperResult<Self> {
+        // Logged pages are made writable again, so read-only ones must not be logged.
+        let mut hpas = Vec::with_capacity(pages);
+        for page in 0..pages {
+            let (hpa, flags) = gpt.query(base + page * PAGE_SIZE_4K)?;
+            if !flags.contains(MappingFlags::WRITE) {
+                return Err(HyperError::InvalidParam);
+            }
+            hpas.push(hpa);
+        }
+        let log = Self {
+            base,
+            pages,
+            bitmap: vec![0; pages.div_ceil(64)],
+            hardware,
+        };
+        for page in 0..pages {
+            let gpa = log.page_addr(page);
+            let result = if hardware {
+                gpt.test_and_clear_dirty(gpa).map(|_| ())
+            } else {
+                write_protect(gpt, gpa)
+            };
+            if let Err(err) = result {
+                if !hardware {
+                    log.restore(gpt, page)?;
+                }
+                return Err(err);
+            }
+        }
+        if !hardware {
+            mem.log_writes(base, hpas);
+        }
+        flush_gstage();
+        Ok(log)
+    }
+
+    /// Returns the first guest physical address of the logged range.
+    pub fn base(&self) -> GuestPhysAddr {
+        self.base
+    }
+
+    /// Returns whether `gpa` is in the logged range.
+    pub fn contains(&self, gpa: GuestPhysAddr) -> bool {
+        gpa >= self.base && gpa < self.base + self.pages * PAGE_SIZE_4K
+    }
+
+    /// Returns whether the logged range overlaps `base..base + size`.
+    pub fn overlaps(&self, base: GuestPhysAddr, size: usize) -> bool {
+        base < self.base + self.pages * PAGE_SIZE_4K && self.base < base + size
+    }
+
+    /// Logs the store to `gpa` that faulted on the G-stage table, and makes its page writable
+    /// again. Returns false if the page is not write-protected by this log.
+    pub fn log_write<G: GuestPageTableTrait>(
+        &mut self,
+        gpt: &mut G,
+        gpa: GuestPhysAddr,
+    ) -> HyperResult<bool> {
+        if self.hardware || !self.contains(gpa) {
+            return Ok(false);
+        }
+        let page = (gpa - self.base) / PAGE_SIZE_4K;
+        self.bitmap[page / 64] |= 1 << (page % 64);
+        let gpa = self.page_addr(page);
+        let (_, flags) = gpt.query(gpa)?;
+        gpt.protect(gpa, flags | MappingFlags::WRITE)?;
+        flush_gstage();
+        Ok(true)
+    }
+
+    /// Returns the bitmap of the pages written to since the last call and clears it. Bit `i % 64`
+    /// of word `i / 64` stands for page `i` of the range. The pages are write-protected again, but
+    /// only in the TLB of the calling hart.
+    pub fn fetch_and_clear<G: GuestPageTableTrait>(
+        &mut self,
+        gpt: &mut G,
+        mem: &VmPages,
+    ) -> HyperResult<Vec<u64>> {
+        if !self.hardware {
+            let written = mem.take_logged_writes(self.base);
+            self.bitmap
+                .iter_mut()
+                .zip(written)
+                .for_each(|(word, written)| *word |= written);
+        } else {
+            for page in 0..self.pages {
+                if gpt.test_and_clear_dirty(self.page_addr(page))? {
+                    self.bitmap[page / 64] |= 1 << (page % 64);
+                }
+            }
+        }
+        let bitmap = core::mem::replace(&mut self.bitmap, vec![0; self.pages.div_ceil(64)]);
+        if !self.hardware {
+            for page in (0..self.pages).filter(|page| bitmap[page / 64] & (1 << (page % 64)) != 0) {
+                write_protect(gpt, self.page_addr(page))?;
+            }
+        }
+        flush_gstage();
+        Ok(bitmap)
+    }
+
+    /// Stops logging, making all the pages writable again.
+    pub fn stop<G: GuestPageTableTrait>(self, gpt: &mut G, mem: &VmPages) -> HyperResult<()> {
+        if !self.hardware {
+            mem.stop_logging_writes(self.base);
+            self.restore(gpt, self.pages)?;
+        }
+        Ok(())
+    }
+
+    /// Makes the first `pages` pages of the range writable again.
+    fn restore<G: GuestPageTableTrait>(&self, gpt: &mut G, pages: usize) -> HyperResult<()> {
+        for page in 0..pages {
+            let gpa = self.page_addr(page);
+            let (_, flags) = gpt.query(gpa)?;
+            gpt.protect(gpa, flags | MappingFlags::WRITE)?;
+        }
+        flush_gstage();
+        Ok(())
+    }
+
+    fn page_addr(&self, page: usize) -> GuestPhysAddr {
+        self.base + page * PAGE_SIZE_4K
+    }
+}
+
+fn write_protect<G: GuestPageTableTrait>(gpt: &mut G, gpa: GuestPhysAddr) -> HyperResult<()> {
+    let (_, flags) = gpt.query(gpa)?;
+    gpt.protect(gpa, flags - MappingFlags::WRITE)
+}
+
+fn flush_gstage() {
+    unsafe { core::arch::riscv64::hfence_gvma_all() };
+}
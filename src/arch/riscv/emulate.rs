@@ -0,0 +1,247 @@
+//! Decoding of guest instructions that the hypervisor emulates.
+
+use riscv_decode::Instruction;
+
+use super::regs::{GeneralPurposeRegisters, GprIndex};
+use crate::{GuestVirtAddr, HyperError, HyperResult};
+
+/// The read-modify-write operation of a CSR instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CsrOp {
+    Write,
+    Set,
+    Clear,
+}
+
+/// A decoded `csrr*` instruction trapped from the guest.
+#[derive(Clone, Copy, Debug)]
+pub struct CsrAccess {
+    /// The CSR number.
+    pub csr: u16,
+    /// The register receiving the old value of the CSR.
+    pub rd: GprIndex,
+    op: CsrOp,
+    operand: usize,
+    // `csrrs`/`csrrc` with x0 (or a zero immediate) as source only read the CSR.
+    writes: bool,
+}
+
+impl CsrAccess {
+    /// Decodes `inst` as a CSR instruction, reading its source operand from `gprs`.
+    pub fn decode(inst: u32, gprs: &GeneralPurposeRegisters) -> HyperResult<Self> {
+        let decoded = riscv_decode::decode(inst).map_err(|_| HyperError::DecodeError)?;
+        let (csr, rd, op, operand, source_is_zero) = match decoded {
+            Instruction::Csrrw(i) => (
+                i.csr(),
+                i.rd(),
+                CsrOp::Write,
+                Self::gpr(gprs, i.rs1()),
+                false,
+            ),
+            Instruction::Csrrs(i) => (
+                i.csr(),
+                i.rd(),
+                CsrOp::Set,
+                Self::gpr(gprs, i.rs1()),
+                i.rs1() == 0,
+            ),
+            Instruction::Csrrc(i) => (
+                i.csr(),
+                i.rd(),
+                CsrOp::Clear,
+                Self::gpr(gprs, i.rs1()),
+                i.rs1() == 0,
+            ),
+            Instruction::Csrrwi(i) => (i.csr(), i.rd(), CsrOp::Write, i.zimm() as usize, false),
+            Instruction::Csrrsi(i) => (
+                i.csr(),
+                i.rd(),
+                CsrOp::Set,
+                i.zimm() as usize,
+                i.zimm() == 0,
+            ),
+            Instruction::Csrrci(i) => (
+                i.csr(),
+                i.rd(),
+                CsrOp::Clear,
+                i.zimm() as usize,
+                i.zimm() == 0,
+            ),
+            _ => return Err(HyperError::InvalidInstruction),
+        };
+        Ok(Self {
+            csr: csr as u16,
+            rd: GprIndex::from_raw(rd).ok_or(HyperError::DecodeError)?,
+            op,
+            operand,
+            writes: !source_is_zero,
+        })
+    }
+
+    /// Returns the value the instruction writes back to the CSR given its current value `old`,
+    /// or `None` if the instruction only reads the CSR.
+    pub fn new_value(&self, old: usize) -> Option<usize> {
+        if !self.writes {
+            return None;
+        }
+        Some(match self.op {
+            CsrOp::Write => self.operand,
+            CsrOp::Set => old | self.operand,
+            CsrOp::Clear => old & !self.operand,
+        })
+    }
+
+    fn gpr(gprs: &GeneralPurposeRegisters, index: u32) -> usize {
+        gprs.reg(GprIndex::from_raw(index).unwrap())
+    }
+}
+
+/// A decoded guest load or store to an emulated MMIO region.
+#[derive(Clone, Copy, Debug)]
+pub enum MmioAccess {
+    /// A load of `width` bytes into `rd`.
+    Load {
+        /// The destination register.
+        rd: GprIndex,
+        /// The access width in bytes.
+        width: usize,
+        /// Whether the loaded value is sign-extended to XLEN.
+        sign_extend: bool,
+    },
+    /// A store of the low `width` bytes of `value`.
+    Store {
+        /// The value of the source register.
+        value: usize,
+        /// The access width in bytes.
+        width: usize,
+    },
+}
+
+impl MmioAccess {
+    /// Decodes the load or store instruction `inst`, reading the stored value from `gprs`.
+    pub fn decode(inst: Instruction, gprs: &GeneralPurposeRegisters) -> HyperResult<Self> {
+        let load = |rd: u32, width: usize, sign_extend: bool| -> HyperResult<Self> {
+            Ok(MmioAccess::Load {
+                rd: GprIndex::from_raw(rd).ok_or(HyperError::DecodeError)?,
+                width,
+                sign_extend,
+            })
+        };
+        let store = |rs2: u32, width: usize| -> HyperResult<Self> {
+            Ok(MmioAccess::Store {
+                value: CsrAccess::gpr(gprs, rs2),
+                width,
+            })
+        };
+        match inst {
+            Instruction::Lb(i) => load(i.rd(), 1, true),
+            Instruction::Lbu(i) => load(i.rd(), 1, false),
+            Instruction::Lh(i) => load(i.rd(), 2, true),
+            Instruction::Lhu(i) => load(i.rd(), 2, false),
+            Instruction::Lw(i) => load(i.rd(), 4, true),
+            Instruction::Lwu(i) => load(i.rd(), 4, false),
+            Instruction::Ld(i) => load(i.rd(), 8, false),
+            Instruction::Sb(i) => store(i.rs2(), 1),
+            Instruction::Sh(i) => store(i.rs2(), 2),
+            Instruction::Sw(i) => store(i.rs2(), 4),
+            Instruction::Sd(i) => store(i.rs2(), 8),
+            _ => Err(HyperError::InvalidInstruction),
+        }
+    }
+
+    /// Completes a load by writing `val`, as read from the device, to the destination register.
+    pub fn complete_load(&self, gprs: &mut GeneralPurposeRegisters, val: usize) {
+        if let MmioAccess::Load {
+            rd,
+            width,
+            sign_extend,
+        } = *self
+        {
+            let bits = width * 8;
+            let val = if bits == usize::BITS as usize {
+                val
+            } else if sign_extend {
+                (((val << (usize::BITS as usize - bits)) as isize) >> (usize::BITS as usize - bits))
+                    as usize
+            } else {
+                val & ((1 << bits) - 1)
+            };
+            gprs.set_reg(rd, val);
+        }
+    }
+}
+
+/// A hypervisor instruction trapped from a guest hypervisor running in VS-mode.
+#[derive(Clone, Copy, Debug)]
+pub enum HypervisorInst {
+    /// `sret`, trapped to switch to the guest hypervisor's virtual mode.
+    Sret,
+    /// `hfence.vvma`, treated as fencing all addresses and ASIDs.
+    HfenceVvma,
+    /// `hfence.gvma`, treated as fencing all addresses and VMIDs.
+    HfenceGvma,
+    /// A load (`hlv`, `hlvx`) or store (`hsv`) at guest virtual address `addr`.
+    Access {
+        /// The load or store performed.
+        access: MmioAccess,
+        /// The guest virtual address accessed.
+        addr: GuestVirtAddr,
+        /// Whether the load requires execute rather than read permission (`hlvx`).
+        exec: bool,
+    },
+}
+
+impl HypervisorInst {
+    /// Decodes `inst` as a hypervisor instruction, reading its operands from `gprs`.
+    pub fn decode(inst: u32, gprs: &GeneralPurposeRegisters) -> Option<Self> {
+        const OPCODE_SYSTEM: u32 = 0x73;
+        const SRET: u32 = 0x1020_0073;
+        if inst == SRET {
+            return Some(Self::Sret);
+        }
+        if inst & 0x7f != OPCODE_SYSTEM {
+            return None;
+        }
+        let rd = (inst >> 7) & 0x1f;
+        let funct3 = (inst >> 12) & 0x7;
+        let rs1 = (inst >> 15) & 0x1f;
+        let rs2 = (inst >> 20) & 0x1f;
+        let funct7 = inst >> 25;
+        match (funct3, funct7) {
+            (0, 0x11) if rd == 0 => Some(Self::HfenceVvma),
+            (0, 0x31) if rd == 0 => Some(Self::HfenceGvma),
+            (4, 0x30..=0x37) => {
+                let width = 1 << ((funct7 >> 1) & 0x3);
+                let (access, exec) = if funct7 & 1 == 0 {
+                    // rs2 selects hlv, hlv.*u or hlvx.*u.
+                    let (sign_extend, exec) = match (rs2, width) {
+                        (0, _) => (true, false),
+                        (1, 1 | 2 | 4) => (false, false),
+                        (3, 2 | 4) => (false, true),
+                        _ => return None,
+                    };
+                    let load = MmioAccess::Load {
+                        rd: GprIndex::from_raw(rd)?,
+                        width,
+                        sign_extend,
+                    };
+                    (load, exec)
+                } else if rd == 0 {
+                    let store = MmioAccess::Store {
+                        value: CsrAccess::gpr(gprs, rs2),
+                        width,
+                    };
+                    (store, false)
+                } else {
+                    return None;
+                };
+                Some(Self::Access {
+                    access,
+                    addr: CsrAccess::gpr(gprs, rs1),
+                    exec,
+                })
+            }
+            _ => None,
+        }
+    }
+}
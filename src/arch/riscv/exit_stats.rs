@@ -0,0 +1,221 @@
+//! Statistics and tracing of the exits of each vCPU.
+//!
+//! Every exit is counted by reason, and the time spent in the guest and in the hypervisor is
+//! accumulated from the `time` and `cycle` counters of the hart. The hypervisor time of a vCPU
+//! runs from its `VM::run` call, or its last exit, to its next entry into the guest, and until
+//! `VM::run` returns. The trace of the last exits of each vCPU is optional, as recording it costs
+//! a few CSR reads and stores on every exit.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use riscv::register::{cycle, time};
+
+use super::vcpu::VmCpuRegisters;
+use crate::{GuestPhysAddr, GuestVirtAddr};
+
+/// The number of exits kept in an `ExitTrace`.
+pub const EXIT_TRACE_LEN: usize = 64;
+
+/// What a guest page fault hit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FaultRegion {
+    /// The emulated PLIC or APLIC.
+    IrqChip,
+    /// An emulated IMSIC interrupt file.
+    Imsic,
+    /// The emulated MMIO region starting at the address.
+    Mmio(GuestPhysAddr),
+    /// The memory region starting at the address, e.g. for writes whose dirty pages are logged.
+    Memory(GuestPhysAddr),
+    /// No region.
+    Unmapped,
+}
+
+/// The exits of a vCPU counted by reason, and the time it spent in the guest and the hypervisor.
+/// Times are in ticks of the `time` CSR.
+#[derive(Clone, Debug, Default)]
+pub struct ExitStats {
+    /// The number of exits of all reasons.
+    pub exits: u64,
+    /// The SBI calls, by extension and function ID.
+    pub ecalls: BTreeMap<(usize, usize), u64>,
+    /// The guest page faults, by what they hit.
+    pub page_faults: BTreeMap<FaultRegion, u64>,
+    /// The timer interrupts injected into the guest.
+    pub timer_interrupts: u64,
+    /// The host external interrupts taken while the guest ran.
+    pub external_interrupts: u64,
+    /// The guest external interrupts of IMSIC interrupt files.
+    pub guest_external_interrupts: u64,
+    /// The virtual instruction exceptions.
+    pub virtual_instructions: u64,
+    /// The breakpoint exceptions taken for a debugger.
+    pub breakpoints: u64,
+    /// The traps of nested guests, handled for their guest hypervisor.
+    pub nested_traps: u64,
+    /// The other exits, which are fatal.
+    pub other: u64,
+    /// The time spent running the guest.
+    pub guest_time: u64,
+    /// The cycles spent running the guest.
+    pub guest_cycles: u64,
+    /// The time spent in the hypervisor handling the vCPU.
+    pub hypervisor_time: u64,
+    /// The cycles spent in the hypervisor handling the vCPU.
+    pub hypervisor_cycles: u64,
+}
+
+/// An exit recorded in an `ExitTrace`, with the trap CSRs the guest exited with.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExitRecord {
+    /// The value of the `time` CSR at the exit.
+    pub time: u64,
+    /// The trap cause.
+    pub scause: usize,
+    /// The trap value.
+    pub stval: usize,
+    /// The guest physical address of a guest page fault, shifted right by 2.
+    pub htval: usize,
+    /// The transformed trapping instruction, if the hardware reports it.
+    pub htinst: usize,
+    /// The trapping instruction address.
+    pub sepc: GuestVirtAddr,
+}
+
+/// The last `EXIT_TRACE_LEN` exits of a vCPU.
+#[derive(Clone)]
+pub struct ExitTrace {
+    records: [ExitRecord; EXIT_TRACE_LEN],
+    // The number of exits recorded, of which the last `EXIT_TRACE_LEN` are kept.
+    count: usize,
+}
+
+impl ExitTrace {
+    fn new() -> Self {
+        Self {
+            records: [ExitRecord::default(); EXIT_TRACE_LEN],
+            count: 0,
+        }
+    }
+
+    /// Returns the number of exits kept.
+    pub fn len(&self) -> usize {
+        self.count.min(EXIT_TRACE_LEN)
+    }
+
+    /// Returns whether no exit was recorded.
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns an iterator over the exits kept, from the oldest.
+    pub fn iter(&self) -> impl Iterator<Item = &ExitRecord> {
+        let start = self.count - self.len();
+        (start..self.count).map(move |i| &self.records[i % EXIT_TRACE_LEN])
+    }
+
+    fn push(&mut self, record: ExitRecord) {
+        self.records[self.count % EXIT_TRACE_LEN] = record;
+        self.count += 1;
+    }
+
+    /// Logs the exits kept, from the oldest.
+    pub fn dump(&self) {
+        for record in self.iter() {
+            error!(
+                "time: {:#x}, scause: {:#x}, stval: {:#x}, htval: {:#x}, htinst: {:#x}, sepc: {:#x}",
+                record.time, record.scause, record.stval, record.htval, record.htinst, record.sepc
+            );
+        }
+    }
+}
+
+/// The exit statistics and trace of a vCPU, with the counter values they are accumulated from.
+#[derive(Default)]
+pub struct VcpuExitStats {
+    stats: ExitStats,
+    trace: Option<Box<ExitTrace>>,
+    // The `time` and `cycle` values at the last entry into the guest.
+    entered_at: (u64, u64),
+    // The `time` and `cycle` values since which the vCPU is in the hypervisor, while it is.
+    exited_at: Option<(u64, u64)>,
+}
+
+impl VcpuExitStats {
+    /// Returns the statistics.
+    pub fn stats(&self) -> &ExitStats {
+        &self.stats
+    }
+
+    /// Returns the statistics, to count the current exit.
+    pub fn stats_mut(&mut self) -> &mut ExitStats {
+        &mut self.stats
+    }
+
+    /// Returns the trace, if enabled.
+    pub fn trace(&self) -> Option<&ExitTrace> {
+        self.trace.as_deref()
+    }
+
+    /// Enables or disables the trace. Enabling it again keeps the exits recorded.
+    pub fn set_trace(&mut self, enabled: bool) {
+        match (enabled, self.trace.is_some()) {
+            (true, false) => self.trace = Some(Box::new(ExitTrace::new())),
+            (false, true) => self.trace = None,
+            _ => {}
+        }
+    }
+
+    /// Resets the statistics and the trace.
+    pub fn reset(&mut self) {
+        self.stats = ExitStats::default();
+        if let Some(trace) = self.trace.as_mut() {
+            **trace = ExitTrace::new();
+        }
+    }
+
+    /// Starts accounting hypervisor time as `VM::run` is called for the vCPU.
+    pub fn switch_in(&mut self) {
+        self.exited_at = Some(now());
+    }
+
+    /// Accounts the hypervisor time up to now as `VM::run` returns.
+    pub fn switch_out(&mut self) {
+        if let Some((time, cycles)) = self.exited_at.take() {
+            let (now_time, now_cycles) = now();
+            self.stats.hypervisor_time += now_time.wrapping_sub(time);
+            self.stats.hypervisor_cycles += now_cycles.wrapping_sub(cycles);
+        }
+    }
+
+    /// Accounts the hypervisor time up to the entry into the guest.
+    pub fn enter(&mut self) {
+        self.switch_out();
+        self.entered_at = now();
+    }
+
+    /// Accounts the guest time up to the exit, with `regs` holding the trap CSRs the guest exited
+    /// with.
+    pub fn exit(&mut self, regs: &VmCpuRegisters) {
+        let (time, cycles) = now();
+        self.stats.guest_time += time.wrapping_sub(self.entered_at.0);
+        self.stats.guest_cycles += cycles.wrapping_sub(self.entered_at.1);
+        self.stats.exits += 1;
+        self.exited_at = Some((time, cycles));
+        if let Some(trace) = self.trace.as_mut() {
+            trace.push(ExitRecord {
+                time,
+                scause: regs.trap_csrs.scause,
+                stval: regs.trap_csrs.stval,
+                htval: regs.trap_csrs.htval,
+                htinst: regs.trap_csrs.htinst,
+                sepc: regs.guest_regs.sepc,
+            });
+        }
+    }
+}
+
+/// Returns the values of the `time` and `cycle` CSRs.
+fn now() -> (u64, u64) {
+    (time::read() as u64, cycle::read() as u64)
+}
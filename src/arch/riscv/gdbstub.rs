@@ -0,0 +1,536 @@
+//! A GDB Remote Serial Protocol server debugging a guest VM.
+//!
+//! Each vCPU is a thread, whose id is its vCPU id plus one as GDB reserves 0. Registers are
+//! numbered as GDB does for RISC-V: the GPRs, then the pc at 32, the FPRs, which are unavailable,
+//! and the supervisor CSRs at 65 plus their number. The stub runs in all-stop mode: when one vCPU
+//! stops, the VMM stops running all of them until the debugger resumes them. Hardware breakpoints
+//! and watchpoints are debug triggers, see `VM::debug_set_trigger`.
+
+use alloc::format;
+use alloc::vec::Vec;
+
+use super::regs::GprIndex;
+use crate::vcpus::VM_CPUS_MAX;
+use crate::{
+    GuestPageTableTrait, GuestVirtAddr, HyperCraftHal, HyperError, HyperResult, TriggerKind, VM,
+};
+
+/// The largest packet accepted, which bounds memory accesses to half as many bytes.
+const PACKET_SIZE: usize = 0x4000;
+/// The register number of the pc.
+const REG_PC: usize = 32;
+/// The register number of the first CSR.
+const REG_FIRST_CSR: usize = 65;
+/// The signals reported in stop replies.
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// The byte stream connecting a `GdbStub` to the debugger, provided by the embedding kernel, e.g.
+/// over a UART or a socket.
+pub trait GdbConnection {
+    /// Returns the next byte received, or `None` if none is available yet.
+    fn read_byte(&mut self) -> HyperResult<Option<u8>>;
+
+    /// Sends `data`.
+    fn write_all(&mut self, data: &[u8]) -> HyperResult<()>;
+}
+
+/// What the VMM should do with the VM being debugged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GdbAction {
+    /// Keep the vCPUs stopped and poll the stub again.
+    Stop,
+    /// Run the vCPUs, polling the stub now and then in case the debugger interrupts them, until
+    /// `VM::run` returns `VmExitReason::Debug` for one of them.
+    Run,
+    /// The debugger detached: its breakpoints are removed and the VM runs on its own.
+    Detach,
+}
+
+/// Where the stub is in receiving a packet.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RxState {
+    Idle,
+    Data,
+    Checksum,
+    ChecksumLow(u8),
+}
+
+/// A GDB stub serving a debugger on a `GdbConnection`.
+pub struct GdbStub<C: GdbConnection> {
+    conn: C,
+    rx: RxState,
+    // The packet being received, without its framing.
+    packet: Vec<u8>,
+    // The vCPU whose registers are accessed, and stopped last.
+    vcpu_id: usize,
+    // The watchpoint the vCPU stopped at, if any, and the address accessed.
+    watch: Option<(TriggerKind, GuestVirtAddr)>,
+    running: bool,
+    detached: bool,
+    // Set once the debugger turned acknowledgments off.
+    no_ack: bool,
+}
+
+impl<C: GdbConnection> GdbStub<C> {
+    /// Creates a stub serving the debugger on `conn`, for a VM with the debugger attached with
+    /// `VM::debug_attach` and its vCPUs stopped.
+    pub fn new(conn: C) -> Self {
+        Self {
+            conn,
+            rx: RxState::Idle,
+            packet: Vec::new(),
+            vcpu_id: 0,
+            watch: None,
+            running: false,
+            detached: false,
+            no_ack: false,
+        }
+    }
+
+    /// Handles the bytes received from the debugger, accessing `vm` as it requests. Must be
+    /// called on the hart that runs the vCPUs of `vm`, see `VM::debug_set_breakpoint`.
+    pub fn poll<H: HyperCraftHal, G: GuestPageTableTrait>(
+        &mut self,
+        vm: &mut VM<H, G>,
+    ) -> HyperResult<GdbAction> {
+        while !self.detached {
+            let byte = match self.conn.read_byte()? {
+                Some(byte) => byte,
+                None => break,
+            };
+            match (self.rx, byte) {
+                // Interrupt request, sent out of band.
+                (RxState::Idle, 0x03) if self.running => {
+                    self.running = false;
+                    self.watch = None;
+                    self.send_stop(SIGINT)?;
+                }
+                (RxState::Idle, b'$') => {
+                    self.packet.clear();
+                    self.rx = RxState::Data;
+                }
+                // Acknowledgments and noise between packets.
+                (RxState::Idle, _) => {}
+                (RxState::Data, b'#') => self.rx = RxState::Checksum,
+                (RxState::Data, _) if self.packet.len() < PACKET_SIZE => self.packet.push(byte),
+                (RxState::Data, _) => {
+                    // Too long to be ours, drop it and ask for a retransmission.
+                    self.rx = RxState::Idle;
+                    self.conn.write_all(b"-")?;
+                }
+                (RxState::Checksum, _) => self.rx = RxState::ChecksumLow(byte),
+                (RxState::ChecksumLow(high), _) => {
+                    self.rx = RxState::Idle;
+                    let expected = hex_digit(high)
+                        .zip(hex_digit(byte))
+                        .map(|(h, l)| h << 4 | l);
+                    if !self.no_ack {
+                        let valid = expected == Some(checksum(&self.packet));
+                        self.conn.write_all(if valid { b"+" } else { b"-" })?;
+                        if !valid {
+                            continue;
+                        }
+                    }
+                    let packet = core::mem::take(&mut self.packet);
+                    let was_running = self.running;
+                    self.handle_packet(vm, &packet)?;
+                    if self.running != was_running {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(if self.detached {
+            GdbAction::Detach
+        } else if self.running {
+            GdbAction::Run
+        } else {
+            GdbAction::Stop
+        })
+    }
+
+    /// Reports to the debugger that `vcpu_id` stopped, `VM::run` having returned
+    /// `VmExitReason::Debug` for it.
+    pub fn vcpu_stopped<H: HyperCraftHal, G: GuestPageTableTrait>(
+        &mut self,
+        vm: &VM<H, G>,
+        vcpu_id: usize,
+    ) -> HyperResult<()> {
+        self.vcpu_id = vcpu_id;
+        self.watch = vm.debug_watch_hit(vcpu_id);
+        self.running = false;
+        self.send_stop(SIGTRAP)
+    }
+
+    /// Handles the packet `packet` with its framing removed.
+    fn handle_packet<H: HyperCraftHal, G: GuestPageTableTrait>(
+        &mut self,
+        vm: &mut VM<H, G>,
+        packet: &[u8],
+    ) -> HyperResult<()> {
+        if packet == b"QStartNoAckMode" {
+            // The reply is still acknowledged.
+            self.send_packet(b"OK")?;
+            self.no_ack = true;
+            return Ok(());
+        }
+        let (cmd, args) = match packet.split_first() {
+            Some((&cmd, args)) => (cmd, args),
+            None => return self.send_packet(b""),
+        };
+        let reply = match cmd {
+            b'?' => return self.send_stop(SIGTRAP),
+            b'q' => self.handle_query(vm, packet),
+            b'H' => match args.split_first().map(|(_, tid)| parse_thread(tid)) {
+                // GDB selects "any" (0) or "all" (-1) threads as often as specific ones.
+                Some(Some(Some(vcpu_id))) => {
+                    if vm.vcpu(vcpu_id).is_ok() {
+                        self.vcpu_id = vcpu_id;
+                        Ok(Vec::from(*b"OK"))
+                    } else {
+                        Err(HyperError::NotFound)
+                    }
+                }
+                Some(Some(None)) => Ok(Vec::from(*b"OK")),
+                _ => Err(HyperError::InvalidParam),
+            },
+            b'T' => match parse_thread(args) {
+                Some(Some(vcpu_id)) => vm.vcpu(vcpu_id).map(|_| Vec::from(*b"OK")),
+                _ => Err(HyperError::InvalidParam),
+            },
+            b'g' => self.read_registers(vm),
+            b'G' => self.write_registers(vm, args),
+            b'p' => parse_hex(args)
+                .ok_or(HyperError::InvalidParam)
+                .and_then(|reg| self.read_register(vm, reg)),
+            b'P' => self.write_register(vm, args),
+            b'm' => read_memory(vm, self.vcpu_id, args),
+            b'M' => write_memory(vm, self.vcpu_id, args),
+            b'Z' | b'z' => {
+                let (ty, args) = split_at(args, b',');
+                let (addr, len) = split_at(args, b',');
+                let kind = match ty {
+                    b"0" => None,
+                    b"1" => Some(TriggerKind::Execute),
+                    b"2" => Some(TriggerKind::Store),
+                    b"3" => Some(TriggerKind::Load),
+                    b"4" => Some(TriggerKind::Access),
+                    _ => return self.send_packet(b""),
+                };
+                let result = match (kind, parse_hex(addr), parse_hex(len)) {
+                    (None, Some(addr), Some(_)) if cmd == b'Z' => {
+                        vm.debug_set_breakpoint(self.vcpu_id, addr)
+                    }
+                    (None, Some(addr), Some(_)) => vm.debug_clear_breakpoint(addr),
+                    (Some(kind), Some(addr), Some(len)) if cmd == b'Z' => {
+                        vm.debug_set_trigger(kind, addr, len)
+                    }
+                    (Some(kind), Some(addr), Some(len)) => vm.debug_clear_trigger(kind, addr, len),
+                    _ => Err(HyperError::InvalidParam),
+                };
+                match result {
+                    // An empty reply tells the debugger the type is not supported.
+                    Err(HyperError::NotSupported) => return self.send_packet(b""),
+                    result => result.map(|_| Vec::from(*b"OK")),
+                }
+            }
+            b'c' | b's' => self.resume(vm, cmd == b's', args).map(|_| Vec::new()),
+            b'v' => return self.handle_v(vm, packet),
+            b'D' => {
+                self.detached = true;
+                vm.debug_detach().map(|_| Vec::from(*b"OK"))
+            }
+            b'k' => {
+                self.detached = true;
+                return vm.debug_detach();
+            }
+            _ => Ok(Vec::new()),
+        };
+        match reply {
+            // Resuming replies with a stop once the vCPUs stop.
+            Ok(_) if self.running => Ok(()),
+            Ok(reply) => self.send_packet(&reply),
+            Err(_) => self.send_packet(b"E01"),
+        }
+    }
+
+    /// Returns the reply to the general query `packet`.
+    fn handle_query<H: HyperCraftHal, G: GuestPageTableTrait>(
+        &self,
+        vm: &mut VM<H, G>,
+        packet: &[u8],
+    ) -> HyperResult<Vec<u8>> {
+        let (name, _) = split_at(packet, b':');
+        Ok(match name {
+            b"qSupported" => format!("PacketSize={:x};QStartNoAckMode+", PACKET_SIZE).into_bytes(),
+            b"qAttached" => Vec::from(*b"1"),
+            b"qC" => format!("QC{:x}", self.vcpu_id + 1).into_bytes(),
+            b"qfThreadInfo" => {
+                let threads: Vec<_> = (0..VM_CPUS_MAX)
+                    .filter(|&vcpu_id| vm.vcpu(vcpu_id).is_ok())
+                    .map(|vcpu_id| format!("{:x}", vcpu_id + 1))
+                    .collect();
+                format!("m{}", threads.join(",")).into_bytes()
+            }
+            b"qsThreadInfo" => Vec::from(*b"l"),
+            _ if name.starts_with(b"qThreadExtraInfo,") => {
+                let tid = &name[b"qThreadExtraInfo,".len()..];
+                let vcpu_id = parse_thread(tid)
+                    .flatten()
+                    .ok_or(HyperError::InvalidParam)?;
+                let mut reply = Vec::new();
+                push_hex(&mut reply, format!("vCPU {}", vcpu_id).as_bytes());
+                reply
+            }
+            _ => Vec::new(),
+        })
+    }
+
+    /// Handles the `v` packet `packet`.
+    fn handle_v<H: HyperCraftHal, G: GuestPageTableTrait>(
+        &mut self,
+        vm: &mut VM<H, G>,
+        packet: &[u8],
+    ) -> HyperResult<()> {
+        if packet == b"vCont?" {
+            return self.send_packet(b"vCont;c;C;s;S");
+        }
+        let actions = match packet.strip_prefix(b"vCont;") {
+            Some(actions) => actions,
+            None => return self.send_packet(b""),
+        };
+        let mut step = None;
+        for action in actions.split(|&b| b == b';') {
+            let (action, tid) = split_at(action, b':');
+            match action.first() {
+                Some(b's' | b'S') => {
+                    let vcpu_id = if tid.is_empty() {
+                        Some(self.vcpu_id)
+                    } else {
+                        parse_thread(tid).flatten()
+                    };
+                    // Only one vCPU is stepped at a time; the first action applies.
+                    step = step.or(vcpu_id);
+                }
+                Some(b'c' | b'C') => {}
+                _ => return self.send_packet(b"E01"),
+            }
+        }
+        let result = match step {
+            Some(vcpu_id) => vm.debug_step(vcpu_id),
+            None => Ok(()),
+        };
+        match result {
+            Ok(()) => {
+                self.running = true;
+                Ok(())
+            }
+            Err(_) => self.send_packet(b"E01"),
+        }
+    }
+
+    /// Resumes the vCPUs, at `args` for the selected one if given, stepping it if `step`.
+    fn resume<H: HyperCraftHal, G: GuestPageTableTrait>(
+        &mut self,
+        vm: &mut VM<H, G>,
+        step: bool,
+        args: &[u8],
+    ) -> HyperResult<()> {
+        if !args.is_empty() {
+            let pc = parse_hex(args).ok_or(HyperError::InvalidParam)?;
+            vm.vcpu(self.vcpu_id)?.set_pc(pc);
+        }
+        if step {
+            vm.debug_step(self.vcpu_id)?;
+        }
+        self.running = true;
+        Ok(())
+    }
+
+    /// Returns the GPRs and the pc of the selected vCPU, as the reply to `g`.
+    fn read_registers<H: HyperCraftHal, G: GuestPageTableTrait>(
+        &self,
+        vm: &mut VM<H, G>,
+    ) -> HyperResult<Vec<u8>> {
+        let mut reply = Vec::new();
+        for reg in 0..=REG_PC {
+            reply.extend(self.read_register(vm, reg)?);
+        }
+        Ok(reply)
+    }
+
+    /// Sets the GPRs and the pc of the selected vCPU from the `G` packet `args`.
+    fn write_registers<H: HyperCraftHal, G: GuestPageTableTrait>(
+        &self,
+        vm: &mut VM<H, G>,
+        args: &[u8],
+    ) -> HyperResult<Vec<u8>> {
+        let data = decode_hex(args).ok_or(HyperError::InvalidParam)?;
+        let vcpu = vm.vcpu(self.vcpu_id)?;
+        for (reg, val) in data.chunks_exact(8).take(REG_PC + 1).enumerate() {
+            let val = usize::from_le_bytes(val.try_into().unwrap());
+            match GprIndex::from_raw(reg as u32) {
+                Some(index) => vcpu.set_gpr(index, val),
+                None => vcpu.set_pc(val),
+            }
+        }
+        Ok(Vec::from(*b"OK"))
+    }
+
+    /// Returns register `reg` of the selected vCPU in hex, or marked unavailable.
+    fn read_register<H: HyperCraftHal, G: GuestPageTableTrait>(
+        &self,
+        vm: &mut VM<H, G>,
+        reg: usize,
+    ) -> HyperResult<Vec<u8>> {
+        let vcpu = vm.vcpu(self.vcpu_id)?;
+        let val = match reg {
+            REG_PC => Some(vcpu.pc()),
+            _ if reg < REG_PC => Some(vcpu.get_gpr(GprIndex::from_raw(reg as u32).unwrap())),
+            _ => reg
+                .checked_sub(REG_FIRST_CSR)
+                .and_then(|csr| u16::try_from(csr).ok())
+                .and_then(|csr| vcpu.get_csr(csr).ok()),
+        };
+        let mut reply = Vec::new();
+        match val {
+            Some(val) => push_hex(&mut reply, &val.to_le_bytes()),
+            None => reply.extend_from_slice(b"xxxxxxxxxxxxxxxx"),
+        }
+        Ok(reply)
+    }
+
+    /// Sets a register of the selected vCPU from the `P` packet `args`.
+    fn write_register<H: HyperCraftHal, G: GuestPageTableTrait>(
+        &self,
+        vm: &mut VM<H, G>,
+        args: &[u8],
+    ) -> HyperResult<Vec<u8>> {
+        let (reg, val) = split_at(args, b'=');
+        let reg = parse_hex(reg).ok_or(HyperError::InvalidParam)?;
+        let val = decode_hex(val)
+            .and_then(|val| val.try_into().ok())
+            .map(usize::from_le_bytes)
+            .ok_or(HyperError::InvalidParam)?;
+        let vcpu = vm.vcpu(self.vcpu_id)?;
+        match reg {
+            REG_PC => vcpu.set_pc(val),
+            _ if reg < REG_PC => vcpu.set_gpr(GprIndex::from_raw(reg as u32).unwrap(), val),
+            _ => {
+                let csr = reg
+                    .checked_sub(REG_FIRST_CSR)
+                    .and_then(|csr| u16::try_from(csr).ok())
+                    .ok_or(HyperError::NotSupported)?;
+                vcpu.set_csr(csr, val)?;
+            }
+        }
+        Ok(Vec::from(*b"OK"))
+    }
+
+    /// Sends a stop reply for the selected vCPU with `signal`.
+    fn send_stop(&mut self, signal: u8) -> HyperResult<()> {
+        let mut reply = format!("T{:02x}thread:{:x};", signal, self.vcpu_id + 1);
+        match self.watch {
+            Some((TriggerKind::Store, addr)) => reply += &format!("watch:{:x};", addr),
+            Some((TriggerKind::Load, addr)) => reply += &format!("rwatch:{:x};", addr),
+            Some((TriggerKind::Access, addr)) => reply += &format!("awatch:{:x};", addr),
+            Some((TriggerKind::Execute, _)) | None => {}
+        }
+        self.send_packet(reply.as_bytes())
+    }
+
+    /// Sends the packet `data`, which must need no escaping.
+    fn send_packet(&mut self, data: &[u8]) -> HyperResult<()> {
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        packet.extend_from_slice(data);
+        packet.push(b'#');
+        push_hex(&mut packet, &[checksum(data)]);
+        self.conn.write_all(&packet)
+    }
+}
+
+/// Handles the `m` packet arguments `args` for `vcpu_id`, returning the memory read in hex.
+fn read_memory<H: HyperCraftHal, G: GuestPageTableTrait>(
+    vm: &mut VM<H, G>,
+    vcpu_id: usize,
+    args: &[u8],
+) -> HyperResult<Vec<u8>> {
+    let (addr, len) = split_at(args, b',');
+    let addr = parse_hex(addr).ok_or(HyperError::InvalidParam)?;
+    let len = parse_hex(len)
+        .filter(|&len| len <= PACKET_SIZE / 2)
+        .ok_or(HyperError::InvalidParam)?;
+    let mut buf = alloc::vec![0; len];
+    vm.debug_read_memory(vcpu_id, addr, &mut buf)?;
+    let mut reply = Vec::with_capacity(len * 2);
+    push_hex(&mut reply, &buf);
+    Ok(reply)
+}
+
+/// Handles the `M` packet arguments `args` for `vcpu_id`.
+fn write_memory<H: HyperCraftHal, G: GuestPageTableTrait>(
+    vm: &mut VM<H, G>,
+    vcpu_id: usize,
+    args: &[u8],
+) -> HyperResult<Vec<u8>> {
+    let (range, data) = split_at(args, b':');
+    let (addr, len) = split_at(range, b',');
+    let addr = parse_hex(addr).ok_or(HyperError::InvalidParam)?;
+    let data = decode_hex(data)
+        .filter(|data| Some(data.len()) == parse_hex(len))
+        .ok_or(HyperError::InvalidParam)?;
+    vm.debug_write_memory(vcpu_id, addr, &data)?;
+    Ok(Vec::from(*b"OK"))
+}
+
+/// Splits `s` at the first `sep`, which is dropped.
+fn split_at(s: &[u8], sep: u8) -> (&[u8], &[u8]) {
+    match s.iter().position(|&b| b == sep) {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, &[]),
+    }
+}
+
+/// Parses the thread id `tid` into a vCPU id, or `None` for any or all threads.
+fn parse_thread(tid: &[u8]) -> Option<Option<usize>> {
+    match tid {
+        b"-1" | b"0" => Some(None),
+        _ => parse_hex(tid)?.checked_sub(1).map(Some),
+    }
+}
+
+fn parse_hex(s: &[u8]) -> Option<usize> {
+    if s.is_empty() {
+        return None;
+    }
+    s.iter().try_fold(0usize, |acc, &c| {
+        acc.checked_mul(16)?.checked_add(hex_digit(c)? as usize)
+    })
+}
+
+fn decode_hex(s: &[u8]) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    s.chunks_exact(2)
+        .map(|pair| Some(hex_digit(pair[0])? << 4 | hex_digit(pair[1])?))
+        .collect()
+}
+
+fn push_hex(out: &mut Vec<u8>, bytes: &[u8]) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    for &b in bytes {
+        out.push(DIGITS[(b >> 4) as usize]);
+        out.push(DIGITS[(b & 0xf) as usize]);
+    }
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
@@ -0,0 +1,280 @@
+//! Pre-copy live migration of a VM.
+//!
+//! The source sends all the guest memory once while the guest keeps running, then in rounds the
+//! pages written since the previous one, as found by dirty logging. Once few enough pages are
+//! written per round, the source VM is stopped for good and the last dirty pages are sent along
+//! with the state of its vCPUs and devices, in the snapshot format. The destination VM, set up
+//! like the source one, writes the pages into its memory, restores the state and resumes. The
+//! writes the hypervisor and the VM's devices make to guest memory, e.g. to virtio buffers, are
+//! logged like the guest's own.
+//!
+//! Each message is a snapshot header followed by the message kind and its payload: a run of pages,
+//! each its guest physical address and contents, or the state of the VM.
+//!
+//! Between rounds the guest runs for a time slice, which `VM::set_run_deadline` bounds even if
+//! its vCPUs never exit on their own. Migrating `src` to `dst`, set up alike on the same host,
+//! through a `MemoryTransport`, with `memory` the ranges of guest RAM:
+//!
+//! ```no_run
+//! use hypercraft::{
+//!     GuestPageTableTrait, GuestPhysAddr, HyperCraftHal, HyperResult, MemoryTransport,
+//!     MigrationDestination, MigrationSource, VmExitReason, VM,
+//! };
+//!
+//! fn migrate<H: HyperCraftHal, G: GuestPageTableTrait>(
+//!     src: &mut VM<H, G>,
+//!     dst: &mut VM<H, G>,
+//!     memory: &[(GuestPhysAddr, usize)],
+//! ) -> HyperResult<()> {
+//!     let (mut tx, mut rx) = MemoryTransport::pair();
+//!     let mut source = MigrationSource::start(src, memory, false)?;
+//!     let mut dest = MigrationDestination::new();
+//!     // Run the guest for 10ms between rounds until few pages are left to send.
+//!     let slice = H::timebase_frequency() / 100;
+//!     while source.send_round(src, &mut tx)? > 16 {
+//!         dest.poll(dst, &mut rx)?;
+//!         src.set_run_deadline(Some(riscv::register::time::read() as u64 + slice));
+//!         src.run(0);
+//!     }
+//!     src.set_run_deadline(None);
+//!     source.complete(src, &mut tx)?;
+//!     assert!(dest.poll(dst, &mut rx)?);
+//!     assert_eq!(src.run(0), VmExitReason::Migrated);
+//!     // The guest goes on from where it stopped.
+//!     dst.run(0);
+//!     Ok(())
+//! }
+//! ```
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::memory::PAGE_SIZE_4K;
+use crate::{GuestPageTableTrait, GuestPhysAddr, HyperCraftHal, HyperError, HyperResult, VM};
+
+const MSG_PAGES: u32 = u32::from_le_bytes(*b"PAGE");
+const MSG_STATE: u32 = u32::from_le_bytes(*b"STAT");
+
+/// The number of pages sent per message.
+const PAGES_PER_MESSAGE: usize = 64;
+
+/// A reliable, ordered channel carrying the messages of a migration to its destination.
+pub trait MigrationTransport {
+    /// Sends the message `data`.
+    fn send(&mut self, data: &[u8]) -> HyperResult<()>;
+
+    /// Returns the next message received, or `None` if none is available yet.
+    fn recv(&mut self) -> HyperResult<Option<Vec<u8>>>;
+}
+
+/// One end of a transport between two VMs on the same host, which queues the messages in memory.
+pub struct MemoryTransport {
+    tx: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    rx: Arc<Mutex<VecDeque<Vec<u8>>>>,
+}
+
+impl MemoryTransport {
+    /// Creates the two connected ends of a transport.
+    pub fn pair() -> (Self, Self) {
+        let a = Arc::new(Mutex::new(VecDeque::new()));
+        let b = Arc::new(Mutex::new(VecDeque::new()));
+        (
+            Self {
+                tx: a.clone(),
+                rx: b.clone(),
+            },
+            Self { tx: b, rx: a },
+        )
+    }
+}
+
+impl MigrationTransport for MemoryTransport {
+    fn send(&mut self, data: &[u8]) -> HyperResult<()> {
+        self.tx.lock().push_back(data.to_vec());
+        Ok(())
+    }
+
+    fn recv(&mut self) -> HyperResult<Option<Vec<u8>>> {
+        Ok(self.rx.lock().pop_front())
+    }
+}
+
+/// The source side of a migration.
+pub struct MigrationSource {
+    // The ranges of guest memory migrated, as `(gpa, size)`.
+    ranges: Vec<(GuestPhysAddr, usize)>,
+    // Set once all the memory has been sent.
+    copied: bool,
+}
+
+impl MigrationSource {
+    /// Starts migrating `vm`, logging the writes to the guest memory ranges `memory`, given as
+    /// `(gpa, size)`. See `VM::start_dirty_log` for `hardware_dirty`.
+    pub fn start<H: HyperCraftHal, G: GuestPageTableTrait>(
+        vm: &mut VM<H, G>,
+        memory: &[(GuestPhysAddr, usize)],
+        hardware_dirty: bool,
+    ) -> HyperResult<Self> {
+        for (i, &(gpa, size)) in memory.iter().enumerate() {
+            if let Err(err) = vm.start_dirty_log(gpa, size, hardware_dirty) {
+                for &(gpa, _) in &memory[..i] {
+                    vm.stop_dirty_log(gpa)?;
+                }
+                return Err(err);
+            }
+        }
+        Ok(Self {
+            ranges: memory.to_vec(),
+            copied: false,
+        })
+    }
+
+    /// Sends a round of memory: all of it the first time, then the pages written since the
+    /// previous round. Returns the number of pages sent, from which the caller decides whether to
+    /// run the guest for another round or to `complete` the migration. Must be called with the
+    /// VM's vCPUs paused, on the hart that runs them.
+    pub fn send_round<H: HyperCraftHal, G: GuestPageTableTrait, T: MigrationTransport>(
+        &mut self,
+        vm: &mut VM<H, G>,
+        transport: &mut T,
+    ) -> HyperResult<usize> {
+        let mut msg = pages_message();
+        let mut batched = 0;
+        let mut sent = 0;
+        for &(base, size) in self.ranges.iter() {
+            let dirty = vm.fetch_dirty_log(base)?;
+            for page in 0..size / PAGE_SIZE_4K {
+                if self.copied && dirty[page / 64] & (1 << (page % 64)) == 0 {
+                    continue;
+                }
+                let gpa = base + page * PAGE_SIZE_4K;
+                msg.write_usize(gpa);
+                vm.read_guest_memory(gpa, msg.alloc_bytes(PAGE_SIZE_4K))?;
+                batched += 1;
+                sent += 1;
+                if batched == PAGES_PER_MESSAGE {
+                    transport.send(&core::mem::replace(&mut msg, pages_message()).finish())?;
+                    batched = 0;
+                }
+            }
+        }
+        if batched != 0 {
+            transport.send(&msg.finish())?;
+        }
+        self.copied = true;
+        Ok(sent)
+    }
+
+    /// Completes the migration: sends the last pages written and the state of the VM, which then
+    /// stops for good, `VM::run` returning `VmExitReason::Migrated`, as it resumes on the
+    /// destination. Dirty logging is stopped whether or not this succeeds; on failure the VM can
+    /// keep running on the source. Returns the number of pages sent.
+    pub fn complete<H: HyperCraftHal, G: GuestPageTableTrait, T: MigrationTransport>(
+        mut self,
+        vm: &mut VM<H, G>,
+        transport: &mut T,
+    ) -> HyperResult<usize> {
+        let result = self.send_round(vm, transport).and_then(|sent| {
+            let state = vm.snapshot(&[])?;
+            let mut msg = SnapshotWriter::new();
+            msg.write_u32(MSG_STATE);
+            msg.write_bytes(&state);
+            transport.send(&msg.finish())?;
+            Ok(sent)
+        });
+        match result {
+            Ok(sent) => {
+                // The state is on its way, so the guest resumes on the destination whatever
+                // happens here.
+                vm.stop_migrated();
+                if let Err(err) = self.cancel(vm) {
+                    warn!("Migration: failed to stop dirty logging: {:?}", err);
+                }
+                Ok(sent)
+            }
+            Err(err) => {
+                self.cancel(vm)?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Abandons the migration, leaving the VM to run on the source.
+    pub fn cancel<H: HyperCraftHal, G: GuestPageTableTrait>(
+        self,
+        vm: &mut VM<H, G>,
+    ) -> HyperResult<()> {
+        for &(gpa, _) in self.ranges.iter() {
+            vm.stop_dirty_log(gpa)?;
+        }
+        Ok(())
+    }
+}
+
+/// The destination side of a migration.
+#[derive(Default)]
+pub struct MigrationDestination {
+    pages: usize,
+    complete: bool,
+}
+
+impl MigrationDestination {
+    /// Prepares to receive a migration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of pages received so far.
+    pub fn pages_received(&self) -> usize {
+        self.pages
+    }
+
+    /// Handles the messages available from `transport`, writing the pages received into the
+    /// memory of `vm` and restoring its state at the end. Returns whether the migration is
+    /// complete, after which `vm` can run. `vm` must be set up like the source VM, and must not
+    /// run before the migration completes. Must be called on the hart that runs its vCPUs.
+    pub fn poll<H: HyperCraftHal, G: GuestPageTableTrait, T: MigrationTransport>(
+        &mut self,
+        vm: &mut VM<H, G>,
+        transport: &mut T,
+    ) -> HyperResult<bool> {
+        while !self.complete {
+            let msg = match transport.recv()? {
+                Some(msg) => msg,
+                None => break,
+            };
+            let mut input = SnapshotReader::new(&msg)?;
+            match input.read_u32()? {
+                MSG_PAGES => {
+                    while !input.is_empty() {
+                        let gpa = input.read_usize()?;
+                        let data = input.read_bytes()?;
+                        if data.len() != PAGE_SIZE_4K {
+                            return Err(HyperError::InvalidParam);
+                        }
+                        vm.write_guest_memory(gpa, data)?;
+                        self.pages += 1;
+                    }
+                }
+                MSG_STATE => {
+                    vm.restore(input.read_bytes()?)?;
+                    if !input.is_empty() {
+                        return Err(HyperError::InvalidParam);
+                    }
+                    self.complete = true;
+                }
+                _ => return Err(HyperError::InvalidParam),
+            }
+        }
+        Ok(self.complete)
+    }
+}
+
+fn pages_message() -> SnapshotWriter {
+    let mut msg = SnapshotWriter::new();
+    msg.write_u32(MSG_PAGES);
+    msg
+}
@@ -1,23 +1,44 @@
+mod crash;
 mod csrs;
+mod debug;
 mod detect;
 mod devices;
+mod dirty_log;
+mod emulate;
 mod ept;
+mod exit_stats;
+mod gdbstub;
+mod migration;
+mod nested;
 mod regs;
 mod sbi;
 mod smp;
+mod snapshot;
+mod triggers;
 mod vcpu;
 mod vm;
 mod vm_pages;
 mod vmexit;
 
+pub use crash::CrashReport;
 use detect::detect_h_extension;
+pub use devices::passthrough::PassthroughDevice;
+pub use devices::virtio;
+pub use devices::MmioDevice;
 pub use ept::NestedPageTable;
+pub use exit_stats::{ExitRecord, ExitStats, ExitTrace, FaultRegion, EXIT_TRACE_LEN};
+pub use gdbstub::{GdbAction, GdbConnection, GdbStub};
+pub use migration::{MemoryTransport, MigrationDestination, MigrationSource, MigrationTransport};
 pub use regs::GprIndex;
 pub use sbi::SbiMessage as HyperCallMsg;
+pub use sbi::{HyperCallHandler, SbiIdentity, EID_HYPERCRAFT};
 pub use smp::PerCpu;
+pub use snapshot::{SnapshotReader, SnapshotWriter};
+pub use triggers::TriggerKind;
 pub use vcpu::VCpu;
 pub use vm::VM;
-pub use vmexit::VmExitInfo;
+pub use vm_pages::VmPages;
+pub use vmexit::{VmExitInfo, VmExitReason};
 
 use self::csrs::{traps, ReadWriteCsr, RiscvCsrTrait, CSR};
 use self::devices::plic::PlicState;
@@ -57,14 +78,16 @@ unsafe fn setup_csrs() {
             | traps::interrupt::VIRTUAL_SUPERVISOR_SOFT,
     );
 
-    // clear all interrupts.
-    CSR.hcounteren.write_value(0xffff_ffff);
+    // Expose cycle, time and instret. The hpmcounters are granted to each vCPU by the PMU
+    // extension.
+    CSR.hcounteren.write_value(sbi::pmu::FIXED_COUNTEREN);
 
     // enable interrupt
     CSR.sie.write_value(
         traps::interrupt::SUPERVISOR_EXTERNAL
             | traps::interrupt::SUPERVISOR_SOFT
-            | traps::interrupt::SUPERVISOR_TIMER,
+            | traps::interrupt::SUPERVISOR_TIMER
+            | traps::interrupt::SUPERVISOR_GUEST_EXTERNEL,
     );
     debug!("sie: {:#x}", CSR.sie.get_value());
 }
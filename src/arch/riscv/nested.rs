@@ -0,0 +1,687 @@
+//! Nested virtualization: a virtual H extension for guests running their own hypervisor.
+//!
+//! The guest hypervisor (L1) runs in VS-mode, where its accesses to the hypervisor and VS CSRs and
+//! its HLV/HSV/HFENCE instructions raise virtual instruction exceptions. They are emulated over
+//! `GuestVirtualHsCsrs` and over the VS CSRs of its guest (L2) kept in `GuestVsCsrs`. L1's `sret`
+//! is trapped too (hstatus.VTSR), so that returning to V=1 switches the vCPU to running L2 in
+//! VS-mode, translated by a shadow G-stage table combining L1's hgatp with our own. Traps from L2
+//! that we don't resolve ourselves are reflected to L1 as traps taken by its HS-mode.
+
+use page_table_entry::MappingFlags;
+
+use super::csrs::{defs::*, traps, RiscvCsrTrait, CSR};
+use super::emulate::{CsrAccess, HypervisorInst, MmioAccess};
+use super::regs::GeneralPurposeRegisters;
+use super::vcpu::{GuestVsCsrs, VmCpuRegisters};
+use super::vm_pages::VmPages;
+use crate::{GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr, HyperError, HyperResult};
+
+const SSTATUS_SIE: usize = 1 << 1;
+const SSTATUS_SPIE: usize = 1 << 5;
+const SSTATUS_SPP: usize = 1 << 8;
+const SSTATUS_SUM: usize = 1 << 18;
+const SSTATUS_MXR: usize = 1 << 19;
+
+const HSTATUS_GVA: usize = 1 << 6;
+const HSTATUS_SPV: usize = 1 << 7;
+const HSTATUS_SPVP: usize = 1 << 8;
+const HSTATUS_HU: usize = 1 << 9;
+const HSTATUS_VTVM: usize = 1 << 20;
+const HSTATUS_VTW: usize = 1 << 21;
+const HSTATUS_VTSR: usize = 1 << 22;
+const HSTATUS_VSXL_64: usize = 2 << 32;
+const HSTATUS_WRITABLE: usize = HSTATUS_GVA
+    | HSTATUS_SPV
+    | HSTATUS_SPVP
+    | HSTATUS_HU
+    | HSTATUS_VTVM
+    | HSTATUS_VTW
+    | HSTATUS_VTSR;
+// The bits of L1's hstatus that control how L2 runs.
+const HSTATUS_L2_TRAPS: usize = HSTATUS_VTVM | HSTATUS_VTW | HSTATUS_VTSR;
+
+// The exceptions that can be delegated to VS-mode.
+const HEDELEG_WRITABLE: usize = 0xb1ff;
+const VS_INTERRUPTS: usize = traps::interrupt::VIRTUAL_SUPERVISOR_SOFT
+    | traps::interrupt::VIRTUAL_SUPERVISOR_TIMER
+    | traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL;
+const S_INTERRUPTS: usize = traps::interrupt::SUPERVISOR_SOFT
+    | traps::interrupt::SUPERVISOR_TIMER
+    | traps::interrupt::SUPERVISOR_EXTERNAL;
+
+const HGATP_MODE_SHIFT: usize = 60;
+const HGATP_MODE_BARE: usize = 0;
+const HGATP_MODE_SV39X4: usize = 8;
+// The root of an Sv39x4 table is 16KiB, so the two low bits of its PPN are ignored.
+const HGATP_PPN_MASK: usize = ((1 << 44) - 1) & !0b11;
+const SATP_MODE_SV39: usize = 8;
+
+const PTE_V: usize = 1 << 0;
+const PTE_R: usize = 1 << 1;
+const PTE_W: usize = 1 << 2;
+const PTE_X: usize = 1 << 3;
+const PTE_U: usize = 1 << 4;
+const PTE_A: usize = 1 << 6;
+const PTE_D: usize = 1 << 7;
+
+const CAUSE_INTERRUPT: usize = 1 << (usize::BITS - 1);
+const CAUSE_LOAD_ACCESS: usize = 5;
+const CAUSE_STORE_ACCESS: usize = 7;
+const CAUSE_INST_PAGE_FAULT: usize = 12;
+const CAUSE_LOAD_PAGE_FAULT: usize = 13;
+const CAUSE_STORE_PAGE_FAULT: usize = 15;
+const CAUSE_INST_GUEST_PAGE_FAULT: usize = 20;
+const CAUSE_LOAD_GUEST_PAGE_FAULT: usize = 21;
+const CAUSE_STORE_GUEST_PAGE_FAULT: usize = 23;
+
+/// The kind of a memory access translated on behalf of a guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Access {
+    Load,
+    Store,
+    Fetch,
+    // A load requiring execute permission (`hlvx`).
+    LoadExec,
+}
+
+impl Access {
+    fn page_fault(self) -> usize {
+        match self {
+            Access::Load | Access::LoadExec => CAUSE_LOAD_PAGE_FAULT,
+            Access::Store => CAUSE_STORE_PAGE_FAULT,
+            Access::Fetch => CAUSE_INST_PAGE_FAULT,
+        }
+    }
+
+    fn guest_page_fault(self) -> usize {
+        match self {
+            Access::Load | Access::LoadExec => CAUSE_LOAD_GUEST_PAGE_FAULT,
+            Access::Store => CAUSE_STORE_GUEST_PAGE_FAULT,
+            Access::Fetch => CAUSE_INST_GUEST_PAGE_FAULT,
+        }
+    }
+
+    /// Returns the G-stage permission this access requires.
+    fn flag(self) -> MappingFlags {
+        match self {
+            Access::Load => MappingFlags::READ,
+            Access::Store => MappingFlags::WRITE,
+            Access::Fetch | Access::LoadExec => MappingFlags::EXECUTE,
+        }
+    }
+
+    /// Returns whether the leaf PTE `pte` grants this access, with `mxr` making executable pages
+    /// readable.
+    fn permitted(self, pte: usize, mxr: bool) -> bool {
+        let rwx = match self {
+            Access::Load => pte & PTE_R != 0 || (mxr && pte & PTE_X != 0),
+            Access::Store => pte & PTE_W != 0,
+            Access::Fetch | Access::LoadExec => pte & PTE_X != 0,
+        };
+        // Accessed and dirty bits are not updated for the guest: it must set them beforehand.
+        rwx && pte & PTE_A != 0 && (self != Access::Store || pte & PTE_D != 0)
+    }
+}
+
+/// A trap delivered to the guest hypervisor, as seen by its HS-mode.
+#[derive(Clone, Copy, Debug)]
+struct Trap {
+    cause: usize,
+    tval: usize,
+    htval: usize,
+    htinst: usize,
+    // Whether `tval` is a guest virtual address.
+    gva: bool,
+}
+
+impl Trap {
+    fn new(cause: usize, tval: usize) -> Self {
+        Self {
+            cause,
+            tval,
+            htval: 0,
+            htinst: 0,
+            gva: false,
+        }
+    }
+}
+
+/// The outcome of a trap taken while running a nested guest.
+#[derive(Clone, Copy, Debug)]
+pub enum NestedExit {
+    /// The trap was handled, e.g. by filling the shadow G-stage table, and the nested guest
+    /// continues as it was.
+    Resolved,
+    /// The trap was reflected to the guest hypervisor, which runs next.
+    Reflected,
+    /// The nested guest accessed the guest hypervisor's physical address given, which our own
+    /// G-stage table does not allow, e.g. emulated MMIO or a page whose writes are logged.
+    Mmio(GuestPhysAddr),
+}
+
+/// The nested virtualization state of a vCPU.
+pub struct NestedVcpu<G: GuestPageTableTrait> {
+    // Whether the vCPU runs in the guest hypervisor's virtual mode, i.e. runs L2.
+    virt: bool,
+    // Maps L2 guest physical addresses to host physical addresses.
+    shadow: G,
+    // Our own hgatp and delegations, put back when returning to L1.
+    host_hgatp: usize,
+    host_hedeleg: usize,
+    host_hideleg: usize,
+    // The VS-level interrupts pending for L1 while L2 runs.
+    l1_hvip: usize,
+}
+
+impl<G: GuestPageTableTrait> NestedVcpu<G> {
+    /// Exposes the H extension to the vCPU whose registers are `regs`, currently running with its
+    /// G-stage table `hgatp`.
+    pub fn new(regs: &mut VmCpuRegisters, hgatp: usize) -> HyperResult<Self> {
+        let nested = Self {
+            virt: false,
+            shadow: G::new()?,
+            host_hgatp: hgatp,
+            host_hedeleg: CSR.hedeleg.get_value(),
+            host_hideleg: CSR.hideleg.get_value(),
+            l1_hvip: 0,
+        };
+        nested.init_regs(regs);
+        Ok(nested)
+    }
+
+    /// Returns whether the vCPU runs the nested guest.
+    pub fn is_virt(&self) -> bool {
+        self.virt
+    }
+
+    /// Returns to running the guest hypervisor after its vCPU registers `regs` have been reset.
+    pub fn reset(&mut self, regs: &mut VmCpuRegisters) -> HyperResult<()> {
+        if self.virt {
+            // Both levels start over: the guest hypervisor with cleared VS CSRs.
+            self.exit_virt(regs);
+            regs.vs_csrs = GuestVsCsrs::default();
+        }
+        self.shadow = G::new()?;
+        self.init_regs(regs);
+        Ok(())
+    }
+
+    fn init_regs(&self, regs: &mut VmCpuRegisters) {
+        regs.virtual_hs_csrs = Default::default();
+        regs.virtual_hs_csrs.hstatus = HSTATUS_VSXL_64;
+        regs.guest_regs.hstatus |= HSTATUS_VTSR;
+    }
+
+    /// Emulates the hypervisor instruction or CSR access `inst` of the guest hypervisor. Returns
+    /// the length of the instruction to skip, or 0 if the vCPU continues elsewhere. Fails with
+    /// `NotSupported` for instructions other than these.
+    pub fn emulate_inst(
+        &mut self,
+        regs: &mut VmCpuRegisters,
+        inst: u32,
+        gprs: &mut GeneralPurposeRegisters,
+        mem: &VmPages,
+    ) -> HyperResult<usize> {
+        let hinst = match HypervisorInst::decode(inst, gprs) {
+            Some(hinst) => hinst,
+            None => {
+                let access = CsrAccess::decode(inst, gprs).map_err(|_| HyperError::NotSupported)?;
+                let old = self.emulate_csr(regs, &access)?;
+                gprs.set_reg(access.rd, old);
+                return Ok(4);
+            }
+        };
+        match hinst {
+            HypervisorInst::Sret => {
+                self.emulate_sret(regs);
+                return Ok(0);
+            }
+            HypervisorInst::HfenceVvma => unsafe { core::arch::riscv64::hfence_vvma_all() },
+            HypervisorInst::HfenceGvma => self.flush_shadow()?,
+            HypervisorInst::Access { access, addr, exec } => {
+                if let Err(trap) = self.emulate_access(regs, access, addr, exec, gprs, mem) {
+                    self.deliver_trap(regs, trap);
+                    return Ok(0);
+                }
+            }
+        }
+        Ok(4)
+    }
+
+    /// Handles the trap the nested guest just took, as saved in `regs`, which is not one of the
+    /// host interrupts. `gpt` is our G-stage table of the guest hypervisor.
+    pub fn handle_guest_trap(
+        &mut self,
+        regs: &mut VmCpuRegisters,
+        mem: &VmPages,
+        gpt: &G,
+    ) -> HyperResult<NestedExit> {
+        let trap_csrs = regs.trap_csrs.clone();
+        let gva = regs.guest_regs.hstatus & HSTATUS_GVA != 0;
+        let access = match trap_csrs.scause {
+            CAUSE_INST_GUEST_PAGE_FAULT => Some(Access::Fetch),
+            CAUSE_LOAD_GUEST_PAGE_FAULT => Some(Access::Load),
+            CAUSE_STORE_GUEST_PAGE_FAULT => Some(Access::Store),
+            _ => None,
+        };
+        let mut trap = Trap {
+            cause: trap_csrs.scause,
+            tval: trap_csrs.stval,
+            htval: 0,
+            htinst: trap_csrs.htinst,
+            gva,
+        };
+        if let Some(access) = access {
+            let l2_gpa = trap_csrs.htval << 2 | trap_csrs.stval & 0x3;
+            let hgatp = regs.virtual_hs_csrs.hgatp;
+            if let Some((l1_gpa, pte)) = walk_gstage(mem, hgatp, l2_gpa, access) {
+                let page = l1_gpa & !0xfff;
+                let (hpa, host_flags) = match gpt.query(page) {
+                    Ok(mapping) => mapping,
+                    Err(_) => return Ok(NestedExit::Mmio(l1_gpa)),
+                };
+                let mut flags = MappingFlags::USER;
+                if pte & PTE_R != 0 {
+                    flags |= MappingFlags::READ;
+                }
+                // Clean pages stay read-only so that the guest hypervisor sees the write fault.
+                if pte & PTE_W != 0 && pte & PTE_D != 0 {
+                    flags |= MappingFlags::WRITE;
+                }
+                if pte & PTE_X != 0 {
+                    flags |= MappingFlags::EXECUTE;
+                }
+                // The nested guest gets no more than what we allow the guest hypervisor.
+                if !host_flags.contains(access.flag()) {
+                    return Ok(NestedExit::Mmio(l1_gpa));
+                }
+                let flags = flags & (host_flags | MappingFlags::USER);
+                // The page may be mapped already with fewer permissions.
+                let _ = self.shadow.unmap(l2_gpa & !0xfff);
+                self.shadow.map(l2_gpa & !0xfff, hpa, flags)?;
+                unsafe { core::arch::riscv64::hfence_gvma_all() };
+                return Ok(NestedExit::Resolved);
+            }
+            trap.htval = trap_csrs.htval;
+        }
+        self.deliver_trap(regs, trap);
+        Ok(NestedExit::Reflected)
+    }
+
+    /// Delivers to the guest hypervisor the highest priority interrupt pending for it and enabled
+    /// while the nested guest runs. Returns whether one was delivered.
+    pub fn deliver_interrupt(&mut self, regs: &mut VmCpuRegisters) -> bool {
+        // Interrupts we raised for L1 since L2 last ran must not reach L2.
+        let ours = CSR.hvip.get_value() & !regs.virtual_hs_csrs.hvip & VS_INTERRUPTS;
+        if ours != 0 {
+            self.l1_hvip |= ours;
+            CSR.hvip.read_and_clear_bits(ours);
+        }
+        // L1's HS-level interrupts are enabled regardless of its sstatus.SIE while V=1.
+        let pending = (self.l1_hvip >> 1) & regs.vs_csrs.vsie;
+        let code = [9, 1, 5]
+            .into_iter()
+            .find(|code| pending & (1 << *code) != 0);
+        match code {
+            Some(code) => {
+                self.deliver_trap(regs, Trap::new(CAUSE_INTERRUPT | code, 0));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Emulates an access of the guest hypervisor to one of the hypervisor or VS CSRs, returning
+    /// its previous value.
+    fn emulate_csr(&mut self, regs: &mut VmCpuRegisters, access: &CsrAccess) -> HyperResult<usize> {
+        let hs = &mut regs.virtual_hs_csrs;
+        let vs = &mut regs.vs_csrs;
+        match access.csr {
+            CSR_HGATP => {
+                let old = hs.hgatp;
+                if let Some(new) = access.new_value(old) {
+                    // Writes of unsupported modes have no effect. VMIDs are not implemented.
+                    let mode = new >> HGATP_MODE_SHIFT;
+                    if mode == HGATP_MODE_BARE || mode == HGATP_MODE_SV39X4 {
+                        hs.hgatp = (mode << HGATP_MODE_SHIFT) | (new & HGATP_PPN_MASK);
+                    }
+                    if hs.hgatp != old {
+                        self.flush_shadow()?;
+                    }
+                }
+                return Ok(old);
+            }
+            CSR_HIP => {
+                // Only VSSIP is writable through hip.
+                let old = hs.hvip & VS_INTERRUPTS;
+                if let Some(new) = access.new_value(old) {
+                    let mask = traps::interrupt::VIRTUAL_SUPERVISOR_SOFT;
+                    hs.hvip = (hs.hvip & !mask) | (new & mask);
+                }
+                return Ok(old);
+            }
+            CSR_VSIP => {
+                let old = (hs.hvip & hs.hideleg & VS_INTERRUPTS) >> 1;
+                if let Some(new) = access.new_value(old) {
+                    let mask = traps::interrupt::VIRTUAL_SUPERVISOR_SOFT & hs.hideleg;
+                    hs.hvip = (hs.hvip & !mask) | ((new << 1) & mask);
+                }
+                return Ok(old);
+            }
+            _ => {}
+        }
+        // No guest external interrupt files or hypervisor environment features are provided.
+        let mut zero = 0;
+        let (csr, mask) = match access.csr {
+            CSR_HSTATUS => (&mut hs.hstatus, HSTATUS_WRITABLE),
+            CSR_HEDELEG => (&mut hs.hedeleg, HEDELEG_WRITABLE),
+            CSR_HIDELEG => (&mut hs.hideleg, VS_INTERRUPTS),
+            CSR_HIE => (&mut hs.hie, VS_INTERRUPTS),
+            CSR_HVIP => (&mut hs.hvip, VS_INTERRUPTS),
+            CSR_HCOUNTEREN => (&mut hs.hcounteren, u32::MAX as usize),
+            CSR_HTVAL => (&mut hs.htval, usize::MAX),
+            CSR_HTINST => (&mut hs.htinst, usize::MAX),
+            CSR_HGEIE | CSR_HGEIP | CSR_HENVCFG => (&mut zero, 0),
+            CSR_HTIMEDELTA => (&mut vs.htimedelta, usize::MAX),
+            CSR_VSSTATUS => (&mut vs.vsstatus, usize::MAX),
+            CSR_VSIE => (&mut vs.vsie, S_INTERRUPTS),
+            CSR_VSTVEC => (&mut vs.vstvec, usize::MAX),
+            CSR_VSSCRATCH => (&mut vs.vsscratch, usize::MAX),
+            CSR_VSEPC => (&mut vs.vsepc, usize::MAX),
+            CSR_VSCAUSE => (&mut vs.vscause, usize::MAX),
+            CSR_VSTVAL => (&mut vs.vstval, usize::MAX),
+            CSR_VSATP => (&mut vs.vsatp, usize::MAX),
+            _ => return Err(HyperError::NotSupported),
+        };
+        let old = *csr;
+        if let Some(new) = access.new_value(old) {
+            *csr = (old & !mask) | (new & mask);
+        }
+        Ok(old)
+    }
+
+    /// Emulates `sret` executed by the guest hypervisor, entering the nested guest if
+    /// hstatus.SPV is set.
+    fn emulate_sret(&mut self, regs: &mut VmCpuRegisters) {
+        // The guest hypervisor's sstatus and sepc are the VS-level CSRs.
+        let vsstatus = CSR.vsstatus.get_value();
+        let to_supervisor = vsstatus & SSTATUS_SPP != 0;
+        let mut new = (vsstatus & !(SSTATUS_SIE | SSTATUS_SPP)) | SSTATUS_SPIE;
+        if vsstatus & SSTATUS_SPIE != 0 {
+            new |= SSTATUS_SIE;
+        }
+        CSR.vsstatus.write_value(new);
+        let pc = CSR.vsepc.get_value();
+        if regs.virtual_hs_csrs.hstatus & HSTATUS_SPV != 0 {
+            regs.virtual_hs_csrs.hstatus &= !HSTATUS_SPV;
+            self.enter_virt(regs);
+        }
+        set_spp(regs, to_supervisor);
+        regs.guest_regs.sepc = pc;
+    }
+
+    /// Performs `access` of `hlv`/`hsv` at the nested guest's virtual address `addr`.
+    fn emulate_access(
+        &mut self,
+        regs: &VmCpuRegisters,
+        access: MmioAccess,
+        addr: GuestVirtAddr,
+        exec: bool,
+        gprs: &mut GeneralPurposeRegisters,
+        mem: &VmPages,
+    ) -> Result<(), Trap> {
+        let kind = match access {
+            MmioAccess::Load { .. } if exec => Access::LoadExec,
+            MmioAccess::Load { .. } => Access::Load,
+            MmioAccess::Store { .. } => Access::Store,
+        };
+        let gpa = translate_l2(regs, mem, addr, kind)?;
+        match access {
+            MmioAccess::Load { width, .. } => {
+                let mut buf = [0u8; 8];
+                mem.copy_from_guest(&mut buf[..width], gpa)
+                    .map_err(|_| Trap::new(CAUSE_LOAD_ACCESS, addr))?;
+                access.complete_load(gprs, usize::from_le_bytes(buf));
+            }
+            MmioAccess::Store { value, width } => {
+                mem.copy_to_guest(gpa, &value.to_le_bytes()[..width])
+                    .map_err(|_| Trap::new(CAUSE_STORE_ACCESS, addr))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delivers `trap` to the guest hypervisor's HS-mode, leaving the nested guest if it runs.
+    fn deliver_trap(&mut self, regs: &mut VmCpuRegisters, trap: Trap) {
+        let from_virt = self.virt;
+        let from_supervisor = regs.guest_regs.sstatus & SSTATUS_SPP != 0;
+        let pc = regs.guest_regs.sepc;
+        if from_virt {
+            self.exit_virt(regs);
+        }
+        let hs = &mut regs.virtual_hs_csrs;
+        hs.hstatus &= !(HSTATUS_SPV | HSTATUS_GVA);
+        if from_virt {
+            hs.hstatus |= HSTATUS_SPV;
+            hs.hstatus &= !HSTATUS_SPVP;
+            if from_supervisor {
+                hs.hstatus |= HSTATUS_SPVP;
+            }
+        }
+        if trap.gva {
+            hs.hstatus |= HSTATUS_GVA;
+        }
+        hs.htval = trap.htval;
+        hs.htinst = trap.htinst;
+
+        let vsstatus = CSR.vsstatus.get_value();
+        let mut new = vsstatus & !(SSTATUS_SIE | SSTATUS_SPIE | SSTATUS_SPP);
+        if vsstatus & SSTATUS_SIE != 0 {
+            new |= SSTATUS_SPIE;
+        }
+        if from_supervisor {
+            new |= SSTATUS_SPP;
+        }
+        CSR.vsstatus.write_value(new);
+        CSR.vsepc.write_value(pc);
+        CSR.vscause.write_value(trap.cause);
+        CSR.vstval.write_value(trap.tval);
+
+        let vstvec = CSR.vstvec.get_value();
+        let mut handler = vstvec & !0b11;
+        if vstvec & 0b11 == 1 && trap.cause & CAUSE_INTERRUPT != 0 {
+            handler += 4 * (trap.cause & !CAUSE_INTERRUPT);
+        }
+        set_spp(regs, true);
+        regs.guest_regs.sepc = handler;
+    }
+
+    /// Switches the vCPU to running the nested guest.
+    fn enter_virt(&mut self, regs: &mut VmCpuRegisters) {
+        swap_vs_csrs(&mut regs.vs_csrs);
+        let hs = &regs.virtual_hs_csrs;
+        CSR.hedeleg.write_value(self.host_hedeleg & hs.hedeleg);
+        CSR.hideleg.write_value(self.host_hideleg & hs.hideleg);
+        self.l1_hvip = CSR.hvip.get_value() & VS_INTERRUPTS;
+        CSR.hvip.write_value(hs.hvip & VS_INTERRUPTS);
+        CSR.hgatp.write_value(self.shadow.token());
+        unsafe { core::arch::riscv64::hfence_gvma_all() };
+        regs.guest_regs.hstatus =
+            (regs.guest_regs.hstatus & !HSTATUS_L2_TRAPS) | (hs.hstatus & HSTATUS_L2_TRAPS);
+        self.virt = true;
+    }
+
+    /// Switches the vCPU back to running the guest hypervisor.
+    fn exit_virt(&mut self, regs: &mut VmCpuRegisters) {
+        swap_vs_csrs(&mut regs.vs_csrs);
+        CSR.hedeleg.write_value(self.host_hedeleg);
+        CSR.hideleg.write_value(self.host_hideleg);
+        let ours = CSR.hvip.get_value() & !regs.virtual_hs_csrs.hvip & VS_INTERRUPTS;
+        CSR.hvip.write_value(self.l1_hvip | ours);
+        CSR.hgatp.write_value(self.host_hgatp);
+        unsafe { core::arch::riscv64::hfence_gvma_all() };
+        regs.guest_regs.hstatus = (regs.guest_regs.hstatus & !HSTATUS_L2_TRAPS) | HSTATUS_VTSR;
+        self.virt = false;
+    }
+
+    /// Installs on this hart the G-stage table of the level the vCPU runs at next: the shadow
+    /// table while the nested guest runs, our own otherwise.
+    pub fn load_gstage(&self) {
+        let hgatp = if self.virt {
+            self.shadow.token()
+        } else {
+            self.host_hgatp
+        };
+        CSR.hgatp.write_value(hgatp);
+        unsafe { core::arch::riscv64::hfence_gvma_all() };
+    }
+
+    /// Drops the shadow G-stage table, e.g. after our G-stage mappings of the guest hypervisor
+    /// lost permissions.
+    pub fn flush_shadow(&mut self) -> HyperResult<()> {
+        self.shadow = G::new()?;
+        if self.virt {
+            CSR.hgatp.write_value(self.shadow.token());
+        }
+        unsafe { core::arch::riscv64::hfence_gvma_all() };
+        Ok(())
+    }
+}
+
+/// Sets the privilege the vCPU runs at next, VS-mode if `supervisor` or VU-mode otherwise.
+fn set_spp(regs: &mut VmCpuRegisters, supervisor: bool) {
+    if supervisor {
+        regs.guest_regs.sstatus |= SSTATUS_SPP;
+    } else {
+        regs.guest_regs.sstatus &= !SSTATUS_SPP;
+    }
+}
+
+/// Exchanges the VS CSRs of the hart with `vs`.
+fn swap_vs_csrs(vs: &mut GuestVsCsrs) {
+    vs.htimedelta = CSR.htimedelta.atomic_replace(vs.htimedelta);
+    vs.vsstatus = CSR.vsstatus.atomic_replace(vs.vsstatus);
+    vs.vsie = CSR.vsie.atomic_replace(vs.vsie);
+    vs.vstvec = CSR.vstvec.atomic_replace(vs.vstvec);
+    vs.vsscratch = CSR.vsscratch.atomic_replace(vs.vsscratch);
+    vs.vsepc = CSR.vsepc.atomic_replace(vs.vsepc);
+    vs.vscause = CSR.vscause.atomic_replace(vs.vscause);
+    vs.vstval = CSR.vstval.atomic_replace(vs.vstval);
+    vs.vsatp = CSR.vsatp.atomic_replace(vs.vsatp);
+}
+
+/// Translates the nested guest's virtual address `gva` for `access` through its VS-stage table
+/// and the guest hypervisor's G-stage table, while the guest hypervisor runs.
+fn translate_l2(
+    regs: &VmCpuRegisters,
+    mem: &VmPages,
+    gva: GuestVirtAddr,
+    access: Access,
+) -> Result<GuestPhysAddr, Trap> {
+    let hgatp = regs.virtual_hs_csrs.hgatp;
+    // Faults of the implicit accesses to the VS-stage table report the original access.
+    let gstage = |gpa: GuestPhysAddr, perm: Access| {
+        walk_gstage(mem, hgatp, gpa, perm)
+            .map(|(l1_gpa, _)| l1_gpa)
+            .ok_or(Trap {
+                cause: access.guest_page_fault(),
+                tval: gva,
+                htval: gpa >> 2,
+                htinst: 0,
+                gva: true,
+            })
+    };
+    let page_fault = Trap {
+        gva: true,
+        ..Trap::new(access.page_fault(), gva)
+    };
+    let vsatp = regs.vs_csrs.vsatp;
+    match vsatp >> 60 {
+        0 => return gstage(gva, access),
+        SATP_MODE_SV39 => {}
+        _ => return Err(page_fault),
+    }
+    // Sv39 addresses are sign-extended from bit 38.
+    if ((gva as isize) << 25 >> 25) as usize != gva {
+        return Err(page_fault);
+    }
+    let root = (vsatp & ((1 << 44) - 1)) << 12;
+    let read_pte = |pte_gpa: GuestPhysAddr| -> Result<Option<usize>, Trap> {
+        let l1_gpa = gstage(pte_gpa, Access::Load)?;
+        Ok(mem.read_u64(l1_gpa).ok().map(|pte| pte as usize))
+    };
+    let (gpa, pte) = walk(root, gva, 9, read_pte)?.ok_or(page_fault)?;
+    let vsstatus = regs.vs_csrs.vsstatus;
+    let supervisor = regs.virtual_hs_csrs.hstatus & HSTATUS_SPVP != 0;
+    let user_ok = if pte & PTE_U != 0 {
+        !supervisor || (vsstatus & SSTATUS_SUM != 0 && access != Access::LoadExec)
+    } else {
+        supervisor
+    };
+    if !user_ok || !access.permitted(pte, vsstatus & SSTATUS_MXR != 0) {
+        return Err(page_fault);
+    }
+    gstage(gpa, access)
+}
+
+/// Translates `gpa` for `access` through the guest hypervisor's G-stage table `hgatp`,
+/// returning the guest hypervisor's physical address and the leaf PTE.
+fn walk_gstage(
+    mem: &VmPages,
+    hgatp: usize,
+    gpa: GuestPhysAddr,
+    access: Access,
+) -> Option<(GuestPhysAddr, usize)> {
+    match hgatp >> HGATP_MODE_SHIFT {
+        HGATP_MODE_BARE => return Some((gpa, PTE_R | PTE_W | PTE_X | PTE_U | PTE_A | PTE_D)),
+        HGATP_MODE_SV39X4 => {}
+        _ => return None,
+    }
+    // Sv39x4 takes 41-bit addresses, with two more bits of root index.
+    if gpa >> 41 != 0 {
+        return None;
+    }
+    let root = (hgatp & HGATP_PPN_MASK) << 12;
+    let read_pte = |pte_gpa| Ok::<_, ()>(mem.read_u64(pte_gpa).ok().map(|pte| pte as usize));
+    let (l1_gpa, pte) = walk(root, gpa, 11, read_pte).ok()??;
+    // All G-stage accesses are user accesses.
+    if pte & PTE_U == 0 || !access.permitted(pte, false) {
+        return None;
+    }
+    Some((l1_gpa, pte))
+}
+
+/// Walks a three-level Sv39 or Sv39x4 table rooted at `root` for `addr`, with `root_bits` bits of
+/// index at the root level. Returns the translated address and the leaf PTE, or `None` on a
+/// translation fault. `read_pte` reads the PTE at a physical address, returning `None` if it is
+/// not in memory.
+fn walk<E>(
+    root: usize,
+    addr: usize,
+    root_bits: usize,
+    read_pte: impl Fn(usize) -> Result<Option<usize>, E>,
+) -> Result<Option<(usize, usize)>, E> {
+    let mut table = root;
+    for level in (0..3).rev() {
+        let shift = 12 + 9 * level;
+        let bits = if level == 2 { root_bits } else { 9 };
+        let index = (addr >> shift) & ((1 << bits) - 1);
+        let pte = match read_pte(table + index * 8)? {
+            Some(pte) => pte,
+            None => return Ok(None),
+        };
+        if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+            return Ok(None);
+        }
+        let ppn = (pte >> 10) & ((1 << 44) - 1);
+        if pte & (PTE_R | PTE_X) != 0 {
+            // Superpages must be aligned.
+            if ppn & ((1 << (9 * level)) - 1) != 0 {
+                return Ok(None);
+            }
+            return Ok(Some(((ppn << 12) | (addr & ((1 << shift) - 1)), pte)));
+        }
+        table = ppn << 12;
+    }
+    Ok(None)
+}
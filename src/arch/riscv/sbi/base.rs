@@ -0,0 +1,80 @@
+//! The Base extension, answered by the hypervisor so that guests see the identity of the VM
+//! rather than that of the host's firmware.
+
+use sbi_spec::base::{
+    GET_MARCHID, GET_MIMPID, GET_MVENDORID, GET_SBI_IMPL_ID, GET_SBI_IMPL_VERSION,
+    GET_SBI_SPEC_VERSION, PROBE_EXTENSION,
+};
+use sbi_spec::binary::SbiRet;
+
+/// The SBI implementation ID reported by hypercraft by default. It is not registered with RISC-V
+/// International and lies in no reserved range, so it is experimental: a guest may mistake it for
+/// a future registered implementation. `SbiIdentity::impl_id` overrides it.
+pub const HYPERCRAFT_IMPL_ID: usize = 0x4843;
+/// The SBI specification version implemented, 2.0, reported by default.
+pub const SPEC_VERSION: usize = 2 << 24;
+/// The implementation version, from the crate's major and minor versions.
+const IMPL_VERSION: usize = {
+    let major = parse_version(env!("CARGO_PKG_VERSION_MAJOR"));
+    let minor = parse_version(env!("CARGO_PKG_VERSION_MINOR"));
+    major << 16 | minor
+};
+
+const fn parse_version(s: &str) -> usize {
+    let bytes = s.as_bytes();
+    let mut val = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        val = val * 10 + (bytes[i] - b'0') as usize;
+        i += 1;
+    }
+    val
+}
+
+/// The machine and SBI implementation identity reported to a guest through the Base extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SbiIdentity {
+    /// The value reported for `mvendorid`.
+    pub mvendorid: usize,
+    /// The value reported for `marchid`.
+    pub marchid: usize,
+    /// The value reported for `mimpid`.
+    pub mimpid: usize,
+    /// The SBI specification version reported, encoded as the major version in bits 30:24 and
+    /// the minor version in bits 23:0. Defaults to `SPEC_VERSION`.
+    pub spec_version: usize,
+    /// The SBI implementation ID reported. Defaults to `HYPERCRAFT_IMPL_ID`.
+    pub impl_id: usize,
+}
+
+impl Default for SbiIdentity {
+    fn default() -> Self {
+        Self {
+            mvendorid: 0,
+            marchid: 0,
+            mimpid: 0,
+            spec_version: SPEC_VERSION,
+            impl_id: HYPERCRAFT_IMPL_ID,
+        }
+    }
+}
+
+/// Handles a call to the Base extension. `probe` tells whether an extension is available to the
+/// guest.
+pub fn handle(
+    function: usize,
+    params: &[usize],
+    identity: &SbiIdentity,
+    probe: impl Fn(usize) -> bool,
+) -> SbiRet {
+    match function {
+        GET_SBI_SPEC_VERSION => SbiRet::success(identity.spec_version),
+        GET_SBI_IMPL_ID => SbiRet::success(identity.impl_id),
+        GET_SBI_IMPL_VERSION => SbiRet::success(IMPL_VERSION),
+        PROBE_EXTENSION => SbiRet::success(probe(params[0]) as usize),
+        GET_MVENDORID => SbiRet::success(identity.mvendorid),
+        GET_MARCHID => SbiRet::success(identity.marchid),
+        GET_MIMPID => SbiRet::success(identity.mimpid),
+        _ => SbiRet::not_supported(),
+    }
+}
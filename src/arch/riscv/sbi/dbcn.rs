@@ -0,0 +1,100 @@
+//! The Debug Console (DBCN) extension, implemented over the VM's console hooks of
+//! `HyperCraftHal`. Buffers are given as guest physical addresses and accessed through `VmPages`.
+
+use sbi_spec::binary::SbiRet;
+
+use crate::arch::vm_pages::VmPages;
+use crate::memory::PAGE_SIZE_4K;
+use crate::HyperCraftHal;
+
+/// The Debug Console extension ID.
+pub const EID_DBCN: usize = 0x4442_434e;
+
+const CONSOLE_WRITE: usize = 0;
+const CONSOLE_READ: usize = 1;
+const CONSOLE_WRITE_BYTE: usize = 2;
+
+/// Size of the chunks the guest buffers are copied in.
+const CHUNK_SIZE: usize = 256;
+
+/// Handles a call to the DBCN extension from the VM `vm_id`.
+pub fn handle<H: HyperCraftHal>(
+    vm_id: usize,
+    function: usize,
+    params: &[usize],
+    mem: &VmPages,
+) -> SbiRet {
+    match function {
+        CONSOLE_WRITE => match buffer_addr(params) {
+            Some(addr) => write::<H>(vm_id, params[0], addr, mem),
+            None => SbiRet::invalid_param(),
+        },
+        CONSOLE_READ => match buffer_addr(params) {
+            Some(addr) => read::<H>(vm_id, params[0], addr, mem),
+            None => SbiRet::invalid_param(),
+        },
+        CONSOLE_WRITE_BYTE => {
+            H::console_putchar(vm_id, params[0] as u8);
+            SbiRet::success(0)
+        }
+        _ => SbiRet::not_supported(),
+    }
+}
+
+/// Returns the guest physical address of the buffer, which must fit in XLEN bits.
+fn buffer_addr(params: &[usize]) -> Option<usize> {
+    match params[2] {
+        0 => Some(params[1]),
+        _ => None,
+    }
+}
+
+fn write<H: HyperCraftHal>(vm_id: usize, len: usize, addr: usize, mem: &VmPages) -> SbiRet {
+    let mut buf = [0u8; CHUNK_SIZE];
+    let mut written = 0;
+    while written < len {
+        let chunk = (len - written).min(CHUNK_SIZE);
+        let gpa = match addr.checked_add(written) {
+            Some(gpa) => gpa,
+            None => break,
+        };
+        if mem.copy_from_guest(&mut buf[..chunk], gpa).is_err() {
+            // Report the bytes already written, if any.
+            break;
+        }
+        buf[..chunk]
+            .iter()
+            .for_each(|&c| H::console_putchar(vm_id, c));
+        written += chunk;
+    }
+    if written == 0 && len != 0 {
+        return SbiRet::invalid_param();
+    }
+    SbiRet::success(written)
+}
+
+fn read<H: HyperCraftHal>(vm_id: usize, len: usize, addr: usize, mem: &VmPages) -> SbiRet {
+    // Stay within the page of `addr`, which is mapped and writable as a whole or not at all.
+    let room = PAGE_SIZE_4K - addr % PAGE_SIZE_4K;
+    let len = len.min(CHUNK_SIZE).min(room);
+    let mut buf = [0u8; CHUNK_SIZE];
+    // Check the buffer is writable before taking any input, which would be lost otherwise.
+    if mem.copy_from_guest(&mut buf[..len], addr).is_err()
+        || mem.copy_to_guest(addr, &buf[..len]).is_err()
+    {
+        return SbiRet::invalid_param();
+    }
+    let mut count = 0;
+    // Read only what is available without blocking.
+    while count < len {
+        match H::console_getchar(vm_id) {
+            Some(c) => buf[count] = c,
+            None => break,
+        }
+        count += 1;
+    }
+    if mem.copy_to_guest(addr, &buf[..count]).is_err() {
+        return SbiRet::invalid_param();
+    }
+    SbiRet::success(count)
+}
@@ -0,0 +1,155 @@
+//! The Hart State Management (HSM) extension, virtualized over the vCPUs of a VM.
+//!
+//! A vCPU that is stopped or suspended doesn't run: `VM::run` reports it idle until another vCPU
+//! starts it or, if suspended, until one of its enabled interrupts is pending.
+
+use sbi_spec::binary::SbiRet;
+use sbi_spec::hsm::{HART_GET_STATUS, HART_START, HART_STOP, HART_SUSPEND};
+
+use crate::arch::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::{GuestPhysAddr, HyperError, HyperResult};
+
+// Hart states reported by `HART_GET_STATUS`.
+const HART_STATE_STARTED: usize = 0;
+const HART_STATE_STOPPED: usize = 1;
+const HART_STATE_START_PENDING: usize = 2;
+const HART_STATE_SUSPENDED: usize = 4;
+
+// Suspend types of `HART_SUSPEND`.
+const SUSPEND_DEFAULT_RETENTIVE: u32 = 0;
+const SUSPEND_DEFAULT_NON_RETENTIVE: u32 = 0x8000_0000;
+const SUSPEND_PLATFORM_RETENTIVE: u32 = 0x1000_0000;
+const SUSPEND_PLATFORM_NON_RETENTIVE: u32 = 0x9000_0000;
+
+/// Where a vCPU starts executing when it is started or resumes from a non-retentive suspend,
+/// with `a0` set to its hart id and `a1` to `opaque`.
+#[derive(Clone, Copy, Debug)]
+pub struct ResumePoint {
+    /// The guest physical address executed first, with the MMU off.
+    pub addr: GuestPhysAddr,
+    /// The value passed by the caller.
+    pub opaque: usize,
+}
+
+/// The state of a vCPU as seen through the HSM extension.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum HartState {
+    /// The vCPU runs.
+    Started,
+    /// The vCPU doesn't run until another vCPU starts it.
+    #[default]
+    Stopped,
+    /// The vCPU was started by another vCPU and runs from the given point next.
+    StartPending(ResumePoint),
+    /// The vCPU waits for an interrupt. It then continues after its suspend call, or from the
+    /// given point if the suspend is non-retentive.
+    Suspended(Option<ResumePoint>),
+}
+
+impl HartState {
+    fn status(&self) -> usize {
+        match self {
+            HartState::Started => HART_STATE_STARTED,
+            HartState::Stopped => HART_STATE_STOPPED,
+            HartState::StartPending(_) => HART_STATE_START_PENDING,
+            HartState::Suspended(_) => HART_STATE_SUSPENDED,
+        }
+    }
+
+    /// Saves the state into a VM snapshot.
+    pub fn save(&self, out: &mut SnapshotWriter) {
+        out.write_usize(self.status());
+        let point = match self {
+            HartState::StartPending(point) => Some(point),
+            HartState::Suspended(point) => {
+                out.write_bool(point.is_some());
+                point.as_ref()
+            }
+            _ => None,
+        };
+        if let Some(point) = point {
+            out.write_usize(point.addr);
+            out.write_usize(point.opaque);
+        }
+    }
+
+    /// Restores a state saved by `save`.
+    pub fn restore(input: &mut SnapshotReader) -> HyperResult<Self> {
+        fn read_point(input: &mut SnapshotReader) -> HyperResult<ResumePoint> {
+            Ok(ResumePoint {
+                addr: input.read_usize()?,
+                opaque: input.read_usize()?,
+            })
+        }
+        let state = match input.read_usize()? {
+            HART_STATE_STARTED => HartState::Started,
+            HART_STATE_STOPPED => HartState::Stopped,
+            HART_STATE_START_PENDING => HartState::StartPending(read_point(input)?),
+            HART_STATE_SUSPENDED => match input.read_bool()? {
+                true => HartState::Suspended(Some(read_point(input)?)),
+                false => HartState::Suspended(None),
+            },
+            _ => return Err(HyperError::InvalidParam),
+        };
+        Ok(state)
+    }
+}
+
+/// Handles a call to the HSM extension made by vCPU `vcpu_id`. `harts` holds the state of all
+/// the VM's vCPUs and `exists` tells whether a hart id names one of them.
+pub fn handle(
+    vcpu_id: usize,
+    function: usize,
+    params: &[usize],
+    harts: &mut [HartState],
+    exists: impl Fn(usize) -> bool,
+) -> SbiRet {
+    match function {
+        HART_START => {
+            let hartid = params[0];
+            if !exists(hartid) {
+                return SbiRet::invalid_param();
+            }
+            match harts[hartid] {
+                HartState::Stopped => {
+                    harts[hartid] = HartState::StartPending(ResumePoint {
+                        addr: params[1],
+                        opaque: params[2],
+                    });
+                    SbiRet::success(0)
+                }
+                _ => SbiRet::already_available(),
+            }
+        }
+        HART_STOP => {
+            // The call doesn't return; the vCPU goes idle once this exit is handled.
+            harts[vcpu_id] = HartState::Stopped;
+            SbiRet::success(0)
+        }
+        HART_GET_STATUS => {
+            let hartid = params[0];
+            if !exists(hartid) {
+                return SbiRet::invalid_param();
+            }
+            SbiRet::success(harts[hartid].status())
+        }
+        HART_SUSPEND => {
+            let resume = match params[0] as u32 {
+                SUSPEND_DEFAULT_RETENTIVE => None,
+                SUSPEND_DEFAULT_NON_RETENTIVE => Some(ResumePoint {
+                    addr: params[1],
+                    opaque: params[2],
+                }),
+                // No platform specific suspend types are implemented.
+                SUSPEND_PLATFORM_RETENTIVE..=0x7fff_ffff | SUSPEND_PLATFORM_NON_RETENTIVE.. => {
+                    return SbiRet::not_supported()
+                }
+                _ => return SbiRet::invalid_param(),
+            };
+            // A retentive suspend returns success once the vCPU resumes.
+            harts[vcpu_id] = HartState::Suspended(resume);
+            SbiRet::success(0)
+        }
+        _ => SbiRet::not_supported(),
+    }
+}
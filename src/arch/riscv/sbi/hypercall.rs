@@ -0,0 +1,95 @@
+//! Hypercalls registered by the embedding kernel, in the vendor- and firmware-specific SBI
+//! extension space.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use sbi_spec::binary::SbiRet;
+
+use super::SbiMessage;
+use crate::{HyperError, HyperResult};
+
+/// The hypercraft vendor extension ID.
+pub const EID_HYPERCRAFT: usize = 0x0948_4352;
+
+/// Function of the hypercraft extension returning whether a function is registered. Reserved.
+const HC_PROBE_FUNCTION: usize = 0;
+
+// The SBI extension ID ranges available for hypercalls.
+const EID_VENDOR_FIRST: usize = 0x0900_0000;
+const EID_VENDOR_LAST: usize = 0x09ff_ffff;
+const EID_FIRMWARE_FIRST: usize = 0x0a00_0000;
+const EID_FIRMWARE_LAST: usize = 0x0aff_ffff;
+
+/// A hypercall handler, given the id of the calling vCPU and the call. The value returned is
+/// passed to the guest in `a1`; errors are translated to SBI error codes.
+pub type HyperCallHandler = Box<dyn FnMut(usize, &SbiMessage) -> HyperResult<usize>>;
+
+/// The hypercalls registered for a VM, by extension and function id.
+#[derive(Default)]
+pub struct HyperCallTable {
+    handlers: BTreeMap<(usize, usize), HyperCallHandler>,
+}
+
+impl HyperCallTable {
+    /// Registers `handler` for the function `function` of the extension `extension`.
+    pub fn register(
+        &mut self,
+        extension: usize,
+        function: usize,
+        handler: HyperCallHandler,
+    ) -> HyperResult<()> {
+        let in_range = matches!(
+            extension,
+            EID_VENDOR_FIRST..=EID_VENDOR_LAST | EID_FIRMWARE_FIRST..=EID_FIRMWARE_LAST
+        );
+        if !in_range || (extension, function) == (EID_HYPERCRAFT, HC_PROBE_FUNCTION) {
+            return Err(HyperError::InvalidParam);
+        }
+        if self.handlers.contains_key(&(extension, function)) {
+            return Err(HyperError::BadState);
+        }
+        self.handlers.insert((extension, function), handler);
+        Ok(())
+    }
+
+    /// Removes the handler of the function `function` of the extension `extension`.
+    pub fn unregister(&mut self, extension: usize, function: usize) -> HyperResult<()> {
+        self.handlers
+            .remove(&(extension, function))
+            .map(|_| ())
+            .ok_or(HyperError::NotFound)
+    }
+
+    /// Returns whether any hypercall of `extension` is available.
+    pub fn has_extension(&self, extension: usize) -> bool {
+        extension == EID_HYPERCRAFT
+            || self
+                .handlers
+                .range((extension, 0)..=(extension, usize::MAX))
+                .next()
+                .is_some()
+    }
+
+    /// Dispatches the hypercall `msg` made by the vCPU `vcpu_id`, or returns `None` if its
+    /// extension has no hypercalls.
+    pub fn handle(&mut self, vcpu_id: usize, msg: &SbiMessage) -> Option<SbiRet> {
+        if !self.has_extension(msg.extension) {
+            return None;
+        }
+        if (msg.extension, msg.function) == (EID_HYPERCRAFT, HC_PROBE_FUNCTION) {
+            let registered = self.handlers.contains_key(&(EID_HYPERCRAFT, msg.params[0]));
+            return Some(SbiRet::success(registered as usize));
+        }
+        let handler = match self.handlers.get_mut(&(msg.extension, msg.function)) {
+            Some(handler) => handler,
+            None => return Some(SbiRet::not_supported()),
+        };
+        Some(match handler(vcpu_id, msg) {
+            Ok(value) => SbiRet::success(value),
+            Err(HyperError::InvalidParam) => SbiRet::invalid_param(),
+            Err(HyperError::NotSupported) => SbiRet::not_supported(),
+            Err(HyperError::PageFault) | Err(HyperError::OutOfRange) => SbiRet::invalid_address(),
+            Err(_) => SbiRet::failed(),
+        })
+    }
+}
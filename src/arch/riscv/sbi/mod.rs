@@ -1,3 +1,15 @@
+pub mod base;
+pub mod dbcn;
+pub mod hsm;
+pub mod hypercall;
+pub mod pmu;
+pub mod srst;
+pub mod sta;
+pub mod susp;
+
+pub use base::SbiIdentity;
+pub use hypercall::{HyperCallHandler, EID_HYPERCRAFT};
+
 use crate::{HyperError, HyperResult};
 
 /// SBI Message used to invoke the specfified SBI extension in the firmware.
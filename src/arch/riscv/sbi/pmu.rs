@@ -0,0 +1,396 @@
+//! The Performance Monitoring Unit (PMU) extension, virtualized per vCPU.
+//!
+//! Hardware counters are programmed through the host's firmware, which alone can write the event
+//! selectors, and keep the host's counter indices so that the guest reads them directly through
+//! its `hpmcounter` CSRs. A vCPU's counters are saved and stopped when it is switched out and
+//! reprogrammed with their saved values when it is switched back in, so each vCPU counts only its
+//! own events. Firmware counters count the SBI events the hypervisor handles for the vCPU.
+
+use rustsbi::{Forward, Pmu};
+use sbi_spec::binary::SbiRet;
+use spin::Once;
+
+use super::super::csrs::{RiscvCsrTrait, CSR};
+use crate::arch::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::{HyperError, HyperResult};
+
+/// The PMU extension ID.
+pub const EID_PMU: usize = 0x504d55;
+
+const NUM_COUNTERS: usize = 0;
+const COUNTER_GET_INFO: usize = 1;
+const COUNTER_CONFIG_MATCHING: usize = 2;
+const COUNTER_START: usize = 3;
+const COUNTER_STOP: usize = 4;
+const COUNTER_FW_READ: usize = 5;
+
+// Configuration, start and stop flags.
+const CFG_FLAG_SKIP_MATCH: usize = 1 << 0;
+const CFG_FLAG_CLEAR_VALUE: usize = 1 << 1;
+const CFG_FLAG_AUTO_START: usize = 1 << 2;
+const START_SET_INIT_VALUE: usize = 1 << 0;
+const STOP_FLAG_RESET: usize = 1 << 0;
+
+// SBI error codes for the states of a counter.
+const ERR_ALREADY_STARTED: usize = -7isize as usize;
+const ERR_ALREADY_STOPPED: usize = -8isize as usize;
+
+/// Event type of firmware events, in bits 16-19 of the event index.
+const EVENT_TYPE_FW: usize = 0xf;
+/// The highest firmware event code defined by the SBI specification.
+const FW_EVENT_MAX: usize = 21;
+/// Number of firmware counters each vCPU has.
+const NUM_FW_COUNTERS: usize = 8;
+/// Counter info type bit marking a firmware counter.
+const INFO_TYPE_FW: usize = 1 << (usize::BITS - 1);
+
+/// Highest number of hardware counters: cycle, time, instret and hpmcounter3-31.
+const MAX_HW_COUNTERS: usize = 32;
+/// Counters always visible to guests: cycle, time and instret.
+pub const FIXED_COUNTEREN: usize = 0b111;
+
+/// Firmware events the hypervisor counts.
+#[derive(Clone, Copy, Debug)]
+#[repr(usize)]
+pub enum FwEvent {
+    /// A `set_timer` call.
+    SetTimer = 5,
+    /// A `remote_fence_i` call.
+    FenceISent = 8,
+    /// A `remote_sfence_vma` call.
+    SfenceVmaSent = 10,
+    /// A `remote_sfence_vma_asid` call.
+    SfenceVmaAsidSent = 12,
+}
+
+/// The host's hardware counters available to guests.
+struct HostCounters {
+    // Number of counters reported by the host, hardware and firmware.
+    num: usize,
+    // Bitmap of the hardware counters.
+    hw_mask: usize,
+}
+
+static HOST_COUNTERS: Once<HostCounters> = Once::new();
+
+fn host_counters() -> &'static HostCounters {
+    HOST_COUNTERS.call_once(|| {
+        let num = Forward.num_counters().min(MAX_HW_COUNTERS);
+        let hw_mask = (0..num)
+            .filter(|&i| {
+                let info = Forward.counter_get_info(i);
+                info.error == 0 && info.value & INFO_TYPE_FW == 0
+            })
+            .fold(0, |mask, i| mask | 1 << i);
+        HostCounters { num, hw_mask }
+    })
+}
+
+/// Reads the hardware counter `index` of this hart.
+fn read_hw_counter(index: usize) -> u64 {
+    macro_rules! read_counter {
+        ($($csr:literal),*) => {
+            match 0xc00 + index {
+                $($csr => {
+                    let val: usize;
+                    unsafe { core::arch::asm!(concat!("csrr {}, ", $csr), out(reg) val) };
+                    val as u64
+                })*
+                _ => 0,
+            }
+        };
+    }
+    read_counter!(
+        0xc00, 0xc01, 0xc02, 0xc03, 0xc04, 0xc05, 0xc06, 0xc07, 0xc08, 0xc09, 0xc0a, 0xc0b, 0xc0c,
+        0xc0d, 0xc0e, 0xc0f, 0xc10, 0xc11, 0xc12, 0xc13, 0xc14, 0xc15, 0xc16, 0xc17, 0xc18, 0xc19,
+        0xc1a, 0xc1b, 0xc1c, 0xc1d, 0xc1e, 0xc1f
+    )
+}
+
+#[derive(Clone, Copy, Default)]
+struct HwCounter {
+    event_idx: usize,
+    event_data: u64,
+    running: bool,
+    // The counter's value while the vCPU is switched out.
+    saved: u64,
+}
+
+#[derive(Clone, Copy, Default)]
+struct FwCounter {
+    event: Option<usize>,
+    running: bool,
+    value: u64,
+}
+
+/// The PMU state of a vCPU.
+#[derive(Default)]
+pub struct VcpuPmu {
+    hw: [Option<HwCounter>; MAX_HW_COUNTERS],
+    fw: [FwCounter; NUM_FW_COUNTERS],
+}
+
+impl VcpuPmu {
+    /// Handles a call to the PMU extension.
+    pub fn handle(&mut self, function: usize, params: &[usize]) -> SbiRet {
+        match function {
+            NUM_COUNTERS => SbiRet::success(host_counters().num + NUM_FW_COUNTERS),
+            COUNTER_GET_INFO => self.counter_info(params[0]),
+            COUNTER_CONFIG_MATCHING => {
+                self.config_matching(params[0], params[1], params[2], params[3], params[4] as u64)
+            }
+            COUNTER_START => self.start(params[0], params[1], params[2], params[3] as u64),
+            COUNTER_STOP => self.stop(params[0], params[1], params[2]),
+            COUNTER_FW_READ => match self.fw_index(params[0]) {
+                Some(i) => SbiRet::success(self.fw[i].value as usize),
+                None => SbiRet::invalid_param(),
+            },
+            _ => SbiRet::not_supported(),
+        }
+    }
+
+    /// Counts an occurrence of `event` in the firmware counters watching it.
+    pub fn record_fw_event(&mut self, event: FwEvent) {
+        for counter in self.fw.iter_mut() {
+            if counter.running && counter.event == Some(event as usize) {
+                counter.value += 1;
+            }
+        }
+    }
+
+    /// Saves and stops the vCPU's hardware counters as it is switched out of this hart, handing
+    /// them back to the host.
+    pub fn switch_out(&mut self) {
+        for (i, counter) in self.hw.iter_mut().enumerate() {
+            if let Some(counter) = counter {
+                counter.saved = read_hw_counter(i);
+                Forward.counter_stop(i, 1, STOP_FLAG_RESET);
+            }
+        }
+        CSR.hcounteren.write_value(FIXED_COUNTEREN);
+    }
+
+    /// Reprograms the vCPU's hardware counters with their saved values as it is switched in on
+    /// this hart.
+    pub fn switch_in(&mut self) {
+        let mut counteren = FIXED_COUNTEREN;
+        for (i, counter) in self.hw.iter().enumerate() {
+            if let Some(counter) = counter {
+                let flags = CFG_FLAG_SKIP_MATCH | CFG_FLAG_CLEAR_VALUE;
+                Forward.counter_config_matching(i, 1, flags, counter.event_idx, counter.event_data);
+                // The value can only be set by starting the counter.
+                Forward.counter_start(i, 1, START_SET_INIT_VALUE, counter.saved);
+                if !counter.running {
+                    Forward.counter_stop(i, 1, 0);
+                }
+                counteren |= 1 << i;
+            }
+        }
+        CSR.hcounteren.write_value(counteren);
+    }
+
+    /// Saves the counters into a VM snapshot. The vCPU must be switched out, so that the values
+    /// of its hardware counters are the saved ones.
+    pub fn save(&self, out: &mut SnapshotWriter) {
+        for counter in self.hw.iter() {
+            out.write_bool(counter.is_some());
+            if let Some(counter) = counter {
+                out.write_usize(counter.event_idx);
+                out.write_u64(counter.event_data);
+                out.write_bool(counter.running);
+                out.write_u64(counter.saved);
+            }
+        }
+        for counter in self.fw.iter() {
+            // Firmware event codes are small, so all ones stands for no event.
+            out.write_usize(counter.event.unwrap_or(usize::MAX));
+            out.write_bool(counter.running);
+            out.write_u64(counter.value);
+        }
+    }
+
+    /// Restores the counters saved by `save`, to be programmed when the vCPU is switched in.
+    /// Fails with `InvalidParam` if a hardware counter in use is not one of this host's.
+    pub fn restore(&mut self, input: &mut SnapshotReader) -> HyperResult<()> {
+        let mut pmu = Self::default();
+        for (i, counter) in pmu.hw.iter_mut().enumerate() {
+            if !input.read_bool()? {
+                continue;
+            }
+            if !Self::is_hw(i) {
+                return Err(HyperError::InvalidParam);
+            }
+            *counter = Some(HwCounter {
+                event_idx: input.read_usize()?,
+                event_data: input.read_u64()?,
+                running: input.read_bool()?,
+                saved: input.read_u64()?,
+            });
+        }
+        for counter in pmu.fw.iter_mut() {
+            counter.event = Some(input.read_usize()?).filter(|&event| event != usize::MAX);
+            counter.running = input.read_bool()?;
+            counter.value = input.read_u64()?;
+        }
+        *self = pmu;
+        Ok(())
+    }
+
+    /// Returns the firmware counter behind the guest's counter index `idx`.
+    fn fw_index(&self, idx: usize) -> Option<usize> {
+        idx.checked_sub(host_counters().num)
+            .filter(|&i| i < NUM_FW_COUNTERS)
+    }
+
+    fn is_hw(idx: usize) -> bool {
+        idx < MAX_HW_COUNTERS && host_counters().hw_mask & (1 << idx) != 0
+    }
+
+    fn counter_info(&self, idx: usize) -> SbiRet {
+        if self.fw_index(idx).is_some() {
+            return SbiRet::success(INFO_TYPE_FW);
+        }
+        if !Self::is_hw(idx) {
+            return SbiRet::invalid_param();
+        }
+        Forward.counter_get_info(idx)
+    }
+
+    /// Returns an iterator over the guest's counter indices selected by `base` and `mask`.
+    fn selected(base: usize, mask: usize) -> impl Iterator<Item = usize> {
+        (0..usize::BITS as usize)
+            .filter(move |bit| mask & (1 << bit) != 0)
+            .filter_map(move |bit| base.checked_add(bit))
+    }
+
+    fn config_matching(
+        &mut self,
+        base: usize,
+        mask: usize,
+        flags: usize,
+        event_idx: usize,
+        event_data: u64,
+    ) -> SbiRet {
+        let start = flags & CFG_FLAG_AUTO_START != 0;
+        let num_counters = host_counters().num + NUM_FW_COUNTERS;
+        if Self::selected(base, mask).any(|idx| idx >= num_counters) {
+            return SbiRet::invalid_param();
+        }
+        if (event_idx >> 16) & 0xf == EVENT_TYPE_FW {
+            let code = event_idx & 0xffff;
+            if code > FW_EVENT_MAX {
+                return SbiRet::invalid_param();
+            }
+            let found = Self::selected(base, mask).find_map(|idx| {
+                let i = self.fw_index(idx)?;
+                let free = self.fw[i].event.is_none() || flags & CFG_FLAG_SKIP_MATCH != 0;
+                free.then_some((idx, i))
+            });
+            return match found {
+                Some((idx, i)) => {
+                    self.fw[i] = FwCounter {
+                        event: Some(code),
+                        running: start,
+                        value: 0,
+                    };
+                    SbiRet::success(idx)
+                }
+                None => SbiRet::not_supported(),
+            };
+        }
+
+        // Only offer the host counters this vCPU isn't using yet.
+        let mut host_mask = 0;
+        for idx in Self::selected(base, mask) {
+            if Self::is_hw(idx) && (flags & CFG_FLAG_SKIP_MATCH != 0 || self.hw[idx].is_none()) {
+                host_mask |= 1 << idx;
+            }
+        }
+        if host_mask == 0 {
+            return SbiRet::not_supported();
+        }
+        let ret = Forward.counter_config_matching(0, host_mask, flags, event_idx, event_data);
+        if ret.error == 0 {
+            if let Some(counter) = self.hw.get_mut(ret.value) {
+                *counter = Some(HwCounter {
+                    event_idx,
+                    event_data,
+                    running: start,
+                    saved: 0,
+                });
+                CSR.hcounteren.read_and_set_bits(1 << ret.value);
+            }
+        }
+        ret
+    }
+
+    fn start(&mut self, base: usize, mask: usize, flags: usize, initial: u64) -> SbiRet {
+        for idx in Self::selected(base, mask) {
+            if let Some(i) = self.fw_index(idx) {
+                let counter = &mut self.fw[i];
+                if counter.event.is_none() {
+                    return SbiRet::invalid_param();
+                }
+                if counter.running {
+                    return SbiRet {
+                        error: ERR_ALREADY_STARTED,
+                        value: 0,
+                    };
+                }
+                if flags & START_SET_INIT_VALUE != 0 {
+                    counter.value = initial;
+                }
+                counter.running = true;
+                continue;
+            }
+            let counter = match self.hw.get_mut(idx).and_then(Option::as_mut) {
+                Some(counter) => counter,
+                None => return SbiRet::invalid_param(),
+            };
+            let ret = Forward.counter_start(idx, 1, flags, initial);
+            if ret.error != 0 {
+                return ret;
+            }
+            counter.running = true;
+        }
+        SbiRet::success(0)
+    }
+
+    fn stop(&mut self, base: usize, mask: usize, flags: usize) -> SbiRet {
+        for idx in Self::selected(base, mask) {
+            if let Some(i) = self.fw_index(idx) {
+                let counter = &mut self.fw[i];
+                if counter.event.is_none() {
+                    return SbiRet::invalid_param();
+                }
+                if !counter.running {
+                    return SbiRet {
+                        error: ERR_ALREADY_STOPPED,
+                        value: 0,
+                    };
+                }
+                counter.running = false;
+                if flags & STOP_FLAG_RESET != 0 {
+                    counter.event = None;
+                }
+                continue;
+            }
+            let slot = match self.hw.get_mut(idx) {
+                Some(slot) if slot.is_some() => slot,
+                _ => return SbiRet::invalid_param(),
+            };
+            let ret = Forward.counter_stop(idx, 1, flags);
+            if ret.error != 0 {
+                return ret;
+            }
+            if flags & STOP_FLAG_RESET != 0 {
+                // The counter is released back to the host.
+                *slot = None;
+                CSR.hcounteren.read_and_clear_bits(1 << idx);
+            } else if let Some(counter) = slot {
+                counter.running = false;
+            }
+        }
+        SbiRet::success(0)
+    }
+}
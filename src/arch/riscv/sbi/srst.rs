@@ -0,0 +1,28 @@
+//! The System Reset (SRST) extension, virtualized so that a guest resets or powers off only its
+//! own VM.
+
+use sbi_spec::binary::SbiRet;
+use sbi_spec::srst::{
+    RESET_REASON_NO_REASON, RESET_REASON_SYSTEM_FAILURE, RESET_TYPE_COLD_REBOOT,
+    RESET_TYPE_SHUTDOWN, RESET_TYPE_WARM_REBOOT, SYSTEM_RESET,
+};
+
+use crate::arch::VmExitReason;
+
+/// Decodes a call to the SRST extension into the VM exit it requests.
+pub fn handle(function: usize, params: &[usize]) -> Result<VmExitReason, SbiRet> {
+    if function != SYSTEM_RESET {
+        return Err(SbiRet::not_supported());
+    }
+    let (reset_type, reset_reason) = (params[0] as u32, params[1] as u32);
+    // Vendor and SBI-implementation specific reasons are accepted as well.
+    if reset_reason > RESET_REASON_SYSTEM_FAILURE && reset_reason < 0xe000_0000 {
+        return Err(SbiRet::invalid_param());
+    }
+    match reset_type {
+        RESET_TYPE_SHUTDOWN if reset_reason == RESET_REASON_NO_REASON => Ok(VmExitReason::Shutdown),
+        RESET_TYPE_SHUTDOWN => Ok(VmExitReason::Failure(reset_reason)),
+        RESET_TYPE_COLD_REBOOT | RESET_TYPE_WARM_REBOOT => Ok(VmExitReason::Reboot),
+        _ => Err(SbiRet::invalid_param()),
+    }
+}
@@ -0,0 +1,122 @@
+//! The Steal-Time Accounting (STA) extension.
+//!
+//! A vCPU steals time from the moment it is switched out of its hart until it is switched back
+//! in, unless it was switched out because it was idle. The accumulated steal time is published in the guest's shared memory area each time the
+//! vCPU is switched in.
+
+use sbi_spec::binary::SbiRet;
+
+use crate::arch::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::arch::vm_pages::VmPages;
+use crate::{GuestPhysAddr, HyperCraftHal, HyperResult};
+
+/// The STA extension ID.
+pub const EID_STA: usize = 0x535441;
+
+const STA_SET_SHMEM: usize = 0;
+
+/// Size and required alignment of the steal-time structure.
+const STA_SHMEM_SIZE: usize = 64;
+const OFFSET_SEQUENCE: usize = 0;
+const OFFSET_STEAL: usize = 8;
+const OFFSET_PREEMPTED: usize = 16;
+
+/// The steal-time accounting state of a vCPU.
+#[derive(Default)]
+pub struct VcpuSta {
+    shmem: Option<GuestPhysAddr>,
+    sequence: u32,
+    // Steal time in nanoseconds.
+    steal: u64,
+    // The `time` value when the vCPU was last switched out.
+    switched_out_at: Option<u64>,
+}
+
+impl VcpuSta {
+    /// Handles a call to the STA extension.
+    pub fn handle(&mut self, function: usize, params: &[usize], mem: &VmPages) -> SbiRet {
+        if function != STA_SET_SHMEM {
+            return SbiRet::not_supported();
+        }
+        let (lo, hi, flags) = (params[0], params[1], params[2]);
+        if flags != 0 {
+            return SbiRet::invalid_param();
+        }
+        // All ones in both halves disables the shared memory.
+        if lo == usize::MAX && hi == usize::MAX {
+            self.shmem = None;
+            return SbiRet::success(0);
+        }
+        if hi != 0 || lo % STA_SHMEM_SIZE != 0 {
+            return SbiRet::invalid_param();
+        }
+        // The whole structure is zeroed when registered, except for the current steal time.
+        self.sequence = 0;
+        if mem.copy_to_guest(lo, &[0u8; STA_SHMEM_SIZE]).is_err()
+            || mem.write_u64(lo + OFFSET_STEAL, self.steal).is_err()
+        {
+            return SbiRet::invalid_address();
+        }
+        self.shmem = Some(lo);
+        SbiRet::success(0)
+    }
+
+    /// Records that the vCPU is being switched out and marks it preempted for the guest, unless
+    /// it is `idle`, waiting for an interrupt: the guest gave that time up, so it is not stolen.
+    pub fn switch_out(&mut self, mem: &VmPages, idle: bool) {
+        if idle {
+            return;
+        }
+        self.switched_out_at = Some(riscv::register::time::read() as u64);
+        self.publish(mem, true);
+    }
+
+    /// Accounts the time the vCPU was switched out as stolen and publishes it to the guest.
+    pub fn switch_in<H: HyperCraftHal>(&mut self, mem: &VmPages) {
+        if let Some(out) = self.switched_out_at.take() {
+            let ticks = (riscv::register::time::read() as u64).saturating_sub(out);
+            let nanos = ticks as u128 * 1_000_000_000 / H::timebase_frequency() as u128;
+            self.steal = self.steal.wrapping_add(nanos as u64);
+        }
+        self.publish(mem, false);
+    }
+
+    /// Saves the shared memory area and the steal time into a VM snapshot.
+    pub fn save(&self, out: &mut SnapshotWriter) {
+        // The shared memory area is 64-byte aligned, so its address is never all ones.
+        out.write_usize(self.shmem.unwrap_or(usize::MAX));
+        out.write_u32(self.sequence);
+        out.write_u64(self.steal);
+    }
+
+    /// Restores the state saved by `save`. The time spent in the snapshot is not stolen.
+    pub fn restore(&mut self, input: &mut SnapshotReader) -> HyperResult<()> {
+        self.shmem = Some(input.read_usize()?).filter(|&shmem| shmem != usize::MAX);
+        self.sequence = input.read_u32()?;
+        self.steal = input.read_u64()?;
+        self.switched_out_at = None;
+        Ok(())
+    }
+
+    fn publish(&mut self, mem: &VmPages, preempted: bool) {
+        let shmem = match self.shmem {
+            Some(shmem) => shmem,
+            None => return,
+        };
+        // An odd sequence number tells the guest an update is in progress.
+        self.sequence = self.sequence.wrapping_add(1);
+        let result = mem
+            .write_u32(shmem + OFFSET_SEQUENCE, self.sequence)
+            .and_then(|_| mem.write_u64(shmem + OFFSET_STEAL, self.steal))
+            .and_then(|_| mem.copy_to_guest(shmem + OFFSET_PREEMPTED, &[preempted as u8]));
+        self.sequence = self.sequence.wrapping_add(1);
+        let result = result.and_then(|_| mem.write_u32(shmem + OFFSET_SEQUENCE, self.sequence));
+        if result.is_err() {
+            warn!(
+                "STA: shared memory at {:#x} is inaccessible, disabling",
+                shmem
+            );
+            self.shmem = None;
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! The System Suspend (SUSP) extension, suspending a whole VM to RAM.
+
+use sbi_spec::binary::SbiRet;
+
+use super::hsm::{HartState, ResumePoint};
+
+/// The SUSP extension ID.
+pub const EID_SUSP: usize = 0x5355_5350;
+
+const SYSTEM_SUSPEND: usize = 0;
+
+const SLEEP_TYPE_SUSPEND_TO_RAM: u32 = 0;
+const SLEEP_TYPE_PLATFORM: u32 = 0x8000_0000;
+
+/// Handles a call to the SUSP extension made by vCPU `vcpu_id`. On success the calling vCPU is
+/// suspended non-retentively, the others being stopped already, so the VM idles until an
+/// interrupt wakes the caller up.
+pub fn handle(
+    vcpu_id: usize,
+    function: usize,
+    params: &[usize],
+    harts: &mut [HartState],
+) -> SbiRet {
+    if function != SYSTEM_SUSPEND {
+        return SbiRet::not_supported();
+    }
+    match params[0] as u32 {
+        SLEEP_TYPE_SUSPEND_TO_RAM => {}
+        SLEEP_TYPE_PLATFORM.. => return SbiRet::not_supported(),
+        _ => return SbiRet::invalid_param(),
+    }
+    let others_stopped = harts
+        .iter()
+        .enumerate()
+        .all(|(id, state)| id == vcpu_id || matches!(state, HartState::Stopped));
+    if !others_stopped {
+        return SbiRet::denied();
+    }
+    harts[vcpu_id] = HartState::Suspended(Some(ResumePoint {
+        addr: params[1],
+        opaque: params[2],
+    }));
+    SbiRet::success(0)
+}
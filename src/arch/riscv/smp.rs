@@ -100,6 +100,11 @@ impl<H: HyperCraftHal> PerCpu<H> {
         pcpu
     }
 
+    /// Returns the id of this CPU.
+    pub fn cpu_id(&self) -> usize {
+        self.cpu_id
+    }
+
     /// Get stack top addr.
     pub fn stack_top_addr(&self) -> HostVirtAddr {
         self.stack_top_addr
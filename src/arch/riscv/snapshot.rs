@@ -0,0 +1,200 @@
+//! The binary format of VM snapshots.
+//!
+//! A snapshot is a header, made of a magic value and the format version, followed by tagged
+//! sections. Each section is its tag and the length of its payload, so that a reader can check it
+//! consumed exactly what the writer produced. Integers are little-endian and `usize` values are
+//! stored as 64 bits.
+
+use alloc::vec::Vec;
+
+use crate::{HyperError, HyperResult};
+
+/// "HCSN" in little-endian.
+pub const SNAPSHOT_MAGIC: u32 = 0x4e53_4348;
+/// The version of the snapshot format written, bumped on any layout change.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Serializes state into a snapshot.
+#[derive(Default)]
+pub struct SnapshotWriter {
+    buf: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    /// Creates a writer for a snapshot, starting with its header.
+    pub fn new() -> Self {
+        let mut writer = Self::default();
+        writer.write_u32(SNAPSHOT_MAGIC);
+        writer.write_u32(SNAPSHOT_VERSION);
+        writer
+    }
+
+    /// Appends a byte.
+    pub fn write_u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    /// Appends a boolean as a byte.
+    pub fn write_bool(&mut self, val: bool) {
+        self.write_u8(val as u8);
+    }
+
+    /// Appends a `u16`.
+    pub fn write_u16(&mut self, val: u16) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    /// Appends a `u32`.
+    pub fn write_u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    /// Appends a `u64`.
+    pub fn write_u64(&mut self, val: u64) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    /// Appends a `usize`.
+    pub fn write_usize(&mut self, val: usize) {
+        self.write_u64(val as u64);
+    }
+
+    /// Appends the `u32` values of `vals`, whose number the reader knows.
+    pub fn write_u32s(&mut self, vals: &[u32]) {
+        vals.iter().for_each(|&val| self.write_u32(val));
+    }
+
+    /// Appends the byte string `data`, prefixed by its length.
+    pub fn write_bytes(&mut self, data: &[u8]) {
+        self.write_usize(data.len());
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Appends a byte string of `len` zeroes, prefixed by its length, and returns it to be filled
+    /// in place.
+    pub fn alloc_bytes(&mut self, len: usize) -> &mut [u8] {
+        self.write_usize(len);
+        let start = self.buf.len();
+        self.buf.resize(start + len, 0);
+        &mut self.buf[start..]
+    }
+
+    /// Appends a section tagged `tag` whose payload is written by `f`, returning what `f` does.
+    pub fn section<T>(&mut self, tag: u32, f: impl FnOnce(&mut Self) -> T) -> T {
+        self.write_u32(tag);
+        let len_at = self.buf.len();
+        self.write_u64(0);
+        let val = f(self);
+        let len = (self.buf.len() - len_at - 8) as u64;
+        self.buf[len_at..len_at + 8].copy_from_slice(&len.to_le_bytes());
+        val
+    }
+
+    /// Returns the snapshot written.
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Deserializes state from a snapshot. Reading past the end of the data or of the current section
+/// fails with `InvalidParam`.
+pub struct SnapshotReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> SnapshotReader<'a> {
+    /// Creates a reader of the snapshot `data`, checking its header. Fails with `NotSupported`
+    /// for versions of the format other than `SNAPSHOT_VERSION`.
+    pub fn new(data: &'a [u8]) -> HyperResult<Self> {
+        let mut reader = Self { data };
+        if reader.read_u32()? != SNAPSHOT_MAGIC {
+            return Err(HyperError::InvalidParam);
+        }
+        if reader.read_u32()? != SNAPSHOT_VERSION {
+            return Err(HyperError::NotSupported);
+        }
+        Ok(reader)
+    }
+
+    /// Returns whether all the data has been read.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Reads a byte.
+    pub fn read_u8(&mut self) -> HyperResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Reads a boolean.
+    pub fn read_bool(&mut self) -> HyperResult<bool> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(HyperError::InvalidParam),
+        }
+    }
+
+    /// Reads a `u16`.
+    pub fn read_u16(&mut self) -> HyperResult<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    /// Reads a `u32`.
+    pub fn read_u32(&mut self) -> HyperResult<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// Reads a `u64`.
+    pub fn read_u64(&mut self) -> HyperResult<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Reads a `usize`.
+    pub fn read_usize(&mut self) -> HyperResult<usize> {
+        usize::try_from(self.read_u64()?).map_err(|_| HyperError::InvalidParam)
+    }
+
+    /// Reads `vals.len()` values written by `write_u32s`.
+    pub fn read_u32s(&mut self, vals: &mut [u32]) -> HyperResult<()> {
+        for val in vals.iter_mut() {
+            *val = self.read_u32()?;
+        }
+        Ok(())
+    }
+
+    /// Reads a byte string written by `write_bytes` or `alloc_bytes`.
+    pub fn read_bytes(&mut self) -> HyperResult<&'a [u8]> {
+        let len = self.read_usize()?;
+        self.take(len)
+    }
+
+    /// Reads the section tagged `tag` with `f`, which must consume all of its payload.
+    pub fn section<T>(
+        &mut self,
+        tag: u32,
+        f: impl FnOnce(&mut SnapshotReader<'a>) -> HyperResult<T>,
+    ) -> HyperResult<T> {
+        if self.read_u32()? != tag {
+            return Err(HyperError::InvalidParam);
+        }
+        let len = self.read_usize()?;
+        let mut section = SnapshotReader {
+            data: self.take(len)?,
+        };
+        let val = f(&mut section)?;
+        if !section.is_empty() {
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(val)
+    }
+
+    fn take(&mut self, len: usize) -> HyperResult<&'a [u8]> {
+        if len > self.data.len() {
+            return Err(HyperError::InvalidParam);
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+}
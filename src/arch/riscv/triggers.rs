@@ -0,0 +1,278 @@
+//! Sdtrig debug triggers on guest addresses, which stop the guest without modifying its memory.
+//!
+//! The trigger CSRs (`tselect`, `tdata1`-`tdata3`) are only writable from M-mode, so triggers are
+//! programmed through the firmware's SBI Debug Triggers extension, which takes the `tdata` values
+//! as they are. They match only in VS and VU modes, with the VS and VU bits of `mcontrol6` and
+//! `icount`, and raise breakpoint exceptions, which the hypervisor takes while a debugger is
+//! attached. Triggers belong to the hart that installs them and are enabled only while the VM's
+//! vCPUs run on it. The hypervisor must run identity-mapped, as the extension's shared memory is
+//! given by physical address.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use sbi_spec::binary::SbiRet;
+
+use crate::vcpus::VM_CPUS_MAX;
+use crate::{GuestVirtAddr, HyperError, HyperResult};
+
+/// The Debug Triggers extension ID.
+const EID_DBTR: usize = 0x4442_5452;
+
+const NUM_TRIGGERS: usize = 0;
+const SET_SHMEM: usize = 1;
+const INSTALL_TRIGGERS: usize = 3;
+const UNINSTALL_TRIGGERS: usize = 5;
+const ENABLE_TRIGGERS: usize = 6;
+const DISABLE_TRIGGERS: usize = 7;
+
+const ERR_NOT_SUPPORTED: usize = -2isize as usize;
+
+// tdata1 fields common to all trigger types.
+const TDATA1_TYPE_SHIFT: u32 = usize::BITS - 4;
+const TYPE_ICOUNT: usize = 3;
+const TYPE_MCONTROL6: usize = 6;
+
+// mcontrol6 fields.
+const MCONTROL6_VS: usize = 1 << 24;
+const MCONTROL6_VU: usize = 1 << 23;
+const MCONTROL6_MATCH_NAPOT: usize = 1 << 7;
+const MCONTROL6_EXECUTE: usize = 1 << 2;
+const MCONTROL6_STORE: usize = 1 << 1;
+const MCONTROL6_LOAD: usize = 1 << 0;
+
+// icount fields.
+const ICOUNT_VS: usize = 1 << 26;
+const ICOUNT_VU: usize = 1 << 25;
+const ICOUNT_COUNT_SHIFT: u32 = 10;
+
+/// The accesses a trigger matches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TriggerKind {
+    /// Instruction fetches, as a hardware breakpoint.
+    Execute,
+    /// Loads, as a read watchpoint.
+    Load,
+    /// Stores, as a write watchpoint.
+    Store,
+    /// Loads and stores, as an access watchpoint.
+    Access,
+}
+
+/// A trigger on a range of guest virtual addresses.
+struct Trigger {
+    kind: TriggerKind,
+    addr: GuestVirtAddr,
+    len: usize,
+    // The index the firmware gave the trigger.
+    index: usize,
+}
+
+/// The debug triggers of a VM.
+#[derive(Default)]
+pub struct Triggers {
+    triggers: Vec<Trigger>,
+    // The vCPU single-stepped with an instruction count trigger and the index of the trigger.
+    step: Option<(usize, usize)>,
+    // The watchpoint each vCPU stopped at last, and the address accessed.
+    watch_hits: [Option<(TriggerKind, GuestVirtAddr)>; VM_CPUS_MAX],
+    // The memory shared with the firmware to pass trigger configurations, once set up: an entry
+    // of `tstate`, `tdata1`, `tdata2` and `tdata3`.
+    shmem: Option<Box<[usize; 4]>>,
+}
+
+impl Triggers {
+    /// Returns whether no trigger is installed.
+    pub fn is_empty(&self) -> bool {
+        self.triggers.is_empty() && self.step.is_none()
+    }
+
+    /// Installs a trigger of `kind` on the `len` bytes from `addr`, which must be a naturally
+    /// aligned power of two for watchpoints of more than one byte.
+    pub fn insert(
+        &mut self,
+        kind: TriggerKind,
+        addr: GuestVirtAddr,
+        len: usize,
+    ) -> HyperResult<()> {
+        let access = match kind {
+            TriggerKind::Execute => MCONTROL6_EXECUTE,
+            TriggerKind::Load => MCONTROL6_LOAD,
+            TriggerKind::Store => MCONTROL6_STORE,
+            TriggerKind::Access => MCONTROL6_LOAD | MCONTROL6_STORE,
+        };
+        let mut tdata1 = TYPE_MCONTROL6 << TDATA1_TYPE_SHIFT | MCONTROL6_VS | MCONTROL6_VU | access;
+        let mut tdata2 = addr;
+        if kind != TriggerKind::Execute && len > 1 {
+            if !len.is_power_of_two() || addr % len != 0 {
+                return Err(HyperError::InvalidParam);
+            }
+            // The trailing ones of the address select the size of the range.
+            tdata1 |= MCONTROL6_MATCH_NAPOT;
+            tdata2 |= len / 2 - 1;
+        }
+        let index = self.install(tdata1, tdata2)?;
+        self.triggers.push(Trigger {
+            kind,
+            addr,
+            len,
+            index,
+        });
+        Ok(())
+    }
+
+    /// Uninstalls the trigger of `kind` on the `len` bytes from `addr`.
+    pub fn remove(
+        &mut self,
+        kind: TriggerKind,
+        addr: GuestVirtAddr,
+        len: usize,
+    ) -> HyperResult<()> {
+        let pos = self
+            .triggers
+            .iter()
+            .position(|t| t.kind == kind && t.addr == addr && t.len == len)
+            .ok_or(HyperError::NotFound)?;
+        let trigger = self.triggers.remove(pos);
+        check(dbtr_call(UNINSTALL_TRIGGERS, trigger.index, 1, 0))?;
+        Ok(())
+    }
+
+    /// Makes `vcpu_id` stop after running its next instruction, counting it with an instruction
+    /// count trigger.
+    pub fn step(&mut self, vcpu_id: usize) -> HyperResult<()> {
+        self.cancel_step()?;
+        let tdata1 =
+            TYPE_ICOUNT << TDATA1_TYPE_SHIFT | ICOUNT_VS | ICOUNT_VU | 1 << ICOUNT_COUNT_SHIFT;
+        let index = self.install(tdata1, 0)?;
+        self.step = Some((vcpu_id, index));
+        Ok(())
+    }
+
+    /// Handles `vcpu_id` taking a breakpoint exception at `pc` with trap value `tval`. Returns
+    /// whether one of the triggers fired, ending the single-step of the vCPU if it did.
+    pub fn hit(&mut self, vcpu_id: usize, pc: GuestVirtAddr, tval: usize) -> HyperResult<bool> {
+        self.watch_hits[vcpu_id] = None;
+        if self.step.is_some_and(|(stepper, _)| stepper == vcpu_id) {
+            self.cancel_step()?;
+            return Ok(true);
+        }
+        for t in self.triggers.iter() {
+            match t.kind {
+                TriggerKind::Execute if t.addr == pc => return Ok(true),
+                TriggerKind::Execute => {}
+                _ if (t.addr..t.addr + t.len).contains(&tval) => {
+                    self.watch_hits[vcpu_id] = Some((t.kind, tval));
+                    return Ok(true);
+                }
+                _ => {}
+            }
+        }
+        Ok(false)
+    }
+
+    /// Returns the kind of the watchpoint `vcpu_id` last stopped at and the address it accessed,
+    /// if it stopped at one.
+    pub fn watch_hit(&self, vcpu_id: usize) -> Option<(TriggerKind, GuestVirtAddr)> {
+        self.watch_hits[vcpu_id]
+    }
+
+    /// Enables the triggers for `vcpu_id`, which is about to run on this hart.
+    pub fn enable(&self, vcpu_id: usize) {
+        self.toggle(vcpu_id, ENABLE_TRIGGERS);
+    }
+
+    /// Disables the triggers enabled by `enable` once `vcpu_id` exits.
+    pub fn disable(&self, vcpu_id: usize) {
+        self.toggle(vcpu_id, DISABLE_TRIGGERS);
+    }
+
+    /// Uninstalls all the triggers.
+    pub fn clear(&mut self) -> HyperResult<()> {
+        self.cancel_step()?;
+        for trigger in core::mem::take(&mut self.triggers) {
+            check(dbtr_call(UNINSTALL_TRIGGERS, trigger.index, 1, 0))?;
+        }
+        Ok(())
+    }
+
+    /// Uninstalls the instruction count trigger, if any.
+    fn cancel_step(&mut self) -> HyperResult<()> {
+        if let Some((_, index)) = self.step.take() {
+            check(dbtr_call(UNINSTALL_TRIGGERS, index, 1, 0))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the indices of the triggers applying to `vcpu_id`.
+    fn indices(&self, vcpu_id: usize) -> impl Iterator<Item = usize> + '_ {
+        let step = self.step.filter(|&(stepper, _)| stepper == vcpu_id);
+        self.triggers
+            .iter()
+            .map(|t| t.index)
+            .chain(step.map(|(_, index)| index))
+    }
+
+    /// Enables or disables, as `function`, the triggers applying to `vcpu_id`. The extension
+    /// takes a base index and a mask of the triggers from it, so that usually a single call
+    /// covers them all.
+    fn toggle(&self, vcpu_id: usize, function: usize) {
+        let bits = usize::BITS as usize;
+        let mut next = 0;
+        while let Some(base) = self.indices(vcpu_id).filter(|&index| index >= next).min() {
+            let mask = self
+                .indices(vcpu_id)
+                .filter(|&index| index >= base && index - base < bits)
+                .fold(0, |mask, index| mask | 1 << (index - base));
+            dbtr_call(function, base, mask, 0);
+            next = base + bits;
+        }
+    }
+
+    /// Installs a trigger configured with `tdata1` and `tdata2`, disabled until `enable`, and
+    /// returns its index.
+    fn install(&mut self, tdata1: usize, tdata2: usize) -> HyperResult<usize> {
+        if check(dbtr_call(NUM_TRIGGERS, tdata1, 0, 0))? == 0 {
+            return Err(HyperError::NotSupported);
+        }
+        let shmem = match self.shmem.as_mut() {
+            Some(shmem) => shmem,
+            None => {
+                let shmem = Box::new([0; 4]);
+                // The address is physical as the hypervisor is identity-mapped.
+                check(dbtr_call(SET_SHMEM, shmem.as_ptr() as usize, 0, 0))?;
+                self.shmem.insert(shmem)
+            }
+        };
+        shmem.copy_from_slice(&[0, tdata1, tdata2, 0]);
+        check(dbtr_call(INSTALL_TRIGGERS, 1, 0, 0))?;
+        // The firmware writes the index of the trigger in the first word of the entry.
+        let index = shmem[0];
+        check(dbtr_call(DISABLE_TRIGGERS, index, 1, 0))?;
+        Ok(index)
+    }
+}
+
+/// Calls `function` of the Debug Triggers extension in the firmware.
+fn dbtr_call(function: usize, arg0: usize, arg1: usize, arg2: usize) -> SbiRet {
+    let (error, value);
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+            in("a6") function,
+            in("a7") EID_DBTR,
+        );
+    }
+    SbiRet { error, value }
+}
+
+/// Returns the value of `ret`, or the error it reports.
+fn check(ret: SbiRet) -> HyperResult<usize> {
+    match ret.error {
+        0 => Ok(ret.value),
+        ERR_NOT_SUPPORTED => Err(HyperError::NotSupported),
+        _ => Err(HyperError::InvalidParam),
+    }
+}
@@ -8,14 +8,18 @@ use tock_registers::LocalRegisterCopy;
 // use alloc::sync::Arc;
 use riscv::register::{htinst, htval, hvip, scause, sstatus, stval};
 
+use crate::arch::snapshot::{SnapshotReader, SnapshotWriter};
 use crate::arch::vmexit::PrivilegeLevel;
 use crate::arch::{traps, RiscvCsrTrait, CSR};
 use crate::{
     arch::sbi::SbiMessage, GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr, HostPhysAddr,
-    HyperCraftHal, VmExitInfo,
+    HyperCraftHal, HyperError, HyperResult, VmExitInfo,
 };
 
-use super::csrs::defs::hstatus;
+use super::csrs::defs::{
+    hstatus, CSR_SATP, CSR_SCAUSE, CSR_SCOUNTEREN, CSR_SEPC, CSR_SIE, CSR_SSCRATCH, CSR_SSTATUS,
+    CSR_STVAL, CSR_STVEC,
+};
 use super::regs::{GeneralPurposeRegisters, GprIndex};
 // use super::Guest;
 
@@ -34,29 +38,99 @@ struct HypervisorCpuState {
 /// Guest GPR and CSR state which must be saved/restored when exiting/entering virtualization.
 #[derive(Default)]
 #[repr(C)]
-struct GuestCpuState {
+pub struct GuestCpuState {
     gprs: GeneralPurposeRegisters,
-    sstatus: usize,
-    hstatus: usize,
+    pub(crate) sstatus: usize,
+    pub(crate) hstatus: usize,
     scounteren: usize,
-    sepc: usize,
+    pub(crate) sepc: usize,
 }
 
+/// The VS-level interrupts pending in `hvip`.
+pub(crate) const VS_INTERRUPTS: usize = traps::interrupt::VIRTUAL_SUPERVISOR_SOFT
+    | traps::interrupt::VIRTUAL_SUPERVISOR_TIMER
+    | traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL;
+
 /// The CSRs that are only in effect when virtualization is enabled (V=1) and must be saved and
 /// restored whenever we switch between VMs.
-#[derive(Default)]
+#[derive(Default, Clone)]
 #[repr(C)]
 pub struct GuestVsCsrs {
-    htimedelta: usize,
-    vsstatus: usize,
-    vsie: usize,
-    vstvec: usize,
-    vsscratch: usize,
-    vsepc: usize,
-    vscause: usize,
-    vstval: usize,
-    vsatp: usize,
-    vstimecmp: usize,
+    pub(crate) htimedelta: usize,
+    pub(crate) vsstatus: usize,
+    pub(crate) vsie: usize,
+    pub(crate) vstvec: usize,
+    pub(crate) vsscratch: usize,
+    pub(crate) vsepc: usize,
+    pub(crate) vscause: usize,
+    pub(crate) vstval: usize,
+    pub(crate) vsatp: usize,
+    pub(crate) vstimecmp: usize,
+}
+
+impl GuestVsCsrs {
+    /// Returns the VS-level CSRs in effect on this hart.
+    pub(crate) fn from_hart() -> Self {
+        Self {
+            htimedelta: CSR.htimedelta.get_value(),
+            vsstatus: CSR.vsstatus.get_value(),
+            vsie: CSR.vsie.get_value(),
+            vstvec: CSR.vstvec.get_value(),
+            vsscratch: CSR.vsscratch.get_value(),
+            vsepc: CSR.vsepc.get_value(),
+            vscause: CSR.vscause.get_value(),
+            vstval: CSR.vstval.get_value(),
+            vsatp: CSR.vsatp.get_value(),
+            vstimecmp: 0,
+        }
+    }
+
+    /// Puts these VS-level CSRs in effect on this hart.
+    pub(crate) fn load_into_hart(&self) {
+        CSR.htimedelta.write_value(self.htimedelta);
+        CSR.vsstatus.write_value(self.vsstatus);
+        CSR.vsie.write_value(self.vsie);
+        CSR.vstvec.write_value(self.vstvec);
+        CSR.vsscratch.write_value(self.vsscratch);
+        CSR.vsepc.write_value(self.vsepc);
+        CSR.vscause.write_value(self.vscause);
+        CSR.vstval.write_value(self.vstval);
+        CSR.vsatp.write_value(self.vsatp);
+    }
+
+    /// Saves the CSRs into a VM snapshot.
+    pub(crate) fn save(&self, out: &mut SnapshotWriter) {
+        for csr in [
+            self.htimedelta,
+            self.vsstatus,
+            self.vsie,
+            self.vstvec,
+            self.vsscratch,
+            self.vsepc,
+            self.vscause,
+            self.vstval,
+            self.vsatp,
+            self.vstimecmp,
+        ] {
+            out.write_usize(csr);
+        }
+    }
+
+    /// Restores CSRs saved by `save`.
+    pub(crate) fn restore(input: &mut SnapshotReader) -> HyperResult<Self> {
+        Ok(Self {
+            htimedelta: input.read_usize()?,
+            vsstatus: input.read_usize()?,
+            vsie: input.read_usize()?,
+            vstvec: input.read_usize()?,
+            vsscratch: input.read_usize()?,
+            vsepc: input.read_usize()?,
+            vscause: input.read_usize()?,
+            vstval: input.read_usize()?,
+            vsatp: input.read_usize()?,
+            vstimecmp: input.read_usize()?,
+        })
+    }
 }
 
 /// Virtualized HS-level CSRs that are used to emulate (part of) the hypervisor extension for the
@@ -64,9 +138,16 @@ pub struct GuestVsCsrs {
 #[derive(Default)]
 #[repr(C)]
 pub struct GuestVirtualHsCsrs {
-    hie: usize,
-    hgeie: usize,
-    hgatp: usize,
+    pub(crate) hstatus: usize,
+    pub(crate) hedeleg: usize,
+    pub(crate) hideleg: usize,
+    pub(crate) hie: usize,
+    pub(crate) hvip: usize,
+    pub(crate) hgeie: usize,
+    pub(crate) hgatp: usize,
+    pub(crate) hcounteren: usize,
+    pub(crate) htval: usize,
+    pub(crate) htinst: usize,
 }
 
 /// CSRs written on an exit from virtualization that are used by the hypervisor to determine the cause
@@ -88,17 +169,18 @@ pub struct VmCpuRegisters {
     // CPU state that's shared between our's and the guest's execution environment. Saved/restored
     // when entering/exiting a VM.
     hyp_regs: HypervisorCpuState,
-    guest_regs: GuestCpuState,
+    pub(crate) guest_regs: GuestCpuState,
 
     // CPU state that only applies when V=1, e.g. the VS-level CSRs. Saved/restored on activation of
-    // the vCPU.
-    vs_csrs: GuestVsCsrs,
+    // the vCPU. With nested virtualization, holds the VS-level CSRs of the guest hypervisor's guest
+    // while the guest hypervisor runs, and the other way around.
+    pub(crate) vs_csrs: GuestVsCsrs,
 
     // Virtualized HS-level CPU state.
-    virtual_hs_csrs: GuestVirtualHsCsrs,
+    pub(crate) virtual_hs_csrs: GuestVirtualHsCsrs,
 
     // Read on VM exit.
-    trap_csrs: VmCpuTrapState,
+    pub(crate) trap_csrs: VmCpuTrapState,
 }
 
 #[allow(dead_code)]
@@ -215,7 +297,17 @@ pub enum VmCpuStatus {
 /// A virtual CPU within a guest
 pub struct VCpu<H: HyperCraftHal> {
     vcpu_id: usize,
+    entry: GuestPhysAddr,
+    // The G-stage page table of the VM.
+    hgatp: usize,
     regs: VmCpuRegisters,
+    // Whether the vCPU is switched in on a hart, which then holds its VS-level CSRs and pending
+    // VS-level interrupts.
+    resident: bool,
+    // The VS-level CSRs and the VS-level interrupts pending in `hvip` while the vCPU is switched
+    // out.
+    saved_vs_csrs: GuestVsCsrs,
+    saved_hvip: usize,
     // gpt: G,
     // pub guest: Arc<Guest>,
     marker: PhantomData<H>,
@@ -224,6 +316,99 @@ pub struct VCpu<H: HyperCraftHal> {
 impl<H: HyperCraftHal> VCpu<H> {
     /// Create a new vCPU
     pub fn new(vcpu_id: usize, entry: GuestPhysAddr) -> Self {
+        Self {
+            vcpu_id,
+            entry,
+            hgatp: 0,
+            regs: Self::initial_regs(entry),
+            resident: false,
+            saved_vs_csrs: GuestVsCsrs::default(),
+            saved_hvip: 0,
+            // gpt,
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns the vCPU to the state it was created in, about to run from its entry point.
+    /// The G-stage page table and the IMSIC guest interrupt file stay in place.
+    pub fn reset(&mut self) {
+        let vgein =
+            LocalRegisterCopy::<usize, hstatus::Register>::new(self.regs.guest_regs.hstatus)
+                .read(hstatus::vgein);
+        self.regs = Self::initial_regs(self.entry);
+        self.set_vgein(vgein);
+        self.update_vs_csrs(|vs| *vs = GuestVsCsrs::default());
+        self.set_pending_irqs(VS_INTERRUPTS, false);
+    }
+
+    /// Puts the VS-level CSRs and pending interrupts of the vCPU in effect on this hart, as it is
+    /// about to run on it.
+    pub(crate) fn switch_in(&mut self) {
+        self.saved_vs_csrs.load_into_hart();
+        CSR.hvip
+            .read_and_clear_bits(VS_INTERRUPTS & !self.saved_hvip);
+        CSR.hvip.read_and_set_bits(self.saved_hvip);
+        self.resident = true;
+    }
+
+    /// Saves the VS-level CSRs and pending interrupts of the vCPU as it stops running on this
+    /// hart, clearing its interrupts from the hart.
+    pub(crate) fn switch_out(&mut self) {
+        let vstimecmp = self.saved_vs_csrs.vstimecmp;
+        self.saved_vs_csrs = GuestVsCsrs {
+            vstimecmp,
+            ..GuestVsCsrs::from_hart()
+        };
+        self.saved_hvip = CSR.hvip.read_and_clear_bits(VS_INTERRUPTS) & VS_INTERRUPTS;
+        self.resident = false;
+    }
+
+    /// Returns the VS-level CSRs of the vCPU, from the hart while it is switched in.
+    pub(crate) fn vs_csrs(&self) -> GuestVsCsrs {
+        if self.resident {
+            GuestVsCsrs::from_hart()
+        } else {
+            self.saved_vs_csrs.clone()
+        }
+    }
+
+    /// Modifies the VS-level CSRs of the vCPU with `f`, on the hart while it is switched in.
+    pub(crate) fn update_vs_csrs<T>(&mut self, f: impl FnOnce(&mut GuestVsCsrs) -> T) -> T {
+        if self.resident {
+            let mut vs = GuestVsCsrs::from_hart();
+            let ret = f(&mut vs);
+            vs.load_into_hart();
+            ret
+        } else {
+            f(&mut self.saved_vs_csrs)
+        }
+    }
+
+    /// Returns the VS-level interrupts pending in `hvip` for the vCPU.
+    pub(crate) fn pending_irqs(&self) -> usize {
+        if self.resident {
+            CSR.hvip.get_value() & VS_INTERRUPTS
+        } else {
+            self.saved_hvip
+        }
+    }
+
+    /// Sets or clears the VS-level interrupts `irqs` in `hvip` for the vCPU, on the hart while
+    /// it is switched in.
+    pub(crate) fn set_pending_irqs(&mut self, irqs: usize, pending: bool) {
+        match (self.resident, pending) {
+            (true, true) => {
+                CSR.hvip.read_and_set_bits(irqs);
+            }
+            (true, false) => {
+                CSR.hvip.read_and_clear_bits(irqs);
+            }
+            (false, true) => self.saved_hvip |= irqs,
+            (false, false) => self.saved_hvip &= !irqs,
+        }
+    }
+
+    fn initial_regs(entry: GuestPhysAddr) -> VmCpuRegisters {
         let mut regs = VmCpuRegisters::default();
         // Set hstatus
         let mut hstatus = LocalRegisterCopy::<usize, hstatus::Register>::new(
@@ -245,23 +430,18 @@ impl<H: HyperCraftHal> VCpu<H> {
 
         // Set entry
         regs.guest_regs.sepc = entry;
-        Self {
-            vcpu_id,
-            regs,
-            // gpt,
-            marker: PhantomData,
-        }
+        regs
     }
 
     /// Initialize nested mmu.
     pub fn init_page_map(&mut self, token: usize) {
         // Set hgatp
         // TODO: Sv39 currently, but should be configurable
-        self.regs.virtual_hs_csrs.hgatp = token;
+        self.hgatp = token;
         unsafe {
             core::arch::asm!(
                 "csrw hgatp, {hgatp}",
-                hgatp = in(reg) self.regs.virtual_hs_csrs.hgatp,
+                hgatp = in(reg) self.hgatp,
             );
             core::arch::riscv64::hfence_gvma_all();
         }
@@ -315,6 +495,17 @@ impl<H: HyperCraftHal> VCpu<H> {
             Trap::Interrupt(Interrupt::SupervisorExternal) => {
                 VmExitInfo::ExternalInterruptEmulation
             }
+            Trap::Exception(Exception::Breakpoint) => VmExitInfo::Debug {
+                pc: regs.guest_regs.sepc,
+                tval: regs.trap_csrs.stval,
+            },
+            Trap::Exception(Exception::VirtualInstruction) => VmExitInfo::VirtualInstruction {
+                fault_pc: regs.guest_regs.sepc,
+                inst: regs.trap_csrs.stval as u32,
+                priv_level: PrivilegeLevel::from_hstatus(regs.guest_regs.hstatus),
+            },
+            // Supervisor guest external interrupt, not known to the `riscv` crate.
+            _ if scause.is_interrupt() && scause.code() == 12 => VmExitInfo::GuestExternalInterrupt,
             Trap::Exception(Exception::LoadGuestPageFault)
             | Trap::Exception(Exception::StoreGuestPageFault) => {
                 let fault_addr = regs.trap_csrs.htval << 2 | regs.trap_csrs.stval & 0x3;
@@ -339,14 +530,11 @@ impl<H: HyperCraftHal> VCpu<H> {
                     priv_level: PrivilegeLevel::from_hstatus(regs.guest_regs.hstatus),
                 }
             }
-            _ => {
-                panic!(
-                    "Unhandled trap: {:?}, sepc: {:#x}, stval: {:#x}",
-                    scause.cause(),
-                    regs.guest_regs.sepc,
-                    regs.trap_csrs.stval
-                );
-            }
+            _ => VmExitInfo::UnhandledTrap {
+                scause: regs.trap_csrs.scause,
+                sepc: regs.guest_regs.sepc,
+                stval: regs.trap_csrs.stval,
+            },
         }
     }
 
@@ -360,36 +548,151 @@ impl<H: HyperCraftHal> VCpu<H> {
         self.regs.guest_regs.gprs.set_reg(index, val);
     }
 
+    /// Gets the address of the next instruction the vCPU runs.
+    pub fn pc(&self) -> GuestVirtAddr {
+        self.regs.guest_regs.sepc
+    }
+
+    /// Makes the vCPU run from `pc`.
+    pub fn set_pc(&mut self, pc: GuestVirtAddr) {
+        self.regs.guest_regs.sepc = pc;
+    }
+
+    /// Reads the supervisor CSR `csr` as the guest sees it. Fails with `NotSupported` for CSRs
+    /// other than sstatus, sie, stvec, scounteren, sscratch, sepc, scause, stval and satp.
+    pub fn get_csr(&self, csr: u16) -> HyperResult<usize> {
+        if csr == CSR_SCOUNTEREN {
+            return Ok(self.regs.guest_regs.scounteren);
+        }
+        let mut vs = self.vs_csrs();
+        vs_csr(&mut vs, csr).map(|val| *val)
+    }
+
+    /// Writes the supervisor CSR `csr` as the guest sees it, see `get_csr`.
+    pub fn set_csr(&mut self, csr: u16, val: usize) -> HyperResult<()> {
+        if csr == CSR_SCOUNTEREN {
+            self.regs.guest_regs.scounteren = val;
+            return Ok(());
+        }
+        self.update_vs_csrs(|vs| vs_csr(vs, csr).map(|reg| *reg = val))
+    }
+
     /// Advance guest pc by `instr_len` bytes
     pub fn advance_pc(&mut self, instr_len: usize) {
         self.regs.guest_regs.sepc += instr_len
     }
 
+    /// Makes the vCPU run from `pc` with the MMU and interrupts off, with its hart id in `a0` and
+    /// `opaque` in `a1`, as a hart started or resumed non-retentively through the SBI.
+    pub fn restart_at(&mut self, pc: GuestPhysAddr, opaque: usize) {
+        let regs = &mut self.regs.guest_regs;
+        regs.gprs.set_reg(GprIndex::A0, self.vcpu_id);
+        regs.gprs.set_reg(GprIndex::A1, opaque);
+        regs.sepc = pc;
+        self.update_vs_csrs(|vs| {
+            vs.vsatp = 0;
+            // Clear vsstatus.SIE.
+            vs.vsstatus &= !(1 << 1);
+        });
+    }
+
     /// Gets the vCPU's id.
     pub fn vcpu_id(&self) -> usize {
         self.vcpu_id
     }
 
+    /// Selects the IMSIC guest interrupt file `vgein` as this vCPU's VS-level external interrupt
+    /// source. 0 selects no file.
+    pub fn set_vgein(&mut self, vgein: usize) {
+        let mut hstatus =
+            LocalRegisterCopy::<usize, hstatus::Register>::new(self.regs.guest_regs.hstatus);
+        hstatus.modify(hstatus::vgein.val(vgein));
+        self.regs.guest_regs.hstatus = hstatus.get();
+    }
+
     /// Gets the vCPU's registers.
     pub fn regs(&mut self) -> &mut VmCpuRegisters {
         &mut self.regs
     }
+
+    /// Saves the GPRs, the CSRs switched on VM entry, the VS-level CSRs and the pending VS-level
+    /// interrupts into a VM snapshot.
+    pub(crate) fn save(&self, out: &mut SnapshotWriter) {
+        let regs = &self.regs.guest_regs;
+        for index in 0..32 {
+            out.write_usize(regs.gprs.reg(GprIndex::from_raw(index).unwrap()));
+        }
+        out.write_usize(regs.sstatus);
+        out.write_usize(regs.hstatus);
+        out.write_usize(regs.scounteren);
+        out.write_usize(regs.sepc);
+        self.vs_csrs().save(out);
+        out.write_usize(self.pending_irqs());
+    }
+
+    /// Restores the state saved by `save`. The IMSIC guest interrupt file stays in place.
+    pub(crate) fn restore(&mut self, input: &mut SnapshotReader) -> HyperResult<()> {
+        let vgein =
+            LocalRegisterCopy::<usize, hstatus::Register>::new(self.regs.guest_regs.hstatus)
+                .read(hstatus::vgein);
+        let regs = &mut self.regs.guest_regs;
+        for index in 0..32 {
+            let val = input.read_usize()?;
+            regs.gprs.set_reg(GprIndex::from_raw(index).unwrap(), val);
+        }
+        regs.sstatus = input.read_usize()?;
+        regs.hstatus = input.read_usize()?;
+        regs.scounteren = input.read_usize()?;
+        regs.sepc = input.read_usize()?;
+        self.set_vgein(vgein);
+        let vs_csrs = GuestVsCsrs::restore(input)?;
+        self.update_vs_csrs(|vs| *vs = vs_csrs);
+        let pending = input.read_usize()? & VS_INTERRUPTS;
+        self.set_pending_irqs(VS_INTERRUPTS & !pending, false);
+        self.set_pending_irqs(pending, true);
+        Ok(())
+    }
 }
 
 // Private methods implements
 impl<H: HyperCraftHal> VCpu<H> {
-    /// Delivers the given exception to the vCPU, setting its register state
-    /// to handle the trap the next time it is run.
-    fn inject_exception(&mut self) {
-        unsafe {
-            core::arch::asm!(
-                "csrw vsepc, {hyp_sepc}",
-                "csrw vscause, {hyp_scause}",
-                "csrr {guest_sepc}, vstvec",
-                hyp_sepc = in(reg) self.regs.guest_regs.sepc,
-                hyp_scause = in(reg) self.regs.trap_csrs.scause,
-                guest_sepc = out(reg) self.regs.guest_regs.sepc,
-            );
-        }
+    /// Delivers the exception `cause` with trap value `tval` to the vCPU, setting its register
+    /// state to handle the trap in VS mode the next time it is run.
+    pub(crate) fn inject_exception(&mut self, cause: usize, tval: usize) {
+        const SSTATUS_SIE: usize = 1 << 1;
+        const SSTATUS_SPIE: usize = 1 << 5;
+        const SSTATUS_SPP: usize = 1 << 8;
+        let (sstatus, sepc) = (self.regs.guest_regs.sstatus, self.regs.guest_regs.sepc);
+        let vstvec = self.update_vs_csrs(|vs| {
+            let mut new = vs.vsstatus & !(SSTATUS_SIE | SSTATUS_SPIE | SSTATUS_SPP);
+            if vs.vsstatus & SSTATUS_SIE != 0 {
+                new |= SSTATUS_SPIE;
+            }
+            // The trap was taken from VS mode if the hart was about to return to it.
+            new |= sstatus & SSTATUS_SPP;
+            vs.vsstatus = new;
+            vs.vsepc = sepc;
+            vs.vscause = cause;
+            vs.vstval = tval;
+            vs.vstvec
+        });
+        let regs = &mut self.regs.guest_regs;
+        regs.sstatus |= SSTATUS_SPP;
+        regs.sepc = vstvec & !0b11;
     }
 }
+
+/// Returns the VS-level CSR among `vs` backing the supervisor CSR `csr`.
+fn vs_csr(vs: &mut GuestVsCsrs, csr: u16) -> HyperResult<&mut usize> {
+    Ok(match csr {
+        CSR_SSTATUS => &mut vs.vsstatus,
+        CSR_SIE => &mut vs.vsie,
+        CSR_STVEC => &mut vs.vstvec,
+        CSR_SSCRATCH => &mut vs.vsscratch,
+        CSR_SEPC => &mut vs.vsepc,
+        CSR_SCAUSE => &mut vs.vscause,
+        CSR_STVAL => &mut vs.vstval,
+        CSR_SATP => &mut vs.vsatp,
+        _ => return Err(HyperError::NotSupported),
+    })
+}
@@ -1,33 +1,131 @@
+use alloc::boxed::Box;
+use alloc::string::ToString;
+use alloc::vec::Vec;
 use core::panic;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use super::{
-    devices::plic::{PlicState, MAX_CONTEXTS},
+    crash::CrashReport,
+    csrs::defs::{CSR_SIREG, CSR_STOPEI},
+    debug::{BreakpointHit, GuestDebug},
+    devices::{
+        aclint::{AclintMtimer, AclintSswi},
+        aplic::{AplicState, APLIC_SIZE},
+        imsic::{self, SoftImsic, VcpuImsic, IMSIC_FILE_SIZE},
+        passthrough::{self, PassthroughDevice},
+        plic::{PlicState, MAX_CONTEXTS},
+        rtc::GoldfishRtc,
+        test::SifiveTest,
+        uart::Uart16550,
+        virtio::{
+            BlockBackend, ConsoleHandle, NetBackend, VirtioBlk, VirtioConsole, VirtioDevice,
+            VirtioMmio, VirtioNet, VirtioVsock, VsockConn, VsockHandle,
+        },
+        EmulatedDevice, IrqChip, MmioDevice,
+    },
+    dirty_log::DirtyLog,
+    emulate::{CsrAccess, MmioAccess},
+    exit_stats::{ExitStats, ExitTrace, FaultRegion, VcpuExitStats},
+    nested::{NestedExit, NestedVcpu},
     regs::GeneralPurposeRegisters,
+    sbi::{
+        base,
+        dbcn::{self, EID_DBCN},
+        hsm::{self, HartState},
+        hypercall::{HyperCallHandler, HyperCallTable},
+        pmu::{FwEvent, VcpuPmu, EID_PMU},
+        srst,
+        sta::{VcpuSta, EID_STA},
+        susp::{self, EID_SUSP},
+        SbiIdentity,
+    },
+    snapshot::{SnapshotReader, SnapshotWriter},
     traps,
+    triggers::TriggerKind,
     vcpu::{self, VmCpuRegisters},
-    vm_pages::VmPages,
+    vm_pages::{VmPages, VmRegionList, VmRegionType},
     HyperCallMsg, RiscvCsrTrait, CSR,
 };
 use crate::{
-    vcpus::VM_CPUS_MAX, GprIndex, GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr, HyperCraftHal,
-    HyperError, HyperResult, VCpu, VmCpus, VmExitInfo,
+    memory::PAGE_SIZE_4K, vcpus::VM_CPUS_MAX, GprIndex, GuestPageTableTrait, GuestPhysAddr,
+    GuestVirtAddr, HostPhysAddr, HyperCraftHal, HyperError, HyperResult, PerCpu, VCpu, VmCpus,
+    VmExitInfo, VmExitReason,
 };
+use page_table_entry::MappingFlags;
+use riscv::register::time;
 use riscv_decode::Instruction;
-use rustsbi::{Forward, RustSBI};
+use rustsbi::{Forward, RustSBI, Timer};
 use sbi_spec::binary::{HartMask, Physical, SbiRet};
+use sbi_spec::{base::EID_BASE, hsm::EID_HSM, rfnc::EID_RFNC, srst::EID_SRST, time::EID_TIME};
+
+/// The address of the host PLIC, which guests using a PLIC see at the same address.
+const HOST_PLIC_BASE: usize = 0xC00_0000;
+
+// Tags of the sections of a VM snapshot.
+const SECTION_VCPU: u32 = u32::from_le_bytes(*b"VCPU");
+const SECTION_IRQCHIP: u32 = u32::from_le_bytes(*b"IRQC");
+const SECTION_DEVICE: u32 = u32::from_le_bytes(*b"MMIO");
+const SECTION_MEMORY: u32 = u32::from_le_bytes(*b"MEM ");
+
+/// The exception code of illegal instructions.
+const EXC_ILLEGAL_INSTRUCTION: usize = 2;
+/// The exception code of breakpoints.
+const EXC_BREAKPOINT: usize = 3;
+
+/// The next id handed out to a VM.
+static NEXT_VM_ID: AtomicUsize = AtomicUsize::new(0);
 
 /// A VM that is being run.
 pub struct VM<H: HyperCraftHal, G: GuestPageTableTrait> {
+    vm_id: usize,
     vcpus: VmCpus<H>,
     gpt: G,
     vm_pages: VmPages,
-    plic: PlicState,
+    irqchip: IrqChip,
     sbi: VmSBI,
+    passthrough_devices: Vec<PassthroughDevice>,
+    // The buffer of the interrupts of our passthrough devices taken while another VM ran.
+    deferred_irqs: Vec<u32>,
+    regions: VmRegionList,
+    mmio_devices: Vec<EmulatedDevice>,
+    // The ranges of guest memory whose writes are logged.
+    dirty_logs: Vec<DirtyLog>,
+    // The images loaded into guest memory at boot and again on every reboot.
+    boot_images: Vec<(GuestPhysAddr, Vec<u8>)>,
+    // The IMSIC interrupt file of each vCPU and the guest address it is mapped at.
+    imsics: [Option<(GuestPhysAddr, VcpuImsic)>; VM_CPUS_MAX],
+    vsock: Option<VsockHandle>,
+    sbi_identity: SbiIdentity,
+    pmus: [VcpuPmu; VM_CPUS_MAX],
+    stas: [VcpuSta; VM_CPUS_MAX],
+    hypercalls: HyperCallTable,
+    // The exit statistics and trace of each vCPU.
+    exit_stats: [VcpuExitStats; VM_CPUS_MAX],
+    // The nested virtualization state of each vCPU, if enabled.
+    nested: [Option<NestedVcpu<G>>; VM_CPUS_MAX],
+    // The breakpoints of the debugger attached, if any.
+    debug: Option<GuestDebug>,
+    // The HSM state of each vCPU.
+    harts: [HartState; VM_CPUS_MAX],
+    // The deadline each vCPU set through the SBI timer. Its interrupt is pending once reached.
+    sbi_timers: [Option<u64>; VM_CPUS_MAX],
+    // Whether each vCPU programs its timer through the SBI rather than an emulated ACLINT timer.
+    sbi_timer_owners: [bool; VM_CPUS_MAX],
+    // A stop requested by the guest through the SBI, reported once the current exit is handled.
+    pending_exit: Option<VmExitReason>,
+    // The `time` value at which `run` returns for the VMM to regain control, if set.
+    run_deadline: Option<u64>,
+    // Set once the guest has powered the VM off.
+    stopped: Option<VmExitReason>,
+    // The state of the vCPU whose fault stopped the VM, if any.
+    crash_report: Option<CrashReport>,
 }
 
+// Base calls never reach `info`: they are answered by `handle_virtual_sbi`, but the derive needs
+// an implementation of the machine information.
 #[derive(RustSBI)]
 struct VmSBI {
-    #[rustsbi(fence, timer, console, reset, info)]
+    #[rustsbi(fence, timer, info)]
     forward: Forward,
 }
 
@@ -35,14 +133,704 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
     /// Create a new VM with `vcpus` vCPUs and `gpt` as the guest page table.
     pub fn new(vcpus: VmCpus<H>, gpt: G) -> HyperResult<Self> {
         Ok(Self {
+            vm_id: NEXT_VM_ID.fetch_add(1, Ordering::Relaxed),
             vcpus,
             gpt,
             vm_pages: VmPages::default(),
-            plic: PlicState::new(0xC00_0000),
+            irqchip: IrqChip::Plic(PlicState::new(HOST_PLIC_BASE)),
             sbi: VmSBI { forward: Forward },
+            passthrough_devices: Vec::new(),
+            deferred_irqs: Vec::new(),
+            regions: VmRegionList::new(),
+            mmio_devices: Vec::new(),
+            dirty_logs: Vec::new(),
+            boot_images: Vec::new(),
+            imsics: Default::default(),
+            vsock: None,
+            sbi_identity: SbiIdentity::default(),
+            pmus: Default::default(),
+            stas: Default::default(),
+            hypercalls: HyperCallTable::default(),
+            exit_stats: Default::default(),
+            nested: Default::default(),
+            debug: None,
+            harts: Self::initial_harts(),
+            sbi_timers: [None; VM_CPUS_MAX],
+            sbi_timer_owners: [false; VM_CPUS_MAX],
+            pending_exit: None,
+            run_deadline: None,
+            stopped: None,
+            crash_report: None,
         })
     }
 
+    /// Returns the id of this VM.
+    pub fn vm_id(&self) -> usize {
+        self.vm_id
+    }
+
+    /// Sets the machine and SBI implementation identity the guest sees through the SBI Base
+    /// extension.
+    pub fn set_sbi_identity(&mut self, identity: SbiIdentity) {
+        self.sbi_identity = identity;
+    }
+
+    /// Registers `handler` for the hypercall `function` of the SBI extension `extension`, which
+    /// must be in the vendor or firmware-specific range, e.g. `EID_HYPERCRAFT`. Fails with
+    /// `BadState` if a handler is already registered.
+    pub fn register_hypercall(
+        &mut self,
+        extension: usize,
+        function: usize,
+        handler: HyperCallHandler,
+    ) -> HyperResult<()> {
+        self.hypercalls.register(extension, function, handler)
+    }
+
+    /// Removes the handler of the hypercall `function` of the SBI extension `extension`.
+    pub fn unregister_hypercall(&mut self, extension: usize, function: usize) -> HyperResult<()> {
+        self.hypercalls.unregister(extension, function)
+    }
+
+    /// Exposes a virtual H extension to the vCPUs, so that the guest can run its own hypervisor.
+    /// Advertising the extension, e.g. in the ISA string of the guest's device tree, is up to the
+    /// VMM. Must be called before the vCPUs first run.
+    pub fn enable_nested_virt(&mut self) -> HyperResult<()> {
+        if self.nested.iter().any(Option::is_some) {
+            return Err(HyperError::BadState);
+        }
+        for vcpu_id in 0..VM_CPUS_MAX {
+            if let Ok(vcpu) = self.vcpus.get_vcpu(vcpu_id) {
+                self.nested[vcpu_id] = Some(NestedVcpu::new(vcpu.regs(), self.gpt.token())?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts logging the guest pages written to in `gpa..gpa + size`, which must be page-aligned
+    /// writable memory mapped with 4K pages. With `hardware_dirty`, the dirty bits the hardware
+    /// sets in the G-stage table are collected instead of write-protecting the pages, which needs
+    /// Svadu enabled by the firmware (`menvcfg.ADUE`) and a table supporting `test_and_clear_dirty`.
+    /// The writes made by the hypervisor and the VM's devices are logged as well.
+    pub fn start_dirty_log(
+        &mut self,
+        gpa: GuestPhysAddr,
+        size: usize,
+        hardware_dirty: bool,
+    ) -> HyperResult<()> {
+        if gpa % PAGE_SIZE_4K != 0 || size % PAGE_SIZE_4K != 0 || size == 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        if self.dirty_logs.iter().any(|log| log.overlaps(gpa, size)) {
+            return Err(HyperError::BadState);
+        }
+        let pages = size / PAGE_SIZE_4K;
+        let log = DirtyLog::start(&mut self.gpt, &self.vm_pages, gpa, pages, hardware_dirty)?;
+        self.dirty_logs.push(log);
+        self.flush_nested_shadows()
+    }
+
+    /// Returns the dirty bitmap of the logged range starting at `gpa` and clears it: bit `i % 64`
+    /// of word `i / 64` is set if 4K page `i` of the range was written to since logging
+    /// started or the bitmap was last fetched. Must be called with the VM's vCPUs paused, on the
+    /// hart that runs them next.
+    pub fn fetch_dirty_log(&mut self, gpa: GuestPhysAddr) -> HyperResult<Vec<u64>> {
+        let log = self
+            .dirty_logs
+            .iter_mut()
+            .find(|log| log.base() == gpa)
+            .ok_or(HyperError::NotFound)?;
+        let bitmap = log.fetch_and_clear(&mut self.gpt, &self.vm_pages)?;
+        self.flush_nested_shadows()?;
+        Ok(bitmap)
+    }
+
+    /// Stops logging the writes to the range starting at `gpa`.
+    pub fn stop_dirty_log(&mut self, gpa: GuestPhysAddr) -> HyperResult<()> {
+        let index = self
+            .dirty_logs
+            .iter()
+            .position(|log| log.base() == gpa)
+            .ok_or(HyperError::NotFound)?;
+        self.dirty_logs
+            .remove(index)
+            .stop(&mut self.gpt, &self.vm_pages)
+    }
+
+    /// Saves the state of the VM into a snapshot: its vCPUs, interrupt controller and emulated
+    /// devices, and the contents of the guest memory ranges `memory`, given as `(gpa, size)`.
+    /// Must be called between runs of the vCPUs, on the hart owning their hardware IMSIC guest
+    /// interrupt files, if any. Fails with `NotSupported` if nested virtualization is enabled or
+    /// a device cannot save its state.
+    pub fn snapshot(&mut self, memory: &[(GuestPhysAddr, usize)]) -> HyperResult<Vec<u8>> {
+        if self.nested.iter().any(Option::is_some) {
+            return Err(HyperError::NotSupported);
+        }
+        self.check_imsic_hart()?;
+        let mut out = SnapshotWriter::new();
+        for vcpu_id in 0..VM_CPUS_MAX {
+            let vcpu = match self.vcpus.get_vcpu(vcpu_id) {
+                Ok(vcpu) => vcpu,
+                Err(_) => continue,
+            };
+            out.section(SECTION_VCPU, |out| {
+                out.write_usize(vcpu_id);
+                vcpu.save(out);
+                self.harts[vcpu_id].save(out);
+                out.write_u64(self.sbi_timers[vcpu_id].unwrap_or(u64::MAX));
+                out.write_bool(self.sbi_timer_owners[vcpu_id]);
+                self.stas[vcpu_id].save(out);
+                out.write_bool(self.imsics[vcpu_id].is_some());
+                if let Some((_, imsic)) = &self.imsics[vcpu_id] {
+                    imsic.save(out);
+                }
+                self.pmus[vcpu_id].save(out);
+            });
+        }
+        out.section(SECTION_IRQCHIP, |out| {
+            out.write_bool(matches!(self.irqchip, IrqChip::Aplic(_)));
+            self.irqchip.save(out);
+        });
+        for dev in self.mmio_devices.iter() {
+            out.section(SECTION_DEVICE, |out| {
+                out.write_usize(dev.base);
+                dev.device.save(out)
+            })?;
+        }
+        for &(gpa, size) in memory {
+            out.section(SECTION_MEMORY, |out| {
+                out.write_usize(gpa);
+                self.vm_pages.copy_from_guest(out.alloc_bytes(size), gpa)
+            })?;
+        }
+        Ok(out.finish())
+    }
+
+    /// Restores a snapshot taken by `snapshot` into this VM, which must have been set up like the
+    /// VM it was taken of: the same vCPUs, interrupt controller and emulated devices, and the
+    /// saved guest memory mapped. Must be called before the vCPUs run, on the same hart as
+    /// `snapshot`. Fails with `NotSupported` for snapshots of another format version. The state
+    /// is restored section by section, so a failure leaves the VM partly restored: it must then
+    /// be restored from another snapshot or reset before it runs.
+    pub fn restore(&mut self, snapshot: &[u8]) -> HyperResult<()> {
+        if self.nested.iter().any(Option::is_some) {
+            return Err(HyperError::NotSupported);
+        }
+        self.check_imsic_hart()?;
+        let mut input = SnapshotReader::new(snapshot)?;
+        for vcpu_id in 0..VM_CPUS_MAX {
+            let vcpu = match self.vcpus.get_vcpu(vcpu_id) {
+                Ok(vcpu) => vcpu,
+                Err(_) => continue,
+            };
+            input.section(SECTION_VCPU, |input| {
+                if input.read_usize()? != vcpu_id {
+                    return Err(HyperError::InvalidParam);
+                }
+                vcpu.restore(input)?;
+                self.harts[vcpu_id] = HartState::restore(input)?;
+                self.sbi_timers[vcpu_id] = Some(input.read_u64()?).filter(|&t| t != u64::MAX);
+                self.sbi_timer_owners[vcpu_id] = input.read_bool()?;
+                self.stas[vcpu_id].restore(input)?;
+                match (input.read_bool()?, &mut self.imsics[vcpu_id]) {
+                    (true, Some((_, imsic))) => imsic.restore(input)?,
+                    (false, None) => {}
+                    _ => return Err(HyperError::InvalidParam),
+                }
+                self.pmus[vcpu_id].restore(input)
+            })?;
+            self.sync_imsic_irq(vcpu_id);
+        }
+        input.section(SECTION_IRQCHIP, |input| {
+            if input.read_bool()? != matches!(self.irqchip, IrqChip::Aplic(_)) {
+                return Err(HyperError::InvalidParam);
+            }
+            self.irqchip.restore(input)
+        })?;
+        for dev in self.mmio_devices.iter_mut() {
+            input.section(SECTION_DEVICE, |input| {
+                if input.read_usize()? != dev.base {
+                    return Err(HyperError::InvalidParam);
+                }
+                dev.device.restore(input)
+            })?;
+        }
+        while !input.is_empty() {
+            input.section(SECTION_MEMORY, |input| {
+                let gpa = input.read_usize()?;
+                self.vm_pages.copy_to_guest(gpa, input.read_bytes()?)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Fails with `BadState` unless the hardware IMSIC guest interrupt files of the vCPUs, which
+    /// are only accessible from their hart, are all on this hart.
+    fn check_imsic_hart(&self) -> HyperResult<()> {
+        let this_hart = PerCpu::<H>::this_cpu().cpu_id();
+        let remote = self.imsics.iter().flatten().any(|(_, imsic)| {
+            matches!(imsic, VcpuImsic::Hardware { hart_id, .. } if *hart_id != this_hart)
+        });
+        if remote {
+            return Err(HyperError::BadState);
+        }
+        Ok(())
+    }
+
+    /// Gets the vCPU `vcpu_id`, e.g. to access its registers while the VM is stopped.
+    pub fn vcpu(&mut self, vcpu_id: usize) -> HyperResult<&mut VCpu<H>> {
+        self.vcpus.get_vcpu(vcpu_id)
+    }
+
+    /// Attaches a debugger to the VM. Breakpoint exceptions are no longer delegated to the guest
+    /// while its vCPUs run, so that `run` returns `VmExitReason::Debug` once a vCPU reaches a
+    /// breakpoint set with `debug_set_breakpoint` or completes a `debug_step`. The guest's own
+    /// breakpoints are still delivered to it.
+    pub fn debug_attach(&mut self) -> HyperResult<()> {
+        if self.debug.is_some() {
+            return Err(HyperError::BadState);
+        }
+        self.debug = Some(GuestDebug::default());
+        Ok(())
+    }
+
+    /// Detaches the debugger, removing its breakpoints from guest code.
+    pub fn debug_detach(&mut self) -> HyperResult<()> {
+        let mut debug = self.debug.take().ok_or(HyperError::BadState)?;
+        debug.clear_all(&self.vm_pages)
+    }
+
+    /// Sets a software breakpoint at the guest virtual address `gva` of `vcpu_id`, by patching a
+    /// breakpoint instruction into the guest code. Guest virtual addresses are translated with
+    /// the VS-stage page table of the vCPU given. This and the other `debug_*` methods must be
+    /// called with the VM's vCPUs stopped, on the hart that runs them.
+    pub fn debug_set_breakpoint(&mut self, vcpu_id: usize, gva: GuestVirtAddr) -> HyperResult<()> {
+        let debug = self.debug.as_mut().ok_or(HyperError::BadState)?;
+        let vsatp = self.vcpus.get_vcpu(vcpu_id)?.vs_csrs().vsatp;
+        debug.set_breakpoint(&self.vm_pages, vsatp, gva)
+    }
+
+    /// Clears the software breakpoint at `gva`.
+    pub fn debug_clear_breakpoint(&mut self, gva: GuestVirtAddr) -> HyperResult<()> {
+        let debug = self.debug.as_mut().ok_or(HyperError::BadState)?;
+        debug.clear_breakpoint(&self.vm_pages, gva)
+    }
+
+    /// Sets a hardware breakpoint or watchpoint of `kind` on the `len` bytes from the guest
+    /// virtual address `gva` with a debug trigger, leaving guest memory untouched. Watchpoints on
+    /// more than one byte must cover a naturally aligned power of two. Fails with `NotSupported`
+    /// if the hart or its firmware has no such trigger.
+    pub fn debug_set_trigger(
+        &mut self,
+        kind: TriggerKind,
+        gva: GuestVirtAddr,
+        len: usize,
+    ) -> HyperResult<()> {
+        let debug = self.debug.as_mut().ok_or(HyperError::BadState)?;
+        debug.set_trigger(kind, gva, len)
+    }
+
+    /// Clears the trigger set by `debug_set_trigger` with the same arguments.
+    pub fn debug_clear_trigger(
+        &mut self,
+        kind: TriggerKind,
+        gva: GuestVirtAddr,
+        len: usize,
+    ) -> HyperResult<()> {
+        let debug = self.debug.as_mut().ok_or(HyperError::BadState)?;
+        debug.clear_trigger(kind, gva, len)
+    }
+
+    /// Returns the kind of the watchpoint `vcpu_id` stopped at last and the address it accessed,
+    /// if its last `VmExitReason::Debug` was for one.
+    pub fn debug_watch_hit(&self, vcpu_id: usize) -> Option<(TriggerKind, GuestVirtAddr)> {
+        self.debug.as_ref()?.watch_hit(vcpu_id)
+    }
+
+    /// Makes `vcpu_id` stop after running its next instruction.
+    pub fn debug_step(&mut self, vcpu_id: usize) -> HyperResult<()> {
+        let debug = self.debug.as_mut().ok_or(HyperError::BadState)?;
+        let vcpu = self.vcpus.get_vcpu(vcpu_id)?;
+        let mut gprs = GeneralPurposeRegisters::default();
+        vcpu.save_gprs(&mut gprs);
+        debug.step(&self.vm_pages, vcpu_id, vcpu.pc(), &gprs, &vcpu.vs_csrs())
+    }
+
+    /// Reads `buf.len()` bytes of guest memory at the guest virtual address `gva` of `vcpu_id`, as
+    /// they are without the debugger's breakpoints.
+    pub fn debug_read_memory(
+        &mut self,
+        vcpu_id: usize,
+        gva: GuestVirtAddr,
+        buf: &mut [u8],
+    ) -> HyperResult<()> {
+        let debug = self.debug.as_ref().ok_or(HyperError::BadState)?;
+        let vsatp = self.vcpus.get_vcpu(vcpu_id)?.vs_csrs().vsatp;
+        debug.read_memory(&self.vm_pages, vsatp, gva, buf)
+    }
+
+    /// Writes `data` to the guest memory at the guest virtual address `gva` of `vcpu_id`, whatever
+    /// the permissions of the guest's mapping.
+    pub fn debug_write_memory(
+        &mut self,
+        vcpu_id: usize,
+        gva: GuestVirtAddr,
+        data: &[u8],
+    ) -> HyperResult<()> {
+        let debug = self.debug.as_mut().ok_or(HyperError::BadState)?;
+        let vsatp = self.vcpus.get_vcpu(vcpu_id)?.vs_csrs().vsatp;
+        debug.write_memory(&self.vm_pages, vsatp, gva, data)
+    }
+
+    /// Copies `dest.len()` bytes of the guest memory at `gpa` to `dest`, through this VM's G-stage
+    /// table whichever VM runs on this hart.
+    pub(crate) fn read_guest_memory(&self, gpa: GuestPhysAddr, dest: &mut [u8]) -> HyperResult<()> {
+        self.with_gstage(|| self.vm_pages.copy_from_guest(dest, gpa))
+    }
+
+    /// Copies `src` to the guest memory at `gpa`, through this VM's G-stage table whichever VM
+    /// runs on this hart.
+    pub(crate) fn write_guest_memory(&self, gpa: GuestPhysAddr, src: &[u8]) -> HyperResult<()> {
+        self.with_gstage(|| self.vm_pages.copy_to_guest(gpa, src))
+    }
+
+    /// Stops the VM for good after it was migrated to another host.
+    pub(crate) fn stop_migrated(&mut self) {
+        self.stop(VmExitReason::Migrated);
+    }
+
+    /// Loads `image` into the guest memory at `gpa`, and again whenever the guest reboots the VM.
+    /// The memory must be mapped.
+    pub fn load_boot_image(&mut self, gpa: GuestPhysAddr, image: Vec<u8>) -> HyperResult<()> {
+        self.write_guest_memory(gpa, &image)?;
+        self.boot_images.push((gpa, image));
+        Ok(())
+    }
+
+    /// Makes `run` return `VmExitReason::Preempted` once `time` reaches `deadline`, even if the
+    /// vCPU never exits on its own, e.g. to send the rounds of a migration between time slices
+    /// of the guest. The deadline holds for the following runs until it is reached or cleared
+    /// with `None`.
+    pub fn set_run_deadline(&mut self, deadline: Option<u64>) {
+        self.run_deadline = deadline;
+    }
+
+    /// Returns the `time` value at which the timer of the idle vCPU `vcpu_id` next fires, if it
+    /// is armed. The VMM should run the vCPU again by then.
+    pub fn vcpu_wakeup_time(&self, vcpu_id: usize) -> Option<u64> {
+        if vcpu_id >= VM_CPUS_MAX {
+            return None;
+        }
+        self.timer_deadline(vcpu_id).filter(|&t| t != u64::MAX)
+    }
+
+    /// Returns the deadline of the timer of `vcpu_id`: the SBI timer if the vCPU uses it, or else
+    /// the earliest timer the emulated devices keep for it.
+    fn timer_deadline(&self, vcpu_id: usize) -> Option<u64> {
+        if self.sbi_timer_owners[vcpu_id] {
+            return self.sbi_timers[vcpu_id];
+        }
+        self.mmio_devices
+            .iter()
+            .filter_map(|dev| dev.device.vcpu_timer(vcpu_id))
+            .min()
+    }
+
+    /// Assigns the host device `device` to this VM. The device's registers are mapped into the
+    /// guest and its interrupts are delivered to this VM's virtual PLIC. Fails with `BadState` if
+    /// the device is already assigned to a VM.
+    pub fn assign_device(&mut self, device: PassthroughDevice) -> HyperResult<()> {
+        passthrough::claim(&device, self.vm_id)?;
+        let flags =
+            MappingFlags::READ | MappingFlags::WRITE | MappingFlags::USER | MappingFlags::DEVICE;
+        if let Err(err) = self.gpt.map_region(
+            device.guest_base(),
+            device.host_base(),
+            device.size(),
+            flags,
+        ) {
+            passthrough::release(&device);
+            return Err(err);
+        }
+        debug!(
+            "VM[{}] assigned device {:#x}-{:#x} at {:#x}, irqs {:?}",
+            self.vm_id,
+            device.host_base(),
+            device.host_base() + device.size(),
+            device.guest_base(),
+            device.irqs()
+        );
+        self.passthrough_devices.push(device);
+        Ok(())
+    }
+
+    /// Returns the host devices assigned to this VM.
+    pub fn passthrough_devices(&self) -> &[PassthroughDevice] {
+        &self.passthrough_devices
+    }
+
+    /// Attaches the emulated device `device` at `base`, with its interrupt line wired to the
+    /// source `irq` of the VM's interrupt controller.
+    pub fn add_mmio_device(
+        &mut self,
+        base: GuestPhysAddr,
+        irq: Option<u32>,
+        mut device: Box<dyn MmioDevice>,
+    ) -> HyperResult<()> {
+        self.regions.add(base, device.size(), VmRegionType::Mmio)?;
+        device.attach(&self.vm_pages);
+        self.mmio_devices.push(EmulatedDevice { base, irq, device });
+        Ok(())
+    }
+
+    /// Attaches an emulated NS16550A UART at `base` raising `irq`, connected to the host console
+    /// through `HyperCraftHal::console_putchar` and `HyperCraftHal::console_getchar`.
+    pub fn add_uart(&mut self, base: GuestPhysAddr, irq: u32) -> HyperResult<()>
+    where
+        H: 'static,
+    {
+        let uart = Uart16550::<H>::new(self.vm_id);
+        self.add_mmio_device(base, Some(irq), Box::new(uart))
+    }
+
+    /// Attaches an emulated SiFive test device at `base`, through which the guest powers off or
+    /// reboots the VM.
+    pub fn add_sifive_test(&mut self, base: GuestPhysAddr) -> HyperResult<()> {
+        self.add_mmio_device(base, None, Box::new(SifiveTest::new()))
+    }
+
+    /// Attaches an emulated Goldfish RTC at `base` raising `irq`, following the host's wall-clock
+    /// time from `HyperCraftHal::wall_time_nanos`.
+    pub fn add_goldfish_rtc(&mut self, base: GuestPhysAddr, irq: u32) -> HyperResult<()>
+    where
+        H: 'static,
+    {
+        self.add_mmio_device(base, Some(irq), Box::new(GoldfishRtc::<H>::new()))
+    }
+
+    /// Attaches an emulated ACLINT MTIMER at `base`. The guest's supervisor timer interrupts are
+    /// then driven by its `mtimecmp` registers.
+    pub fn add_aclint_mtimer(&mut self, base: GuestPhysAddr) -> HyperResult<()> {
+        self.add_mmio_device(base, None, Box::new(AclintMtimer::new()))
+    }
+
+    /// Attaches an emulated ACLINT SSWI at `base`, through which the guest's harts send each
+    /// other supervisor software interrupts.
+    pub fn add_aclint_sswi(&mut self, base: GuestPhysAddr) -> HyperResult<()> {
+        self.add_mmio_device(base, None, Box::new(AclintSswi::new()))
+    }
+
+    /// Attaches the virtio device `device` over a virtio-mmio transport at `base`, raising `irq`.
+    pub fn add_virtio_device<D: VirtioDevice + 'static>(
+        &mut self,
+        base: GuestPhysAddr,
+        irq: u32,
+        device: D,
+    ) -> HyperResult<()> {
+        self.add_mmio_device(base, Some(irq), Box::new(VirtioMmio::new(device)))
+    }
+
+    /// Attaches a virtio-blk device at `base` raising `irq`, whose storage is `backend`.
+    pub fn add_virtio_blk(
+        &mut self,
+        base: GuestPhysAddr,
+        irq: u32,
+        backend: Box<dyn BlockBackend>,
+    ) -> HyperResult<()> {
+        self.add_virtio_device(base, irq, VirtioBlk::new(backend))
+    }
+
+    /// Attaches a virtio-net device with the MAC address `mac` at `base` raising `irq`, whose
+    /// frames are exchanged with `backend`.
+    pub fn add_virtio_net(
+        &mut self,
+        base: GuestPhysAddr,
+        irq: u32,
+        mac: [u8; 6],
+        backend: Box<dyn NetBackend>,
+    ) -> HyperResult<()> {
+        self.add_virtio_device(base, irq, VirtioNet::new(mac, backend))
+    }
+
+    /// Attaches a multiport virtio console at `base` raising `irq`, with one port per entry of
+    /// `port_names`. Port 0 is the guest's console. Returns the handle through which the host
+    /// exchanges data with the ports.
+    pub fn add_virtio_console(
+        &mut self,
+        base: GuestPhysAddr,
+        irq: u32,
+        port_names: &[&str],
+    ) -> HyperResult<ConsoleHandle> {
+        let (console, handle) = VirtioConsole::new(port_names)?;
+        self.add_virtio_device(base, irq, console)?;
+        Ok(handle)
+    }
+
+    /// Attaches a virtio-vsock device at `base` raising `irq`, giving the guest the CID
+    /// `guest_cid`. The host end of the sockets is driven through the `vsock_*` methods.
+    pub fn add_virtio_vsock(
+        &mut self,
+        base: GuestPhysAddr,
+        irq: u32,
+        guest_cid: u64,
+    ) -> HyperResult<()> {
+        if self.vsock.is_some() {
+            return Err(HyperError::BadState);
+        }
+        let (vsock, handle) = VirtioVsock::new(guest_cid)?;
+        self.add_virtio_device(base, irq, vsock)?;
+        self.vsock = Some(handle);
+        Ok(())
+    }
+
+    /// Returns a handle to the host side of the VM's vsock device, which may be used from other
+    /// host contexts while the VM runs.
+    pub fn vsock_handle(&self) -> HyperResult<VsockHandle> {
+        self.vsock.clone().ok_or(HyperError::NotFound)
+    }
+
+    /// Accepts vsock connections from the guest to the host port `port`.
+    pub fn vsock_listen(&self, port: u32) -> HyperResult<()> {
+        self.vsock_ref()?.listen(port)
+    }
+
+    /// Returns the next connection the guest made to the listening host port `port`, if any.
+    pub fn vsock_accept(&self, port: u32) -> HyperResult<Option<VsockConn>> {
+        self.vsock_ref()?.accept(port)
+    }
+
+    /// Initiates a vsock connection to `guest_port` in the guest.
+    pub fn vsock_connect(&self, guest_port: u32) -> HyperResult<VsockConn> {
+        self.vsock_ref()?.connect(guest_port)
+    }
+
+    /// Sends `data` to the guest on `conn`, returning how many bytes were accepted.
+    pub fn vsock_send(&self, conn: VsockConn, data: &[u8]) -> HyperResult<usize> {
+        self.vsock_ref()?.send(conn, data)
+    }
+
+    /// Receives data from the guest on `conn` into `buf`, returning how many bytes were read.
+    pub fn vsock_recv(&self, conn: VsockConn, buf: &mut [u8]) -> HyperResult<usize> {
+        self.vsock_ref()?.recv(conn, buf)
+    }
+
+    /// Closes the vsock connection `conn`.
+    pub fn vsock_close(&self, conn: VsockConn) -> HyperResult<()> {
+        self.vsock_ref()?.close(conn)
+    }
+
+    /// Replaces the VM's PLIC with an emulated AIA APLIC domain at `base`. Must be called before
+    /// the VM is run.
+    pub fn use_aplic(&mut self, base: GuestPhysAddr) -> HyperResult<()> {
+        self.regions.add(base, APLIC_SIZE, VmRegionType::Mmio)?;
+        self.irqchip = IrqChip::Aplic(AplicState::new(base));
+        Ok(())
+    }
+
+    /// Gives vCPU `vcpu_id` an AIA IMSIC interrupt file at `guest_addr`. `s_file_base` is the
+    /// address of the supervisor-level interrupt file of the IMSIC of the current hart, which the
+    /// vCPU is bound to. A hardware guest interrupt file is used if one is free on this hart,
+    /// otherwise the interrupt file is emulated in software.
+    pub fn init_vcpu_imsic(
+        &mut self,
+        vcpu_id: usize,
+        guest_addr: GuestPhysAddr,
+        s_file_base: HostPhysAddr,
+    ) -> HyperResult<()> {
+        if self
+            .imsics
+            .get(vcpu_id)
+            .ok_or(HyperError::InvalidParam)?
+            .is_some()
+        {
+            return Err(HyperError::BadState);
+        }
+        self.regions
+            .add(guest_addr, IMSIC_FILE_SIZE, VmRegionType::Imsic)?;
+        let hart_id = PerCpu::<H>::this_cpu().cpu_id();
+        let imsic = match imsic::alloc_guest_file(hart_id) {
+            Some(file) => {
+                let flags = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::USER;
+                let hpa = VcpuImsic::guest_file_addr(s_file_base, file);
+                if let Err(err) = self.gpt.map(guest_addr, hpa, flags) {
+                    imsic::free_guest_file(hart_id, file);
+                    return Err(err);
+                }
+                self.vcpus.get_vcpu(vcpu_id)?.set_vgein(file);
+                debug!(
+                    "VM[{}] vCPU {} IMSIC guest file {} at {:#x}",
+                    self.vm_id, vcpu_id, file, guest_addr
+                );
+                VcpuImsic::Hardware {
+                    hart_id,
+                    file,
+                    addr: hpa,
+                }
+            }
+            None => {
+                // Leave the page unmapped so that MSI writes trap and are emulated.
+                warn!(
+                    "VM[{}] vCPU {}: no free IMSIC guest file, emulating it",
+                    self.vm_id, vcpu_id
+                );
+                VcpuImsic::Software(SoftImsic::new())
+            }
+        };
+        self.imsics[vcpu_id] = Some((guest_addr, imsic));
+        Ok(())
+    }
+
+    /// Returns the state of the vCPU whose fault stopped the VM, once `run` returned
+    /// `VmExitReason::Crash`.
+    pub fn crash_report(&self) -> Option<&CrashReport> {
+        self.crash_report.as_ref()
+    }
+
+    /// Returns the exit statistics of `vcpu_id`.
+    pub fn exit_stats(&self, vcpu_id: usize) -> HyperResult<&ExitStats> {
+        let stats = self
+            .exit_stats
+            .get(vcpu_id)
+            .ok_or(HyperError::InvalidParam)?;
+        Ok(stats.stats())
+    }
+
+    /// Resets the exit statistics and trace of `vcpu_id`.
+    pub fn reset_exit_stats(&mut self, vcpu_id: usize) -> HyperResult<()> {
+        let stats = self
+            .exit_stats
+            .get_mut(vcpu_id)
+            .ok_or(HyperError::InvalidParam)?;
+        stats.reset();
+        Ok(())
+    }
+
+    /// Enables or disables the trace of the last exits of all the vCPUs, which is dumped when
+    /// one of them crashes.
+    pub fn set_exit_trace(&mut self, enabled: bool) {
+        for stats in self.exit_stats.iter_mut() {
+            stats.set_trace(enabled);
+        }
+    }
+
+    /// Returns the trace of the last exits of `vcpu_id`, if enabled with `set_exit_trace`.
+    pub fn exit_trace(&self, vcpu_id: usize) -> Option<&ExitTrace> {
+        self.exit_stats.get(vcpu_id)?.trace()
+    }
+
+    /// Logs the trace of the last exits of `vcpu_id`, if enabled with `set_exit_trace`.
+    pub fn dump_exit_trace(&self, vcpu_id: usize) {
+        if let Some(trace) = self.exit_trace(vcpu_id) {
+            error!(
+                "Last {} exits of VM {} vCPU {}:",
+                trace.len(),
+                self.vm_id,
+                vcpu_id
+            );
+            trace.dump();
+        }
+    }
+
     /// Initialize `VCpu` by `vcpu_id`.
     pub fn init_vcpu(&mut self, vcpu_id: usize) {
         let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
@@ -50,33 +838,99 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
     }
 
     #[allow(unused_variables, deprecated)]
-    /// Run the host VM's vCPU with ID `vcpu_id`. Does not return.
-    pub fn run(&mut self, vcpu_id: usize) {
+    /// Run the host VM's vCPU with ID `vcpu_id` until the guest powers off or reboots the VM, the
+    /// vCPU goes idle, the run deadline is reached, or it hits a fault that cannot be handled,
+    /// which stops the VM with a crash report.
+    pub fn run(&mut self, vcpu_id: usize) -> VmExitReason {
         let mut vm_exit_info: VmExitInfo;
         let mut gprs = GeneralPurposeRegisters::default();
+        if let Some(reason) = self.stopped {
+            return reason;
+        }
+        self.switch_in(vcpu_id);
         loop {
             let mut len = 4;
             let mut advance_pc = false;
+            // Deliver interrupts of our devices which arrived while another VM was running.
+            let mut deferred = core::mem::take(&mut self.deferred_irqs);
+            passthrough::take_deferred_irqs(self.vm_id, |irq| deferred.push(irq));
+            for irq in deferred.drain(..) {
+                self.inject_host_irq(irq);
+            }
+            self.deferred_irqs = deferred;
+            if let IrqChip::Aplic(aplic) = &mut self.irqchip {
+                aplic.set_active_hart(vcpu_id);
+            }
+            self.in_host_gstage(vcpu_id, Self::poll_devices);
+            self.sync_vcpu_irqs(vcpu_id);
+            if !self.wake_vcpu(vcpu_id) {
+                self.switch_out(vcpu_id, VmExitReason::Idle);
+                return VmExitReason::Idle;
+            }
+            // Put back the single-step breakpoints lifted for the other vCPUs to get past them.
+            if let Some(debug) = self.debug.as_mut() {
+                if let Err(err) = debug.arm_steps(&self.vm_pages, vcpu_id) {
+                    return self.crash(vcpu_id, format_args!("Single-step with error {:?}", err));
+                }
+            }
             {
                 let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+                // Interrupts of the guest hypervisor preempt its nested guest.
+                if let Some(nested) = self.nested[vcpu_id].as_mut().filter(|n| n.is_virt()) {
+                    nested.deliver_interrupt(vcpu.regs());
+                }
+                // Breakpoints are taken by the debugger, which reflects the guest's own ones.
+                let hedeleg = self.debug.as_ref().map(|debug| {
+                    debug.enable_triggers(vcpu_id);
+                    CSR.hedeleg
+                        .read_and_clear_bits(traps::exception::BREAKPOINT)
+                });
+                self.exit_stats[vcpu_id].enter();
                 vm_exit_info = vcpu.run();
+                self.exit_stats[vcpu_id].exit(vcpu.regs());
+                if let (Some(hedeleg), Some(debug)) = (hedeleg, self.debug.as_ref()) {
+                    CSR.hedeleg.write_value(hedeleg);
+                    debug.disable_triggers(vcpu_id);
+                }
                 vcpu.save_gprs(&mut gprs);
             }
+            self.count_exit(vcpu_id, &vm_exit_info);
 
             match vm_exit_info {
+                _ if self.is_nested_guest_trap(vcpu_id, &vm_exit_info) => {
+                    match self.handle_nested_guest_trap(vcpu_id, &mut gprs) {
+                        Ok(inst_len) => {
+                            len = inst_len;
+                        }
+                        Err(err) => {
+                            return self.crash(
+                                vcpu_id,
+                                format_args!(
+                                    "Nested guest trap {:?} with error {:?}",
+                                    vm_exit_info, err
+                                ),
+                            )
+                        }
+                    }
+                    advance_pc = true;
+                }
                 VmExitInfo::Ecall(sbi_msg) => {
-                    let sbi_ret =
-                        self.sbi
-                            .handle_ecall(sbi_msg.extension, sbi_msg.function, sbi_msg.params);
+                    let sbi_ret = match self.handle_virtual_sbi(vcpu_id, &sbi_msg) {
+                        Some(sbi_ret) => sbi_ret,
+                        None => self.sbi.handle_ecall(
+                            sbi_msg.extension,
+                            sbi_msg.function,
+                            sbi_msg.params,
+                        ),
+                    };
                     // handle CSR operations to time extension
                     if sbi_msg.extension == rustsbi::spec::time::EID_TIME
                         && sbi_msg.function == rustsbi::spec::time::SET_TIMER
                     {
-                        CSR.hvip
-                            .read_and_clear_bits(traps::interrupt::VIRTUAL_SUPERVISOR_TIMER);
-                        //  Enable host timer interrupt
-                        CSR.sie
-                            .read_and_set_bits(traps::interrupt::SUPERVISOR_TIMER);
+                        // The interrupt and the host timer follow the deadline from the next
+                        // iteration on.
+                        self.sbi_timers[vcpu_id] = Some(sbi_msg.params[0] as u64);
+                        self.sbi_timer_owners[vcpu_id] = true;
                     }
                     gprs.set_reg(GprIndex::A0, sbi_ret.error);
                     gprs.set_reg(GprIndex::A1, sbi_ret.value);
@@ -94,28 +948,80 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
                                 len = inst_len;
                             }
                             Err(err) => {
-                                panic!(
-                                    "Page fault at {:#x} addr@{:#x} with error {:?}",
-                                    falut_pc, fault_addr, err
+                                return self.crash(
+                                    vcpu_id,
+                                    format_args!(
+                                        "Page fault at {:#x} addr@{:#x} with error {:?}",
+                                        falut_pc, fault_addr, err
+                                    ),
                                 )
                             }
                         }
                         advance_pc = true;
                     }
                     super::vmexit::PrivilegeLevel::User => {
-                        panic!("User page fault")
+                        return self.crash(vcpu_id, format_args!("User page fault"))
                     }
                 },
                 VmExitInfo::TimerInterruptEmulation => {
-                    // debug!("timer irq emulation");
-                    // Enable guest timer interrupt
-                    CSR.hvip
-                        .read_and_set_bits(traps::interrupt::VIRTUAL_SUPERVISOR_TIMER);
-                    // Clear host timer interrupt
-                    CSR.sie
-                        .read_and_clear_bits(traps::interrupt::SUPERVISOR_TIMER);
+                    // The host timer fired for the deadline of one of the vCPUs: the next
+                    // iteration raises its interrupt if it is this one and rearms the host timer.
                 }
                 VmExitInfo::ExternalInterruptEmulation => self.handle_irq(),
+                VmExitInfo::GuestExternalInterrupt => self.handle_guest_external_irq(),
+                VmExitInfo::VirtualInstruction { fault_pc, inst, .. } => {
+                    match self.handle_virtual_instruction(vcpu_id, fault_pc, inst, &mut gprs) {
+                        Ok(inst_len) => {
+                            len = inst_len;
+                        }
+                        Err(err) => {
+                            return self.crash(
+                                vcpu_id,
+                                format_args!(
+                                    "Virtual instruction {:#x} at {:#x} with error {:?}",
+                                    inst, fault_pc, err
+                                ),
+                            )
+                        }
+                    }
+                    advance_pc = true;
+                }
+                VmExitInfo::Debug { pc, tval } => {
+                    let hit = match self.debug.as_mut() {
+                        Some(debug) => debug.hit(&self.vm_pages, vcpu_id, pc, tval),
+                        None => Ok(BreakpointHit::Guest),
+                    };
+                    match hit {
+                        Ok(BreakpointHit::Stop) => {
+                            self.switch_out(vcpu_id, VmExitReason::Debug);
+                            return VmExitReason::Debug;
+                        }
+                        Ok(BreakpointHit::Retry) => {}
+                        Ok(BreakpointHit::Guest) => {
+                            let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+                            vcpu.inject_exception(EXC_BREAKPOINT, tval);
+                        }
+                        Err(err) => {
+                            return self.crash(
+                                vcpu_id,
+                                format_args!("Breakpoint at {:#x} with error {:?}", pc, err),
+                            )
+                        }
+                    }
+                }
+                VmExitInfo::UnhandledTrap {
+                    scause,
+                    sepc,
+                    stval,
+                } => {
+                    return self.crash(
+                        vcpu_id,
+                        format_args!(
+                            "Unhandled trap: {:#x}, sepc: {:#x}, stval: {:#x}",
+                            scause, sepc, stval
+                        ),
+                    )
+                }
                 _ => {}
             }
 
@@ -126,6 +1032,17 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
                     vcpu.advance_pc(len);
                 }
             }
+            if let Some(reason) = self.take_exit_request() {
+                self.switch_out(vcpu_id, reason);
+                self.stop(reason);
+                return reason;
+            }
+            let now = time::read() as u64;
+            if self.run_deadline.map_or(false, |d| now >= d) {
+                self.run_deadline = None;
+                self.switch_out(vcpu_id, VmExitReason::Preempted);
+                return VmExitReason::Preempted;
+            }
         }
     }
 }
@@ -139,23 +1056,45 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
         fault_addr: GuestPhysAddr,
         gprs: &mut GeneralPurposeRegisters,
     ) -> HyperResult<usize> {
-        //  plic
-        if fault_addr >= self.plic.base() && fault_addr < self.plic.base() + 0x0400_0000 {
-            self.handle_plic(inst_addr, inst, fault_addr, gprs)
+        // A store to a write-protected page whose writes are logged is retried once logged.
+        if let Some(log) = self
+            .dirty_logs
+            .iter_mut()
+            .find(|log| log.contains(fault_addr))
+        {
+            if log.log_write(&mut self.gpt, fault_addr)? {
+                return Ok(0);
+            }
+        }
+        //  plic or aplic
+        if self.irqchip.contains(fault_addr) {
+            self.handle_irqchip(inst_addr, inst, fault_addr, gprs)
+        } else if self
+            .regions
+            .find(fault_addr)
+            .map_or(false, |r| r.region_type() == VmRegionType::Imsic)
+        {
+            self.handle_imsic(inst_addr, inst, fault_addr, gprs)
+        } else if self
+            .regions
+            .find(fault_addr)
+            .map_or(false, |r| r.region_type() == VmRegionType::Mmio)
+        {
+            self.handle_mmio_device(inst_addr, inst, fault_addr, gprs)
         } else {
             error!("inst_addr: {:#x}, fault_addr: {:#x}", inst_addr, fault_addr);
             Err(HyperError::PageFault)
         }
     }
 
+    /// Decodes the load or store instruction `inst` at `inst_addr` which faulted on an emulated
+    /// MMIO region, returning its length and the decoded instruction.
     #[allow(clippy::needless_late_init)]
-    fn handle_plic(
-        &mut self,
+    fn decode_mmio_inst(
+        &self,
         inst_addr: GuestVirtAddr,
         mut inst: u32,
-        fault_addr: GuestPhysAddr,
-        gprs: &mut GeneralPurposeRegisters,
-    ) -> HyperResult<usize> {
+    ) -> HyperResult<(usize, Instruction)> {
         if inst == 0 {
             // If hinst does not provide information about trap,
             // we must read the instruction from guest's memory maunally.
@@ -166,32 +1105,657 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
         let inst = match len {
             2 => i1 as u32,
             4 => inst,
-            _ => unreachable!(),
+            // Longer encodings are not loads or stores.
+            _ => return Err(HyperError::DecodeError),
         };
         // assert!(len == 4);
         let decode_inst = riscv_decode::decode(inst).map_err(|_| HyperError::DecodeError)?;
+        Ok((len, decode_inst))
+    }
+
+    fn handle_irqchip(
+        &mut self,
+        inst_addr: GuestVirtAddr,
+        inst: u32,
+        fault_addr: GuestPhysAddr,
+        gprs: &mut GeneralPurposeRegisters,
+    ) -> HyperResult<usize> {
+        let (len, decode_inst) = self.decode_mmio_inst(inst_addr, inst)?;
         match decode_inst {
             Instruction::Sw(i) => {
                 let val = gprs.reg(GprIndex::from_raw(i.rs2()).unwrap()) as u32;
-                self.plic.write_u32(fault_addr, val)
+                self.irqchip.write_u32(fault_addr, val)
             }
             Instruction::Lw(i) => {
-                let val = self.plic.read_u32(fault_addr);
+                let val = self.irqchip.read_u32(fault_addr);
                 gprs.set_reg(GprIndex::from_raw(i.rd()).unwrap(), val as usize)
             }
             _ => return Err(HyperError::InvalidInstruction),
         }
+        self.sync_aplic();
         Ok(len)
     }
 
+    fn handle_imsic(
+        &mut self,
+        inst_addr: GuestVirtAddr,
+        inst: u32,
+        fault_addr: GuestPhysAddr,
+        gprs: &mut GeneralPurposeRegisters,
+    ) -> HyperResult<usize> {
+        let (len, decode_inst) = self.decode_mmio_inst(inst_addr, inst)?;
+        let (owner, (base, imsic)) = self
+            .imsics
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(owner, imsic)| Some((owner, imsic.as_mut()?)))
+            .find(|(_, (base, _))| (*base..*base + IMSIC_FILE_SIZE).contains(&fault_addr))
+            .ok_or(HyperError::NotFound)?;
+        let imsic = match imsic {
+            VcpuImsic::Software(imsic) => imsic,
+            // Hardware interrupt files are mapped and never fault.
+            VcpuImsic::Hardware { .. } => return Err(HyperError::BadState),
+        };
+        let offset = fault_addr - *base;
+        match decode_inst {
+            Instruction::Sw(i) => {
+                let val = gprs.reg(GprIndex::from_raw(i.rs2()).unwrap()) as u32;
+                imsic.write_u32(offset, val)
+            }
+            Instruction::Lw(i) => {
+                let val = imsic.read_u32(offset);
+                gprs.set_reg(GprIndex::from_raw(i.rd()).unwrap(), val as usize)
+            }
+            _ => return Err(HyperError::InvalidInstruction),
+        }
+        self.sync_imsic_irq(owner);
+        Ok(len)
+    }
+
+    fn handle_mmio_device(
+        &mut self,
+        inst_addr: GuestVirtAddr,
+        inst: u32,
+        fault_addr: GuestPhysAddr,
+        gprs: &mut GeneralPurposeRegisters,
+    ) -> HyperResult<usize> {
+        let (len, decode_inst) = self.decode_mmio_inst(inst_addr, inst)?;
+        let access = MmioAccess::decode(decode_inst, gprs)?;
+        let dev = self
+            .mmio_devices
+            .iter_mut()
+            .find(|dev| dev.contains(fault_addr))
+            .ok_or(HyperError::NotFound)?;
+        let offset = fault_addr - dev.base;
+        match access {
+            MmioAccess::Load { width, .. } => {
+                let val = dev.device.read(offset, width)?;
+                access.complete_load(gprs, val);
+            }
+            MmioAccess::Store { value, width } => dev.device.write(offset, width, value)?,
+        }
+        self.sync_device_irqs();
+        Ok(len)
+    }
+
+    fn vsock_ref(&self) -> HyperResult<&VsockHandle> {
+        self.vsock.as_ref().ok_or(HyperError::NotFound)
+    }
+
+    /// Lets the emulated devices make progress and updates their interrupt lines.
+    fn poll_devices(&mut self) {
+        for dev in self.mmio_devices.iter_mut() {
+            dev.device.poll();
+        }
+        self.sync_device_irqs();
+    }
+
+    /// Propagates the interrupt line levels of the emulated devices to the interrupt controller.
+    fn sync_device_irqs(&mut self) {
+        for dev in self.mmio_devices.iter() {
+            if let Some(irq) = dev.irq {
+                self.irqchip.set_irq_level(irq, dev.device.irq_level());
+            }
+        }
+        self.sync_aplic();
+    }
+
+    /// Handles the SBI calls of the extensions the hypervisor virtualizes. Returns `None` for
+    /// calls to be forwarded to the host's firmware.
+    fn handle_virtual_sbi(&mut self, vcpu_id: usize, msg: &HyperCallMsg) -> Option<SbiRet> {
+        if let Some(event) = Self::sbi_fw_event(msg) {
+            self.pmus[vcpu_id].record_fw_event(event);
+        }
+        match msg.extension {
+            EID_BASE => Some(base::handle(
+                msg.function,
+                &msg.params,
+                &self.sbi_identity,
+                |eid| self.probe_sbi_extension(eid),
+            )),
+            EID_DBCN => Some(dbcn::handle::<H>(
+                self.vm_id,
+                msg.function,
+                &msg.params,
+                &self.vm_pages,
+            )),
+            EID_HSM => {
+                let vcpus = &self.vcpus;
+                Some(hsm::handle(
+                    vcpu_id,
+                    msg.function,
+                    &msg.params,
+                    &mut self.harts,
+                    |hartid| vcpus.has_vcpu(hartid),
+                ))
+            }
+            EID_SUSP => Some(susp::handle(
+                vcpu_id,
+                msg.function,
+                &msg.params,
+                &mut self.harts,
+            )),
+            EID_PMU => Some(self.pmus[vcpu_id].handle(msg.function, &msg.params)),
+            EID_STA => Some(self.stas[vcpu_id].handle(msg.function, &msg.params, &self.vm_pages)),
+            EID_SRST => Some(match srst::handle(msg.function, &msg.params) {
+                Ok(reason) => {
+                    // The call doesn't return; the VM stops once this exit is handled.
+                    self.pending_exit = Some(reason);
+                    SbiRet::success(0)
+                }
+                Err(sbi_ret) => sbi_ret,
+            }),
+            _ => self.hypercalls.handle(vcpu_id, msg),
+        }
+    }
+
+    /// Returns whether the SBI extension `eid` is available to the guest.
+    fn probe_sbi_extension(&self, eid: usize) -> bool {
+        matches!(
+            eid,
+            EID_BASE
+                | EID_TIME
+                | EID_RFNC
+                | EID_HSM
+                | EID_DBCN
+                | EID_PMU
+                | EID_SRST
+                | EID_SUSP
+                | EID_STA
+        ) || self.hypercalls.has_extension(eid)
+    }
+
+    /// Returns the PMU firmware event counted for the SBI call `msg`, if any.
+    fn sbi_fw_event(msg: &HyperCallMsg) -> Option<FwEvent> {
+        match (msg.extension, msg.function) {
+            (EID_TIME, rustsbi::spec::time::SET_TIMER) => Some(FwEvent::SetTimer),
+            (EID_RFNC, rustsbi::spec::rfnc::REMOTE_FENCE_I) => Some(FwEvent::FenceISent),
+            (EID_RFNC, rustsbi::spec::rfnc::REMOTE_SFENCE_VMA) => Some(FwEvent::SfenceVmaSent),
+            (EID_RFNC, rustsbi::spec::rfnc::REMOTE_SFENCE_VMA_ASID) => {
+                Some(FwEvent::SfenceVmaAsidSent)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns a request to stop the VM made by the guest, through the SBI or an emulated
+    /// device, if any.
+    fn take_exit_request(&mut self) -> Option<VmExitReason> {
+        self.pending_exit.take().or_else(|| {
+            self.mmio_devices
+                .iter_mut()
+                .find_map(|dev| dev.device.take_exit_request())
+        })
+    }
+
+    /// Runs `f` with this VM's G-stage table installed on this hart, restoring the previous one
+    /// afterwards.
+    fn with_gstage<T>(&self, f: impl FnOnce() -> T) -> T {
+        let prev = CSR.hgatp.get_value();
+        CSR.hgatp.write_value(self.gpt.token());
+        unsafe { core::arch::riscv64::hfence_gvma_all() };
+        let val = f();
+        CSR.hgatp.write_value(prev);
+        unsafe { core::arch::riscv64::hfence_gvma_all() };
+        val
+    }
+
+    /// Restores the per-vCPU state of `vcpu_id` kept by the hypervisor as it is switched in on
+    /// this hart.
+    fn switch_in(&mut self, vcpu_id: usize) {
+        if let Ok(vcpu) = self.vcpus.get_vcpu(vcpu_id) {
+            vcpu.switch_in();
+        }
+        // The guest takes the interrupts of its hardware interrupt file directly.
+        if let Some((_, VcpuImsic::Hardware { file, .. })) = &self.imsics[vcpu_id] {
+            imsic::disable_notification(*file);
+        }
+        self.exit_stats[vcpu_id].switch_in();
+        self.pmus[vcpu_id].switch_in();
+        self.in_host_gstage(vcpu_id, |this| {
+            this.stas[vcpu_id].switch_in::<H>(&this.vm_pages)
+        });
+    }
+
+    /// Saves the per-vCPU state of `vcpu_id` kept by the hypervisor as it is switched out of this
+    /// hart, with `run` returning `reason`.
+    fn switch_out(&mut self, vcpu_id: usize, reason: VmExitReason) {
+        self.pmus[vcpu_id].switch_out();
+        let idle = matches!(reason, VmExitReason::Idle);
+        self.in_host_gstage(vcpu_id, |this| {
+            this.stas[vcpu_id].switch_out(&this.vm_pages, idle)
+        });
+        self.exit_stats[vcpu_id].switch_out();
+        if let Ok(vcpu) = self.vcpus.get_vcpu(vcpu_id) {
+            vcpu.switch_out();
+        }
+        // Interrupts arriving at the hardware interrupt file while the vCPU isn't resident are
+        // signalled to the hypervisor, to run it again.
+        if let Some((_, VcpuImsic::Hardware { file, .. })) = &self.imsics[vcpu_id] {
+            imsic::enable_notification(*file);
+        }
+    }
+
+    /// Raises or clears the external interrupt of `vcpu_id` according to its software interrupt
+    /// file, whether or not the vCPU is resident.
+    fn sync_imsic_irq(&mut self, vcpu_id: usize) {
+        if let Some((_, VcpuImsic::Software(imsic))) = &self.imsics[vcpu_id] {
+            if let Ok(vcpu) = self.vcpus.get_vcpu(vcpu_id) {
+                vcpu.set_pending_irqs(
+                    traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL,
+                    imsic.pending(),
+                );
+            }
+        }
+    }
+
+    /// Carries out a stop requested by the guest or the VMM. Powering off stops all the VM's vCPUs for
+    /// good, while a reboot returns them, the emulated devices and the interrupt controller to
+    /// their initial state and reloads the boot images.
+    fn stop(&mut self, reason: VmExitReason) {
+        match reason {
+            VmExitReason::Reboot => {
+                for dev in self.mmio_devices.iter_mut() {
+                    dev.device.reset();
+                }
+                self.irqchip.reset();
+                self.sync_aplic();
+                for (_, imsic) in self.imsics.iter_mut().flatten() {
+                    if let VcpuImsic::Software(imsic) = imsic {
+                        *imsic = SoftImsic::new();
+                    }
+                }
+                for (gpa, image) in self.boot_images.iter() {
+                    if let Err(err) = self.write_guest_memory(*gpa, image) {
+                        error!(
+                            "VM[{}] failed to reload boot image at {:#x}: {:?}",
+                            self.vm_id, gpa, err
+                        );
+                    }
+                }
+                self.pmus = Default::default();
+                self.stas = Default::default();
+                self.harts = Self::initial_harts();
+                self.sbi_timers = [None; VM_CPUS_MAX];
+                self.sbi_timer_owners = [false; VM_CPUS_MAX];
+                for vcpu_id in 0..VM_CPUS_MAX {
+                    if let Ok(vcpu) = self.vcpus.get_vcpu(vcpu_id) {
+                        vcpu.reset();
+                        if let Some(nested) = self.nested[vcpu_id].as_mut() {
+                            // Only fails when out of memory, leaving the vCPU without nested
+                            // virtualization.
+                            if nested.reset(vcpu.regs()).is_err() {
+                                self.nested[vcpu_id] = None;
+                            }
+                        }
+                    }
+                }
+            }
+            VmExitReason::Shutdown
+            | VmExitReason::Failure(_)
+            | VmExitReason::Crash
+            | VmExitReason::Migrated => self.stopped = Some(reason),
+            VmExitReason::Idle | VmExitReason::Debug | VmExitReason::Preempted => {}
+        }
+    }
+
+    /// Returns the HSM state of the vCPUs of a VM which has just been powered on: only the boot
+    /// vCPU runs, the others wait to be started through the SBI.
+    fn initial_harts() -> [HartState; VM_CPUS_MAX] {
+        let mut harts = [HartState::Stopped; VM_CPUS_MAX];
+        harts[0] = HartState::Started;
+        harts
+    }
+
+    /// Starts `vcpu_id` if another vCPU asked to, or resumes it if it is suspended and one of its
+    /// enabled interrupts is pending. Returns whether the vCPU can run.
+    fn wake_vcpu(&mut self, vcpu_id: usize) -> bool {
+        let resume = match self.harts[vcpu_id] {
+            HartState::Started => return true,
+            HartState::Stopped => return false,
+            HartState::StartPending(point) => Some(point),
+            HartState::Suspended(point) => {
+                // The VS-level bits of hip line up with the bits of vsie shifted by one.
+                let mut pending = CSR.hip.get_value() >> 1;
+                // The hardware interrupt file only reaches hip once the vCPU runs with it
+                // selected in hstatus.VGEIN.
+                if let Some((_, imsic @ VcpuImsic::Hardware { .. })) = &self.imsics[vcpu_id] {
+                    if imsic.pending() {
+                        pending |= traps::interrupt::SUPERVISOR_EXTERNAL;
+                    }
+                }
+                let pending = pending & CSR.vsie.get_value();
+                let wakeup = traps::interrupt::SUPERVISOR_SOFT
+                    | traps::interrupt::SUPERVISOR_TIMER
+                    | traps::interrupt::SUPERVISOR_EXTERNAL;
+                if pending & wakeup == 0 {
+                    return false;
+                }
+                point
+            }
+        };
+        self.harts[vcpu_id] = HartState::Started;
+        if let Some(point) = resume {
+            let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+            vcpu.restart_at(point.addr, point.opaque);
+        }
+        true
+    }
+
+    /// Applies the software interrupts that emulated devices raise directly at `vcpu_id` and its
+    /// timer interrupt, pending once its deadline is reached. The host timer is programmed for the
+    /// earliest deadline still ahead among the vCPUs, which time-share this hart, and the run
+    /// deadline.
+    fn sync_vcpu_irqs(&mut self, vcpu_id: usize) {
+        for dev in self.mmio_devices.iter_mut() {
+            if dev.device.take_vcpu_soft_irq(vcpu_id) {
+                CSR.hvip
+                    .read_and_set_bits(traps::interrupt::VIRTUAL_SUPERVISOR_SOFT);
+            }
+        }
+        let now = time::read() as u64;
+        if self.timer_deadline(vcpu_id).map_or(false, |d| now >= d) {
+            CSR.hvip
+                .read_and_set_bits(traps::interrupt::VIRTUAL_SUPERVISOR_TIMER);
+        } else {
+            CSR.hvip
+                .read_and_clear_bits(traps::interrupt::VIRTUAL_SUPERVISOR_TIMER);
+        }
+        let next = (0..VM_CPUS_MAX)
+            .filter_map(|id| self.timer_deadline(id))
+            .chain(self.run_deadline)
+            .filter(|&d| d > now && d != u64::MAX)
+            .min();
+        match next {
+            Some(next) => {
+                Forward.set_timer(next);
+                CSR.sie
+                    .read_and_set_bits(traps::interrupt::SUPERVISOR_TIMER);
+            }
+            None => {
+                CSR.sie
+                    .read_and_clear_bits(traps::interrupt::SUPERVISOR_TIMER);
+            }
+        }
+    }
+
+    /// Emulates the hypervisor instructions and CSRs of guest hypervisors, and the accesses to the
+    /// indirect IMSIC registers (`sireg`, `stopei`) of vCPUs whose interrupt file is emulated in
+    /// software. Returns the length of the instruction to skip, or 0 if an exception was raised
+    /// in the guest instead.
+    fn handle_virtual_instruction(
+        &mut self,
+        vcpu_id: usize,
+        inst_addr: GuestVirtAddr,
+        mut inst: u32,
+        gprs: &mut GeneralPurposeRegisters,
+    ) -> HyperResult<usize> {
+        if inst == 0 {
+            inst = self.vm_pages.fetch_guest_instruction(inst_addr)?;
+        }
+        if let Some(nested) = self.nested[vcpu_id].as_mut() {
+            let vcpu = self.vcpus.get_vcpu(vcpu_id)?;
+            match nested.emulate_inst(vcpu.regs(), inst, gprs, &self.vm_pages) {
+                Err(HyperError::NotSupported) => {}
+                result => return result,
+            }
+        }
+        let access = CsrAccess::decode(inst, gprs)?;
+        let imsic = match self.imsics.get_mut(vcpu_id) {
+            Some(Some((_, VcpuImsic::Software(imsic)))) => imsic,
+            _ => return Err(HyperError::NotSupported),
+        };
+        let iselect = CSR.vsiselect.get_value();
+        let old = match access.csr {
+            CSR_SIREG => imsic.read_ireg(iselect),
+            CSR_STOPEI => Ok(imsic.topei()),
+            _ => return Err(HyperError::NotSupported),
+        };
+        let result = old.and_then(|old| match access.new_value(old) {
+            Some(new) if access.csr == CSR_SIREG => imsic.write_ireg(iselect, new).map(|_| old),
+            Some(_) => {
+                // Any write to stopei claims the top interrupt.
+                imsic.claim_top();
+                Ok(old)
+            }
+            None => Ok(old),
+        });
+        let old = match result {
+            Ok(old) => old,
+            // The selected register does not exist.
+            Err(_) => {
+                let vcpu = self.vcpus.get_vcpu(vcpu_id)?;
+                vcpu.inject_exception(EXC_ILLEGAL_INSTRUCTION, inst as usize);
+                return Ok(0);
+            }
+        };
+        self.sync_imsic_irq(vcpu_id);
+        gprs.set_reg(access.rd, old);
+        Ok(4)
+    }
+
+    /// Drops the shadow G-stage tables of nested guests, which may grant writes we now log.
+    fn flush_nested_shadows(&mut self) -> HyperResult<()> {
+        for nested in self.nested.iter_mut().flatten() {
+            nested.flush_shadow()?;
+        }
+        Ok(())
+    }
+
+    /// Counts the exit `info` of `vcpu_id` in its statistics.
+    fn count_exit(&mut self, vcpu_id: usize, info: &VmExitInfo) {
+        let nested = self.is_nested_guest_trap(vcpu_id, info);
+        let region = match info {
+            VmExitInfo::PageFault { fault_addr, .. } => self.fault_region(*fault_addr),
+            _ => FaultRegion::Unmapped,
+        };
+        let stats = self.exit_stats[vcpu_id].stats_mut();
+        match info {
+            _ if nested => stats.nested_traps += 1,
+            VmExitInfo::Ecall(msg) => {
+                *stats
+                    .ecalls
+                    .entry((msg.extension, msg.function))
+                    .or_default() += 1
+            }
+            VmExitInfo::PageFault { .. } => *stats.page_faults.entry(region).or_default() += 1,
+            VmExitInfo::TimerInterruptEmulation => stats.timer_interrupts += 1,
+            VmExitInfo::ExternalInterruptEmulation => stats.external_interrupts += 1,
+            VmExitInfo::GuestExternalInterrupt => stats.guest_external_interrupts += 1,
+            VmExitInfo::VirtualInstruction { .. } => stats.virtual_instructions += 1,
+            VmExitInfo::Debug { .. } => stats.breakpoints += 1,
+            VmExitInfo::HostInterruot(_) | VmExitInfo::UnhandledTrap { .. } => stats.other += 1,
+        }
+    }
+
+    /// Returns what a guest page fault on `addr` hit, as `handle_page_fault` dispatches it.
+    fn fault_region(&self, addr: GuestPhysAddr) -> FaultRegion {
+        if self.irqchip.contains(addr) {
+            return FaultRegion::IrqChip;
+        }
+        match self.regions.find(addr) {
+            Some(r) if r.region_type() == VmRegionType::Imsic => FaultRegion::Imsic,
+            Some(r) if r.region_type() == VmRegionType::Mmio => FaultRegion::Mmio(r.start()),
+            Some(r) => FaultRegion::Memory(r.start()),
+            None => FaultRegion::Unmapped,
+        }
+    }
+
+    /// Stops the VM for good as `vcpu_id` hit a fault that cannot be handled for the reason
+    /// `args`, logging the crash report of the vCPU and its exit trace. Other VMs are unaffected.
+    fn crash(&mut self, vcpu_id: usize, args: core::fmt::Arguments) -> VmExitReason {
+        let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+        let report = CrashReport::new(vcpu_id, vcpu, &self.vm_pages, args.to_string());
+        error!("VM {} {}", self.vm_id, report);
+        self.dump_exit_trace(vcpu_id);
+        self.crash_report = Some(report);
+        self.switch_out(vcpu_id, VmExitReason::Crash);
+        self.stop(VmExitReason::Crash);
+        VmExitReason::Crash
+    }
+
+    /// Returns whether `info` is a trap taken by the nested guest running on `vcpu_id`, rather than
+    /// an interrupt of the host.
+    fn is_nested_guest_trap(&self, vcpu_id: usize, info: &VmExitInfo) -> bool {
+        let host_interrupt = matches!(
+            info,
+            VmExitInfo::TimerInterruptEmulation
+                | VmExitInfo::ExternalInterruptEmulation
+                | VmExitInfo::GuestExternalInterrupt
+                | VmExitInfo::HostInterruot(_)
+        );
+        !host_interrupt && self.nested[vcpu_id].as_ref().is_some_and(|n| n.is_virt())
+    }
+
+    /// Handles a trap taken by the nested guest running on `vcpu_id`, returning the length of the
+    /// instruction to skip.
+    fn handle_nested_guest_trap(
+        &mut self,
+        vcpu_id: usize,
+        gprs: &mut GeneralPurposeRegisters,
+    ) -> HyperResult<usize> {
+        let regs = self.vcpus.get_vcpu(vcpu_id)?.regs();
+        let (pc, mut inst) = (regs.guest_regs.sepc, regs.trap_csrs.htinst as u32);
+        // The guest hypervisor's G-stage tables are in its memory, read through our own table.
+        let exit = self.in_host_gstage(vcpu_id, |this| {
+            let nested = this.nested[vcpu_id].as_mut().ok_or(HyperError::BadState)?;
+            let regs = this.vcpus.get_vcpu(vcpu_id)?.regs();
+            nested.handle_guest_trap(regs, &this.vm_pages, &this.gpt)
+        })?;
+        match exit {
+            NestedExit::Resolved | NestedExit::Reflected => Ok(0),
+            // Accesses of the nested guest to our emulated devices are emulated as the guest
+            // hypervisor's own.
+            NestedExit::Mmio(gpa) => {
+                // The instruction is fetched through the nested guest's tables, still installed.
+                if inst == 0 {
+                    inst = self.vm_pages.fetch_guest_instruction(pc)?;
+                }
+                self.in_host_gstage(vcpu_id, |this| this.handle_page_fault(pc, inst, gpa, gprs))
+            }
+        }
+    }
+
+    /// Runs `f` with our G-stage table installed on this hart if the nested guest of `vcpu_id`
+    /// runs, so that the hypervisor accesses the guest hypervisor's memory rather than the nested
+    /// guest's. The table of the level the vCPU runs at next is installed again afterwards.
+    fn in_host_gstage<T>(&mut self, vcpu_id: usize, f: impl FnOnce(&mut Self) -> T) -> T {
+        if !self.nested[vcpu_id].as_ref().map_or(false, |n| n.is_virt()) {
+            return f(self);
+        }
+        CSR.hgatp.write_value(self.gpt.token());
+        unsafe { core::arch::riscv64::hfence_gvma_all() };
+        let val = f(self);
+        if let Some(nested) = self.nested[vcpu_id].as_ref() {
+            nested.load_gstage();
+        }
+        val
+    }
+
+    /// Handles a supervisor guest external interrupt. The interrupt files of our vCPUs are about
+    /// to become resident again, so their notifications are no longer needed.
+    fn handle_guest_external_irq(&mut self) {
+        let pending = imsic::pending_files();
+        for (_, imsic) in self.imsics.iter().flatten() {
+            if let VcpuImsic::Hardware { file, .. } = imsic {
+                if pending & (1 << file) != 0 {
+                    imsic::disable_notification(*file);
+                }
+            }
+        }
+    }
+
     fn handle_irq(&mut self) {
         let context_id = 1;
-        let claim_and_complete_addr = self.plic.base() + 0x0020_0004 + 0x1000 * context_id;
+        let claim_and_complete_addr = HOST_PLIC_BASE + 0x0020_0004 + 0x1000 * context_id;
         let irq = unsafe { core::ptr::read_volatile(claim_and_complete_addr as *const u32) };
-        assert!(irq != 0);
-        self.plic.claim_complete[context_id] = irq;
+        // Another context may have claimed the interrupt first.
+        if irq == 0 {
+            return;
+        }
+        match passthrough::irq_owner(irq) {
+            Some(owner) if owner != self.vm_id => {
+                if passthrough::defer_irq(owner, irq).is_err() {
+                    warn!("VM[{}] irq {} dropped: deferred queue full", owner, irq);
+                    unsafe {
+                        core::ptr::write_volatile(claim_and_complete_addr as *mut u32, irq);
+                    }
+                }
+            }
+            // Interrupts that are not assigned to any VM keep going to the running guest, which
+            // shares the host's device layout.
+            _ => self.inject_host_irq(irq),
+        }
+    }
 
-        CSR.hvip
-            .read_and_set_bits(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
+    /// Injects the interrupt `irq`, claimed from the host PLIC, into the guest.
+    fn inject_host_irq(&mut self, irq: u32) {
+        self.irqchip.inject_irq(irq);
+        self.sync_aplic();
+    }
+
+    /// Completes at the host PLIC the interrupts the APLIC is done with, as it has no completion
+    /// for the guest to forward, and forwards the MSIs it generated to the interrupt files of the
+    /// target vCPUs.
+    fn sync_aplic(&mut self) {
+        let aplic = match &mut self.irqchip {
+            IrqChip::Aplic(aplic) => aplic,
+            IrqChip::Plic(_) => return,
+        };
+        let context_id = 1;
+        let claim_and_complete_addr = HOST_PLIC_BASE + 0x0020_0004 + 0x1000 * context_id;
+        aplic.take_host_completions(|irq| unsafe {
+            core::ptr::write_volatile(claim_and_complete_addr as *mut u32, irq);
+        });
+        for msi in aplic.take_msis() {
+            match self.imsics.get_mut(msi.hart) {
+                Some(Some((_, imsic))) => {
+                    imsic.send_msi(msi.eiid);
+                    if let (VcpuImsic::Software(imsic), Ok(vcpu)) =
+                        (imsic, self.vcpus.get_vcpu(msi.hart))
+                    {
+                        vcpu.set_pending_irqs(
+                            traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL,
+                            imsic.pending(),
+                        );
+                    }
+                }
+                _ => warn!(
+                    "VM[{}] APLIC MSI to hart {} without IMSIC",
+                    self.vm_id, msi.hart
+                ),
+            }
+        }
+    }
+}
+
+impl<H: HyperCraftHal, G: GuestPageTableTrait> Drop for VM<H, G> {
+    fn drop(&mut self) {
+        passthrough::release_all(self.vm_id);
+        for (_, imsic) in self.imsics.iter().flatten() {
+            if let VcpuImsic::Hardware { hart_id, file, .. } = imsic {
+                imsic::free_guest_file(*hart_id, *file);
+            }
+        }
     }
 }
@@ -1,9 +1,15 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::arch::global_asm;
 
 use arrayvec::ArrayVec;
 use riscv_decode::Instruction;
+use spin::Mutex;
 
-use crate::{GuestPhysAddr, HyperError, HyperResult};
+use super::csrs::{RiscvCsrTrait, CSR};
+use crate::memory::PAGE_SIZE_4K;
+use crate::{GuestPhysAddr, HostPhysAddr, HyperError, HyperResult};
 global_asm!(include_str!("mem_extable.S"));
 
 extern "C" {
@@ -14,7 +20,7 @@ extern "C" {
 
 // Types of regions in a VM's guest physical address space.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
-enum VmRegionType {
+pub enum VmRegionType {
     // Memory that is private to this VM.
     Confidential,
     // Memory that is shared with the parent
@@ -49,11 +55,140 @@ pub struct VmRegionList {
     regions: ArrayVec<VmRegion, MAX_MEM_REGIONS>,
 }
 
+impl VmRegion {
+    /// Returns the type of this region.
+    pub fn region_type(&self) -> VmRegionType {
+        self.region_type
+    }
+
+    /// Returns the first guest physical address of this region.
+    pub fn start(&self) -> GuestPhysAddr {
+        self.start
+    }
+
+    /// Returns the size of this region in bytes.
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+impl VmRegionList {
+    /// Creates an empty region list.
+    pub const fn new() -> Self {
+        Self {
+            regions: ArrayVec::new_const(),
+        }
+    }
+
+    /// Adds the region `start..start + size` of type `region_type`. The region must not overlap
+    /// with existing ones.
+    pub fn add(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+        region_type: VmRegionType,
+    ) -> HyperResult<()> {
+        let end = start.checked_add(size).ok_or(HyperError::InvalidParam)?;
+        if size == 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        if self.regions.iter().any(|r| start < r.end && r.start < end) {
+            return Err(HyperError::BadState);
+        }
+        self.regions
+            .try_push(VmRegion {
+                start,
+                end,
+                region_type,
+            })
+            .map_err(|_| HyperError::NoMemory)
+    }
+
+    /// Returns the region containing `addr`, if any.
+    pub fn find(&self, addr: GuestPhysAddr) -> Option<&VmRegion> {
+        self.regions
+            .iter()
+            .find(|r| r.start <= addr && addr < r.end)
+    }
+
+    /// Returns an iterator over the regions of type `region_type`.
+    pub fn iter_type(&self, region_type: VmRegionType) -> impl Iterator<Item = &VmRegion> {
+        self.regions
+            .iter()
+            .filter(move |r| r.region_type == region_type)
+    }
+}
+
+impl Default for VmRegionList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A range of guest pages write-protected in the G-stage table to log the guest's writes. The
+/// hypervisor writes to them through their host physical address and logs the write itself.
+struct LoggedPages {
+    base: GuestPhysAddr,
+    hpas: Vec<HostPhysAddr>,
+    // Bit `i % 64` of word `i / 64` is set once the hypervisor wrote to page `i` of the range.
+    written: Vec<u64>,
+}
+
+impl LoggedPages {
+    fn contains(&self, gpa: GuestPhysAddr) -> bool {
+        gpa >= self.base && gpa < self.base + self.hpas.len() * PAGE_SIZE_4K
+    }
+
+    /// Writes `src`, which must not cross a page boundary, at `gpa`.
+    fn write(&mut self, gpa: GuestPhysAddr, src: &[u8]) {
+        let page = (gpa - self.base) / PAGE_SIZE_4K;
+        self.written[page / 64] |= 1 << (page % 64);
+        let hpa = self.hpas[page] + gpa % PAGE_SIZE_4K;
+        // Safety: the page is mapped to the guest, and so owned by the VM, while it is logged and
+        // `src` doesn't cross its end.
+        unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), hpa as *mut u8, src.len()) };
+    }
+}
+
 /// Represents the activate VM address space. Used to directly access a guest's memory.
-#[derive(Default)]
-pub struct VmPages;
+///
+/// Clones of a handle share the pages whose writes are logged, so that the writes made through
+/// any of them, e.g. by the VM's devices, are logged too.
+#[derive(Clone, Default)]
+pub struct VmPages {
+    logged: Arc<Mutex<Vec<LoggedPages>>>,
+}
 
 impl VmPages {
+    /// Starts logging the writes made through this handle to the pages from `base`, mapped to
+    /// the host pages `hpas` and write-protected in the G-stage table.
+    pub(crate) fn log_writes(&self, base: GuestPhysAddr, hpas: Vec<HostPhysAddr>) {
+        let written = vec![0; hpas.len().div_ceil(64)];
+        self.logged.lock().push(LoggedPages {
+            base,
+            hpas,
+            written,
+        });
+    }
+
+    /// Returns the bitmap of the pages from `base` written to through this handle since the last
+    /// call, and clears it.
+    pub(crate) fn take_logged_writes(&self, base: GuestPhysAddr) -> Vec<u64> {
+        let mut logged = self.logged.lock();
+        match logged.iter_mut().find(|range| range.base == base) {
+            Some(range) => {
+                let len = range.written.len();
+                core::mem::replace(&mut range.written, vec![0; len])
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Stops logging the writes to the pages from `base`.
+    pub(crate) fn stop_logging_writes(&self, base: GuestPhysAddr) {
+        self.logged.lock().retain(|range| range.base != base);
+    }
+
     /// Fetches and decodes the instruction at `pc` in the guest's virtual address.
     pub fn fetch_guest_instruction(&self, pc: GuestPhysAddr) -> HyperResult<u32> {
         let mut raw_inst = 0u32;
@@ -66,4 +201,102 @@ impl VmPages {
         // let inst = riscv_decode::decode(raw_inst).map_err(|_| HyperError::DecodeError)?;
         Ok(raw_inst)
     }
+
+    /// Copies `dest.len()` bytes from guest physical address `gpa` to `dest`.
+    pub fn copy_from_guest(&self, dest: &mut [u8], gpa: GuestPhysAddr) -> HyperResult<()> {
+        let _bare = BareVsatp::new();
+        // Safety: _copy_from_guest internally detects and handles an invalid guest physical
+        // address and will only write up to `dest.len()` bytes to `dest`.
+        let copied = unsafe { _copy_from_guest(dest.as_mut_ptr(), gpa, dest.len()) };
+        if copied != dest.len() {
+            return Err(HyperError::PageFault);
+        }
+        Ok(())
+    }
+
+    /// Copies `src` to guest physical address `gpa`.
+    pub fn copy_to_guest(&self, gpa: GuestPhysAddr, src: &[u8]) -> HyperResult<()> {
+        let mut logged = self.logged.lock();
+        if logged.is_empty() {
+            return self.copy_to_mapped_guest(gpa, src);
+        }
+        // Write-protected pages would fault, write them directly a page at a time.
+        let mut offset = 0;
+        while offset < src.len() {
+            let addr = gpa + offset;
+            let len = (PAGE_SIZE_4K - addr % PAGE_SIZE_4K).min(src.len() - offset);
+            let chunk = &src[offset..offset + len];
+            match logged.iter_mut().find(|range| range.contains(addr)) {
+                Some(range) => range.write(addr, chunk),
+                None => self.copy_to_mapped_guest(addr, chunk)?,
+            }
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// Copies `src` to guest physical address `gpa` through the G-stage table.
+    fn copy_to_mapped_guest(&self, gpa: GuestPhysAddr, src: &[u8]) -> HyperResult<()> {
+        let _bare = BareVsatp::new();
+        // Safety: _copy_to_guest internally detects and handles an invalid guest physical
+        // address and will only read up to `src.len()` bytes from `src`.
+        let copied = unsafe { _copy_to_guest(gpa, src.as_ptr(), src.len()) };
+        if copied != src.len() {
+            return Err(HyperError::PageFault);
+        }
+        Ok(())
+    }
+
+    /// Reads a little-endian `u16` from guest physical address `gpa`.
+    pub fn read_u16(&self, gpa: GuestPhysAddr) -> HyperResult<u16> {
+        let mut buf = [0u8; 2];
+        self.copy_from_guest(&mut buf, gpa)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    /// Reads a little-endian `u32` from guest physical address `gpa`.
+    pub fn read_u32(&self, gpa: GuestPhysAddr) -> HyperResult<u32> {
+        let mut buf = [0u8; 4];
+        self.copy_from_guest(&mut buf, gpa)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    /// Reads a little-endian `u64` from guest physical address `gpa`.
+    pub fn read_u64(&self, gpa: GuestPhysAddr) -> HyperResult<u64> {
+        let mut buf = [0u8; 8];
+        self.copy_from_guest(&mut buf, gpa)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    /// Writes `val` as a little-endian `u16` to guest physical address `gpa`.
+    pub fn write_u16(&self, gpa: GuestPhysAddr, val: u16) -> HyperResult<()> {
+        self.copy_to_guest(gpa, &val.to_le_bytes())
+    }
+
+    /// Writes `val` as a little-endian `u32` to guest physical address `gpa`.
+    pub fn write_u32(&self, gpa: GuestPhysAddr, val: u32) -> HyperResult<()> {
+        self.copy_to_guest(gpa, &val.to_le_bytes())
+    }
+
+    /// Writes `val` as a little-endian `u64` to guest physical address `gpa`.
+    pub fn write_u64(&self, gpa: GuestPhysAddr, val: u64) -> HyperResult<()> {
+        self.copy_to_guest(gpa, &val.to_le_bytes())
+    }
+}
+
+/// Disables VS-stage translation while alive, so that HLV/HSV take guest physical addresses.
+struct BareVsatp(usize);
+
+impl BareVsatp {
+    fn new() -> Self {
+        let vsatp = CSR.vsatp.get_value();
+        CSR.vsatp.write_value(0);
+        Self(vsatp)
+    }
+}
+
+impl Drop for BareVsatp {
+    fn drop(&mut self) {
+        CSR.vsatp.write_value(self.0);
+    }
 }
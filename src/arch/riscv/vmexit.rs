@@ -44,6 +44,8 @@ pub enum VmExitInfo {
     VirtualInstruction {
         /// Virtual instruction addr.
         fault_pc: GuestVirtAddr,
+        /// Virtual instruction, or 0 if the hardware does not report it in stval.
+        inst: u32,
         /// Virtual instruction privilege level.
         priv_level: PrivilegeLevel,
     },
@@ -54,4 +56,55 @@ pub enum VmExitInfo {
     TimerInterruptEmulation,
     /// An external interrupt for the running vCPU that can't be delegated and must be injected.
     ExternalInterruptEmulation,
+    /// A supervisor guest external interrupt: an IMSIC guest interrupt file enabled in `hgeie`
+    /// has a pending interrupt.
+    GuestExternalInterrupt,
+    /// A breakpoint exception, raised by a breakpoint instruction or a debug trigger. Only taken
+    /// from the guest while a debugger is attached, see `VM::debug_attach`.
+    Debug {
+        /// The address of the instruction the breakpoint is at.
+        pc: GuestVirtAddr,
+        /// The trap value: the address accessed for watchpoints.
+        tval: usize,
+    },
+    /// Any other trap. Fatal, unless the vCPU runs a nested guest whose hypervisor it is
+    /// reflected to.
+    UnhandledTrap {
+        /// The trap cause.
+        scause: usize,
+        /// The trapping instruction address.
+        sepc: GuestVirtAddr,
+        /// The trap value.
+        stval: usize,
+    },
+}
+
+/// The reason `VM::run` returned control to the VMM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmExitReason {
+    /// The guest powered the machine off.
+    Shutdown,
+    /// The guest rebooted the VM. Its vCPUs, emulated devices and interrupt controller are back
+    /// in their initial state and the images given to `VM::load_boot_image` reloaded; the VMM
+    /// restores any other guest memory it needs before running the VM again.
+    Reboot,
+    /// The guest powered the machine off reporting a failure, with the code it gave.
+    Failure(u32),
+    /// A vCPU hit a fault the hypervisor cannot handle. The VM is stopped for good and
+    /// `VM::crash_report` describes the state of the vCPU.
+    Crash,
+    /// The VM was migrated with `MigrationSource::complete` and is stopped for good, to resume on
+    /// the destination.
+    Migrated,
+    /// The vCPU is stopped or suspended through the SBI and has no interrupt pending. The VMM
+    /// may run other vCPUs and should run this one again once it may have been woken up, e.g. at
+    /// `VM::vcpu_wakeup_time` or when one of its devices has work.
+    Idle,
+    /// The vCPU stopped at a breakpoint or after a single step for the debugger attached with
+    /// `VM::debug_attach`. The VMM should stop running the VM's vCPUs and hand control to the
+    /// debugger.
+    Debug,
+    /// The deadline set with `VM::set_run_deadline` was reached. The vCPU can run again once
+    /// the VMM has done what it regained control for.
+    Preempted,
 }
@@ -24,6 +24,23 @@ pub trait HyperCraftHal: Sized {
     fn alloc_pages(num_pages: usize) -> Option<HostPhysAddr>;
     /// Gives back the allocated pages starts from `pa` to the page allocator.
     fn dealloc_pages(pa: HostPhysAddr, num_pages: usize);
+    /// Writes a byte output by the serial or SBI debug console of the VM `vm_id` to the host
+    /// console.
+    fn console_putchar(_vm_id: usize, _c: u8) {}
+    /// Reads a byte of input for the serial or SBI debug console of the VM `vm_id`, if one is
+    /// available.
+    fn console_getchar(_vm_id: usize) -> Option<u8> {
+        None
+    }
+    /// Returns the frequency of the `time` CSR in Hz.
+    fn timebase_frequency() -> u64 {
+        // The timebase of the QEMU virt machine.
+        10_000_000
+    }
+    /// Returns the wall-clock time in nanoseconds since the Unix epoch, as seen by guest RTCs.
+    fn wall_time_nanos() -> u64 {
+        0
+    }
     // /// VM-Exit handler
     // fn vmexit_handler(vcpu: &mut crate::VCpu<Self>, vm_exit_info: VmExitInfo);
 }
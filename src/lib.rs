@@ -42,7 +42,11 @@ mod vcpus;
 pub type HyperResult<T = ()> = Result<T, HyperError>;
 
 pub use arch::{
-    init_hv_runtime, GprIndex, HyperCallMsg, NestedPageTable, PerCpu, VCpu, VmExitInfo, VM,
+    init_hv_runtime, virtio, CrashReport, ExitRecord, ExitStats, ExitTrace, FaultRegion, GdbAction,
+    GdbConnection, GdbStub, GprIndex, HyperCallHandler, HyperCallMsg, MemoryTransport,
+    MigrationDestination, MigrationSource, MigrationTransport, MmioDevice, NestedPageTable,
+    PassthroughDevice, PerCpu, SbiIdentity, SnapshotReader, SnapshotWriter, TriggerKind, VCpu,
+    VmExitInfo, VmExitReason, VmPages, EID_HYPERCRAFT, EXIT_TRACE_LEN, VM,
 };
 
 pub use hal::HyperCraftHal;
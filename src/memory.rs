@@ -1,4 +1,4 @@
-use crate::{HyperCraftHal, HyperResult};
+use crate::{HyperCraftHal, HyperError, HyperResult};
 use page_table_entry::MappingFlags;
 
 /// Guest physical address.
@@ -48,6 +48,31 @@ pub trait GuestPageTableTrait {
     /// `gpa` maps to.
     fn translate(&self, gpa: GuestPhysAddr) -> HyperResult<HostPhysAddr>;
 
+    /// Query the host physical frame and the flags of the mapping of the
+    /// guest physical frame `gpa`. Only needed for dirty logging and nested
+    /// virtualization.
+    fn query(&self, gpa: GuestPhysAddr) -> HyperResult<(HostPhysAddr, MappingFlags)> {
+        let _ = gpa;
+        Err(HyperError::NotSupported)
+    }
+
+    /// Change the flags of the mapping of the guest physical frame `gpa` to
+    /// `flags`, keeping the host frame it maps to. The default implementation
+    /// maps the frame again, which only suits 4K mappings.
+    fn protect(&mut self, gpa: GuestPhysAddr, flags: MappingFlags) -> HyperResult<()> {
+        let (hpa, _) = self.query(gpa)?;
+        self.unmap(gpa)?;
+        self.map(gpa, hpa, flags)
+    }
+
+    /// Return whether the hardware set the dirty bit of the mapping of the
+    /// guest physical frame `gpa` (Svadu), and clear it. Only needed for
+    /// hardware dirty tracking.
+    fn test_and_clear_dirty(&mut self, gpa: GuestPhysAddr) -> HyperResult<bool> {
+        let _ = gpa;
+        Err(HyperError::NotSupported)
+    }
+
     /// Get guest page table token.
     fn token(&self) -> usize;
 }
@@ -34,6 +34,13 @@ impl<H: HyperCraftHal> VmCpus<H> {
         Ok(())
     }
 
+    /// Returns whether the vCPU with `vcpu_id` exists.
+    pub fn has_vcpu(&self, vcpu_id: usize) -> bool {
+        self.inner
+            .get(vcpu_id)
+            .is_some_and(|once| once.is_completed())
+    }
+
     /// Returns a reference to the vCPU with `vcpu_id` if it exists.
     pub fn get_vcpu(&mut self, vcpu_id: usize) -> HyperResult<&mut VCpu<H>> {
         let vcpu = self